@@ -1,5 +1,6 @@
 fn main() {
+    #[cfg(feature = "gui")]
     slint_build::compile("ui/appwindow.slint").unwrap();
-
-    
+    #[cfg(feature = "grpc")]
+    tonic_build::compile_protos("proto/rustyrabbit.proto").unwrap();
 }
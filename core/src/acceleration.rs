@@ -0,0 +1,325 @@
+#[cfg(feature = "cuda")]
+use crate::cuda;
+use anyhow::Result;
+use opencv::{
+    core::{self, Mat, Scalar, Size, UMat, UMatUsageFlags, BORDER_CONSTANT, CV_16SC2, CV_32FC1, CV_8UC3},
+    imgproc::{cvt_color, remap, resize, COLOR_BGR2RGBA, INTER_AREA, INTER_LINEAR},
+    prelude::*,
+};
+use std::{
+    sync::atomic::{AtomicU8, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Where color conversion, resizing and undistortion of frames run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    #[default]
+    Cpu,
+    /// Through OpenCV's transparent API on an OpenCL device
+    OpenCl,
+    /// On an NVIDIA GPU through OpenCV's CUDA modules, e.g. a Jetson. Only in builds with
+    /// the cuda feature.
+    Cuda,
+}
+
+impl Backend {
+    pub fn label(self) -> &'static str {
+        match self {
+            Backend::Cpu => "the CPU",
+            Backend::OpenCl => "OpenCL",
+            Backend::Cuda => "CUDA",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Backend::OpenCl,
+            2 => Backend::Cuda,
+            _ => Backend::Cpu,
+        }
+    }
+
+    /// Whether this build and machine can run it
+    pub fn available(self) -> bool {
+        match self {
+            Backend::Cpu => true,
+            Backend::OpenCl => core::have_opencl().unwrap_or(false),
+            #[cfg(feature = "cuda")]
+            Backend::Cuda => cuda::available(),
+            #[cfg(not(feature = "cuda"))]
+            Backend::Cuda => false,
+        }
+    }
+}
+
+static BACKEND: AtomicU8 = AtomicU8::new(Backend::Cpu as u8);
+
+/// Moves frame conversions to `requested` when it is there, otherwise they stay on the CPU,
+/// and returns where they run. The CPU unless asked for: copying each frame to the device and
+/// back can take longer than the work itself, [`benchmark`] tells.
+pub fn set_backend(requested: Backend) -> Backend {
+    let backend = if requested.available() {
+        requested
+    } else {
+        log::warn!("{} isn't available, converting frames on the CPU", requested.label());
+        Backend::Cpu
+    };
+    switch(backend);
+    match backend {
+        Backend::Cpu => {}
+        Backend::OpenCl => match core::Device::get_default().and_then(|device| device.name()) {
+            Ok(name) => log::info!("Converting frames with OpenCL on {}", name),
+            Err(_) => log::info!("Converting frames with OpenCL"),
+        },
+        Backend::Cuda => log::info!("Converting frames with CUDA"),
+    }
+    backend
+}
+
+pub fn backend() -> Backend {
+    Backend::from_u8(BACKEND.load(Ordering::Relaxed))
+}
+
+/// Threads OpenCV splits a single conversion or resize across on the CPU. It picks one per
+/// core on its own, fewer leave cores to detection and the preview on small machines.
+pub fn set_threads(threads: i32) -> Result<()> {
+    core::set_num_threads(threads)?;
+    log::info!("OpenCV runs on {} threads", core::get_num_threads()?);
+    Ok(())
+}
+
+fn switch(backend: Backend) {
+    BACKEND.store(backend as u8, Ordering::Relaxed);
+    // OpenCV's own OpenCL dispatch follows along, so nothing else ends up on the device
+    if let Err(e) = core::set_use_opencl(backend == Backend::OpenCl) {
+        log::warn!("Unable to switch OpenCL: {:?}", e);
+    }
+}
+
+// The first failure on the device moves frames back to the CPU for good, which redoes the work
+fn fallback(what: &str, e: opencv::Error) {
+    let backend = backend();
+    if backend != Backend::Cpu {
+        log::warn!("{} {} failed, converting frames on the CPU from now on: {:?}", backend.label(), what, e);
+        switch(Backend::Cpu);
+    }
+}
+
+/// Like `imgproc::cvt_color`, on the device when there is one
+pub fn convert_color(src: &Mat, dst: &mut Mat, code: i32) -> Result<()> {
+    let done = match backend() {
+        Backend::Cpu => return Ok(cvt_color(src, dst, code, 0)?),
+        Backend::OpenCl => on_opencl(src, dst, |src, dst| cvt_color(src, dst, code, 0)),
+        #[cfg(feature = "cuda")]
+        Backend::Cuda => cuda::convert_color(src, dst, code),
+        #[cfg(not(feature = "cuda"))]
+        Backend::Cuda => unreachable!("CUDA is never selected without the cuda feature"),
+    };
+    if let Err(e) = done {
+        fallback("color conversion", e);
+        cvt_color(src, dst, code, 0)?;
+    }
+    Ok(())
+}
+
+/// Like `imgproc::resize` to a fixed size, on the device when there is one
+pub fn resize_to(src: &Mat, dst: &mut Mat, size: Size, interpolation: i32) -> Result<()> {
+    let done = match backend() {
+        Backend::Cpu => return Ok(resize(src, dst, size, 0.0, 0.0, interpolation)?),
+        Backend::OpenCl => on_opencl(src, dst, |src, dst| resize(src, dst, size, 0.0, 0.0, interpolation)),
+        #[cfg(feature = "cuda")]
+        Backend::Cuda => cuda::resize_to(src, dst, size, interpolation),
+        #[cfg(not(feature = "cuda"))]
+        Backend::Cuda => unreachable!("CUDA is never selected without the cuda feature"),
+    };
+    if let Err(e) = done {
+        fallback("resizing", e);
+        resize(src, dst, size, 0.0, 0.0, interpolation)?;
+    }
+    Ok(())
+}
+
+/// Lookup tables for [`remap_with`], kept on the device as well once they have been used
+/// there so only the frames travel
+pub struct RemapMaps {
+    x: Mat,
+    y: Mat,
+    opencl: Option<(UMat, UMat)>,
+    #[cfg(feature = "cuda")]
+    cuda: Option<(core::GpuMat, core::GpuMat)>,
+}
+
+impl RemapMaps {
+    pub fn new(x: Mat, y: Mat) -> Self {
+        RemapMaps {
+            x,
+            y,
+            opencl: None,
+            #[cfg(feature = "cuda")]
+            cuda: None,
+        }
+    }
+
+    /// The map type to build tables in for the current backend: CUDA only takes floats, the
+    /// CPU and OpenCL are faster with the fixed point pair
+    pub fn map_type() -> i32 {
+        match backend() {
+            Backend::Cuda => CV_32FC1,
+            Backend::Cpu | Backend::OpenCl => CV_16SC2,
+        }
+    }
+}
+
+/// Like `imgproc::remap` with linear interpolation and black borders, on the device when
+/// there is one
+pub fn remap_with(src: &Mat, dst: &mut Mat, maps: &mut RemapMaps) -> Result<()> {
+    let done = match backend() {
+        Backend::Cpu => return remap_on_cpu(src, dst, maps),
+        Backend::OpenCl => remap_on_opencl(src, dst, maps),
+        #[cfg(feature = "cuda")]
+        Backend::Cuda => remap_on_cuda(src, dst, maps),
+        #[cfg(not(feature = "cuda"))]
+        Backend::Cuda => unreachable!("CUDA is never selected without the cuda feature"),
+    };
+    if let Err(e) = done {
+        fallback("undistortion", e);
+        remap_on_cpu(src, dst, maps)?;
+    }
+    Ok(())
+}
+
+fn remap_on_cpu(src: &Mat, dst: &mut Mat, maps: &RemapMaps) -> Result<()> {
+    remap(src, dst, &maps.x, &maps.y, INTER_LINEAR, BORDER_CONSTANT, Scalar::default())?;
+    Ok(())
+}
+
+fn remap_on_opencl(src: &Mat, dst: &mut Mat, maps: &mut RemapMaps) -> opencv::Result<()> {
+    let (map_x, map_y) = match maps.opencl.take() {
+        Some(device) => device,
+        None => (upload(&maps.x)?, upload(&maps.y)?),
+    };
+    let remapped = on_opencl(src, dst, |src, dst| {
+        remap(src, dst, &map_x, &map_y, INTER_LINEAR, BORDER_CONSTANT, Scalar::default())
+    });
+    maps.opencl = Some((map_x, map_y));
+    remapped
+}
+
+#[cfg(feature = "cuda")]
+fn remap_on_cuda(src: &Mat, dst: &mut Mat, maps: &mut RemapMaps) -> opencv::Result<()> {
+    let (map_x, map_y) = match maps.cuda.take() {
+        Some(device) => device,
+        None => (cuda::upload(&maps.x)?, cuda::upload(&maps.y)?),
+    };
+    let remapped = cuda::remap_with(src, dst, &map_x, &map_y);
+    maps.cuda = Some((map_x, map_y));
+    remapped
+}
+
+fn upload(mat: &Mat) -> opencv::Result<UMat> {
+    let mut umat = UMat::new(UMatUsageFlags::USAGE_DEFAULT)?;
+    mat.copy_to(&mut umat)?;
+    Ok(umat)
+}
+
+// Copies `src` to the device, runs `work` there and copies the result back into `dst`
+fn on_opencl(
+    src: &Mat,
+    dst: &mut Mat,
+    work: impl FnOnce(&UMat, &mut UMat) -> opencv::Result<()>,
+) -> opencv::Result<()> {
+    let src = upload(src)?;
+    let mut result = UMat::new(UMatUsageFlags::USAGE_DEFAULT)?;
+    work(&src, &mut result)?;
+    result.copy_to(dst)
+}
+
+/// Mean time per frame of the conversions a camera frame goes through on each backend, None
+/// for those that aren't there
+#[derive(Debug, Clone, Copy)]
+pub struct Benchmark {
+    pub frame_size: Size,
+    pub cpu: Duration,
+    pub opencl: Option<Duration>,
+    pub cuda: Option<Duration>,
+}
+
+impl Benchmark {
+    pub fn summary(&self) -> String {
+        let ms = |took: Option<Duration>| match took {
+            Some(took) => format!("{:.2} ms", took.as_secs_f64() * 1000.0),
+            None => "not available".to_string(),
+        };
+        let fastest = [(Backend::OpenCl, self.opencl), (Backend::Cuda, self.cuda)]
+            .into_iter()
+            .filter_map(|(backend, took)| Some((backend, took?)))
+            .filter(|&(_, took)| took < self.cpu)
+            .min_by_key(|&(_, took)| took);
+        format!(
+            "{}x{} per frame: CPU {}, OpenCL {}, CUDA {}. {}",
+            self.frame_size.width,
+            self.frame_size.height,
+            ms(Some(self.cpu)),
+            ms(self.opencl),
+            ms(self.cuda),
+            match fastest {
+                Some((Backend::Cuda, _)) => "Use --cuda",
+                Some(_) => "Use --opencl",
+                None => "Stay on the CPU",
+            }
+        )
+    }
+}
+
+/// Times `frames` rounds of halving, converting and undistorting a frame of `frame_size` on
+/// every backend there is. Leaves the backend as it was.
+pub fn benchmark(frame_size: Size, frames: u32) -> Result<Benchmark> {
+    let was = backend();
+    // The pixel values make no difference to how long any of this takes
+    let bgr = Mat::new_size_with_default(frame_size, CV_8UC3, Scalar::new(40.0, 120.0, 200.0, 0.0))?;
+    // Identity maps, remapping costs the same whatever the lens
+    let mut map_x = Mat::new_size_with_default(frame_size, CV_32FC1, Scalar::default())?;
+    let mut map_y = Mat::new_size_with_default(frame_size, CV_32FC1, Scalar::default())?;
+    for row in 0..frame_size.height {
+        for col in 0..frame_size.width {
+            *map_x.at_2d_mut::<f32>(row, col)? = col as f32;
+            *map_y.at_2d_mut::<f32>(row, col)? = row as f32;
+        }
+    }
+    let half = Size::new(frame_size.width / 2, frame_size.height / 2);
+
+    let run = |backend: Backend| -> Result<Option<Duration>> {
+        if !backend.available() {
+            return Ok(None);
+        }
+        switch(backend);
+        let mut maps = RemapMaps::new(map_x.try_clone()?, map_y.try_clone()?);
+        let (mut scaled, mut rgba, mut undistorted) = (Mat::default(), Mat::default(), Mat::default());
+        // One round first, kernels compile and tables are uploaded on first use
+        let rounds = frames.max(1);
+        let mut started = Instant::now();
+        for round in 0..=rounds {
+            if round == 1 {
+                started = Instant::now();
+            }
+            resize_to(&bgr, &mut scaled, half, INTER_AREA)?;
+            convert_color(&scaled, &mut rgba, COLOR_BGR2RGBA)?;
+            remap_with(&bgr, &mut undistorted, &mut maps)?;
+        }
+        let took = started.elapsed() / rounds;
+        // Unless a step failed and fell back to the CPU
+        Ok((backend() == backend).then_some(took))
+    };
+
+    let cpu = run(Backend::Cpu)?.unwrap_or_default();
+    let opencl = run(Backend::OpenCl)?;
+    let cuda = run(Backend::Cuda)?;
+    switch(was);
+    Ok(Benchmark {
+        frame_size,
+        cpu,
+        opencl,
+        cuda,
+    })
+}
@@ -0,0 +1,190 @@
+use crate::{
+    bayer::{self, BayerConfig, BayerPattern, Demosaic},
+    error::CameraError,
+    source::{Control, FrameSource, SourceMetadata},
+};
+use anyhow::{bail, Context, Result};
+use aravis::{Aravis, Buffer, BufferExt, Camera, CameraExt, Stream, StreamExt};
+use opencv::{
+    core::{Mat, Size},
+    imgproc::{cvt_color, COLOR_GRAY2BGR, COLOR_RGB2BGR},
+    prelude::*,
+};
+
+// Buffers queued on the stream, enough to ride out a slow UI frame
+const STREAM_BUFFERS: usize = 8;
+const POP_TIMEOUT_US: u64 = 200_000;
+
+/// GenICam features we expose, anything left as None keeps the camera's current value
+#[derive(Debug, Default, Clone)]
+pub struct AravisSettings {
+    pub exposure_us: Option<f64>,
+    pub gain_db: Option<f64>,
+    pub pixel_format: Option<String>,
+    pub trigger_source: Option<String>,
+    pub demosaic: Demosaic,
+}
+
+/// GigE Vision / USB3 Vision camera driven natively through Aravis, so the GenICam
+/// feature set (exposure, trigger, pixel format) stays under our control
+pub struct AravisSource {
+    camera: Camera,
+    stream: Stream,
+    size: Size,
+    fps: f64,
+    demosaic: Demosaic,
+}
+
+impl AravisSource {
+    pub fn open(device_id: Option<&str>, settings: &AravisSettings) -> Result<Self> {
+        let aravis = Aravis::initialize().context("Unable to initialize Aravis")?;
+        let camera = aravis
+            .get_camera(device_id)
+            .with_context(|| format!("Unable to open Aravis camera {}", device_id.unwrap_or("(first found)")))?;
+
+        if let Some(format) = &settings.pixel_format {
+            camera.set_pixel_format_from_string(format)?;
+        }
+        if let Some(exposure) = settings.exposure_us {
+            camera.set_exposure_time_auto(aravis::Auto::Off)?;
+            camera.set_exposure_time(exposure)?;
+        }
+        if let Some(gain) = settings.gain_db {
+            camera.set_gain_auto(aravis::Auto::Off)?;
+            camera.set_gain(gain)?;
+        }
+        match &settings.trigger_source {
+            Some(source) => camera.set_trigger(source)?,
+            None => camera.clear_triggers()?,
+        }
+
+        let (_, _, width, height) = camera.region()?;
+        let fps = camera.frame_rate().unwrap_or(30.0);
+
+        let stream = camera.create_stream()?;
+        let payload = camera.payload()? as usize;
+        for _ in 0..STREAM_BUFFERS {
+            stream.push_buffer(&Buffer::new_allocate(payload));
+        }
+        camera.start_acquisition()?;
+
+        log::info!(
+            "Aravis camera: {} {}, {}x{} @ {} FPS",
+            camera.vendor_name().unwrap_or_default(),
+            camera.model_name().unwrap_or_default(),
+            width,
+            height,
+            fps
+        );
+
+        Ok(AravisSource {
+            camera,
+            stream,
+            size: Size::new(width, height),
+            fps,
+            demosaic: settings.demosaic,
+        })
+    }
+}
+
+impl FrameSource for AravisSource {
+    fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        let Some(buffer) = self.stream.timeout_pop_buffer(POP_TIMEOUT_US) else {
+            return Ok(false);
+        };
+
+        let result = if buffer.status() == aravis::BufferStatus::Success {
+            to_bgr(&buffer, self.demosaic, frame).map(|_| true)
+        } else {
+            Ok(false)
+        };
+
+        // Hand the buffer back to the stream whatever happened
+        self.stream.push_buffer(&buffer);
+        result
+    }
+
+    fn metadata(&self) -> Result<SourceMetadata> {
+        Ok(SourceMetadata {
+            size: self.size,
+            fps: self.fps,
+            live: true,
+        })
+    }
+
+    fn trigger(&mut self) -> Result<()> {
+        self.camera.software_trigger()?;
+        Ok(())
+    }
+
+    // Exposure time in microseconds
+    fn exposure(&self) -> Option<f64> {
+        self.camera.exposure_time().ok()
+    }
+
+    // GenICam feature names, in the camera's units (exposure in microseconds, gain in dB)
+    fn set_control(&mut self, control: Control, value: f64) -> Result<bool> {
+        match control {
+            Control::Exposure => {
+                self.camera.set_exposure_time_auto(aravis::Auto::Off)?;
+                self.camera.set_exposure_time(value)?;
+            }
+            Control::Gain => {
+                self.camera.set_gain_auto(aravis::Auto::Off)?;
+                self.camera.set_gain(value)?;
+            }
+            Control::Focus | Control::WhiteBalance => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for AravisSource {
+    fn drop(&mut self) {
+        if let Err(e) = self.camera.stop_acquisition() {
+            log::error!("Error stopping Aravis acquisition: {:?}", e);
+        }
+    }
+}
+
+fn to_bgr(buffer: &Buffer, demosaic: Demosaic, frame: &mut Mat) -> Result<()> {
+    let width = buffer.image_width();
+    let height = buffer.image_height();
+    let data = buffer.data();
+    let format = buffer.image_pixel_format();
+
+    let bayer = |pattern| {
+        Some(BayerConfig {
+            pattern,
+            algorithm: demosaic,
+        })
+    };
+    let (channels, conversion, bayer) = match format {
+        aravis::PixelFormat::MONO_8 => (1, Some(COLOR_GRAY2BGR), None),
+        aravis::PixelFormat::BAYER_RG_8 => (1, None, bayer(BayerPattern::Rggb)),
+        aravis::PixelFormat::BAYER_BG_8 => (1, None, bayer(BayerPattern::Bggr)),
+        aravis::PixelFormat::BAYER_GR_8 => (1, None, bayer(BayerPattern::Grbg)),
+        aravis::PixelFormat::BAYER_GB_8 => (1, None, bayer(BayerPattern::Gbrg)),
+        aravis::PixelFormat::RGB_8_PACKED => (3, Some(COLOR_RGB2BGR), None),
+        aravis::PixelFormat::BGR_8_PACKED => (3, None, None),
+        other => {
+            return Err(CameraError::UnsupportedFormat {
+                device: "The Aravis camera".to_string(),
+                format: format!("{:?}", other),
+            }
+            .into())
+        }
+    };
+
+    let raw = Mat::from_slice(data)?;
+    let raw = raw.reshape(channels, height)?;
+    if raw.cols() != width {
+        bail!("Aravis buffer size does not match {}x{}", width, height);
+    }
+    match (conversion, bayer) {
+        (_, Some(config)) => bayer::demosaic(&raw, height, config, frame)?,
+        (Some(code), None) => cvt_color(&raw, frame, code, 0)?,
+        (None, None) => raw.copy_to(frame)?,
+    }
+    Ok(())
+}
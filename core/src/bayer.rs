@@ -0,0 +1,78 @@
+use crate::acceleration;
+use anyhow::{bail, Result};
+use opencv::{core::Mat, imgproc::*, prelude::*};
+
+/// Colour filter layout of the sensor, named by its top-left 2x2 block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum BayerPattern {
+    Rggb,
+    Bggr,
+    Grbg,
+    Gbrg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Demosaic {
+    // Fast, slight zipper artifacts on edges
+    #[default]
+    Bilinear,
+    // Variable number of gradients, slower but no sharpening halos
+    Vng,
+    // Edge aware interpolation
+    EdgeAware,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BayerConfig {
+    pub pattern: BayerPattern,
+    pub algorithm: Demosaic,
+}
+
+impl BayerPattern {
+    /// V4L2 fourcc for the 8 bit variant of this pattern
+    pub fn fourcc(self) -> [char; 4] {
+        match self {
+            BayerPattern::Rggb => ['R', 'G', 'G', 'B'],
+            BayerPattern::Bggr => ['B', 'A', '8', '1'],
+            BayerPattern::Grbg => ['G', 'R', 'B', 'G'],
+            BayerPattern::Gbrg => ['G', 'B', 'R', 'G'],
+        }
+    }
+}
+
+// OpenCV names Bayer codes after the second row's pair, not the top-left block
+fn conversion_code(config: BayerConfig) -> i32 {
+    use BayerPattern::*;
+    use Demosaic::*;
+    match (config.pattern, config.algorithm) {
+        (Rggb, Bilinear) => COLOR_BayerBG2BGR,
+        (Bggr, Bilinear) => COLOR_BayerRG2BGR,
+        (Grbg, Bilinear) => COLOR_BayerGB2BGR,
+        (Gbrg, Bilinear) => COLOR_BayerGR2BGR,
+        (Rggb, Vng) => COLOR_BayerBG2BGR_VNG,
+        (Bggr, Vng) => COLOR_BayerRG2BGR_VNG,
+        (Grbg, Vng) => COLOR_BayerGB2BGR_VNG,
+        (Gbrg, Vng) => COLOR_BayerGR2BGR_VNG,
+        (Rggb, EdgeAware) => COLOR_BayerBG2BGR_EA,
+        (Bggr, EdgeAware) => COLOR_BayerRG2BGR_EA,
+        (Grbg, EdgeAware) => COLOR_BayerGB2BGR_EA,
+        (Gbrg, EdgeAware) => COLOR_BayerGR2BGR_EA,
+    }
+}
+
+/// Debayers a single channel raw frame into BGR. Backends that hand back the raw buffer
+/// as one long row get reshaped to `height` rows first.
+pub fn demosaic(raw: &Mat, height: i32, config: BayerConfig, bgr: &mut Mat) -> Result<()> {
+    if raw.channels() != 1 {
+        bail!("Expected a single channel raw Bayer frame, got {} channels", raw.channels());
+    }
+    if raw.rows() == 1 && height > 1 {
+        let raw = raw.reshape(1, height)?;
+        acceleration::convert_color(&raw, bgr, conversion_code(config))?;
+    } else {
+        acceleration::convert_color(raw, bgr, conversion_code(config))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,309 @@
+use crate::{
+    acceleration::{self, Backend},
+    calibration,
+    frame::{Frame, FrameBuffer},
+    geometry::FrameGeometry,
+    source::SourceSpec,
+    target::{self, CalibrationTarget},
+    target_print::{self, TargetKind, TargetSpec},
+};
+use anyhow::{bail, Result};
+use opencv::{
+    calib3d::project_points_def,
+    core::{copy_make_border, Mat, Point2f, Point3f, Scalar, Size, Vector, BORDER_CONSTANT},
+    imgproc::{resize, COLOR_GRAY2RGBA, INTER_AREA},
+    prelude::*,
+};
+use serde::Serialize;
+use std::{
+    env::consts::{ARCH, OS},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+
+/// Frame sizes conversion and detection are timed at, from VGA to 4K
+pub const RESOLUTIONS: [(i32, i32); 4] = [(640, 480), (1280, 720), (1920, 1080), (3840, 2160)];
+// Frames read from the camera before timing, while exposure and buffers settle
+const CAPTURE_WARMUP: u32 = 10;
+// Dots per inch the targets are rendered at before they are fitted into a frame
+const TARGET_DPI: f64 = 200.0;
+// Share of the frame's width or height the target fills, about as far as a calibration holds it
+const TARGET_FILL: f64 = 0.6;
+const BACKGROUND: f64 = 128.0;
+// Views of the board the solver is timed on, more than a calibration usually takes
+const SOLVER_VIEWS: usize = 20;
+// Most solves timed, one takes as long as hundreds of detections
+const SOLVER_ROUNDS: u32 = 5;
+
+/// Timings of every stage a calibration goes through on this machine, as written by
+/// `rustyrabbit bench`. Times are means over the rounds in milliseconds.
+#[derive(Debug, Clone, Serialize)]
+pub struct Report {
+    pub host: Host,
+    pub rounds: u32,
+    /// None when the camera didn't open or deliver, see `capture_error`
+    pub capture: Option<CaptureTiming>,
+    pub capture_error: Option<String>,
+    pub conversion: Vec<ConversionTiming>,
+    pub detection: Vec<DetectionTiming>,
+    pub solver: SolverTiming,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Host {
+    pub os: &'static str,
+    pub arch: &'static str,
+    pub cpus: usize,
+    pub opencv: String,
+    pub opencl: bool,
+    pub cuda: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CaptureTiming {
+    pub source: String,
+    pub width: i32,
+    pub height: i32,
+    pub frames: u32,
+    pub fps: f64,
+    pub read_ms: f64,
+}
+
+/// Halving, converting and undistorting one frame, None on a backend that isn't there
+#[derive(Debug, Clone, Serialize)]
+pub struct ConversionTiming {
+    pub width: i32,
+    pub height: i32,
+    pub cpu_ms: f64,
+    pub opencl_ms: Option<f64>,
+    pub cuda_ms: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DetectionTiming {
+    pub target: String,
+    pub width: i32,
+    pub height: i32,
+    pub detect_ms: f64,
+    /// Whether the target was found, a time without it only says how long a miss takes
+    pub found: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SolverTiming {
+    pub rounds: u32,
+    pub views: usize,
+    pub points_per_view: usize,
+    pub solve_ms: f64,
+}
+
+/// Times the camera `source`, conversion and detection of the built-in targets at every
+/// one of [`RESOLUTIONS`], and the solver, each over `rounds`. A camera that doesn't open
+/// is reported and the rest timed all the same, the numbers are for choosing hardware.
+pub fn run(source: &SourceSpec, rounds: u32) -> Result<Report> {
+    let rounds = rounds.max(1);
+    let host = Host {
+        os: OS,
+        arch: ARCH,
+        cpus: thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+        opencv: opencv::core::get_version_string()?,
+        opencl: Backend::OpenCl.available(),
+        cuda: Backend::Cuda.available(),
+    };
+
+    log::info!("Timing capture from {}", source.describe());
+    let (capture, capture_error) = match capture(source, rounds) {
+        Ok(timing) => (Some(timing), None),
+        Err(e) => {
+            log::warn!("Not timing capture: {:?}", e);
+            (None, Some(e.to_string()))
+        }
+    };
+
+    let mut conversion = Vec::new();
+    for (width, height) in RESOLUTIONS {
+        log::info!("Timing conversion at {}x{}", width, height);
+        let timing = acceleration::benchmark(Size::new(width, height), rounds)?;
+        conversion.push(ConversionTiming {
+            width,
+            height,
+            cpu_ms: ms(timing.cpu),
+            opencl_ms: timing.opencl.map(ms),
+            cuda_ms: timing.cuda.map(ms),
+        });
+    }
+
+    let mut detection = Vec::new();
+    for spec in targets() {
+        let target = target::from_spec(&spec)?;
+        let printed = target_print::raster(&spec, TARGET_DPI)?;
+        for (width, height) in RESOLUTIONS {
+            log::info!("Timing detection of the {} at {}x{}", target.describe(), width, height);
+            detection.push(detect(target.as_ref(), &printed, Size::new(width, height), rounds)?);
+        }
+    }
+
+    log::info!("Timing the solver");
+    let solver = solve(rounds)?;
+    Ok(Report {
+        host,
+        rounds,
+        capture,
+        capture_error,
+        conversion,
+        detection,
+        solver,
+    })
+}
+
+fn ms(took: Duration) -> f64 {
+    took.as_secs_f64() * 1000.0
+}
+
+// Each built-in target at a common size
+fn targets() -> [TargetSpec; 3] {
+    [
+        TargetSpec {
+            kind: TargetKind::ChessBoard,
+            rows: 6,
+            cols: 9,
+            square_mm: 25.0,
+        },
+        TargetSpec {
+            kind: TargetKind::CircleGrid,
+            rows: 11,
+            cols: 4,
+            square_mm: 20.0,
+        },
+        TargetSpec {
+            kind: TargetKind::Charuco {
+                dictionary: "DICT_6X6_250".to_string(),
+            },
+            rows: 6,
+            cols: 9,
+            square_mm: 25.0,
+        },
+    ]
+}
+
+fn capture(spec: &SourceSpec, rounds: u32) -> Result<CaptureTiming> {
+    let mut source = spec.open()?;
+    let mut frame = Mat::default();
+    for _ in 0..CAPTURE_WARMUP {
+        if !source.read(&mut frame)? {
+            bail!("The source ran out while warming up");
+        }
+    }
+    let started = Instant::now();
+    let mut frames = 0;
+    while frames < rounds && source.read(&mut frame)? {
+        frames += 1;
+    }
+    if frames == 0 {
+        bail!("The source delivered no frames");
+    }
+    let took = started.elapsed();
+    let size = frame.size()?;
+    Ok(CaptureTiming {
+        source: spec.describe(),
+        width: size.width,
+        height: size.height,
+        frames,
+        fps: frames as f64 / took.as_secs_f64(),
+        read_ms: ms(took / frames),
+    })
+}
+
+fn detect(target: &dyn CalibrationTarget, printed: &Mat, size: Size, rounds: u32) -> Result<DetectionTiming> {
+    let frame = frame_with(printed, size)?;
+    // Once first, the grey copy is made then and cached by the frame as in the pipeline
+    let found = target.detect(&frame)?.is_some();
+    let started = Instant::now();
+    for _ in 0..rounds {
+        target.detect(&frame)?;
+    }
+    Ok(DetectionTiming {
+        target: target.describe(),
+        width: size.width,
+        height: size.height,
+        detect_ms: ms(started.elapsed() / rounds),
+        found,
+    })
+}
+
+// A frame of `size` with the printed target in the middle, square on to the camera
+fn frame_with(printed: &Mat, size: Size) -> Result<Frame> {
+    let fit = (TARGET_FILL * size.width as f64 / printed.cols() as f64)
+        .min(TARGET_FILL * size.height as f64 / printed.rows() as f64);
+    let fitted_size = Size::new((printed.cols() as f64 * fit) as i32, (printed.rows() as f64 * fit) as i32);
+    let mut fitted = Mat::default();
+    resize(printed, &mut fitted, fitted_size, 0.0, 0.0, INTER_AREA)?;
+    let (left, top) = ((size.width - fitted_size.width) / 2, (size.height - fitted_size.height) / 2);
+    let (right, bottom) = (size.width - fitted_size.width - left, size.height - fitted_size.height - top);
+    let mut gray = Mat::default();
+    let background = Scalar::all(BACKGROUND);
+    copy_make_border(&fitted, &mut gray, top, bottom, left, right, BORDER_CONSTANT, background)?;
+    let mut rgba = Mat::default();
+    acceleration::convert_color(&gray, &mut rgba, COLOR_GRAY2RGBA)?;
+    Ok(Frame {
+        data: Arc::new(FrameBuffer::copy_of(rgba.data_bytes()?)),
+        width: size.width,
+        height: size.height,
+        captured_at: Instant::now(),
+        timestamp: OffsetDateTime::now_utc(),
+        sequence: 1,
+        exposure: None,
+        source_id: Arc::from("bench"),
+        geometry: FrameGeometry::full_sensor(size),
+        gray: Default::default(),
+    })
+}
+
+// Solves the views a 9x6 chess board would give in a 1080p camera, projected exactly but
+// for a little noise
+fn solve(rounds: u32) -> Result<SolverTiming> {
+    let size = Size::new(1920, 1080);
+    let focal = 0.9 * size.width as f64;
+    let camera_matrix = Mat::from_slice_2d(&[
+        [focal, 0.0, size.width as f64 / 2.0],
+        [0.0, focal, size.height as f64 / 2.0],
+        [0.0, 0.0, 1.0],
+    ])?;
+    let board = target::ChessBoard::new(9, 6, 25.0);
+    let points = board.object_points();
+    let mut object_points = Vector::<Vector<Point3f>>::new();
+    let mut image_points = Vector::<Vector<Point2f>>::new();
+    for view in 0..SOLVER_VIEWS {
+        let t = view as f64;
+        let rvec = Mat::from_slice_2d(&[[0.4 * (t * 0.9).sin(), 0.4 * (t * 0.7).cos(), 0.15 * (t * 1.3).sin()]])?;
+        let tvec = Mat::from_slice_2d(&[[-100.0 + 60.0 * t.sin(), -62.0 + 40.0 * t.cos(), 500.0 + 80.0 * (t * 0.5).sin()]])?;
+        let mut projected = Vector::<Point2f>::new();
+        project_points_def(&points, &rvec, &tvec, &camera_matrix, &Mat::default(), &mut projected)?;
+        let noisy = projected
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                let noise = 0.1 * ((view * 97 + i * 13) as f32).sin();
+                Point2f::new(p.x + noise, p.y - noise)
+            })
+            .collect();
+        object_points.push(points.clone());
+        image_points.push(noisy);
+    }
+
+    // Once first, like the other stages
+    let rounds = rounds.min(SOLVER_ROUNDS);
+    calibration::solve(&object_points, &image_points, size)?;
+    let started = Instant::now();
+    for _ in 0..rounds {
+        calibration::solve(&object_points, &image_points, size)?;
+    }
+    Ok(SolverTiming {
+        rounds,
+        views: SOLVER_VIEWS,
+        points_per_view: points.len(),
+        solve_ms: ms(started.elapsed() / rounds),
+    })
+}
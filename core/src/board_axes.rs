@@ -0,0 +1,134 @@
+use anyhow::Result;
+use opencv::{
+    calib3d::{project_points_def, rodrigues, solve_pnp_def},
+    core::{Mat, Point, Point2f, Point3f, Scalar, Size, Vector},
+    imgproc::{circle, line, put_text, FILLED, FONT_HERSHEY_SIMPLEX, LINE_AA},
+    prelude::*,
+};
+
+// Axis length in board squares
+const AXIS_SQUARES: f32 = 3.0;
+// A board within this many degrees of the image plane counts as level
+const LEVEL_DEGREES: f64 = 2.0;
+// Tilt at the rim of the bubble level
+const BUBBLE_RANGE_DEGREES: f64 = 45.0;
+
+/// Where the board sits relative to the camera, from its detected corners and known
+/// intrinsics. Rodrigues rotation and translation in the unit of the object points.
+pub struct BoardFrame {
+    rvec: Mat,
+    tvec: Mat,
+}
+
+impl BoardFrame {
+    /// `camera_matrix` and `dist_coeffs` have to be for the frame the corners were found in
+    pub fn solve(
+        object_points: &Vector<Point3f>,
+        corners: &Vector<Point2f>,
+        camera_matrix: &Mat,
+        dist_coeffs: &Mat,
+    ) -> Result<Option<Self>> {
+        let mut rvec = Mat::default();
+        let mut tvec = Mat::default();
+        if !solve_pnp_def(object_points, corners, camera_matrix, dist_coeffs, &mut rvec, &mut tvec)? {
+            return Ok(None);
+        }
+        Ok(Some(BoardFrame { rvec, tvec }))
+    }
+
+    /// Tilt and yaw of the board against the image plane in degrees, both 0 for a frontal
+    /// board. Tilt is positive with the top of the board towards the camera, yaw with its
+    /// right side away from the camera.
+    pub fn angles(&self) -> Result<(f64, f64)> {
+        let mut rotation = Mat::default();
+        rodrigues(&self.rvec, &mut rotation, &mut Mat::default())?;
+        // Board normal in camera coordinates, turned to point away from the camera
+        let mut normal = [
+            *rotation.at_2d::<f64>(0, 2)?,
+            *rotation.at_2d::<f64>(1, 2)?,
+            *rotation.at_2d::<f64>(2, 2)?,
+        ];
+        if normal[2] < 0.0 {
+            normal = normal.map(|n| -n);
+        }
+        Ok((
+            (-normal[1]).atan2(normal[2]).to_degrees(),
+            (-normal[0]).atan2(normal[2]).to_degrees(),
+        ))
+    }
+
+    /// The board's X, Y and Z axes from its first corner in red, green and blue, like OpenCV's
+    /// drawFrameAxes. Z follows the right hand rule, so on a board facing the camera it
+    /// points away from the camera.
+    pub fn draw_axes(&self, rgba: &mut Mat, square_size: f64, camera_matrix: &Mat, dist_coeffs: &Mat) -> Result<()> {
+        let length = square_size as f32 * AXIS_SQUARES;
+        let axes: Vector<Point3f> = Vector::from_iter([
+            Point3f::new(0.0, 0.0, 0.0),
+            Point3f::new(length, 0.0, 0.0),
+            Point3f::new(0.0, length, 0.0),
+            Point3f::new(0.0, 0.0, length),
+        ]);
+        let mut projected: Vector<Point2f> = Vector::new();
+        project_points_def(&axes, &self.rvec, &self.tvec, camera_matrix, dist_coeffs, &mut projected)?;
+        let points: Vec<Point> = projected
+            .iter()
+            .map(|p| Point::new(p.x.round() as i32, p.y.round() as i32))
+            .collect();
+
+        let origin = points[0];
+        let thickness = (rgba.rows() / 240).max(2);
+        for (end, color, label) in [
+            (points[1], Scalar::new(255.0, 0.0, 0.0, 255.0), "X"),
+            (points[2], Scalar::new(0.0, 255.0, 0.0, 255.0), "Y"),
+            (points[3], Scalar::new(0.0, 0.0, 255.0, 255.0), "Z"),
+        ] {
+            line(rgba, origin, end, color, thickness, LINE_AA, 0)?;
+            put_text(rgba, label, Point::new(end.x + 4, end.y - 4), FONT_HERSHEY_SIMPLEX, 0.6, color, 2, LINE_AA, false)?;
+        }
+        Ok(())
+    }
+}
+
+/// Stand-in intrinsics for a camera that was never calibrated: a pinhole with a 53 degree
+/// horizontal field of view centred on the frame and no distortion. Good enough for telling
+/// a frontal board from a tilted one.
+pub fn nominal_intrinsics(frame_size: Size) -> Result<(Mat, Mat)> {
+    let focal = frame_size.width as f64;
+    let (cx, cy) = ((frame_size.width - 1) as f64 / 2.0, (frame_size.height - 1) as f64 / 2.0);
+    let camera_matrix = Mat::from_slice_2d(&[[focal, 0.0, cx], [0.0, focal, cy], [0.0, 0.0, 1.0]])?;
+    let dist_coeffs = Mat::from_slice(&[0.0f64; 5])?.try_clone()?;
+    Ok((camera_matrix, dist_coeffs))
+}
+
+/// Tilt and yaw as numbers in the top-left corner and as a bubble level in the top-right: the
+/// dot moves with the board's normal and turns green once the board is level
+pub fn draw_level(rgba: &mut Mat, (tilt, yaw): (f64, f64), approximate: bool) -> Result<()> {
+    let level = tilt.abs() < LEVEL_DEGREES && yaw.abs() < LEVEL_DEGREES;
+    let white = Scalar::new(255.0, 255.0, 255.0, 255.0);
+    let green = Scalar::new(0.0, 220.0, 0.0, 255.0);
+    let amber = Scalar::new(255.0, 190.0, 0.0, 255.0);
+    let color = if level { green } else { amber };
+
+    let mut text = format!("Tilt {:+.1} deg, yaw {:+.1} deg", tilt, yaw);
+    if level {
+        text += ", level";
+    }
+    if approximate {
+        text += " (approx., not calibrated)";
+    }
+    // Dark outline first so the numbers read on both black and white squares
+    for (outline, thickness) in [(Scalar::new(0.0, 0.0, 0.0, 255.0), 4), (color, 1)] {
+        put_text(rgba, &text, Point::new(8, 22), FONT_HERSHEY_SIMPLEX, 0.6, outline, thickness, LINE_AA, false)?;
+    }
+
+    let radius = (rgba.rows() / 12).max(20);
+    let center = Point::new(rgba.cols() - radius - 8, radius + 8);
+    circle(rgba, center, radius, white, 1, LINE_AA, 0)?;
+    let level_radius = (LEVEL_DEGREES / BUBBLE_RANGE_DEGREES * radius as f64).round().max(3.0) as i32;
+    circle(rgba, center, level_radius, white, 1, LINE_AA, 0)?;
+    let offset = |angle: f64| (angle / BUBBLE_RANGE_DEGREES).clamp(-1.0, 1.0) * radius as f64;
+    // Right side away moves the dot right, top towards the camera moves it up
+    let bubble = Point::new(center.x + offset(yaw).round() as i32, center.y - offset(tilt).round() as i32);
+    circle(rgba, bubble, (radius / 6).max(3), color, FILLED, LINE_AA, 0)?;
+    Ok(())
+}
@@ -0,0 +1,869 @@
+use crate::{
+    board_axes::{self, BoardFrame},
+    coverage::Coverage,
+    detection::{DetectionConfig, DetectionPool, StageTimings},
+    error::{CalibrationError, DetectionError},
+    events::{Event, EventBus},
+    focus::FocusMeter,
+    frame::{Frame, FrameFeed},
+    geometry::FrameGeometry,
+    guidance::PoseGuidance,
+    lock::Locked,
+    metrics,
+    pose_view::{self, BoardPose},
+    preview::{self, PreviewOverlay, Undistort},
+    profiles::{self, Calibration, CameraRole},
+    runtime,
+    session_log,
+    stability::{StabilityGate, MAX_CORNER_MOTION_PX},
+    target::{CalibrationTarget, DetectedView},
+    tuning::Tuning,
+    workflow::{Workflow, WorkflowState},
+};
+use anyhow::{bail, Result};
+use opencv::{
+    calib3d::calibrate_camera_extended,
+    core::{Mat, Point2f, Point3f, Size, TermCriteria, TermCriteria_Type, Vector, CV_32F},
+    imgproc::{resize, INTER_AREA},
+    prelude::*,
+};
+use std::{
+    io::{stdout, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tokio::{
+    sync::{watch, Notify},
+    task::block_in_place,
+};
+use tokio_util::sync::CancellationToken;
+
+/// "Finish Now" only works once there are enough views for a usable solve
+pub const MIN_CALIBRATION_FRAMES: usize = 5;
+/// How long shutting down waits for a cancelled calibration, long enough for a solve that
+/// was already running to return
+pub const CANCEL_TIMEOUT: Duration = Duration::from_secs(30);
+const THUMBNAIL_WIDTH: i32 = 96;
+// Clicking a thumbnail in the capture gallery shows the view this wide
+const ENLARGED_WIDTH: i32 = 480;
+// How long the preview flashes after a view is accepted
+const FLASH_DURATION: Duration = Duration::from_millis(250);
+// How often a wait for the solver looks whether it was cancelled
+const CANCEL_POLL: Duration = Duration::from_millis(50);
+// How often a running solve tells the front ends it's still going
+const SOLVER_HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// The calibration buttons every front end has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Button {
+    /// Solve with the views captured so far
+    Finish,
+    /// Take the next detection, in manual capture
+    Capture,
+    /// Drop the most recent view
+    Undo,
+    /// Stop without saving. Stays pressed until the next calibration or re-solve starts.
+    Cancel,
+}
+
+/// Button presses from the UI for the running calibration. A press wakes the calibration
+/// straight away, also while it is waiting for a frame.
+#[derive(Clone)]
+pub struct CalibrationControls {
+    finish: Arc<AtomicBool>,
+    capture: Arc<AtomicBool>,
+    undo: Arc<AtomicBool>,
+    // Index of a captured view to drop, from the gallery
+    delete: Arc<Mutex<Option<usize>>>,
+    // A new one for every calibration, they can't be un-cancelled
+    cancel: Arc<Mutex<CancellationToken>>,
+    changed: Arc<Notify>,
+}
+
+impl Default for CalibrationControls {
+    fn default() -> Self {
+        CalibrationControls {
+            finish: Arc::default(),
+            capture: Arc::default(),
+            undo: Arc::default(),
+            delete: Arc::default(),
+            cancel: Arc::new(Mutex::new(runtime::shutdown_token().child_token())),
+            changed: Arc::default(),
+        }
+    }
+}
+
+impl CalibrationControls {
+    pub fn press(&self, button: Button) {
+        session_log::pressed(button);
+        match self.flag(button) {
+            Some(flag) => flag.store(true, Ordering::Relaxed),
+            None => self.cancellation().cancel(),
+        }
+        self.changed.notify_one();
+    }
+
+    /// Drops the captured view with this index in the gallery
+    pub fn delete(&self, index: usize) {
+        session_log::deleted(index);
+        *self.delete.locked() = Some(index);
+        self.changed.notify_one();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.locked().is_cancelled()
+    }
+
+    /// Cancelled by the Cancel button, or for every calibration at once when the app shuts
+    /// down. The work of one calibration waits on this or a child of it.
+    pub fn cancellation(&self) -> CancellationToken {
+        self.cancel.locked().clone()
+    }
+
+    pub fn reset(&self) {
+        for flag in [&self.finish, &self.capture, &self.undo] {
+            flag.store(false, Ordering::Relaxed);
+        }
+        *self.delete.locked() = None;
+        *self.cancel.locked() = runtime::shutdown_token().child_token();
+    }
+
+    // Cancel is the token instead, and stays pressed
+    fn flag(&self, button: Button) -> Option<&AtomicBool> {
+        match button {
+            Button::Finish => Some(&self.finish),
+            Button::Capture => Some(&self.capture),
+            Button::Undo => Some(&self.undo),
+            Button::Cancel => None,
+        }
+    }
+
+    fn is_pressed(&self, button: Button) -> bool {
+        self.flag(button).map_or_else(|| self.is_cancelled(), |flag| flag.load(Ordering::Relaxed))
+    }
+
+    // Whether the button was pressed since the last time, and forgets the press
+    fn take(&self, button: Button) -> bool {
+        self.flag(button).map_or_else(|| self.is_cancelled(), |flag| flag.swap(false, Ordering::Relaxed))
+    }
+
+    // Resolves once something was pressed. A press while nobody waits isn't lost, it
+    // wakes the next wait.
+    async fn pressed(&self) {
+        self.changed.notified().await;
+    }
+}
+
+/// What a running calibration needs from the rest of the app
+pub struct CalibrationSession {
+    /// The camera's frames, the calibration subscribes when it starts
+    pub frames: FrameFeed,
+    /// The result is saved for this role
+    pub role: CameraRole,
+    pub overlay: PreviewOverlay,
+    pub undistort: Undistort,
+    pub focus: FocusMeter,
+    pub controls: CalibrationControls,
+    /// Only accept a detection when the user asks for one
+    pub manual_capture: bool,
+    /// Automatic capture waits for the board to hold still this many frames
+    pub stable_frames: usize,
+    /// Time to move the board to a new pose after each automatic capture
+    pub countdown: Duration,
+    /// Confirm each accepted view with the terminal bell and a flash of the preview, the
+    /// operator is watching the board rather than the status line
+    pub confirm_beep: bool,
+    pub confirm_flash: bool,
+    /// Worker threads and queue policy for finding the target
+    pub detection: DetectionConfig,
+    /// Overrides for the thresholds and overlays above, looked at with every frame
+    pub tuning: watch::Receiver<Tuning>,
+    /// The views of the last calibration, kept for excluding views and solving again
+    pub views: SharedViews,
+    /// Progress, views and results go out here
+    pub events: EventBus,
+    /// Started as Configured by the front end, moved on as the calibration gets further
+    pub workflow: Workflow,
+}
+
+impl CalibrationSession {
+    pub fn set_calibrating(&self, calibrating: bool) {
+        self.events.publish(Event::CalibratingChanged(calibrating));
+    }
+
+    // In manual mode, whether the user asked for this frame. Presses while no target is
+    // in view are dropped rather than grabbing whatever is detected next. Otherwise any
+    // detection once the target is still.
+    fn accept_view(&self, found: bool, stable: bool) -> bool {
+        let requested = self.controls.take(Button::Capture);
+        if !self.manual_capture {
+            return found && stable;
+        }
+        if requested && !found {
+            self.events.publish(Event::Status("No target in view, nothing captured".to_string()));
+        }
+        requested && found
+    }
+
+    fn set_progress(&self, captured: usize, required: usize, status: String) {
+        self.events.publish(Event::CalibrationProgress {
+            captured,
+            required,
+            status,
+        });
+    }
+}
+
+/// One accepted view of the target
+#[derive(Clone)]
+pub struct CapturedView {
+    object_points: Vector<Point3f>,
+    image_points: Vector<Point2f>,
+    // Which frame the view came from, to line it up with recordings and machine logs
+    sequence: u64,
+    timestamp: OffsetDateTime,
+    // RGBA with the detected corners drawn in
+    thumbnail: Mat,
+    enlarged: Mat,
+    included: bool,
+    // RMS reprojection error of this view in the last solve that included it
+    error: Option<f64>,
+    // Board pose from that solve
+    pose: Option<([f64; 3], [f64; 3])>,
+}
+
+impl CapturedView {
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn timestamp(&self) -> OffsetDateTime {
+        self.timestamp
+    }
+
+    /// THUMBNAIL_WIDTH wide, for lists
+    pub fn thumbnail(&self) -> &Mat {
+        &self.thumbnail
+    }
+
+    /// ENLARGED_WIDTH wide, for a closer look at one view
+    pub fn enlarged(&self) -> &Mat {
+        &self.enlarged
+    }
+
+    pub fn included(&self) -> bool {
+        self.included
+    }
+
+    pub fn error(&self) -> Option<f64> {
+        self.error
+    }
+}
+
+/// Everything needed to solve the calibration again
+#[derive(Clone)]
+pub struct CalibrationViews {
+    role: CameraRole,
+    frame_size: Size,
+    geometry: FrameGeometry,
+    // Corners of the target's points in mm, the unit of the object points
+    outline: [[f64; 2]; 4],
+    // Solved intrinsics in frame pixels
+    camera_matrix: [[f64; 3]; 3],
+    views: Vec<CapturedView>,
+}
+
+impl CalibrationViews {
+    pub fn role(&self) -> CameraRole {
+        self.role
+    }
+
+    pub fn views(&self) -> &[CapturedView] {
+        &self.views
+    }
+
+    /// Frame number and reprojection error of every view, no error for views left out
+    pub fn errors(&self) -> Vec<(u64, Option<f64>)> {
+        self.views.iter().map(|view| (view.sequence, view.error)).collect()
+    }
+
+    /// Camera and board poses of the last solve seen from `azimuth` degrees around the
+    /// vertical axis, as RGBA. None before the first solve.
+    pub fn render_poses(&self, azimuth: f64) -> Result<Option<Mat>> {
+        let poses: Vec<BoardPose> = self
+            .views
+            .iter()
+            .filter_map(|view| {
+                view.pose.map(|(rvec, tvec)| BoardPose {
+                    rvec,
+                    tvec,
+                    included: view.included,
+                })
+            })
+            .collect();
+        if poses.is_empty() {
+            return Ok(None);
+        }
+        let rendered = pose_view::render(
+            &poses,
+            self.outline,
+            &self.camera_matrix,
+            self.frame_size,
+            azimuth,
+        )?;
+        Ok(Some(rendered))
+    }
+}
+
+pub type SharedViews = Arc<Mutex<Option<CalibrationViews>>>;
+
+/// Captures views of `target` and solves for them. Runs until the views are captured and
+/// solved, or it is cancelled. The target is looked for on a [`DetectionPool`], drawing the
+/// overlay and solving block the runtime thread this runs on, so run it on the
+/// multi-threaded [`crate::runtime::runtime`].
+///
+/// Start the session's workflow in [`WorkflowState::Configured`] first, before spawning
+/// this, so a second start is turned down straight away. It ends up Saved, or back where it
+/// started when the calibration is cancelled or fails.
+#[tracing::instrument(name = "calibrate", skip_all, fields(role = ?session.role))]
+pub async fn run_calibration(
+    target: Arc<dyn CalibrationTarget>,
+    required_frames: usize,
+    session: &CalibrationSession,
+) -> Result<()> {
+    let result = capture_and_solve(target, required_frames, session).await;
+    session.workflow.settle();
+    result
+}
+
+async fn capture_and_solve(
+    target: Arc<dyn CalibrationTarget>,
+    required_frames: usize,
+    session: &CalibrationSession,
+) -> Result<()> {
+    session.workflow.advance(WorkflowState::Capturing)?;
+    log::info!("Calibrating with a {}", target.describe());
+    let cancel = session.controls.cancellation();
+    let mut detections = DetectionPool::start(
+        Arc::clone(&target),
+        session.frames.subscribe(),
+        session.detection,
+        session.tuning.clone(),
+        cancel.child_token(),
+    );
+    let target = target.as_ref();
+    session.set_progress(0, required_frames, format!("Captured frames: 0 of {}", required_frames));
+    session.events.publish(Event::ViewsCleared);
+    session.overlay.clear();
+
+    let mut views: Vec<CapturedView> = Vec::new();
+    let mut geometry = None;
+    let mut frame_size = Size::default();
+    let mut coverage: Option<Coverage> = None;
+    let mut guidance = PoseGuidance::default();
+    let mut stability = StabilityGate::new(session.stable_frames);
+    let mut countdown_until: Option<Instant> = None;
+    let mut flash_until: Option<Instant> = None;
+    let mut detection_failed = false;
+    let mut timings = StageTimings::default();
+
+    // Capture frames and detect the target
+    while views.len() < required_frames {
+        let controls = &session.controls;
+        if controls.is_cancelled() {
+            session.set_progress(0, required_frames, "Calibration cancelled, nothing was saved".to_string());
+            return Ok(());
+        }
+        if views.len() >= MIN_CALIBRATION_FRAMES && controls.is_pressed(Button::Finish) {
+            break;
+        }
+        // Drop the most recent view, e.g. one that caught the board mid-motion, or one picked
+        // in the gallery
+        let undo = controls.take(Button::Undo);
+        let delete = controls.delete.locked().take();
+        let removed = match (delete, undo) {
+            (Some(index), _) => Some(index),
+            (None, true) => views.len().checked_sub(1),
+            (None, false) => None,
+        };
+        let removed = removed.filter(|&index| index < views.len());
+        if let Some(index) = removed {
+            let view = views.remove(index);
+            if let Some(coverage) = coverage.as_mut() {
+                coverage.remove(&view.image_points);
+            }
+            guidance.remove(index);
+            session.set_progress(
+                views.len(),
+                required_frames,
+                format!("Removed view {}, captured frames: {} of {}", index + 1, views.len(), required_frames),
+            );
+            session.events.publish(Event::ViewRemoved(index));
+        }
+
+        // A button press is handled straight away rather than with the next detection
+        let detection = tokio::select! {
+            detection = detections.recv() => detection,
+            _ = controls.pressed() => continue,
+            _ = cancel.cancelled() => continue,
+        };
+        let Some(detection) = detection else {
+            bail!("Detection stopped unexpectedly");
+        };
+        timings.detected(&detection);
+        let frame = detection.frame;
+        let drawing_started = Instant::now();
+
+        // Edits to the tuning file apply from the next frame on
+        let tuning = session.tuning.borrow().clone();
+        stability.tune(
+            tuning.stable_frames.unwrap_or(session.stable_frames),
+            tuning.max_corner_motion_px.unwrap_or(MAX_CORNER_MOTION_PX),
+        );
+        let countdown = tuning.countdown_secs.map_or(session.countdown, Duration::from_secs);
+
+        // Drawing keeps a thread busy for a while, the runtime moves its other tasks off it
+        block_in_place(|| -> Result<()> {
+            // Owned copy, the detection overlay is drawn onto it
+            let mut frame_mat = Mat::from_slice(frame.data.as_slice())?.reshape(4, frame.height)?.try_clone()?;
+            let size = Size::new(frame.width, frame.height);
+            if coverage.as_ref().map(|coverage| coverage.frame_size()) != Some(size) {
+                coverage = Some(Coverage::new(size)); // First frame, or the camera changed
+            }
+            let coverage = coverage.as_mut().unwrap();
+
+            // A frame the target can't be looked for in is skipped, the next one may do. The
+            // first failure is shown, not one per frame.
+            let detected = match detection.result {
+                Ok(detected) => detected,
+                Err(e) => {
+                    if !detection_failed {
+                        let error = DetectionError::Failed {
+                            target: target.describe(),
+                            reason: format!("{:#}", e),
+                        };
+                        log::warn!("{}", error);
+                        session.events.publish(Event::Error(error.to_string()));
+                        detection_failed = true;
+                    }
+                    None
+                }
+            };
+
+            // Give the user time to move the target before the next automatic capture
+            let remaining = countdown_until.map(|until| until.saturating_duration_since(Instant::now()));
+            if remaining == Some(Duration::ZERO) {
+                countdown_until = None;
+                stability.reset();
+                ring_bell(); // Hold still now
+            }
+            let counting_down = countdown_until.is_some();
+
+            let stable = stability.update(detected.as_ref().map(|view| &view.image_points));
+            let accepted = session.accept_view(detected.is_some(), stable && !counting_down);
+            if let Some(view) = detected.as_ref().filter(|_| accepted) {
+                stability.reset(); // The next view needs its own still period
+                if !session.manual_capture && !countdown.is_zero() {
+                    countdown_until = Some(Instant::now() + countdown);
+                }
+
+                views.push(CapturedView {
+                    object_points: view.object_points.clone(),
+                    image_points: view.image_points.clone(),
+                    sequence: frame.sequence,
+                    timestamp: frame.timestamp,
+                    thumbnail: thumbnail(&frame_mat, target, view, THUMBNAIL_WIDTH)?,
+                    enlarged: thumbnail(&frame_mat, target, view, ENLARGED_WIDTH)?,
+                    included: true,
+                    error: None,
+                    pose: None,
+                });
+                geometry = Some(frame.geometry);
+                frame_size = size;
+                if session.confirm_beep {
+                    ring_bell();
+                }
+                if session.confirm_flash {
+                    flash_until = Some(Instant::now() + FLASH_DURATION);
+                }
+                coverage.add(&view.image_points);
+                guidance.add(&view.image_points, target.grid_size(), size);
+                session.events.publish(Event::ViewAccepted(Box::new(views[views.len() - 1].clone())));
+
+                let mut status = format!(
+                    "Captured frames: {} of {}, {} of {} regions not covered yet",
+                    views.len(),
+                    required_frames,
+                    coverage.uncovered(),
+                    coverage.regions()
+                );
+                if let Some(hint) = guidance.hint(coverage).filter(|_| views.len() < required_frames) {
+                    status += &format!(". Next: {}", hint);
+                }
+                session.set_progress(views.len(), required_frames, status);
+            }
+
+            // Focus on the target itself while it is in view, the background may be at another depth
+            let points = detected.as_ref().map(|view| &view.image_points);
+            session.focus.measure(&frame, points)?;
+
+            // Show what was detected in the main preview, over a map of where points have
+            // been seen so far
+            if tuning.coverage_overlay.unwrap_or(true) {
+                coverage.draw(&mut frame_mat)?;
+            }
+            // With intrinsics from an earlier calibration, the target's own axes show which way
+            // its coordinates run. Its angle to the image plane is shown either way, to take
+            // frontal or deliberately tilted views.
+            if let Some(view) = &detected {
+                target.draw_overlay(&mut frame_mat, view)?;
+            }
+            if let Some(view) = detected.as_ref().filter(|_| tuning.board_axes_overlay.unwrap_or(true)) {
+                let intrinsics = session.undistort.frame_intrinsics(&frame.geometry)?;
+                let approximate = intrinsics.is_none();
+                let (camera_matrix, dist_coeffs) = match intrinsics {
+                    Some(intrinsics) => intrinsics,
+                    None => board_axes::nominal_intrinsics(size)?,
+                };
+                if let Some(board) =
+                    BoardFrame::solve(&view.object_points, &view.image_points, &camera_matrix, &dist_coeffs)?
+                {
+                    if !approximate {
+                        board.draw_axes(&mut frame_mat, target.spacing(), &camera_matrix, &dist_coeffs)?;
+                    }
+                    board_axes::draw_level(&mut frame_mat, board.angles()?, approximate)?;
+                }
+            }
+            if flash_until.is_some_and(|until| Instant::now() < until) {
+                preview::draw_flash(&mut frame_mat)?;
+            }
+            if let Some(remaining) = remaining.filter(|_| counting_down) {
+                preview::draw_banner(&mut frame_mat, &format!("{}", remaining.as_secs() + 1))?;
+            }
+            session.overlay.show(frame_mat, &frame)?;
+            Ok(())
+        })?;
+        timings.drawn(drawing_started.elapsed());
+    }
+    drop(detections);
+
+    // Detection slower than the camera only costs frames, but say how many and where the time goes
+    if let Some(summary) = timings.summary() {
+        if timings.skipped() > 0 {
+            log::warn!("Calibration fell behind the camera: {}", summary);
+        } else {
+            log::info!("Calibration stages: {}", summary);
+        }
+    }
+    let Some(geometry) = geometry else {
+        return Err(CalibrationError::NoViews.into());
+    };
+    session.workflow.advance(WorkflowState::Solving)?;
+    let captured_frames = views.len();
+    session.set_progress(
+        captured_frames,
+        captured_frames,
+        format!("Calibrating with {} frames...", captured_frames),
+    );
+
+    let mut set = CalibrationViews {
+        role: session.role,
+        frame_size,
+        geometry,
+        outline: outline(&target.object_points()),
+        camera_matrix: [[0.0; 3]; 3],
+        views,
+    };
+    let result = block_in_place(|| {
+        solve_and_save(&mut set, &session.undistort, &session.controls, &session.events, &session.workflow)
+    });
+    *session.views.locked() = Some(set);
+    result
+}
+
+/// Solves again with only the views still ticked in the table. Like [`run_calibration`],
+/// the workflow is started first, in [`WorkflowState::Solving`], and settled here.
+#[tracing::instrument(name = "calibrate", skip_all)]
+pub fn resolve(
+    views: &SharedViews,
+    undistort: &Undistort,
+    controls: &CalibrationControls,
+    events: &EventBus,
+    workflow: &Workflow,
+) -> Result<()> {
+    let result = solve_again(views, undistort, controls, events, workflow);
+    workflow.settle();
+    result
+}
+
+fn solve_again(
+    views: &SharedViews,
+    undistort: &Undistort,
+    controls: &CalibrationControls,
+    events: &EventBus,
+    workflow: &Workflow,
+) -> Result<()> {
+    // Taken out while solving so the UI thread never waits on the lock
+    let Some(mut set) = views.locked().take() else {
+        return Err(CalibrationError::NothingToSolve.into());
+    };
+    let included = set.views.iter().filter(|view| view.included).count();
+    let result = if included < MIN_CALIBRATION_FRAMES {
+        Err(CalibrationError::TooFewViews {
+            required: MIN_CALIBRATION_FRAMES,
+            selected: included,
+        }
+        .into())
+    } else {
+        events.publish(Event::Status(format!("Calibrating with {} of {} views...", included, set.views.len())));
+        solve_and_save(&mut set, undistort, controls, events, workflow)
+    };
+    *views.locked() = Some(set);
+    result
+}
+
+pub fn set_view_included(views: &SharedViews, index: usize, included: bool) {
+    if let Some(view) = views.locked().as_mut().and_then(|set| set.views.get_mut(index)) {
+        view.included = included;
+    }
+}
+
+// A cancel while solving returns without the result and leaves the saved calibration as it was
+fn solve_and_save(
+    set: &mut CalibrationViews,
+    undistort: &Undistort,
+    controls: &CalibrationControls,
+    events: &EventBus,
+    workflow: &Workflow,
+) -> Result<()> {
+    let mut object_points: Vector<Vector<Point3f>> = Vector::new();
+    let mut image_points: Vector<Vector<Point2f>> = Vector::new();
+    for view in set.views.iter().filter(|view| view.included) {
+        object_points.push(view.object_points.clone());
+        image_points.push(view.image_points.clone());
+    }
+
+    let Some(Solution {
+        rms,
+        camera_matrix,
+        dist_coeffs,
+        rvecs,
+        tvecs,
+        per_view_errors,
+    }) = solve_unless_cancelled(object_points, image_points, set.frame_size, &controls.cancellation(), events)?
+    else {
+        events.publish(Event::Status("Calibration cancelled, the previous result is kept".to_string()));
+        return Ok(());
+    };
+
+    let mut errors = per_view_errors.data_typed::<f64>()?.iter();
+    let mut solved = 0;
+    for view in set.views.iter_mut() {
+        view.error = if view.included { errors.next().copied() } else { None };
+        if view.included {
+            let (rvec, tvec) = (rvecs.get(solved)?, tvecs.get(solved)?);
+            let rvec = [*rvec.at::<f64>(0)?, *rvec.at::<f64>(1)?, *rvec.at::<f64>(2)?];
+            let tvec = [*tvec.at::<f64>(0)?, *tvec.at::<f64>(1)?, *tvec.at::<f64>(2)?];
+            view.pose = Some((rvec, tvec));
+            solved += 1;
+        }
+    }
+    for (r, row) in set.camera_matrix.iter_mut().enumerate() {
+        for (c, value) in row.iter_mut().enumerate() {
+            *value = *camera_matrix.at_2d::<f64>(r as i32, c as i32)?;
+        }
+    }
+
+    workflow.advance(WorkflowState::Reviewed)?;
+    let included = set.views.iter().filter(|view| view.included).count();
+    log::info!("RMS reprojection error: {:.3} px", rms);
+    events.publish(Event::Status(format!("Calibrated from {} frames, RMS error {:.3} px", included, rms)));
+    log::info!("Camera matrix: {:?}", camera_matrix);
+    let geometry = set.geometry;
+    if !geometry.is_full_sensor() {
+        // Solved on cropped, scaled or rotated frames, report it in full sensor coordinates as well
+        let sensor_matrix = geometry.to_sensor_camera_matrix(&camera_matrix)?;
+        log::info!("Camera matrix (sensor coordinates): {:?}", sensor_matrix);
+    }
+    log::info!("Distortion coefficients: {:?}", dist_coeffs);
+    if !geometry.orientation.is_identity() {
+        let sensor_dist_coeffs = geometry.to_sensor_dist_coeffs(&dist_coeffs)?;
+        log::info!("Distortion coefficients (sensor coordinates): {:?}", sensor_dist_coeffs);
+    }
+    for (index, view) in set.views.iter().enumerate() {
+        match view.error {
+            Some(error) => log::info!(
+                "View {}: frame {} captured at {}, error {:.3} px",
+                index, view.sequence, view.timestamp, error
+            ),
+            None => log::info!("View {}: frame {} captured at {}, excluded", index, view.sequence, view.timestamp),
+        }
+    }
+
+    // Keep the result with the role it was taken for
+    let calibration = Calibration::from_frames(&geometry, &camera_matrix, &dist_coeffs, rms)?;
+    let role = set.role;
+    if session_log::replaying() {
+        log::info!("Replayed calibration of the {}, the stored one is left as it was", role.label());
+    } else {
+        profiles::update(|profiles| profiles.get_mut(role).calibration = Some(calibration.clone()))?;
+        log::info!("Saved calibration for the {}", role.label());
+    }
+    workflow.advance(WorkflowState::Saved)?;
+
+    undistort.set_calibration(Some(calibration.clone()));
+    metrics::set_calibration_rms(role, Some(calibration.rms));
+    events.publish(Event::Solved {
+        views: Box::new(set.clone()),
+        calibration,
+    });
+    Ok(())
+}
+
+pub(crate) struct Solution {
+    rms: f64,
+    camera_matrix: Mat,
+    dist_coeffs: Mat,
+    rvecs: Vector<Mat>,
+    tvecs: Vector<Mat>,
+    per_view_errors: Mat,
+}
+
+// OpenCV can't be interrupted mid-solve, so the solver gets a thread of its own and a cancel
+// returns straight away, None. The thread runs on to the end in the background and its
+// result is dropped. Nor does it report its iterations, so while it runs the status says
+// how long it has been going, a large set of views takes minutes.
+fn solve_unless_cancelled(
+    object_points: Vector<Vector<Point3f>>,
+    image_points: Vector<Vector<Point2f>>,
+    frame_size: Size,
+    cancel: &CancellationToken,
+    events: &EventBus,
+) -> Result<Option<Solution>> {
+    let views = object_points.len();
+    let points: usize = object_points.iter().map(|view| view.len()).sum();
+    log::info!("Solving {} views with {} points", views, points);
+    let (sender, solved) = mpsc::channel();
+    thread::Builder::new().name("rustyrabbit-solver".to_string()).spawn(move || {
+        let _ = sender.send(solve(&object_points, &image_points, frame_size));
+    })?;
+    let started = Instant::now();
+    let mut heartbeat = started + SOLVER_HEARTBEAT;
+    loop {
+        match solved.recv_timeout(CANCEL_POLL) {
+            Ok(solution) => {
+                log::info!("Solved in {:.1} s", started.elapsed().as_secs_f64());
+                return solution.map(Some);
+            }
+            Err(RecvTimeoutError::Timeout) if cancel.is_cancelled() => return Ok(None),
+            Err(RecvTimeoutError::Timeout) if Instant::now() >= heartbeat => {
+                heartbeat += SOLVER_HEARTBEAT;
+                let status = format!(
+                    "Solving {} views with {} points, {} s so far...",
+                    views,
+                    points,
+                    started.elapsed().as_secs()
+                );
+                log::debug!("{}", status);
+                events.publish(Event::Status(status));
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => bail!("The solver stopped without a result"),
+        }
+    }
+}
+
+/// Solves the intrinsics from views detected outside a calibration session, in frames
+/// delivered with `geometry`, for scripts that collect their own. Nothing is saved.
+pub fn solve_views(views: &[DetectedView], geometry: &FrameGeometry) -> Result<Calibration> {
+    if views.len() < MIN_CALIBRATION_FRAMES {
+        bail!("{} views are too few to solve, a calibration needs at least {}", views.len(), MIN_CALIBRATION_FRAMES);
+    }
+    let object_points: Vector<Vector<Point3f>> = views.iter().map(|view| view.object_points.clone()).collect();
+    let image_points: Vector<Vector<Point2f>> = views.iter().map(|view| view.image_points.clone()).collect();
+    let frame_size = geometry.orientation.output_size(geometry.scaled_size);
+    let solution = solve(&object_points, &image_points, frame_size)?;
+    Calibration::from_frames(geometry, &solution.camera_matrix, &solution.dist_coeffs, solution.rms)
+}
+
+// Camera calibration using the captured points
+pub(crate) fn solve(
+    object_points: &Vector<Vector<Point3f>>,
+    image_points: &Vector<Vector<Point2f>>,
+    frame_size: Size,
+) -> Result<Solution> {
+    let mut camera_matrix = Mat::eye(3, 3, CV_32F)?.to_mat()?; // 3x3 camera matrix
+    let mut dist_coeffs = Mat::zeros(8, 1, CV_32F)?.to_mat()?; // Distortion coefficients
+    let mut rvecs = opencv::types::VectorOfMat::new();
+    let mut tvecs = opencv::types::VectorOfMat::new();
+    let mut std_dev_intrinsics = Mat::default();
+    let mut std_dev_extrinsics = Mat::default();
+    let mut per_view_errors = Mat::default();
+
+    let rms = calibrate_camera_extended(
+        object_points,
+        image_points,
+        frame_size,
+        &mut camera_matrix,
+        &mut dist_coeffs,
+        &mut rvecs,
+        &mut tvecs,
+        &mut std_dev_intrinsics,
+        &mut std_dev_extrinsics,
+        &mut per_view_errors,
+        0, // Calibration flags (can be customized)
+        TermCriteria::new(
+            TermCriteria_Type::COUNT as i32 | TermCriteria_Type::EPS as i32,
+            30,
+            0.1,
+        )?,
+    )
+    .map_err(CalibrationError::Solver)?;
+    Ok(Solution {
+        rms,
+        camera_matrix,
+        dist_coeffs,
+        rvecs,
+        tvecs,
+        per_view_errors,
+    })
+}
+
+fn thumbnail(
+    rgba: &Mat,
+    target: &dyn CalibrationTarget,
+    view: &DetectedView,
+    width: i32,
+) -> Result<Mat> {
+    let mut annotated = rgba.try_clone()?;
+    target.draw_overlay(&mut annotated, view)?;
+    let height = (width * rgba.rows() / rgba.cols()).max(1);
+    let mut small = Mat::default();
+    resize(&annotated, &mut small, Size::new(width, height), 0.0, 0.0, INTER_AREA)?;
+    Ok(small)
+}
+
+// Bounding rectangle of the target's points on its own plane, for drawing its poses
+fn outline(points: &Vector<Point3f>) -> [[f64; 2]; 4] {
+    let (mut low, mut high) = ([f64::MAX; 2], [f64::MIN; 2]);
+    for point in points.iter() {
+        for (axis, value) in [point.x as f64, point.y as f64].into_iter().enumerate() {
+            low[axis] = low[axis].min(value);
+            high[axis] = high[axis].max(value);
+        }
+    }
+    [[low[0], low[1]], [high[0], low[1]], [high[0], high[1]], [low[0], high[1]]]
+}
+
+// Audible cue from the terminal, the UI toolkit has no sound
+fn ring_bell() {
+    print!("\x07");
+    let _ = stdout().flush();
+}
@@ -0,0 +1,101 @@
+use crate::{
+    config,
+    source::{CaptureMode, Control, FrameSource},
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const SETTINGS_FILE: &str = "cameras.toml";
+
+/// Image settings for one physical camera, in the device's own units. Anything left as
+/// None is not touched when the camera is opened.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraSettings {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub fps: Option<i32>,
+    pub exposure: Option<f64>,
+    pub gain: Option<f64>,
+    pub focus: Option<f64>,
+    pub white_balance: Option<f64>,
+}
+
+impl CameraSettings {
+    pub fn is_empty(&self) -> bool {
+        *self == CameraSettings::default()
+    }
+
+    /// Values set in `overrides` win over ours
+    pub fn merged_with(&self, overrides: &CameraSettings) -> CameraSettings {
+        CameraSettings {
+            width: overrides.width.or(self.width),
+            height: overrides.height.or(self.height),
+            fps: overrides.fps.or(self.fps),
+            exposure: overrides.exposure.or(self.exposure),
+            gain: overrides.gain.or(self.gain),
+            focus: overrides.focus.or(self.focus),
+            white_balance: overrides.white_balance.or(self.white_balance),
+        }
+    }
+
+    pub fn mode(&self) -> CaptureMode {
+        CaptureMode {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+        }
+    }
+
+    /// Resolution is handled when the camera is opened, this sets the image controls
+    pub fn apply_controls(&self, source: &mut dyn FrameSource) -> Result<()> {
+        let controls = [
+            (Control::Exposure, self.exposure),
+            (Control::Gain, self.gain),
+            (Control::Focus, self.focus),
+            (Control::WhiteBalance, self.white_balance),
+        ];
+        for (control, value) in controls {
+            let Some(value) = value else {
+                continue;
+            };
+            if !source.set_control(control, value)? {
+                log::warn!("The camera has no {:?} control, {} not applied", control, value);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Identifies a physical camera across runs. Device indices shuffle when cameras are
+/// plugged in a different order, so on Linux use the card name plus the USB port.
+#[cfg(target_os = "linux")]
+pub fn device_key(index: i32) -> String {
+    let sysfs = format!("/sys/class/video4linux/video{}", index);
+    let name = std::fs::read_to_string(format!("{}/name", sysfs))
+        .map(|name| name.trim().to_string())
+        .unwrap_or_else(|_| format!("video{}", index));
+    let port = std::fs::canonicalize(format!("{}/device", sysfs))
+        .ok()
+        .and_then(|path| path.file_name().map(|p| p.to_string_lossy().into_owned()));
+    match port {
+        Some(port) => format!("{} @ {}", name, port),
+        None => name,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn device_key(index: i32) -> String {
+    format!("camera {}", index)
+}
+
+pub fn load(key: &str) -> Result<Option<CameraSettings>> {
+    let mut all: BTreeMap<String, CameraSettings> = config::load(SETTINGS_FILE)?.unwrap_or_default();
+    Ok(all.remove(key))
+}
+
+pub fn save(key: &str, settings: &CameraSettings) -> Result<()> {
+    let mut all: BTreeMap<String, CameraSettings> = config::load(SETTINGS_FILE)?.unwrap_or_default();
+    all.insert(key.to_string(), settings.clone());
+    Ok(config::save(SETTINGS_FILE, &all)?)
+}
@@ -0,0 +1,507 @@
+use crate::{
+    acceleration,
+    events::{Event, EventBus},
+    frame::{Frame, FrameBuffer, FrameFeed},
+    geometry::{FrameGeometry, Orientation},
+    metrics::{self, Stage},
+    recording::Recording,
+    runtime::{self, runtime},
+    session_log,
+    source::{FrameSource, SourceSpec, TriggerMode, CAMERA_BACKENDS},
+    watchdog::Watchdog,
+};
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Rect, Size},
+    imgproc::{COLOR_BGR2RGBA, INTER_AREA, INTER_LINEAR},
+    prelude::*,
+};
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tokio::{
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+    task::{self, JoinHandle},
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span};
+
+// Consecutive empty reads from a live camera before we treat it as unplugged
+const MAX_FAILED_READS: u32 = 50;
+// Unreadable frames in a row from an image folder or video before capture gives up on it
+const MAX_UNREADABLE_FRAMES: u32 = 10;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+// Pause after a read that had no frame, before asking the source again
+const EMPTY_READ_WAIT: Duration = Duration::from_millis(10);
+// A camera that froze again sooner than this after being reopened gets the next backend
+const STEADY_AFTER: Duration = Duration::from_secs(600);
+/// How long stopping waits for the camera task. A read stuck in the driver never returns,
+/// the task is left behind then rather than hanging the app.
+pub const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct CaptureConfig {
+    pub source_spec: SourceSpec,
+    /// Size the source delivers
+    pub sensor_size: Size,
+    /// Only this part of the sensor image flows through the pipeline
+    pub roi: Option<Rect>,
+    /// Early downscale after the ROI crop, 1.0 keeps full resolution
+    pub scale: f64,
+    /// Flip/rotate applied last, to cancel out how the camera is mounted
+    pub orientation: Orientation,
+    pub fps: f64,
+    pub trigger_mode: TriggerMode,
+    /// Record into this folder from the start
+    pub record_dir: Option<PathBuf>,
+}
+
+impl CaptureConfig {
+    pub fn geometry(&self) -> Result<FrameGeometry> {
+        let geometry = match self.roi {
+            Some(roi) => FrameGeometry::with_roi(self.sensor_size, roi)?,
+            None => FrameGeometry::full_sensor(self.sensor_size),
+        };
+        Ok(geometry
+            .scaled_to(self.cropped_size(), self.scaled_size())
+            .oriented(self.orientation))
+    }
+
+    fn cropped_size(&self) -> Size {
+        self.roi.map(|roi| roi.size()).unwrap_or(self.sensor_size)
+    }
+
+    /// Size of the frames handed to preview, recording and calibration
+    pub fn frame_size(&self) -> Size {
+        self.orientation.output_size(self.scaled_size())
+    }
+
+    fn scaled_size(&self) -> Size {
+        let cropped = self.cropped_size();
+        if self.scale == 1.0 {
+            return cropped;
+        }
+        Size::new(
+            ((cropped.width as f64 * self.scale).round() as i32).max(1),
+            ((cropped.height as f64 * self.scale).round() as i32).max(1),
+        )
+    }
+}
+
+/// A running camera task, stopped to switch to another camera or on exit
+pub struct CaptureHandle {
+    cancel: CancellationToken,
+    trigger_sender: UnboundedSender<()>,
+    record_sender: UnboundedSender<Option<PathBuf>>,
+    task: JoinHandle<Result<()>>,
+}
+
+impl CaptureHandle {
+    /// Asks a software triggered camera for one frame
+    pub fn trigger(&self) {
+        let _ = self.trigger_sender.send(());
+    }
+
+    /// Starts a new recording in the folder, or stops recording with None
+    pub fn record(&self, dir: Option<PathBuf>) {
+        let _ = self.record_sender.send(dir);
+    }
+
+    /// Returns once the camera is closed and any recording finished, or with an error when
+    /// that takes longer than [`STOP_TIMEOUT`]. Call from outside the runtime's tasks, it
+    /// blocks until then.
+    pub fn stop(self) -> Result<()> {
+        // The task may already have ended with an error, which it returns here
+        self.cancel.cancel();
+        runtime::join_within(self.task, STOP_TIMEOUT, "camera task")?
+    }
+}
+
+/// Starts capturing on the shared [`runtime`]. Reads and image processing run on its
+/// blocking threads, the task itself only waits: for the next read, a trigger, a reconnect
+/// or to be stopped. Connection and recording changes go out on `events`.
+pub fn start_camera_task(
+    config: CaptureConfig,
+    source: Box<dyn FrameSource>,
+    frames: FrameFeed,
+    events: EventBus,
+) -> Result<CaptureHandle> {
+    let span = tracing::info_span!("capture", source = %config.source_spec.describe());
+    let camera = Camera::new(config, source, frames, events)?;
+    let cancel = runtime::shutdown_token().child_token();
+    let (trigger_sender, triggers) = unbounded_channel();
+    let (record_sender, records) = unbounded_channel();
+    let task = runtime().spawn(run(camera, cancel.clone(), triggers, records).instrument(span));
+    Ok(CaptureHandle {
+        cancel,
+        trigger_sender,
+        record_sender,
+        task,
+    })
+}
+
+async fn run(
+    mut camera: Camera,
+    cancel: CancellationToken,
+    mut triggers: UnboundedReceiver<()>,
+    mut records: UnboundedReceiver<Option<PathBuf>>,
+) -> Result<()> {
+    let recording = camera.record_dir.take();
+    if recording.is_some() {
+        camera = blocking(camera, move |camera| camera.set_recording(recording)).await?;
+    }
+    // A failed read ends the task, but only after the recording is closed
+    let mut failure = None;
+    loop {
+        while let Ok(dir) = records.try_recv() {
+            camera = blocking(camera, move |camera| camera.set_recording(dir)).await?;
+        }
+
+        if camera.source.is_none() {
+            // The device went away, keep trying to reopen it until it comes back
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = sleep(RECONNECT_INTERVAL) => {}
+            }
+            camera = blocking(camera, Camera::reopen).await?;
+            continue;
+        }
+
+        if camera.trigger_mode == TriggerMode::Software {
+            // Only acquire when someone asks for a frame
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                Some(dir) = records.recv() => {
+                    camera = blocking(camera, move |camera| camera.set_recording(dir)).await?;
+                    continue;
+                }
+                trigger = triggers.recv() => if trigger.is_none() {
+                    break;
+                },
+            }
+        } else if cancel.is_cancelled() {
+            break;
+        }
+
+        let (returned, read) = blocking_result(camera, Camera::read).await?;
+        camera = returned;
+        let read = match read {
+            Ok(read) => read,
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        };
+        if !read {
+            // Nothing to show yet (or the image folder or video has run out)
+            tokio::select! {
+                _ = cancel.cancelled() => break,
+                _ = sleep(EMPTY_READ_WAIT) => {}
+            }
+        }
+    }
+    // Close the file properly, an unfinished MP4 can't be played back
+    blocking(camera, |camera| camera.set_recording(None)).await?;
+    failure.map_or(Ok(()), Err)
+}
+
+// Hands the camera to a blocking thread for OpenCV work and back to the task afterwards,
+// still in the task's span
+async fn blocking(mut camera: Camera, work: impl FnOnce(&mut Camera) + Send + 'static) -> Result<Camera> {
+    let span = Span::current();
+    Ok(task::spawn_blocking(move || {
+        let _entered = span.enter();
+        work(&mut camera);
+        camera
+    })
+    .await?)
+}
+
+async fn blocking_result<T: Send + 'static>(
+    mut camera: Camera,
+    work: fn(&mut Camera) -> T,
+) -> Result<(Camera, T)> {
+    let span = Span::current();
+    Ok(task::spawn_blocking(move || {
+        let _entered = span.enter();
+        let result = work(&mut camera);
+        (camera, result)
+    })
+    .await?)
+}
+
+// The source and everything the capture task keeps between frames
+struct Camera {
+    // None while the device is unplugged
+    source: Option<Box<dyn FrameSource>>,
+    // Whether the source can go away and come back, see SourceMetadata
+    live: bool,
+    source_spec: SourceSpec,
+    source_id: Arc<str>,
+    geometry: FrameGeometry,
+    sensor_size: Size,
+    roi: Option<Rect>,
+    scaled_size: Size,
+    frame_size: Size,
+    orientation: Orientation,
+    fps: f64,
+    trigger_mode: TriggerMode,
+    // Recording to start with, taken when the task starts
+    record_dir: Option<PathBuf>,
+    recording: Option<Recording>,
+    sequence: u64,
+    failed_reads: u32,
+    watchdog: Watchdog,
+    // Index into CAMERA_BACKENDS of the backend the camera is opened through
+    backend: usize,
+    // Freezes in a row, each less than STEADY_AFTER after the camera was reopened
+    freezes: u32,
+    reopened_at: Option<Instant>,
+    frame_bgr: Mat,
+    frame_resized: Mat,
+    frame_scaled: Mat,
+    frame_oriented: Mat,
+    frame_rgba: Mat,
+    frames: FrameFeed,
+    events: EventBus,
+}
+
+impl Camera {
+    fn new(
+        config: CaptureConfig,
+        source: Box<dyn FrameSource>,
+        frames: FrameFeed,
+        events: EventBus,
+    ) -> Result<Self> {
+        let geometry = config.geometry()?;
+        let frame_size = config.frame_size();
+        let scaled_size = config.scaled_size();
+        let CaptureConfig {
+            source_spec,
+            sensor_size,
+            roi,
+            orientation,
+            fps,
+            trigger_mode,
+            record_dir,
+            ..
+        } = config;
+        let camera = Camera {
+            live: source.metadata()?.live,
+            source: Some(source),
+            source_id: source_spec.describe().into(),
+            source_spec,
+            geometry,
+            sensor_size,
+            roi,
+            scaled_size,
+            frame_size,
+            orientation,
+            fps,
+            trigger_mode,
+            record_dir,
+            recording: None,
+            sequence: 0,
+            failed_reads: 0,
+            watchdog: Watchdog::default(),
+            backend: 0,
+            freezes: 0,
+            reopened_at: None,
+            frame_bgr: Mat::default(),
+            frame_resized: Mat::default(),
+            frame_scaled: Mat::default(),
+            frame_oriented: Mat::default(),
+            frame_rgba: Mat::default(),
+            frames,
+            events,
+        };
+        camera.set_camera_status(true, format!("{} connected", camera.source_spec.describe()));
+        Ok(camera)
+    }
+
+    fn set_camera_status(&self, connected: bool, status: String) {
+        log::info!("{}", status);
+        self.events.publish(Event::CameraStateChanged { connected, status });
+    }
+
+    fn error(&self, message: String) {
+        log::error!("{}", message);
+        self.events.publish(Event::Error(message));
+    }
+
+    fn set_recording(&mut self, dir: Option<PathBuf>) {
+        if let Some(finished) = self.recording.take() {
+            let path = finished.path().to_path_buf();
+            match finished.finish() {
+                Ok(()) => log::info!("Recording saved to {}", path.display()),
+                Err(e) => self.error(format!("Error finishing {}: {:?}", path.display(), e)),
+            }
+        }
+        if let Some(dir) = dir {
+            match Recording::start(&dir, self.frame_size, self.fps) {
+                Ok(started) => {
+                    log::info!("Recording to {}", started.path().display());
+                    self.recording = Some(started);
+                }
+                Err(e) => self.error(format!("Error starting a recording: {:?}", e)),
+            }
+        }
+        metrics::set_recording(self.recording.is_some());
+        self.events.publish(Event::RecordingChanged(self.recording.is_some()));
+    }
+
+    fn reopen(&mut self) {
+        if let Ok(reopened) = self.source_spec.open_with_backend(CAMERA_BACKENDS[self.backend].0) {
+            self.set_camera_status(true, format!("{} reconnected", self.source_spec.describe()));
+            self.source = Some(reopened);
+            self.failed_reads = 0;
+            self.watchdog.reset();
+        }
+    }
+
+    // The camera still delivers, but only the same frame over and over. It is closed before
+    // it is opened again, a UVC device can't be open twice. Reopening through the same
+    // backend is tried first, a camera that keeps freezing moves on to the next one.
+    fn frozen(&mut self) {
+        self.source = None;
+        let described = self.source_spec.describe();
+        self.freezes = match self.reopened_at {
+            Some(at) if at.elapsed() < STEADY_AFTER => self.freezes + 1,
+            _ => 1,
+        };
+        if self.freezes > 1 && self.source_spec.has_backends() {
+            self.backend = (self.backend + 1) % CAMERA_BACKENDS.len();
+        }
+        let (backend, backend_name) = CAMERA_BACKENDS[self.backend];
+        self.set_camera_status(false, format!("{} froze, reopening it through {}", described, backend_name));
+        self.reopened_at = Some(Instant::now());
+        self.failed_reads = 0;
+        self.watchdog.reset();
+        match self.source_spec.open_with_backend(backend) {
+            Ok(reopened) => {
+                self.source = Some(reopened);
+                self.set_camera_status(true, format!("{} recovered through {}", described, backend_name));
+            }
+            // Left closed, the task keeps trying to reopen it like an unplugged camera
+            Err(e) => self.error(format!("Unable to reopen {} after it froze: {:?}", described, e)),
+        }
+    }
+
+    fn disconnected(&mut self) {
+        let status = format!("{} disconnected, waiting for it to return", self.source_spec.describe());
+        self.set_camera_status(false, status);
+        self.source = None;
+    }
+
+    // One frame from the source through to the subscribers and the recording, false when
+    // the source had none
+    fn read(&mut self) -> Result<bool> {
+        let Some(active) = self.source.as_mut() else {
+            return Ok(false);
+        };
+        if self.trigger_mode == TriggerMode::Software {
+            active.trigger()?;
+        }
+
+        let captured_at = Instant::now();
+        let timestamp = OffsetDateTime::now_utc();
+        match active.read(&mut self.frame_bgr) {
+            Ok(true) => {
+                self.failed_reads = 0;
+                if self.live && self.watchdog.stalled(active.device_timestamp(), &self.frame_bgr)? {
+                    self.frozen();
+                    return Ok(false);
+                }
+            }
+            Ok(false) => {
+                // A camera waiting on a hardware trigger legitimately goes quiet, so don't
+                // count those
+                if self.live && self.trigger_mode == TriggerMode::FreeRunning {
+                    self.failed_reads += 1;
+                    if self.failed_reads >= MAX_FAILED_READS {
+                        self.disconnected();
+                    }
+                }
+                return Ok(false);
+            }
+            Err(e) if self.live => {
+                self.error(format!("Error reading frame: {:?}", e));
+                self.disconnected();
+                return Ok(false);
+            }
+            // An image folder or video doesn't come back from being reopened, it would only
+            // start over. A bad frame is skipped, a file that keeps failing ends the task.
+            Err(e) => {
+                self.failed_reads += 1;
+                if self.failed_reads >= MAX_UNREADABLE_FRAMES {
+                    return Err(e);
+                }
+                self.error(format!("Skipping a frame that can't be read: {:?}", e));
+                return Ok(false);
+            }
+        }
+        metrics::record_since(Stage::Capture, captured_at);
+        let converting = Instant::now();
+        let exposure = active.exposure();
+
+        // A reopened device may come back in a different mode, keep the pipeline's size
+        let sensor_frame = if self.frame_bgr.size()? != self.sensor_size {
+            acceleration::resize_to(&self.frame_bgr, &mut self.frame_resized, self.sensor_size, INTER_LINEAR)?;
+            &self.frame_resized
+        } else {
+            &self.frame_bgr
+        };
+
+        // Crop to the ROI before anything else touches the pixels
+        let cropped;
+        let frame = match self.roi {
+            Some(roi) => {
+                cropped = Mat::roi(sensor_frame, roi)?;
+                &*cropped
+            }
+            None => sensor_frame,
+        };
+
+        // Then shrink, so preview and detection never see the full resolution
+        let frame = if frame.size()? != self.scaled_size {
+            acceleration::resize_to(frame, &mut self.frame_scaled, self.scaled_size, INTER_AREA)?;
+            &self.frame_scaled
+        } else {
+            frame
+        };
+
+        let frame = if self.orientation.is_identity() {
+            frame
+        } else {
+            self.orientation.apply(frame, &mut self.frame_oriented)?;
+            &self.frame_oriented
+        };
+
+        self.sequence += 1;
+        acceleration::convert_color(frame, &mut self.frame_rgba, COLOR_BGR2RGBA)?;
+        metrics::record_since(Stage::Convert, converting);
+        session_log::frame(self.sequence, timestamp, exposure, frame);
+        metrics::frame_captured(captured_at);
+
+        self.frames.publish(Frame {
+            data: Arc::new(FrameBuffer::copy_of(self.frame_rgba.data_bytes()?)),
+            width: self.frame_size.width,
+            height: self.frame_size.height,
+            captured_at,
+            timestamp,
+            sequence: self.sequence,
+            exposure,
+            source_id: Arc::clone(&self.source_id),
+            geometry: self.geometry,
+            gray: Default::default(),
+        });
+
+        if let Some(active) = self.recording.as_mut() {
+            active.write(frame, self.sequence, timestamp, exposure, &self.source_id)?;
+        }
+        Ok(true)
+    }
+}
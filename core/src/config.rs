@@ -0,0 +1,42 @@
+use crate::error::PersistenceError;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{fs, path::PathBuf};
+
+/// ~/.config/rustyrabbit on Linux, the platform equivalent elsewhere
+pub fn config_dir() -> Result<PathBuf, PersistenceError> {
+    let dir = dirs::config_dir().ok_or(PersistenceError::NoConfigDir)?.join("rustyrabbit");
+    fs::create_dir_all(&dir).map_err(|source| PersistenceError::CreateDir {
+        path: dir.clone(),
+        source,
+    })?;
+    Ok(dir)
+}
+
+/// Reads a TOML file from the config directory, None if it doesn't exist yet
+pub fn load<T: DeserializeOwned>(file_name: &str) -> Result<Option<T>, PersistenceError> {
+    let path = config_dir()?.join(file_name);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(source) => return Err(PersistenceError::Read { path, source }),
+    };
+    match toml::from_str(&text) {
+        Ok(value) => Ok(Some(value)),
+        Err(source) => Err(PersistenceError::Parse { path, source }),
+    }
+}
+
+pub fn save<T: Serialize>(file_name: &str, value: &T) -> Result<(), PersistenceError> {
+    let path = config_dir()?.join(file_name);
+    let text = toml::to_string_pretty(value)?;
+    // Write then rename so a crash never leaves a half written file behind
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text).map_err(|source| PersistenceError::Write {
+        path: tmp.clone(),
+        source,
+    })?;
+    fs::rename(&tmp, &path).map_err(|source| PersistenceError::Write { path, source })?;
+    Ok(())
+}
@@ -0,0 +1,198 @@
+use crate::{
+    acceleration,
+    frame::Frame,
+    target::{CalibrationTarget, DetectedView},
+};
+use anyhow::{bail, Context, Result};
+use opencv::{
+    core::{self as cv, Mat, Point, Point2f, Point3f, Size, TermCriteria, TermCriteria_Type, Vector, CV_32F},
+    imgproc::{self, INTER_AREA, MORPH_RECT},
+    prelude::*,
+};
+use ort::{
+    session::{builder::GraphOptimizationLevel, Session},
+    value::Tensor,
+};
+use std::{path::Path, sync::Arc};
+
+// Longest side frames are shrunk to for the model, a multiple of the stride models use
+const INPUT_SIZE: i32 = 640;
+const INPUT_STRIDE: i32 = 32;
+// Heatmap value a corner has to reach
+const THRESHOLD: f32 = 0.5;
+// How far a corner may sit from its place on the grid, in squares
+const GRID_TOLERANCE: f32 = 0.25;
+
+/// A learned chess board corner detector in ONNX format, run with ONNX Runtime. The model
+/// takes a 1x1xHxW float grey image in 0..1, H and W multiples of 32, and returns a corner
+/// heatmap of the same shape or a fraction of its size: corners are the peaks above 0.5.
+pub struct CornerModel {
+    session: Session,
+    name: String,
+}
+
+impl CornerModel {
+    pub fn load(path: &Path) -> Result<Self> {
+        let session = Session::builder()?
+            .with_optimization_level(GraphOptimizationLevel::Level3)?
+            .commit_from_file(path)
+            .with_context(|| format!("Unable to load the corner model {}", path.display()))?;
+        let name = path.file_stem().map_or_else(|| "model".into(), |stem| stem.to_string_lossy().into_owned());
+        log::info!("Loaded the corner model {}", path.display());
+        Ok(CornerModel { session, name })
+    }
+
+    /// The corners the model finds in a grey frame, in frame pixels, strongest first
+    pub fn corners(&self, gray: &Mat) -> Result<Vec<(Point2f, f32)>> {
+        let (width, height) = (gray.cols(), gray.rows());
+        let scale = INPUT_SIZE as f64 / width.max(height) as f64;
+        let round = |side: i32| (((side as f64 * scale) as i32 / INPUT_STRIDE).max(1) * INPUT_STRIDE);
+        let input_size = Size::new(round(width), round(height));
+        let mut small = Mat::default();
+        acceleration::resize_to(gray, &mut small, input_size, INTER_AREA)?;
+        let mut input = Mat::default();
+        small.convert_to(&mut input, CV_32F, 1.0 / 255.0, 0.0)?;
+        let pixels = input.data_typed::<f32>()?.to_vec();
+        let shape = [1usize, 1, input_size.height as usize, input_size.width as usize];
+        let outputs = self.session.run(ort::inputs![Tensor::from_array((shape, pixels))?]?)?;
+        let (shape, heat) = outputs[0].try_extract_raw_tensor::<f32>()?;
+        let [.., rows, cols] = shape[..] else {
+            bail!("The corner model returned a {:?} tensor, not a heatmap", shape);
+        };
+        let heat = Mat::new_rows_cols_with_data(rows as i32, cols as i32, heat)?;
+
+        // Peaks are the pixels the 3x3 maximum leaves unchanged
+        let mut peaks = Mat::default();
+        let kernel = imgproc::get_structuring_element(MORPH_RECT, Size::new(3, 3), Point::new(-1, -1))?;
+        imgproc::dilate_def(&heat, &mut peaks, &kernel)?;
+        let (x_scale, y_scale) = (width as f32 / cols as f32, height as f32 / rows as f32);
+        let mut corners = Vec::new();
+        for row in 0..rows as i32 {
+            for col in 0..cols as i32 {
+                let value = *heat.at_2d::<f32>(row, col)?;
+                if value >= THRESHOLD && value >= *peaks.at_2d::<f32>(row, col)? {
+                    let corner = Point2f::new((col as f32 + 0.5) * x_scale - 0.5, (row as f32 + 0.5) * y_scale - 0.5);
+                    corners.push((corner, value));
+                }
+            }
+        }
+        corners.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(corners)
+    }
+}
+
+/// A chess board found by its own detector, and by a [`CornerModel`] in the frames that
+/// one gives up on, e.g. under glare or heavy blur. The model's corners are put in grid
+/// order through the board's outline and refined like the classical ones, so its views
+/// are used like any other.
+pub struct WithCornerModel {
+    target: Arc<dyn CalibrationTarget>,
+    model: Arc<CornerModel>,
+}
+
+impl WithCornerModel {
+    /// `target`'s points have to be chess board corners listed row by row
+    pub fn new(target: Arc<dyn CalibrationTarget>, model: Arc<CornerModel>) -> Self {
+        WithCornerModel { target, model }
+    }
+
+    // The grid the corners lie on, in the target's point order, None when they don't make one
+    fn order(&self, corners: &[Point2f]) -> Result<Option<Vector<Point2f>>> {
+        let mut hull = Vector::<Point2f>::new();
+        imgproc::convex_hull(&Vector::from_slice(corners), &mut hull, true, true)?;
+        let perimeter = imgproc::arc_length(&hull, true)?;
+        let mut outline = Vector::<Point2f>::new();
+        for epsilon in [0.01, 0.02, 0.04, 0.08] {
+            imgproc::approx_poly_dp(&hull, &mut outline, perimeter * epsilon, true)?;
+            if outline.len() <= 4 {
+                break;
+            }
+        }
+        if outline.len() != 4 {
+            return Ok(None);
+        }
+        // From the corner nearest the image's top left, around the outline
+        let mut outline = outline.to_vec();
+        let first = (0..4).min_by(|&a, &b| (outline[a].x + outline[a].y).total_cmp(&(outline[b].x + outline[b].y)));
+        outline.rotate_left(first.unwrap_or(0));
+
+        let size = self.target.grid_size();
+        let (last_col, last_row) = ((size.width - 1) as f32, (size.height - 1) as f32);
+        let grid = Vector::from_slice(&[
+            Point2f::new(0., 0.),
+            Point2f::new(last_col, 0.),
+            Point2f::new(last_col, last_row),
+            Point2f::new(0., last_row),
+        ]);
+        // The rows can run along either side of the outline
+        let along_second = vec![outline[0], outline[3], outline[2], outline[1]];
+        for quad in [outline.clone(), along_second] {
+            let to_grid = imgproc::get_perspective_transform_def(&Vector::from_slice(&quad), &grid)?;
+            let mut on_grid = Vector::<Point2f>::new();
+            cv::perspective_transform(&Vector::from_slice(corners), &mut on_grid, &to_grid)?;
+            let mut ordered = vec![None; corners.len()];
+            let fits = on_grid.iter().zip(corners).all(|(place, &corner)| {
+                let (col, row) = (place.x.round(), place.y.round());
+                let near = (place.x - col).abs() < GRID_TOLERANCE && (place.y - row).abs() < GRID_TOLERANCE;
+                let inside = (0.0..=last_col).contains(&col) && (0.0..=last_row).contains(&row);
+                let index = (row * size.width as f32 + col) as usize;
+                near && inside && ordered[index].replace(corner).is_none()
+            });
+            if fits {
+                return Ok(Some(ordered.into_iter().flatten().collect()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl CalibrationTarget for WithCornerModel {
+    fn describe(&self) -> String {
+        format!("{} or the {} corner model", self.target.describe(), self.model.name)
+    }
+
+    fn grid_size(&self) -> Size {
+        self.target.grid_size()
+    }
+
+    fn object_points(&self) -> Vector<Point3f> {
+        self.target.object_points()
+    }
+
+    fn spacing(&self) -> f64 {
+        self.target.spacing()
+    }
+
+    fn detect(&self, frame: &Frame) -> Result<Option<DetectedView>> {
+        if let Some(view) = self.target.detect(frame)? {
+            return Ok(Some(view));
+        }
+        let gray = frame.gray_mat()?;
+        let size = self.grid_size();
+        let count = (size.width * size.height) as usize;
+        let corners = self.model.corners(&gray)?;
+        if corners.len() < count {
+            return Ok(None);
+        }
+        // Stray peaks on glare are the weakest
+        let corners: Vec<Point2f> = corners.into_iter().take(count).map(|(corner, _)| corner).collect();
+        let Some(mut corners) = self.order(&corners)? else {
+            return Ok(None);
+        };
+        let term_criteria = TermCriteria::new(
+            TermCriteria_Type::COUNT as i32 | TermCriteria_Type::EPS as i32,
+            30,
+            0.1,
+        )?;
+        imgproc::corner_sub_pix(&*gray, &mut corners, Size::new(11, 11), Size::new(-1, -1), term_criteria)?;
+        log::debug!("The corner model found the {} the classical detector missed", self.target.describe());
+        Ok(Some(DetectedView {
+            image_points: corners,
+            object_points: self.object_points(),
+        }))
+    }
+
+    fn draw_overlay(&self, rgba: &mut Mat, view: &DetectedView) -> Result<()> {
+        self.target.draw_overlay(rgba, view)
+    }
+}
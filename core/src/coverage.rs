@@ -0,0 +1,141 @@
+use anyhow::Result;
+use opencv::{
+    core::{add_weighted, Mat, Point, Point2f, Rect, Scalar, Size, Vector},
+    imgproc::{line, rectangle, FILLED, LINE_8},
+    prelude::*,
+};
+
+// The frame is split into this many regions, a good calibration has corners in all of them
+const GRID_COLS: i32 = 8;
+const GRID_ROWS: i32 = 6;
+// Views touching a region before it counts as well covered
+const WELL_COVERED: u32 = 3;
+const TINT_ALPHA: f64 = 0.3;
+
+/// Where accepted chessboard corners have landed so far, per region of the frame
+pub struct Coverage {
+    frame_size: Size,
+    views: Vec<u32>,
+}
+
+impl Coverage {
+    pub fn new(frame_size: Size) -> Self {
+        Coverage {
+            frame_size,
+            views: vec![0; (GRID_COLS * GRID_ROWS) as usize],
+        }
+    }
+
+    pub fn frame_size(&self) -> Size {
+        self.frame_size
+    }
+
+    /// Counts each region once per view, however many corners fall into it
+    pub fn add(&mut self, corners: &Vector<Point2f>) {
+        let touched = self.touched(corners);
+        for (views, touched) in self.views.iter_mut().zip(touched) {
+            if touched {
+                *views += 1;
+            }
+        }
+    }
+
+    /// Takes back a view that was added earlier
+    pub fn remove(&mut self, corners: &Vector<Point2f>) {
+        let touched = self.touched(corners);
+        for (views, touched) in self.views.iter_mut().zip(touched) {
+            if touched {
+                *views = views.saturating_sub(1);
+            }
+        }
+    }
+
+    fn touched(&self, corners: &Vector<Point2f>) -> Vec<bool> {
+        let mut touched = vec![false; self.views.len()];
+        for corner in corners.iter() {
+            if let Some(cell) = self.cell(corner) {
+                touched[cell] = true;
+            }
+        }
+        touched
+    }
+
+    pub fn regions(&self) -> usize {
+        self.views.len()
+    }
+
+    pub fn uncovered(&self) -> usize {
+        self.views.iter().filter(|&&views| views == 0).count()
+    }
+
+    /// Which ninth of the frame has the most regions nobody has seen corners in, None once
+    /// everything is covered
+    pub fn least_covered_area(&self) -> Option<&'static str> {
+        const AREAS: [[&str; 3]; 3] = [
+            ["top-left corner", "top edge", "top-right corner"],
+            ["left edge", "middle", "right edge"],
+            ["bottom-left corner", "bottom edge", "bottom-right corner"],
+        ];
+        let mut empty = [[0; 3]; 3];
+        for row in 0..GRID_ROWS {
+            for col in 0..GRID_COLS {
+                if self.views[(row * GRID_COLS + col) as usize] == 0 {
+                    empty[(row * 3 / GRID_ROWS) as usize][(col * 3 / GRID_COLS) as usize] += 1;
+                }
+            }
+        }
+        let (count, area) = (0..3)
+            .flat_map(|row| (0..3).map(move |col| (row, col)))
+            .map(|(row, col)| (empty[row][col], AREAS[row][col]))
+            .max_by_key(|(count, _)| *count)?;
+        (count > 0).then_some(area)
+    }
+
+    /// Tints an RGBA preview frame: red where nothing was seen yet, amber for thin coverage,
+    /// green once a region is well covered
+    pub fn draw(&self, rgba: &mut Mat) -> Result<()> {
+        let mut tint = rgba.try_clone()?;
+        for row in 0..GRID_ROWS {
+            for col in 0..GRID_COLS {
+                let views = self.views[(row * GRID_COLS + col) as usize];
+                let color = match views {
+                    0 => Scalar::new(255.0, 0.0, 0.0, 255.0),
+                    v if v < WELL_COVERED => Scalar::new(255.0, 190.0, 0.0, 255.0),
+                    _ => Scalar::new(0.0, 200.0, 0.0, 255.0),
+                };
+                rectangle(&mut tint, self.cell_rect(col, row), color, FILLED, LINE_8, 0)?;
+            }
+        }
+        let original = rgba.try_clone()?;
+        add_weighted(&original, 1.0 - TINT_ALPHA, &tint, TINT_ALPHA, 0.0, rgba, -1)?;
+
+        let grid_color = Scalar::new(255.0, 255.0, 255.0, 255.0);
+        for col in 1..GRID_COLS {
+            let x = col * self.frame_size.width / GRID_COLS;
+            line(rgba, Point::new(x, 0), Point::new(x, self.frame_size.height), grid_color, 1, LINE_8, 0)?;
+        }
+        for row in 1..GRID_ROWS {
+            let y = row * self.frame_size.height / GRID_ROWS;
+            line(rgba, Point::new(0, y), Point::new(self.frame_size.width, y), grid_color, 1, LINE_8, 0)?;
+        }
+        Ok(())
+    }
+
+    fn cell(&self, point: Point2f) -> Option<usize> {
+        let col = (point.x * GRID_COLS as f32 / self.frame_size.width as f32).floor() as i32;
+        let row = (point.y * GRID_ROWS as f32 / self.frame_size.height as f32).floor() as i32;
+        if (0..GRID_COLS).contains(&col) && (0..GRID_ROWS).contains(&row) {
+            Some((row * GRID_COLS + col) as usize)
+        } else {
+            None
+        }
+    }
+
+    fn cell_rect(&self, col: i32, row: i32) -> Rect {
+        let x0 = col * self.frame_size.width / GRID_COLS;
+        let y0 = row * self.frame_size.height / GRID_ROWS;
+        let x1 = (col + 1) * self.frame_size.width / GRID_COLS;
+        let y1 = (row + 1) * self.frame_size.height / GRID_ROWS;
+        Rect::new(x0, y0, x1 - x0, y1 - y0)
+    }
+}
@@ -0,0 +1,42 @@
+use opencv::{
+    core::{self, GpuMat, Mat, Size, Stream},
+    cudaimgproc, cudawarping,
+    imgproc::INTER_LINEAR,
+    prelude::*,
+};
+
+// The conversions acceleration hands to an NVIDIA GPU. Every frame is uploaded, converted
+// and downloaded again, only the undistortion tables stay on the device between frames.
+
+pub fn available() -> bool {
+    core::get_cuda_enabled_device_count().is_ok_and(|devices| devices > 0)
+}
+
+pub fn upload(mat: &Mat) -> opencv::Result<GpuMat> {
+    let mut device = GpuMat::new_def()?;
+    device.upload(mat)?;
+    Ok(device)
+}
+
+pub fn convert_color(src: &Mat, dst: &mut Mat, code: i32) -> opencv::Result<()> {
+    let src = upload(src)?;
+    let mut result = GpuMat::new_def()?;
+    cudaimgproc::cvt_color(&src, &mut result, code, 0, &mut Stream::null()?)?;
+    result.download(dst)
+}
+
+pub fn resize_to(src: &Mat, dst: &mut Mat, size: Size, interpolation: i32) -> opencv::Result<()> {
+    let src = upload(src)?;
+    let mut result = GpuMat::new_def()?;
+    cudawarping::resize(&src, &mut result, size, 0.0, 0.0, interpolation, &mut Stream::null()?)?;
+    result.download(dst)
+}
+
+// The maps have to be CV_32FC1, see RemapMaps::map_type
+pub fn remap_with(src: &Mat, dst: &mut Mat, map_x: &GpuMat, map_y: &GpuMat) -> opencv::Result<()> {
+    let src = upload(src)?;
+    let mut result = GpuMat::new_def()?;
+    // Black borders like the CPU path
+    cudawarping::remap_def(&src, &mut result, map_x, map_y, INTER_LINEAR)?;
+    result.download(dst)
+}
@@ -0,0 +1,285 @@
+use crate::{
+    frame::{Frame, FrameSubscriber},
+    metrics::{self, Stage},
+    preprocess,
+    runtime::runtime,
+    session_log,
+    target::{CalibrationTarget, DetectedView},
+    tuning::Tuning,
+};
+use anyhow::Result;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::{mpsc, watch, Semaphore},
+    time::sleep_until,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{Instrument, Span};
+
+/// Detections running at once unless set otherwise. A 4K chess board keeps a core busy for
+/// longer than a frame interval, two of them keep the overlay moving without starving the
+/// camera and preview of cores.
+pub const DEFAULT_WORKERS: usize = 2;
+
+/// Which frames detection takes while it is slower than the camera
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum QueuePolicy {
+    /// The newest frame whenever a worker is free, the ones before it are skipped
+    #[default]
+    Latest,
+    /// Every frame in capture order on a single worker, for image folders and video files
+    /// where a skipped frame is a lost view. A live camera that outruns detection still
+    /// loses the frames its queue has no room for.
+    Every,
+}
+
+/// How detection runs next to the camera
+#[derive(Debug, Clone, Copy)]
+pub struct DetectionConfig {
+    pub workers: usize,
+    pub policy: QueuePolicy,
+    /// Most frames a second looked at while taking the newest, None for as many as the
+    /// workers get through. Leaves the cores to the camera and preview, which keep their rate.
+    pub max_fps: Option<f64>,
+}
+
+impl Default for DetectionConfig {
+    fn default() -> Self {
+        Self {
+            workers: DEFAULT_WORKERS,
+            policy: QueuePolicy::default(),
+            max_fps: None,
+        }
+    }
+}
+
+/// A frame with what was found in it
+pub struct Detection {
+    pub frame: Frame,
+    pub result: Result<Option<DetectedView>>,
+    /// From capture until a worker took the frame
+    pub queued: Duration,
+    /// Preprocessing the frame and looking for the target
+    pub detect: Duration,
+}
+
+/// Looks for a target in the frames of a feed on the runtime's blocking threads, so a slow
+/// detection holds up neither the camera task nor whoever handles the results. A frame is
+/// only taken once a worker is free, and goes through the preprocessing set in `tuning` as
+/// it is then. Taking the newest frames, results that piled up while whoever handles them
+/// was busy are skipped for the newest, and fewer detections run at once until it keeps
+/// up. Cancelling `cancel` or dropping the pool stops it, detections still running finish
+/// and are thrown away.
+pub struct DetectionPool {
+    results: mpsc::Receiver<Detection>,
+    // Capture time of the newest frame returned, a slower worker's older frame is dropped
+    newest: Option<Instant>,
+    policy: QueuePolicy,
+    // One permit per detection that may run at once
+    workers: Arc<Semaphore>,
+    running: usize,
+    max_running: usize,
+    cancel: CancellationToken,
+}
+
+impl DetectionPool {
+    pub fn start(
+        target: Arc<dyn CalibrationTarget>,
+        frames: FrameSubscriber,
+        config: DetectionConfig,
+        tuning: watch::Receiver<Tuning>,
+        cancel: CancellationToken,
+    ) -> Self {
+        // Workers finish in any order, one alone keeps every frame in sequence
+        let workers = match config.policy {
+            QueuePolicy::Latest => config.workers.max(1),
+            QueuePolicy::Every => 1,
+        };
+        let (sender, results) = mpsc::channel(workers);
+        let span = tracing::info_span!("detect", target = %target.describe(), workers);
+        let permits = Arc::new(Semaphore::new(workers));
+        let dispatched = dispatch(target, frames, config, Arc::clone(&permits), tuning, sender, cancel.clone());
+        runtime().spawn(dispatched.instrument(span));
+        Self {
+            results,
+            newest: None,
+            policy: config.policy,
+            workers: permits,
+            running: workers,
+            max_running: workers,
+            cancel,
+        }
+    }
+
+    /// The next detection of a frame newer than the last one returned. Safe to cancel, e.g.
+    /// in `select!`. None once the pool stopped, which only happens if a worker panicked.
+    pub async fn recv(&mut self) -> Option<Detection> {
+        loop {
+            let waited = self.results.is_empty();
+            let mut detection = self.results.recv().await?;
+            if self.policy == QueuePolicy::Latest {
+                let mut backlog = false;
+                while let Ok(newer) = self.results.try_recv() {
+                    backlog = true;
+                    if newer.frame.captured_at > detection.frame.captured_at {
+                        detection = newer;
+                    }
+                }
+                self.adapt(waited, backlog);
+            }
+            if self.newest.is_some_and(|newest| detection.frame.captured_at <= newest) {
+                continue;
+            }
+            self.newest = Some(detection.frame.captured_at);
+            return Some(detection);
+        }
+    }
+
+    // A backlog means detections finish faster than they are handled and the extra ones
+    // only take cores from whoever handles them, one fewer runs at a time. Having to wait
+    // for a result gives one back, up to the configured workers.
+    fn adapt(&mut self, waited: bool, backlog: bool) {
+        let running = self.running;
+        if backlog && self.running > 1 {
+            // Only a free permit can be taken away, a busy pool is tried again next time
+            self.running -= self.workers.forget_permits(1);
+        } else if waited && !backlog && self.running < self.max_running {
+            self.workers.add_permits(1);
+            self.running += 1;
+        }
+        if self.running != running {
+            log::debug!("Running {} detections at once", self.running);
+        }
+    }
+}
+
+impl Drop for DetectionPool {
+    fn drop(&mut self) {
+        self.cancel.cancel();
+    }
+}
+
+async fn dispatch(
+    target: Arc<dyn CalibrationTarget>,
+    frames: FrameSubscriber,
+    config: DetectionConfig,
+    workers: Arc<Semaphore>,
+    tuning: watch::Receiver<Tuning>,
+    results: mpsc::Sender<Detection>,
+    cancel: CancellationToken,
+) {
+    let interval = match config.policy {
+        QueuePolicy::Latest => config.max_fps.filter(|&fps| fps > 0.0).map(|fps| Duration::from_secs_f64(1.0 / fps)),
+        QueuePolicy::Every => None,
+    };
+    let mut next_frame: Option<Instant> = None;
+    loop {
+        if let Some(at) = next_frame {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = sleep_until(at.into()) => {}
+            }
+        }
+        let permit = tokio::select! {
+            _ = cancel.cancelled() => return,
+            permit = Arc::clone(&workers).acquire_owned() => match permit {
+                Ok(permit) => permit,
+                Err(_) => return,
+            },
+        };
+        let frame = tokio::select! {
+            _ = cancel.cancelled() => return,
+            frame = frames.recv() => frame,
+        };
+        let frame = match config.policy {
+            QueuePolicy::Latest => frames.latest().unwrap_or(frame),
+            QueuePolicy::Every => frame,
+        };
+        next_frame = interval.map(|interval| Instant::now() + interval);
+        let target = Arc::clone(&target);
+        let steps = tuning.borrow().preprocess.clone();
+        let results = results.clone();
+        let span = Span::current();
+        runtime().spawn_blocking(move || {
+            let _entered = span.enter();
+            let started = Instant::now();
+            let queued = started.saturating_duration_since(frame.captured_at);
+            let result = preprocess::for_detection(&steps, &frame).and_then(|processed| target.detect(&processed));
+            let detect = started.elapsed();
+            metrics::record(Stage::Detect, detect);
+            session_log::detected(frame.sequence, &result);
+            tracing::debug!(
+                sequence = frame.sequence,
+                queued_ms = queued.as_secs_f64() * 1000.0,
+                detect_ms = detect.as_secs_f64() * 1000.0,
+                found = matches!(result, Ok(Some(_))),
+                "Looked for the target"
+            );
+            let detection = Detection {
+                frame,
+                result,
+                queued,
+                detect,
+            };
+            // The worker stays busy until its result is taken, so a slow consumer slows
+            // detection down instead of results piling up. Fails once the pool is gone.
+            let _ = results.blocking_send(detection);
+            drop(permit);
+        });
+    }
+}
+
+/// Mean time per stage of the frames a calibration looked at, to tell whether detection or
+/// drawing the overlay holds it up, and how many frames it never got to
+#[derive(Debug, Default)]
+pub struct StageTimings {
+    frames: u32,
+    queued: Duration,
+    detect: Duration,
+    overlay: Duration,
+    skipped: u64,
+    last_sequence: Option<u64>,
+}
+
+impl StageTimings {
+    pub fn detected(&mut self, detection: &Detection) {
+        self.frames += 1;
+        self.queued += detection.queued;
+        self.detect += detection.detect;
+        // Sequence numbers start over when the camera is switched
+        let sequence = detection.frame.sequence;
+        if let Some(last) = self.last_sequence.filter(|&last| sequence > last) {
+            self.skipped += sequence - last - 1;
+        }
+        self.last_sequence = Some(sequence);
+    }
+
+    pub fn drawn(&mut self, overlay: Duration) {
+        self.overlay += overlay;
+    }
+
+    /// Frames from the camera that weren't looked at, skipped or dropped from a full queue
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// For the log, None before the first frame
+    pub fn summary(&self) -> Option<String> {
+        if self.frames == 0 {
+            return None;
+        }
+        let mean_ms = |total: Duration| total.as_secs_f64() * 1000.0 / self.frames as f64;
+        Some(format!(
+            "{:.1} ms queued, {:.1} ms detecting, {:.1} ms drawing on average over {} frames, {} skipped",
+            mean_ms(self.queued),
+            mean_ms(self.detect),
+            mean_ms(self.overlay),
+            self.frames,
+            self.skipped
+        ))
+    }
+}
@@ -0,0 +1,87 @@
+use crate::profiles::Calibration;
+use anyhow::Result;
+use opencv::{
+    calib3d::project_points_def,
+    core::{Mat, Point, Point2d, Point3d, Scalar, Vector, CV_8UC4},
+    imgproc::{line, put_text, FONT_HERSHEY_SIMPLEX, LINE_AA},
+    prelude::*,
+};
+
+const VIEW_WIDTH: i32 = 320;
+const GRID_COLS: i32 = 16;
+const GRID_ROWS: i32 = 12;
+// Points per grid line, enough for the curves to look smooth
+const SAMPLES: i32 = 32;
+
+/// Where the lens moves a regular grid on the sensor: grey is the ideal pinhole image, red
+/// where the solved model says the lines really land. A model that folds over or flies off
+/// the edges is wrong however low its RMS error.
+pub fn render(calibration: &Calibration) -> Result<Mat> {
+    let sensor = (calibration.sensor_width as f64, calibration.sensor_height as f64);
+    let scale = VIEW_WIDTH as f64 / sensor.0;
+    let view_height = (sensor.1 * scale).round().max(1.0) as i32;
+    // Room around the frame so barrel or pincushion bulging past the edges stays visible
+    let margin = VIEW_WIDTH / 8;
+    let mut canvas = Mat::new_rows_cols_with_default(
+        view_height + 2 * margin,
+        VIEW_WIDTH + 2 * margin,
+        CV_8UC4,
+        Scalar::new(255.0, 255.0, 255.0, 255.0),
+    )?;
+    let to_view = |p: Point2d| {
+        Point::new(
+            (p.x * scale).round() as i32 + margin,
+            (p.y * scale).round() as i32 + margin,
+        )
+    };
+
+    let m = calibration.camera_matrix;
+    let (fx, fy, cx, cy) = (m[0][0], m[1][1], m[0][2], m[1][2]);
+    let mut lines: Vec<Vec<Point2d>> = Vec::new();
+    for col in 0..=GRID_COLS {
+        let u = sensor.0 * col as f64 / GRID_COLS as f64;
+        lines.push((0..=SAMPLES).map(|i| Point2d::new(u, sensor.1 * i as f64 / SAMPLES as f64)).collect());
+    }
+    for row in 0..=GRID_ROWS {
+        let v = sensor.1 * row as f64 / GRID_ROWS as f64;
+        lines.push((0..=SAMPLES).map(|i| Point2d::new(sensor.0 * i as f64 / SAMPLES as f64, v)).collect());
+    }
+
+    let camera_matrix = calibration.camera_matrix()?;
+    let dist_coeffs = calibration.dist_coeffs()?;
+    let no_motion = Mat::from_slice(&[0.0f64; 3])?.try_clone()?;
+    let ideal_color = Scalar::new(170.0, 170.0, 170.0, 255.0);
+    let distorted_color = Scalar::new(220.0, 0.0, 0.0, 255.0);
+    let mut max_shift: f64 = 0.0;
+    for ideal in &lines {
+        // Ideal pixels back to rays through the pinhole, then through the lens model
+        let rays: Vector<Point3d> = ideal
+            .iter()
+            .map(|p| Point3d::new((p.x - cx) / fx, (p.y - cy) / fy, 1.0))
+            .collect();
+        let mut distorted: Vector<Point2d> = Vector::new();
+        project_points_def(&rays, &no_motion, &no_motion, &camera_matrix, &dist_coeffs, &mut distorted)?;
+
+        for (i, pair) in ideal.windows(2).enumerate() {
+            line(&mut canvas, to_view(pair[0]), to_view(pair[1]), ideal_color, 1, LINE_AA, 0)?;
+            let (a, b) = (distorted.get(i)?, distorted.get(i + 1)?);
+            line(&mut canvas, to_view(a), to_view(b), distorted_color, 1, LINE_AA, 0)?;
+        }
+        for (p, q) in ideal.iter().zip(distorted.iter()) {
+            max_shift = max_shift.max((q.x - p.x).hypot(q.y - p.y));
+        }
+    }
+
+    put_text(
+        &mut canvas,
+        &format!("Largest shift {:.1} px", max_shift),
+        Point::new(4, 14),
+        FONT_HERSHEY_SIMPLEX,
+        0.4,
+        Scalar::new(0.0, 0.0, 0.0, 255.0),
+        1,
+        LINE_AA,
+        false,
+    )?;
+    Ok(canvas)
+}
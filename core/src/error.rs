@@ -0,0 +1,70 @@
+// What can go wrong, by area. Functions still return anyhow::Result so context can be
+// added on the way up; a front end that reacts to a kind of failure rather than just
+// showing it downcasts to one of these.
+
+use crate::workflow::WorkflowState;
+use std::{io, path::PathBuf};
+use thiserror::Error;
+
+/// Opening or reading a frame source
+#[derive(Debug, Error)]
+pub enum CameraError {
+    #[error("Unable to open {device}, is it connected and not in use by another program?")]
+    NotFound { device: String },
+    #[error("Unable to open {device}: {reason}")]
+    Backend { device: String, reason: String },
+    #[error("{device} delivers {format} frames, which aren't supported")]
+    UnsupportedFormat { device: String, format: String },
+    #[error("Hardware triggering needs a camera opened through Aravis or Spinnaker")]
+    HardwareTriggerUnsupported,
+}
+
+/// Finding a calibration target in a frame
+#[derive(Debug, Error)]
+pub enum DetectionError {
+    #[error("A target needs at least 2x2 points and a positive square size")]
+    InvalidTarget,
+    #[error("Unknown ArUco dictionary {0:?}")]
+    UnknownDictionary(String),
+    #[error("Looking for the {target} failed: {reason}")]
+    Failed { target: String, reason: String },
+}
+
+/// Collecting views and solving for the intrinsics
+#[derive(Debug, Error)]
+pub enum CalibrationError {
+    #[error("No views captured")]
+    NoViews,
+    #[error("Run a calibration first")]
+    NothingToSolve,
+    #[error("Keep at least {required} views to calibrate, {selected} are selected")]
+    TooFewViews { required: usize, selected: usize },
+    #[error("The solver failed: {0}")]
+    Solver(#[from] opencv::Error),
+}
+
+/// Reading and writing the files in the config folder
+#[derive(Debug, Error)]
+pub enum PersistenceError {
+    #[error("No config folder on this platform")]
+    NoConfigDir,
+    #[error("Unable to create {}: {source}", .path.display())]
+    CreateDir { path: PathBuf, source: io::Error },
+    #[error("Unable to read {}: {source}", .path.display())]
+    Read { path: PathBuf, source: io::Error },
+    #[error("Unable to parse {}: {source}", .path.display())]
+    Parse { path: PathBuf, source: toml::de::Error },
+    #[error("Unable to write {}: {source}", .path.display())]
+    Write { path: PathBuf, source: io::Error },
+    #[error("Unable to store the settings: {0}")]
+    Serialize(#[from] toml::ser::Error),
+}
+
+/// Moving through the calibration workflow
+#[derive(Debug, Error)]
+pub enum WorkflowError {
+    #[error("A calibration is {} already, wait for it or cancel it first", .0.label())]
+    Busy(WorkflowState),
+    #[error("The calibration can't go from {} to {}", .from.label(), .to.label())]
+    NotAllowed { from: WorkflowState, to: WorkflowState },
+}
@@ -0,0 +1,71 @@
+use crate::{
+    calibration::{CalibrationViews, CapturedView},
+    lock::Locked,
+    profiles::Calibration,
+    workflow::WorkflowState,
+};
+use std::sync::{Arc, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// What the camera and calibration tasks tell the front ends. Messages are for the status
+/// line and have been logged already. Views are copied for every subscriber, OpenCV's
+/// images can't be shared between threads.
+#[derive(Clone)]
+pub enum Event {
+    /// The camera was opened, went away or came back
+    CameraStateChanged { connected: bool, status: String },
+    /// A recording started or stopped
+    RecordingChanged(bool),
+    /// A calibration or re-solve started or ended
+    CalibratingChanged(bool),
+    /// The calibration workflow moved to this state
+    WorkflowChanged(WorkflowState),
+    CalibrationProgress { captured: usize, required: usize, status: String },
+    /// A calibration message without a change in progress
+    Status(String),
+    /// A new calibration starts without views
+    ViewsCleared,
+    /// A view was taken, after the ones accepted before it
+    ViewAccepted(Box<CapturedView>),
+    /// The view with this index was undone or deleted
+    ViewRemoved(usize),
+    /// A solve was saved, with its views and their errors
+    Solved { views: Box<CalibrationViews>, calibration: Calibration },
+    /// A task failed or gave up on something
+    Error(String),
+}
+
+/// Carries [`Event`]s from the tasks to every front end that subscribed, in the order they
+/// were published. Core code publishes without knowing who listens, so the same camera and
+/// calibration run under the window, the terminal, the browser or no interface at all.
+#[derive(Clone, Default)]
+pub struct EventBus(Arc<Mutex<Vec<UnboundedSender<Event>>>>);
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        // Subscribers that went away are forgotten here
+        self.0.locked().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// A listener for the events published from now on. Events queue up until they are
+    /// received, so a subscriber has to keep receiving or be dropped.
+    pub fn subscribe(&self) -> EventSubscriber {
+        let (sender, receiver) = unbounded_channel();
+        self.0.locked().push(sender);
+        EventSubscriber(receiver)
+    }
+}
+
+/// One listener's events. Receiving returns None once every [`EventBus`] is dropped.
+pub struct EventSubscriber(UnboundedReceiver<Event>);
+
+impl EventSubscriber {
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.0.recv().await
+    }
+
+    /// For a listener on its own thread, outside the runtime
+    pub fn blocking_recv(&mut self) -> Option<Event> {
+        self.0.blocking_recv()
+    }
+}
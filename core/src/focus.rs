@@ -0,0 +1,77 @@
+use crate::{frame::Frame, geometry::FrameGeometry, lock::Locked};
+use anyhow::Result;
+use opencv::{
+    core::{mean_std_dev, no_array, Mat, Point2f, Rect, Vector, BORDER_DEFAULT, CV_64F},
+    imgproc::{bounding_rect, laplacian},
+    prelude::*,
+};
+use std::sync::{Arc, Mutex};
+
+// Headroom left above the peak on the focus bar, so the peak marker isn't pinned to the end
+const BAR_HEADROOM: f64 = 1.25;
+
+#[derive(Default)]
+struct FocusState {
+    current: Option<f64>,
+    peak: f64,
+    // The peak only compares frames of the same camera and crop
+    geometry: Option<FrameGeometry>,
+}
+
+/// Sharpness of the live image, for focusing a lens by hand: turn until the number peaks
+#[derive(Clone, Default)]
+pub struct FocusMeter(Arc<Mutex<FocusState>>);
+
+impl FocusMeter {
+    /// Measures a frame, or only the part covered by `corners` when a board was found
+    pub fn measure(&self, frame: &Frame, corners: Option<&Vector<Point2f>>) -> Result<()> {
+        let whole = Rect::new(0, 0, frame.width, frame.height);
+        let region = match corners {
+            Some(corners) if !corners.is_empty() => bounding_rect(corners)? & whole,
+            _ => whole,
+        };
+        if region.width < 3 || region.height < 3 {
+            return Ok(());
+        }
+        let gray = frame.gray_mat()?;
+        let value = variance_of_laplacian(&Mat::roi(&*gray, region)?.try_clone()?)?;
+        let geometry = frame.geometry;
+
+        let mut state = self.0.locked();
+        if state.geometry != Some(geometry) {
+            state.geometry = Some(geometry);
+            state.peak = 0.0;
+        }
+        state.current = Some(value);
+        state.peak = state.peak.max(value);
+        Ok(())
+    }
+
+    pub fn reset_peak(&self) {
+        let mut state = self.0.locked();
+        state.peak = state.current.unwrap_or(0.0);
+    }
+
+    /// Current value and peak as fractions of the bar, plus a label. None before the first frame.
+    pub fn reading(&self) -> Option<(f32, f32, String)> {
+        let state = self.0.locked();
+        let current = state.current?;
+        let full_scale = (state.peak * BAR_HEADROOM).max(f64::EPSILON);
+        Some((
+            (current / full_scale) as f32,
+            (state.peak / full_scale) as f32,
+            format!("Sharpness {:.0} (peak {:.0})", current, state.peak),
+        ))
+    }
+}
+
+// Sharp edges give a strong second derivative, blur flattens it
+fn variance_of_laplacian(gray: &Mat) -> Result<f64> {
+    let mut response = Mat::default();
+    laplacian(gray, &mut response, CV_64F, 1, 1.0, 0.0, BORDER_DEFAULT)?;
+    let mut mean = Mat::default();
+    let mut std_dev = Mat::default();
+    mean_std_dev(&response, &mut mean, &mut std_dev, &no_array())?;
+    let std_dev = *std_dev.at::<f64>(0)?;
+    Ok(std_dev * std_dev)
+}
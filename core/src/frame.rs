@@ -0,0 +1,327 @@
+use crate::{
+    acceleration,
+    geometry::FrameGeometry,
+    lock::Locked,
+    metrics::{self, Stage},
+};
+use anyhow::Result;
+use opencv::{boxed_ref::BoxedRef, core::{Mat, Size}, imgproc::COLOR_RGBA2GRAY, prelude::*};
+use std::{
+    collections::VecDeque,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex, MutexGuard, OnceLock, PoisonError, Weak,
+    },
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+use tokio::sync::Notify;
+
+/// One captured image plus what we know about how and when it was taken
+#[derive(Debug, Clone)]
+pub struct Frame {
+    /// Tightly packed RGBA8 pixels, width * height * 4 bytes. Shared by every clone of the
+    /// frame, a stage that draws on it makes its own buffer.
+    pub data: Arc<FrameBuffer>,
+    pub width: i32,
+    pub height: i32,
+    /// Monotonic capture time, for latency measurements
+    pub captured_at: Instant,
+    /// Wall clock capture time, for pairing frames with machine positions
+    pub timestamp: OffsetDateTime,
+    /// Increments by one for every frame the camera task reads
+    pub sequence: u64,
+    /// Exposure as reported by the source, in the source's own units
+    pub exposure: Option<f64>,
+    pub source_id: Arc<str>,
+    /// Where this frame sits on the sensor (capture ROI)
+    pub geometry: FrameGeometry,
+    // Grey copy of `data`, made by whichever consumer asks first and shared by all clones.
+    // Frames with other pixels start without one, see with_pixels.
+    pub(crate) gray: Arc<OnceLock<FrameBuffer>>,
+}
+
+impl Frame {
+    /// Milliseconds since the frame was captured
+    pub fn age_ms(&self) -> f64 {
+        self.captured_at.elapsed().as_secs_f64() * 1000.0
+    }
+
+    /// A frame that didn't come from a camera task, such as an image a script loaded, taken
+    /// as the whole sensor
+    pub fn from_rgba(data: FrameBuffer, width: i32, height: i32) -> Frame {
+        Frame {
+            data: Arc::new(data),
+            width,
+            height,
+            captured_at: Instant::now(),
+            timestamp: OffsetDateTime::now_utc(),
+            sequence: 0,
+            exposure: None,
+            source_id: Arc::from("external"),
+            geometry: FrameGeometry::full_sensor(Size::new(width, height)),
+            gray: Arc::default(),
+        }
+    }
+
+    /// This frame with other pixels of the same size, e.g. with an overlay drawn on them
+    pub fn with_pixels(self, data: FrameBuffer) -> Frame {
+        Frame {
+            data: Arc::new(data),
+            gray: Arc::default(),
+            ..self
+        }
+    }
+
+    /// The frame in 8 bit grey, width * height bytes. Converted once, however many of
+    /// detection, focus and marker finding look at the frame.
+    pub fn gray(&self) -> Result<&[u8]> {
+        if let Some(gray) = self.gray.get() {
+            return Ok(gray);
+        }
+        let pixels = Mat::from_slice(self.data.as_slice())?;
+        let mut gray = Mat::default();
+        acceleration::convert_color(&pixels.reshape(4, self.height)?, &mut gray, COLOR_RGBA2GRAY)?;
+        // Two consumers converting at once both get the copy that was stored first
+        let converted = FrameBuffer::copy_of(gray.data_bytes()?);
+        Ok(self.gray.get_or_init(|| converted))
+    }
+
+    /// [`Frame::gray`] as a single channel image borrowing the bytes
+    pub fn gray_mat(&self) -> Result<BoxedRef<'_, Mat>> {
+        Ok(Mat::new_rows_cols_with_data(self.height, self.width, self.gray()?)?)
+    }
+}
+
+/// Free buffers kept for reuse. A few frames' worth covers the camera task, the subscriber
+/// queues and the preview's overlays at once, more would only hold on to memory.
+pub const BUFFERS_KEPT: usize = 12;
+
+// Pixel buffers of frames nobody holds any more
+static FREE_BUFFERS: Mutex<Vec<Vec<u8>>> = Mutex::new(Vec::new());
+
+/// The pixels of a frame. Dropping the last reference hands the memory back for the next
+/// frame instead of freeing it, a 1080p stream at 30 fps would otherwise allocate around
+/// 250 MB a second for the camera alone, plus as much for every overlay drawn.
+#[derive(Debug, Default)]
+pub struct FrameBuffer(Vec<u8>);
+
+impl FrameBuffer {
+    /// A copy of `bytes`, in a buffer given back by an earlier frame when one is free
+    pub fn copy_of(bytes: &[u8]) -> FrameBuffer {
+        let mut buffer = {
+            let mut free = FREE_BUFFERS.locked();
+            // Same size first, frames on one camera all are
+            let found = free
+                .iter()
+                .position(|buffer| buffer.capacity() == bytes.len())
+                .or_else(|| free.iter().position(|buffer| buffer.capacity() >= bytes.len()));
+            found.map(|index| free.swap_remove(index)).unwrap_or_default()
+        };
+        buffer.clear();
+        buffer.extend_from_slice(bytes);
+        FrameBuffer(buffer)
+    }
+}
+
+impl From<Vec<u8>> for FrameBuffer {
+    fn from(bytes: Vec<u8>) -> Self {
+        FrameBuffer(bytes)
+    }
+}
+
+impl Deref for FrameBuffer {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for FrameBuffer {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for FrameBuffer {
+    fn drop(&mut self) {
+        let mut free = FREE_BUFFERS.locked();
+        if free.len() < BUFFERS_KEPT && self.0.capacity() > 0 {
+            free.push(std::mem::take(&mut self.0));
+        }
+    }
+}
+
+/// Frames a subscriber can fall behind by before the oldest is dropped
+pub const QUEUE_DEPTH: usize = 4;
+
+/// Hands every frame of the camera task to each consumer without copying the pixels.
+/// Each subscriber has its own short queue, so a stalled preview or calibration drops its
+/// oldest frames instead of growing without limit or holding up the others.
+#[derive(Clone, Default)]
+pub struct FrameFeed(Arc<Mutex<Vec<Weak<FrameQueue>>>>);
+
+#[derive(Default)]
+struct FrameQueue {
+    // With when each was published, for timing how long frames wait
+    frames: Mutex<VecDeque<(Instant, Frame)>>,
+    // Keeps a wakeup for a consumer that isn't waiting yet, so none is lost
+    published: Notify,
+    // The same for consumers on threads of their own, which wait holding the queue's lock
+    arrived: Condvar,
+    dropped: AtomicU64,
+}
+
+impl FrameFeed {
+    pub fn publish(&self, frame: Frame) {
+        let mut subscribers = self.0.locked();
+        // Subscribers that went away are forgotten here
+        subscribers.retain(|queue| queue.strong_count() > 0);
+        let published_at = Instant::now();
+        let mut deepest = 0;
+        for queue in subscribers.iter().filter_map(Weak::upgrade) {
+            let mut frames = queue.frames.locked();
+            if frames.len() >= QUEUE_DEPTH {
+                frames.pop_front();
+                queue.dropped.fetch_add(1, Ordering::Relaxed);
+                metrics::frame_dropped();
+            }
+            frames.push_back((published_at, frame.clone()));
+            deepest = deepest.max(frames.len());
+            queue.published.notify_one();
+            queue.arrived.notify_all();
+        }
+        metrics::set_queues(subscribers.len(), deepest);
+    }
+
+    /// A consumer that gets the frames published from now on. Every consumer (preview,
+    /// calibration, ...) takes its own, none of them takes frames from another.
+    pub fn subscribe(&self) -> FrameSubscriber {
+        let queue = Arc::new(FrameQueue::default());
+        self.0.locked().push(Arc::downgrade(&queue));
+        FrameSubscriber(queue)
+    }
+}
+
+pub struct FrameSubscriber(Arc<FrameQueue>);
+
+impl FrameSubscriber {
+    /// The oldest frame not taken yet, if there is one
+    pub fn try_recv(&self) -> Option<Frame> {
+        self.0.frames.locked().pop_front().map(taken)
+    }
+
+    /// Waits for the oldest frame not taken yet. Safe to cancel, e.g. in `select!`: a frame
+    /// is only taken when it is returned.
+    pub async fn recv(&self) -> Frame {
+        loop {
+            if let Some(frame) = self.try_recv() {
+                return frame;
+            }
+            self.0.published.notified().await;
+        }
+    }
+
+    /// Waits up to `timeout` for the oldest frame not taken yet, for consumers on a thread
+    /// rather than a task. None when no frame came in time.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Frame> {
+        self.wait(timeout).pop_front().map(taken)
+    }
+
+    /// The newest frame, skipping any older ones still queued, for showing frames rather
+    /// than processing each of them. Skipped frames don't count as dropped.
+    pub fn latest(&self) -> Option<Frame> {
+        let mut frames = self.0.frames.locked();
+        let latest = frames.pop_back();
+        frames.clear();
+        latest.map(taken)
+    }
+
+    /// Waits up to `timeout` for a frame, then takes the newest like [`FrameSubscriber::latest`]
+    pub fn latest_timeout(&self, timeout: Duration) -> Option<Frame> {
+        let mut frames = self.wait(timeout);
+        let latest = frames.pop_back();
+        frames.clear();
+        latest.map(taken)
+    }
+
+    // The queue, once it has a frame or `timeout` is up
+    fn wait(&self, timeout: Duration) -> MutexGuard<'_, VecDeque<(Instant, Frame)>> {
+        let frames = self.0.frames.locked();
+        self.0
+            .arrived
+            .wait_timeout_while(frames, timeout, |frames| frames.is_empty())
+            .unwrap_or_else(PoisonError::into_inner)
+            .0
+    }
+
+    /// Frames lost because this subscriber was [`QUEUE_DEPTH`] frames behind
+    pub fn dropped(&self) -> u64 {
+        self.0.dropped.load(Ordering::Relaxed)
+    }
+}
+
+fn taken((published_at, frame): (Instant, Frame)) -> Frame {
+    metrics::record_since(Stage::Transit, published_at);
+    frame
+}
+
+/// Sortable and safe in file names, e.g. 20261014T093012.345Z
+pub fn file_timestamp(time: OffsetDateTime) -> String {
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+        time.year(),
+        time.month() as u8,
+        time.day(),
+        time.hour(),
+        time.minute(),
+        time.second(),
+        time.millisecond()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn frame() -> Frame {
+        Frame::from_rgba(FrameBuffer::from(vec![0; 4]), 1, 1)
+    }
+
+    #[test]
+    fn recv_timeout_wakes_for_a_frame_published_while_waiting() {
+        let feed = FrameFeed::default();
+        let subscriber = feed.subscribe();
+        let publisher = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            feed.publish(frame());
+        });
+        let started = Instant::now();
+        assert!(subscriber.recv_timeout(Duration::from_secs(10)).is_some());
+        assert!(started.elapsed() < Duration::from_secs(10));
+        publisher.join().unwrap();
+    }
+
+    #[test]
+    fn latest_timeout_skips_to_the_newest_frame() {
+        let feed = FrameFeed::default();
+        let subscriber = feed.subscribe();
+        for _ in 0..3 {
+            feed.publish(frame());
+        }
+        assert!(subscriber.latest_timeout(Duration::ZERO).is_some());
+        assert!(subscriber.latest_timeout(Duration::from_millis(20)).is_none());
+    }
+
+    #[test]
+    fn recv_timeout_gives_up_without_a_frame() {
+        let feed = FrameFeed::default();
+        let subscriber = feed.subscribe();
+        assert!(subscriber.recv_timeout(Duration::from_millis(20)).is_none());
+        feed.publish(frame());
+        assert!(subscriber.recv_timeout(Duration::ZERO).is_some());
+    }
+}
@@ -0,0 +1,303 @@
+use anyhow::{bail, Result};
+use opencv::{
+    core::{self, Mat, Point, Point2d, Rect, Size, CV_64F, ROTATE_180, ROTATE_90_CLOCKWISE, ROTATE_90_COUNTERCLOCKWISE},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum Flip {
+    #[default]
+    None,
+    // Mirror left/right
+    Horizontal,
+    // Mirror top/bottom
+    Vertical,
+    Both,
+}
+
+/// Clockwise, applied after the flip
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum Rotation {
+    #[default]
+    #[cfg_attr(feature = "clap", value(name = "0"))]
+    #[serde(rename = "0")]
+    None,
+    #[cfg_attr(feature = "clap", value(name = "90"))]
+    #[serde(rename = "90")]
+    Cw90,
+    #[cfg_attr(feature = "clap", value(name = "180"))]
+    #[serde(rename = "180")]
+    Cw180,
+    #[cfg_attr(feature = "clap", value(name = "270"))]
+    #[serde(rename = "270")]
+    Cw270,
+}
+
+/// Compensates for how the camera is mounted, e.g. an upward looking PnP camera
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Orientation {
+    pub flip: Flip,
+    pub rotation: Rotation,
+}
+
+// Pixel mapping u' = A u + b, A is always a signed permutation
+type Affine = ([[f64; 2]; 2], [f64; 2]);
+
+impl Orientation {
+    pub fn is_identity(&self) -> bool {
+        *self == Orientation::default()
+    }
+
+    pub fn output_size(&self, size: Size) -> Size {
+        match self.rotation {
+            Rotation::Cw90 | Rotation::Cw270 => Size::new(size.height, size.width),
+            Rotation::None | Rotation::Cw180 => size,
+        }
+    }
+
+    pub fn apply(&self, src: &Mat, dst: &mut Mat) -> Result<()> {
+        let flip_code = match self.flip {
+            Flip::None => None,
+            Flip::Horizontal => Some(1),
+            Flip::Vertical => Some(0),
+            Flip::Both => Some(-1),
+        };
+        let rotate_code = match self.rotation {
+            Rotation::None => None,
+            Rotation::Cw90 => Some(ROTATE_90_CLOCKWISE),
+            Rotation::Cw180 => Some(ROTATE_180),
+            Rotation::Cw270 => Some(ROTATE_90_COUNTERCLOCKWISE),
+        };
+        match (flip_code, rotate_code) {
+            (None, None) => src.copy_to(dst)?,
+            (Some(flip), None) => core::flip(src, dst, flip)?,
+            (None, Some(rotate)) => core::rotate(src, dst, rotate)?,
+            (Some(flip), Some(rotate)) => {
+                let mut flipped = Mat::default();
+                core::flip(src, &mut flipped, flip)?;
+                core::rotate(&flipped, dst, rotate)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Where a pixel of an unoriented image of `size` ends up
+    fn affine(&self, size: Size) -> Affine {
+        let w = (size.width - 1) as f64;
+        let h = (size.height - 1) as f64;
+        let (fa, fb) = match self.flip {
+            Flip::None => ([[1.0, 0.0], [0.0, 1.0]], [0.0, 0.0]),
+            Flip::Horizontal => ([[-1.0, 0.0], [0.0, 1.0]], [w, 0.0]),
+            Flip::Vertical => ([[1.0, 0.0], [0.0, -1.0]], [0.0, h]),
+            Flip::Both => ([[-1.0, 0.0], [0.0, -1.0]], [w, h]),
+        };
+        // Flipping keeps the size, so the rotation sees the same w and h
+        let (ra, rb) = match self.rotation {
+            Rotation::None => ([[1.0, 0.0], [0.0, 1.0]], [0.0, 0.0]),
+            Rotation::Cw90 => ([[0.0, -1.0], [1.0, 0.0]], [h, 0.0]),
+            Rotation::Cw180 => ([[-1.0, 0.0], [0.0, -1.0]], [w, h]),
+            Rotation::Cw270 => ([[0.0, 1.0], [-1.0, 0.0]], [0.0, w]),
+        };
+        let mut a = [[0.0; 2]; 2];
+        let mut b = rb;
+        for i in 0..2 {
+            for j in 0..2 {
+                a[i][j] = ra[i][0] * fa[0][j] + ra[i][1] * fa[1][j];
+            }
+            b[i] += ra[i][0] * fb[0] + ra[i][1] * fb[1];
+        }
+        (a, b)
+    }
+}
+
+/// How a delivered frame relates to the full sensor image, so intrinsics solved on
+/// processed frames can be mapped back to sensor coordinates. Frames are cropped to
+/// the ROI first, then scaled, then flipped and rotated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameGeometry {
+    pub sensor_size: Size,
+    /// Top-left corner of the capture ROI in sensor pixels
+    pub crop_offset: Point,
+    /// Delivered pixels per sensor pixel, per axis, before orientation
+    pub scale_x: f64,
+    pub scale_y: f64,
+    /// Frame size after scaling, before orientation
+    pub scaled_size: Size,
+    pub orientation: Orientation,
+}
+
+impl FrameGeometry {
+    pub fn full_sensor(sensor_size: Size) -> Self {
+        FrameGeometry {
+            sensor_size,
+            crop_offset: Point::new(0, 0),
+            scale_x: 1.0,
+            scale_y: 1.0,
+            scaled_size: sensor_size,
+            orientation: Orientation::default(),
+        }
+    }
+
+    pub fn is_full_sensor(&self) -> bool {
+        self.crop_offset == Point::new(0, 0)
+            && self.scale_x == 1.0
+            && self.scale_y == 1.0
+            && self.orientation.is_identity()
+    }
+
+    /// Scales the cropped region so it comes out at `size`
+    pub fn scaled_to(self, cropped: Size, size: Size) -> Self {
+        FrameGeometry {
+            scale_x: size.width as f64 / cropped.width as f64,
+            scale_y: size.height as f64 / cropped.height as f64,
+            scaled_size: size,
+            ..self
+        }
+    }
+
+    pub fn oriented(self, orientation: Orientation) -> Self {
+        FrameGeometry { orientation, ..self }
+    }
+
+    pub fn with_roi(sensor_size: Size, roi: Rect) -> Result<Self> {
+        if roi.x < 0
+            || roi.y < 0
+            || roi.width <= 0
+            || roi.height <= 0
+            || roi.x + roi.width > sensor_size.width
+            || roi.y + roi.height > sensor_size.height
+        {
+            bail!(
+                "ROI {}x{}+{}+{} does not fit inside the {}x{} sensor",
+                roi.width,
+                roi.height,
+                roi.x,
+                roi.y,
+                sensor_size.width,
+                sensor_size.height
+            );
+        }
+        Ok(FrameGeometry {
+            sensor_size,
+            crop_offset: Point::new(roi.x, roi.y),
+            scale_x: 1.0,
+            scale_y: 1.0,
+            scaled_size: roi.size(),
+            orientation: Orientation::default(),
+        })
+    }
+
+    /// Maps a camera matrix solved on delivered frames back into sensor coordinates:
+    /// undo the orientation, then the scale (pixel centres sit at +0.5), then shift by
+    /// the crop offset
+    pub fn to_sensor_camera_matrix(&self, camera_matrix: &Mat) -> Result<Mat> {
+        let mut sensor = Mat::default();
+        camera_matrix.convert_to(&mut sensor, CV_64F, 1.0, 0.0)?;
+
+        // A is a signed permutation, so its inverse is its transpose
+        let (a, b) = self.orientation.affine(self.scaled_size);
+        let dx = *sensor.at_2d::<f64>(0, 2)? - b[0];
+        let dy = *sensor.at_2d::<f64>(1, 2)? - b[1];
+        let cx = a[0][0] * dx + a[1][0] * dy;
+        let cy = a[0][1] * dx + a[1][1] * dy;
+        let (mut fx, mut fy) = (*sensor.at_2d::<f64>(0, 0)?, *sensor.at_2d::<f64>(1, 1)?);
+        if a[0][0] == 0.0 {
+            std::mem::swap(&mut fx, &mut fy);
+        }
+
+        *sensor.at_2d_mut::<f64>(0, 0)? = fx / self.scale_x;
+        *sensor.at_2d_mut::<f64>(1, 1)? = fy / self.scale_y;
+        *sensor.at_2d_mut::<f64>(0, 2)? = (cx + 0.5) / self.scale_x - 0.5 + self.crop_offset.x as f64;
+        *sensor.at_2d_mut::<f64>(1, 2)? = (cy + 0.5) / self.scale_y - 0.5 + self.crop_offset.y as f64;
+        Ok(sensor)
+    }
+
+    /// Radial terms survive flips and rotations unchanged, the tangential pair (p2, p1)
+    /// transforms like a pixel direction
+    pub fn to_sensor_dist_coeffs(&self, dist_coeffs: &Mat) -> Result<Mat> {
+        let mut sensor = Mat::default();
+        dist_coeffs.convert_to(&mut sensor, CV_64F, 1.0, 0.0)?;
+        if sensor.total() < 4 {
+            return Ok(sensor);
+        }
+        let (a, _) = self.orientation.affine(self.scaled_size);
+        let p1 = *sensor.at::<f64>(2)?;
+        let p2 = *sensor.at::<f64>(3)?;
+        *sensor.at_mut::<f64>(3)? = a[0][0] * p2 + a[1][0] * p1;
+        *sensor.at_mut::<f64>(2)? = a[0][1] * p2 + a[1][1] * p1;
+        Ok(sensor)
+    }
+
+    /// The reverse of to_sensor_camera_matrix, for applying stored intrinsics to delivered frames
+    pub fn to_frame_camera_matrix(&self, sensor_matrix: &Mat) -> Result<Mat> {
+        let mut frame = Mat::default();
+        sensor_matrix.convert_to(&mut frame, CV_64F, 1.0, 0.0)?;
+
+        let mut fx = *frame.at_2d::<f64>(0, 0)? * self.scale_x;
+        let mut fy = *frame.at_2d::<f64>(1, 1)? * self.scale_y;
+        let cx = (*frame.at_2d::<f64>(0, 2)? - self.crop_offset.x as f64 + 0.5) * self.scale_x - 0.5;
+        let cy = (*frame.at_2d::<f64>(1, 2)? - self.crop_offset.y as f64 + 0.5) * self.scale_y - 0.5;
+
+        let (a, b) = self.orientation.affine(self.scaled_size);
+        if a[0][0] == 0.0 {
+            std::mem::swap(&mut fx, &mut fy);
+        }
+        *frame.at_2d_mut::<f64>(0, 0)? = fx;
+        *frame.at_2d_mut::<f64>(1, 1)? = fy;
+        *frame.at_2d_mut::<f64>(0, 2)? = a[0][0] * cx + a[0][1] * cy + b[0];
+        *frame.at_2d_mut::<f64>(1, 2)? = a[1][0] * cx + a[1][1] * cy + b[1];
+        Ok(frame)
+    }
+
+    pub fn to_frame_dist_coeffs(&self, sensor_dist_coeffs: &Mat) -> Result<Mat> {
+        let mut frame = Mat::default();
+        sensor_dist_coeffs.convert_to(&mut frame, CV_64F, 1.0, 0.0)?;
+        if frame.total() < 4 {
+            return Ok(frame);
+        }
+        let (a, _) = self.orientation.affine(self.scaled_size);
+        let p1 = *frame.at::<f64>(2)?;
+        let p2 = *frame.at::<f64>(3)?;
+        *frame.at_mut::<f64>(3)? = a[0][0] * p2 + a[0][1] * p1;
+        *frame.at_mut::<f64>(2)? = a[1][0] * p2 + a[1][1] * p1;
+        Ok(frame)
+    }
+
+    /// Where a sensor pixel ends up in delivered frames
+    pub fn to_frame_point(&self, sensor: Point2d) -> Point2d {
+        let x = (sensor.x - self.crop_offset.x as f64 + 0.5) * self.scale_x - 0.5;
+        let y = (sensor.y - self.crop_offset.y as f64 + 0.5) * self.scale_y - 0.5;
+        let (a, b) = self.orientation.affine(self.scaled_size);
+        Point2d::new(a[0][0] * x + a[0][1] * y + b[0], a[1][0] * x + a[1][1] * y + b[1])
+    }
+
+    /// Which sensor pixel a point in a delivered frame came from
+    pub fn to_sensor_point(&self, frame: Point2d) -> Point2d {
+        let (a, b) = self.orientation.affine(self.scaled_size);
+        let dx = frame.x - b[0];
+        let dy = frame.y - b[1];
+        let x = a[0][0] * dx + a[1][0] * dy;
+        let y = a[0][1] * dx + a[1][1] * dy;
+        Point2d::new(
+            (x + 0.5) / self.scale_x - 0.5 + self.crop_offset.x as f64,
+            (y + 0.5) / self.scale_y - 0.5 + self.crop_offset.y as f64,
+        )
+    }
+}
+
+/// Parses X,Y,WIDTH,HEIGHT
+pub fn parse_rect(s: &str) -> Result<Rect, String> {
+    let values = s
+        .split(',')
+        .map(|v| v.trim().parse::<i32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| format!("expected X,Y,WIDTH,HEIGHT, got '{}'", s))?;
+    match values[..] {
+        [x, y, width, height] => Ok(Rect::new(x, y, width, height)),
+        _ => Err(format!("expected X,Y,WIDTH,HEIGHT, got '{}'", s)),
+    }
+}
@@ -0,0 +1,114 @@
+use crate::coverage::Coverage;
+use opencv::core::{Point2f, Size, Vector};
+
+// Opposite board edges differing by more than this count as a deliberate tilt
+const TILT_RATIO: f32 = 1.15;
+// Share of the frame the board's outline covers
+const CLOSE_AREA: f32 = 0.25;
+const FAR_AREA: f32 = 0.08;
+// Distance and tilt advice only makes sense once a few views are in
+const MIN_VIEWS_FOR_POSES: usize = 3;
+
+// What one accepted view contributes to pose diversity, from the outer corners alone so
+// it works before anything is solved
+struct ViewShape {
+    // Left edge over right edge, and top edge over bottom edge, as seen in the image. The
+    // longer edge is the one closer to the camera.
+    yaw: f32,
+    pitch: f32,
+    area: f32,
+}
+
+/// Tells an inexperienced user which kind of view the calibration is still missing
+#[derive(Default)]
+pub struct PoseGuidance {
+    views: Vec<ViewShape>,
+}
+
+impl PoseGuidance {
+    pub fn add(&mut self, corners: &Vector<Point2f>, board_size: Size, frame_size: Size) {
+        let (w, h) = (board_size.width as usize, board_size.height as usize);
+        // The outer corners are only where the grid says with every point in view
+        if corners.len() != w * h {
+            return;
+        }
+        let (Ok(a), Ok(b), Ok(c), Ok(d)) = (
+            corners.get(0),
+            corners.get(w - 1),
+            corners.get((h - 1) * w),
+            corners.get(h * w - 1),
+        ) else {
+            return;
+        };
+        // Outline in order around the board, then sorted into image left/right and top/bottom
+        let outline = [a, b, d, c];
+        let edge = |i: usize| {
+            let (p, q) = (outline[i], outline[(i + 1) % 4]);
+            let middle = Point2f::new((p.x + q.x) / 2.0, (p.y + q.y) / 2.0);
+            ((p.x - q.x).hypot(p.y - q.y), middle, (q.x - p.x).abs() < (q.y - p.y).abs())
+        };
+        let edges: Vec<_> = (0..4).map(edge).collect();
+        let mut upright: Vec<_> = edges.iter().filter(|(_, _, upright)| *upright).collect();
+        let mut level: Vec<_> = edges.iter().filter(|(_, _, upright)| !*upright).collect();
+        // A board turned 45 degrees in the image has no clear left or right edge
+        let (yaw, pitch) = if upright.len() == 2 && level.len() == 2 {
+            upright.sort_by(|p, q| p.1.x.total_cmp(&q.1.x));
+            level.sort_by(|p, q| p.1.y.total_cmp(&q.1.y));
+            (upright[0].0 / upright[1].0, level[0].0 / level[1].0)
+        } else {
+            (1.0, 1.0)
+        };
+
+        // Shoelace formula over the outline
+        let twice_area: f32 = (0..4)
+            .map(|i| {
+                let (p, q) = (outline[i], outline[(i + 1) % 4]);
+                p.x * q.y - q.x * p.y
+            })
+            .sum();
+        let area = twice_area.abs() / 2.0 / (frame_size.width * frame_size.height) as f32;
+        self.views.push(ViewShape { yaw, pitch, area });
+    }
+
+    /// Views are kept in capture order, so the index matches the calibration's own list
+    pub fn remove(&mut self, index: usize) {
+        if index < self.views.len() {
+            self.views.remove(index);
+        }
+    }
+
+    /// The suggestion rotates through everything still missing, so one hard to reach
+    /// corner doesn't block the rest of the advice
+    pub fn hint(&self, coverage: &Coverage) -> Option<String> {
+        let mut missing = Vec::new();
+        if let Some(area) = coverage.least_covered_area() {
+            missing.push(format!("move the board to the {}", area));
+        }
+        if self.views.len() >= MIN_VIEWS_FOR_POSES {
+            let seen = |test: &dyn Fn(&ViewShape) -> bool| self.views.iter().any(test);
+            if !seen(&|view| view.yaw > TILT_RATIO) {
+                missing.push("turn the board so its right side points away from the camera".to_string());
+            }
+            if !seen(&|view| view.yaw < 1.0 / TILT_RATIO) {
+                missing.push("turn the board so its left side points away from the camera".to_string());
+            }
+            if !seen(&|view| view.pitch > TILT_RATIO) {
+                missing.push("tilt the top of the board towards the camera".to_string());
+            }
+            if !seen(&|view| view.pitch < 1.0 / TILT_RATIO) {
+                missing.push("tilt the top of the board away from the camera".to_string());
+            }
+            if !seen(&|view| view.area > CLOSE_AREA) {
+                missing.push("come closer so the board fills more of the view".to_string());
+            }
+            if !seen(&|view| view.area < FAR_AREA) {
+                missing.push("move the board further away".to_string());
+            }
+        }
+        if missing.is_empty() {
+            return None;
+        }
+        let index = self.views.len() % missing.len();
+        Some(missing.swap_remove(index))
+    }
+}
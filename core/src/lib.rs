@@ -0,0 +1,123 @@
+//! Camera capture, calibration target detection, calibration solving and camera profiles
+//! for the cameras of a pick and place machine, without any user interface.
+//!
+//! The Rusty Rabbit window, terminal, browser and headless interfaces are all built on this crate.
+//! The pieces fit together like this:
+//!
+//! - [`source::SourceSpec`] describes a camera, a network stream, a folder of stills, a
+//!   video file or the synthetic test source and opens it as a [`source::FrameSource`],
+//!   the trait any other source implements too; [`capture::start_camera_task`] runs it as a task
+//!   and publishes RGBA [`frame::Frame`]s to a [`frame::FrameFeed`], cropped, scaled and
+//!   oriented as set in [`capture::CaptureConfig`]. Every consumer subscribes to the feed
+//!   and shares the same pixels, with a short queue of its own so a slow one drops frames
+//!   rather than holding up the camera.
+//! - [`calibration::run_calibration`] reads frames from that feed, finds a
+//!   [`target::CalibrationTarget`] in them on a [`detection::DetectionPool`] of worker
+//!   threads that takes the newest frame, after the [`preprocess`] steps if any, collects
+//!   views and solves the intrinsics.
+//!   Buttons come in through [`calibration::CalibrationControls`], and the result is saved
+//!   with the camera's [`profiles::CameraProfile`]. Targets besides the built-in chess
+//!   board, circle grid and ChArUco board implement the trait themselves, in the app or
+//!   as a shared library that [`plugin`] loads at runtime; with the onnx feature a
+//!   [`corner_model::CornerModel`] finds chess boards the classical detector misses.
+//!   Where a calibration is, from idle through capturing and solving to saved, is a
+//!   [`workflow::Workflow`] that also turns down a second calibration while one runs.
+//! - Both run on the tokio [`runtime::runtime`]. Stopping the camera or cancelling a
+//!   calibration returns once the task has wound down. Neither knows about a user
+//!   interface: camera changes, progress, accepted views, results and errors go out as
+//!   [`events::Event`]s on an [`events::EventBus`] that each front end subscribes to.
+//!   How long frames spend in each stage on the way is kept by [`metrics`], and a
+//!   [`watchdog::Watchdog`] reopens a camera that froze without reporting an error.
+//!   [`session_log`] keeps a whole session, frames, buttons and detections, to replay it
+//!   through the same pipeline later, and [`shared_frames::SharedFrames`] hands the frames
+//!   to other processes on the machine through shared memory, [`virtual_camera::VirtualCamera`]
+//!   undistorted to any video application as a v4l2loopback camera, and [`ndi::NdiOutput`]
+//!   to monitors on the network as an NDI source.
+//! - [`acceleration`] moves color conversion, resizing and undistortion onto an OpenCL
+//!   device, or with the cuda feature an NVIDIA GPU, when asked to and one is there.
+//!   [`bench`] times capture, conversion, detection and solving on the machine it runs on.
+//! - [`vision`] finds fiducials and parts on the nozzle for a pick and place controller
+//!   that leaves its machine vision to this app, and with the machine feature [`machine::Machine`]
+//!   jogs a machine over a serial port, Klipper's Moonraker or LinuxCNC's linuxcncrsh through a
+//!   [`machine::CapturePlan`] to capture views on its own.
+//! - [`preview::Undistort`] straightens frames with a saved calibration, and
+//!   [`geometry::FrameGeometry`] converts intrinsics and points between the full sensor and
+//!   cropped or scaled frames.
+//! - [`config`] reads and writes the TOML files in the user's config folder that profiles,
+//!   camera settings and front end state are kept in, and [`tuning::watch`] follows the one
+//!   with thresholds and overlays that change while a calibration runs.
+//!
+//! A minimal calibration without a window:
+//!
+//! ```ignore
+//! use rustyrabbit_core::{calibration::*, capture::*, preview::*, profiles, target::ChessBoard};
+//!
+//! let frames = rustyrabbit_core::frame::FrameFeed::default();
+//! let events = rustyrabbit_core::events::EventBus::default();
+//! let mut progress = events.subscribe(); // Receive from it on another thread or task
+//! let handle = start_camera_task(config, source, frames.clone(), events.clone())?;
+//! let workflow = rustyrabbit_core::workflow::Workflow::new(events.clone());
+//! workflow.start(rustyrabbit_core::workflow::WorkflowState::Configured)?;
+//! let session = CalibrationSession {
+//!     frames,
+//!     role: profiles::CameraRole::Bottom,
+//!     events,
+//!     workflow,
+//!     ..
+//! };
+//! let target = std::sync::Arc::new(ChessBoard::new(9, 6, 25.0));
+//! rustyrabbit_core::runtime::runtime().block_on(run_calibration(target, 15, &session))?;
+//! handle.stop()?;
+//! ```
+
+pub mod acceleration;
+#[cfg(feature = "aravis")]
+pub mod aravis_source;
+pub mod bayer;
+pub mod bench;
+pub mod board_axes;
+pub mod calibration;
+pub mod camera_settings;
+pub mod capture;
+pub mod config;
+#[cfg(feature = "onnx")]
+pub mod corner_model;
+pub mod coverage;
+#[cfg(feature = "cuda")]
+pub mod cuda;
+pub mod detection;
+pub mod distortion_view;
+pub mod error;
+pub mod events;
+pub mod focus;
+pub mod frame;
+pub mod geometry;
+pub mod guidance;
+pub mod lock;
+#[cfg(feature = "machine")]
+pub mod machine;
+pub mod markers;
+pub mod metrics;
+pub mod ndi;
+pub mod plugin;
+pub mod pose_view;
+pub mod preprocess;
+pub mod preview;
+pub mod profiles;
+pub mod recording;
+pub mod runtime;
+pub mod session_log;
+pub mod shared_frames;
+pub mod source;
+#[cfg(feature = "spinnaker")]
+pub mod spinnaker_source;
+pub mod stability;
+pub mod synthetic;
+pub mod target;
+pub mod target_print;
+pub mod tuning;
+pub mod uvc;
+pub mod virtual_camera;
+pub mod vision;
+pub mod watchdog;
+pub mod workflow;
@@ -0,0 +1,14 @@
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+/// Locking that doesn't panic. A thread that panicked while holding the lock leaves it
+/// poisoned, but the data is still whatever that thread last wrote, and a preview with a
+/// stale overlay beats taking the whole app down with it.
+pub trait Locked<T> {
+    fn locked(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T> Locked<T> for Mutex<T> {
+    fn locked(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
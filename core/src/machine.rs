@@ -0,0 +1,566 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use serialport::{ClearBuffer, SerialPort};
+use std::{
+    fmt,
+    fs,
+    io::{BufRead, BufReader, ErrorKind, Write},
+    net::TcpStream,
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+// How long a read waits for the controller before the deadline is checked again
+const READ_TIMEOUT: Duration = Duration::from_millis(200);
+// Longer than any move or homing cycle a capture plan should need
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(120);
+// Marlin boards reset when the port opens and print a banner before listening
+const STARTUP: Duration = Duration::from_secs(2);
+// Moonraker answers a script once Klipper has run it, homing included
+const MOONRAKER_TIMEOUT: Duration = Duration::from_secs(120);
+// linuxcncrsh's port when started without --port
+const LINUXCNC_PORT: u16 = 5007;
+
+/// The motion controller's firmware, for the few commands where their G-code differs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Firmware {
+    #[default]
+    Marlin,
+    Grbl,
+    Smoothie,
+}
+
+impl Firmware {
+    // Answers once every move sent before it has finished, not just been planned
+    fn wait_for_moves(self) -> &'static str {
+        match self {
+            Firmware::Marlin | Firmware::Smoothie => "M400",
+            Firmware::Grbl => "G4 P0",
+        }
+    }
+
+    fn home(self) -> &'static str {
+        match self {
+            Firmware::Marlin | Firmware::Smoothie => "G28",
+            Firmware::Grbl => "$H",
+        }
+    }
+}
+
+/// A machine position in mm, in the controller's absolute coordinates. Z is left where it
+/// is when not given.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Position {
+    pub x: f64,
+    pub y: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub z: Option<f64>,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "X{:.3} Y{:.3}", self.x, self.y)?;
+        if let Some(z) = self.z {
+            write!(f, " Z{:.3}", z)?;
+        }
+        Ok(())
+    }
+}
+
+fn default_baud() -> u32 {
+    115_200
+}
+
+fn default_feed() -> f64 {
+    3000.0
+}
+
+fn default_settle_ms() -> u64 {
+    500
+}
+
+/// The positions to move the camera to for an automated calibration, a view captured at
+/// each, and the controller that moves it: a serial port, a Klipper machine's Moonraker or
+/// LinuxCNC's linuxcncrsh. Read from a TOML file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CapturePlan {
+    /// Such as /dev/ttyUSB0 or COM3
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<String>,
+    /// Moonraker's address, such as http://mainsailos.local:7125
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub moonraker: Option<String>,
+    /// The host, and port if not 5007, that linuxcncrsh listens on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linuxcnc: Option<String>,
+    /// linuxcncrsh's connect password, EMC unless it was started with another
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub linuxcnc_password: Option<String>,
+    #[serde(default = "default_baud")]
+    pub baud: u32,
+    #[serde(default)]
+    pub firmware: Firmware,
+    /// Home the machine before the first move
+    #[serde(default)]
+    pub home: bool,
+    #[serde(default = "default_feed")]
+    pub feed_mm_min: f64,
+    /// How long the head is left to stop shaking after a move before the view is taken
+    #[serde(default = "default_settle_ms")]
+    pub settle_ms: u64,
+    pub positions: Vec<Position>,
+}
+
+impl CapturePlan {
+    pub fn load(path: &Path) -> Result<CapturePlan> {
+        let text = fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+        let plan: CapturePlan = toml::from_str(&text).with_context(|| format!("Unable to parse {}", path.display()))?;
+        let controllers = [plan.port.is_some(), plan.moonraker.is_some(), plan.linuxcnc.is_some()];
+        if controllers.iter().filter(|&&given| given).count() != 1 {
+            bail!("{} needs one of a serial port, a moonraker address or a linuxcnc host", path.display());
+        }
+        if plan.positions.is_empty() {
+            bail!("{} has no positions", path.display());
+        }
+        if plan.feed_mm_min <= 0.0 {
+            bail!("The feed rate in {} has to be more than 0", path.display());
+        }
+        Ok(plan)
+    }
+
+    pub fn settle(&self) -> Duration {
+        Duration::from_millis(self.settle_ms)
+    }
+
+    /// Connects to the plan's controller
+    pub fn connect(&self) -> Result<Box<dyn Machine>> {
+        if let Some(port) = &self.port {
+            return Ok(Box::new(SerialMachine::open(port, self.baud, self.firmware)?));
+        }
+        if let Some(url) = &self.moonraker {
+            return Ok(Box::new(Moonraker::connect(url)?));
+        }
+        match &self.linuxcnc {
+            Some(host) => {
+                let password = self.linuxcnc_password.as_deref().unwrap_or("EMC");
+                Ok(Box::new(LinuxCnc::connect(host, password)?))
+            }
+            None => bail!("The capture plan has no controller to connect to"),
+        }
+    }
+}
+
+/// Something that moves the camera, or the target under it
+pub trait Machine: Send {
+    fn home(&mut self) -> Result<()>;
+
+    /// Moves there and returns once the machine has stopped
+    fn move_to(&mut self, position: Position, feed_mm_min: f64) -> Result<()>;
+
+    /// Where the toolhead is now, None when the controller can't say
+    fn position(&mut self) -> Result<Option<Position>> {
+        Ok(None)
+    }
+
+    /// Each joint's position in mm or degrees, for hand-eye calibration. None when the
+    /// controller can't say.
+    fn joints(&mut self) -> Result<Option<Vec<f64>>> {
+        Ok(None)
+    }
+}
+
+/// A motion controller on a serial port that answers each line of G-code with "ok", as
+/// Marlin, GRBL and Smoothieware do
+pub struct SerialMachine {
+    writer: Box<dyn SerialPort>,
+    reader: BufReader<Box<dyn SerialPort>>,
+    firmware: Firmware,
+}
+
+impl SerialMachine {
+    /// Opens the port and sets up absolute moves in mm
+    pub fn open(port: &str, baud: u32, firmware: Firmware) -> Result<SerialMachine> {
+        let writer = serialport::new(port, baud)
+            .timeout(READ_TIMEOUT)
+            .open()
+            .with_context(|| format!("Unable to open {}", port))?;
+        thread::sleep(STARTUP);
+        writer.clear(ClearBuffer::Input)?;
+        let reader = BufReader::new(writer.try_clone()?);
+        let mut machine = SerialMachine {
+            writer,
+            reader,
+            firmware,
+        };
+        machine.send("G21")?;
+        machine.send("G90")?;
+        log::info!("Connected to the {:?} controller on {}", firmware, port);
+        Ok(machine)
+    }
+
+    /// Sends one line and waits for the controller to take it
+    pub fn send(&mut self, command: &str) -> Result<()> {
+        log::debug!("> {}", command);
+        writeln!(self.writer, "{}", command)?;
+        self.writer.flush()?;
+        await_ok(&mut self.reader, command, Instant::now() + COMMAND_TIMEOUT)
+    }
+}
+
+// Reads the controller's answers to `command` until its "ok", skipping what it prints in
+// between, such as Marlin's "echo:busy: processing" during a long move
+fn await_ok(reader: &mut impl BufRead, command: &str, deadline: Instant) -> Result<()> {
+    let mut line = String::new();
+    loop {
+        // A line cut off by the read timeout is kept and finished on the next read
+        match reader.read_line(&mut line) {
+            Ok(0) => bail!("The controller closed the connection"),
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                if Instant::now() >= deadline {
+                    bail!("The controller didn't answer {}", command);
+                }
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        }
+        let answer = line.trim().to_string();
+        line.clear();
+        if answer.is_empty() {
+            continue;
+        }
+        log::debug!("< {}", answer);
+        if answer.starts_with("ok") {
+            return Ok(());
+        }
+        // GRBL answers error:N and raises ALARM:N, Smoothie halts with !!
+        if answer.starts_with("error") || answer.starts_with("ALARM") || answer.starts_with("!!") {
+            bail!("The controller turned down {}: {}", command, answer);
+        }
+    }
+}
+
+impl Machine for SerialMachine {
+    fn home(&mut self) -> Result<()> {
+        self.send(self.firmware.home())?;
+        self.send(self.firmware.wait_for_moves())
+    }
+
+    fn move_to(&mut self, position: Position, feed_mm_min: f64) -> Result<()> {
+        self.send(&format!("G1 {} F{:.0}", position, feed_mm_min))?;
+        self.send(self.firmware.wait_for_moves())
+    }
+}
+
+/// A Klipper machine through Moonraker's HTTP API
+pub struct Moonraker {
+    url: String,
+    agent: ureq::Agent,
+}
+
+impl Moonraker {
+    /// Fails unless Klipper is ready for moves
+    pub fn connect(url: &str) -> Result<Moonraker> {
+        let moonraker = Moonraker {
+            url: url.trim_end_matches('/').to_string(),
+            agent: ureq::AgentBuilder::new().timeout(MOONRAKER_TIMEOUT).build(),
+        };
+        ready(&moonraker.get("/printer/info", &[])?)?;
+        moonraker.script("G21\nG90")?;
+        log::info!("Connected to Klipper through Moonraker at {}", moonraker.url);
+        Ok(moonraker)
+    }
+
+    fn get(&self, path: &str, query: &[(&str, &str)]) -> Result<Value> {
+        let mut request = self.agent.get(&format!("{}{}", self.url, path));
+        for (name, value) in query {
+            request = request.query(name, value);
+        }
+        answer(request.call())
+    }
+
+    // Returns once Klipper has run the whole script
+    fn script(&self, script: &str) -> Result<()> {
+        log::debug!("> {}", script);
+        let request = self.agent.post(&format!("{}/printer/gcode/script", self.url)).query("script", script);
+        answer(request.call()).with_context(|| format!("Klipper turned down {}", script))?;
+        Ok(())
+    }
+}
+
+// Fails with Klipper's message from /printer/info while it starts up, shut down or failed
+fn ready(info: &Value) -> Result<()> {
+    let state = info["state"].as_str().unwrap_or("unknown");
+    if state != "ready" {
+        let message = info["state_message"].as_str().unwrap_or("");
+        bail!("Klipper is {}, not ready: {}", state, message.trim());
+    }
+    Ok(())
+}
+
+// Where /printer/objects/query says the toolhead is
+fn toolhead(status: &Value) -> Result<Position> {
+    let axes = &status["status"]["toolhead"]["position"];
+    match (axes[0].as_f64(), axes[1].as_f64(), axes[2].as_f64()) {
+        (Some(x), Some(y), z) => Ok(Position { x, y, z }),
+        _ => bail!("Moonraker didn't say where the toolhead is"),
+    }
+}
+
+// Moonraker's result, or the message of its error
+fn answer(response: Result<ureq::Response, ureq::Error>) -> Result<Value> {
+    let body: Value = match response {
+        Ok(response) => response.into_json()?,
+        Err(ureq::Error::Status(code, response)) => {
+            let body: Value = response.into_json().unwrap_or_default();
+            let message = body["error"]["message"].as_str().unwrap_or("no message");
+            bail!("Moonraker answered {}: {}", code, message);
+        }
+        Err(e) => return Err(e.into()),
+    };
+    Ok(body["result"].clone())
+}
+
+impl Machine for Moonraker {
+    fn home(&mut self) -> Result<()> {
+        self.script("G28")
+    }
+
+    fn move_to(&mut self, position: Position, feed_mm_min: f64) -> Result<()> {
+        self.script(&format!("G1 {} F{:.0}\nM400", position, feed_mm_min))
+    }
+
+    fn position(&mut self) -> Result<Option<Position>> {
+        let status = self.get("/printer/objects/query", &[("toolhead", "position")])?;
+        Ok(Some(toolhead(&status)?))
+    }
+}
+
+/// A LinuxCNC machine through linuxcncrsh, its text protocol over TCP. Moves go in as MDI
+/// commands, so the machine has to be out of estop and idle.
+pub struct LinuxCnc {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl LinuxCnc {
+    pub fn connect(host: &str, password: &str) -> Result<LinuxCnc> {
+        let address = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:{}", host, LINUXCNC_PORT)
+        };
+        let writer = TcpStream::connect(&address).with_context(|| format!("Unable to connect to {}", address))?;
+        writer.set_read_timeout(Some(COMMAND_TIMEOUT))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        let mut linuxcnc = LinuxCnc { writer, reader };
+
+        linuxcnc.write(&format!("hello {} rustyrabbit 1.1", password))?;
+        let hello = linuxcnc.reply(|line| line.starts_with("HELLO"))?;
+        if !hello.contains("ACK") || hello.contains("NAK") {
+            bail!("linuxcncrsh turned down the password: {}", hello);
+        }
+        // Without verbose a set that worked answers nothing, so there'd be no telling when it's done
+        linuxcnc.write("set echo off")?;
+        linuxcnc.write("set verbose on")?;
+        linuxcnc.write("get verbose")?;
+        linuxcnc.reply(|line| line.starts_with("VERBOSE"))?;
+        linuxcnc.set("enable EMCTOO")?;
+        linuxcnc.set(&format!("timeout {}", COMMAND_TIMEOUT.as_secs()))?;
+        linuxcnc.set("estop off")?;
+        linuxcnc.set("machine on")?;
+        linuxcnc.set("mode mdi")?;
+        log::info!("Connected to LinuxCNC at {}", address);
+        Ok(linuxcnc)
+    }
+
+    fn write(&mut self, command: &str) -> Result<()> {
+        log::debug!("> {}", command);
+        write!(self.writer, "{}\r\n", command)?;
+        Ok(self.writer.flush()?)
+    }
+
+    // The next line the test picks, skipping echoes and anything else in between
+    fn reply(&mut self, wanted: impl Fn(&str) -> bool) -> Result<String> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line).context("linuxcncrsh didn't answer")? == 0 {
+                bail!("linuxcncrsh closed the connection");
+            }
+            let answer = line.trim();
+            log::debug!("< {}", answer);
+            if wanted(answer) {
+                return Ok(answer.to_string());
+            }
+        }
+    }
+
+    fn set(&mut self, command: &str) -> Result<()> {
+        self.write(&format!("set {}", command))?;
+        let answer = self.reply(|line| line.starts_with("SET") && (line.ends_with("ACK") || line.ends_with("NAK")))?;
+        if answer.ends_with("NAK") {
+            bail!("LinuxCNC turned down {}", command);
+        }
+        Ok(())
+    }
+
+    // The numbers after the name in the answer to get, such as JOINT_POS 1.0 2.0 3.0
+    fn get_numbers(&mut self, name: &str) -> Result<Vec<f64>> {
+        self.write(&format!("get {}", name))?;
+        let key = name.to_uppercase();
+        let answer = self.reply(|line| line.starts_with(&key))?;
+        answer
+            .split_whitespace()
+            .skip(1)
+            .map(|number| number.parse().with_context(|| format!("{:?} isn't a number in {}", number, answer)))
+            .collect()
+    }
+}
+
+impl Machine for LinuxCnc {
+    fn home(&mut self) -> Result<()> {
+        self.set("mode manual")?;
+        self.set("home -1")?;
+        self.set("wait done")?;
+        self.set("mode mdi")
+    }
+
+    fn move_to(&mut self, position: Position, feed_mm_min: f64) -> Result<()> {
+        self.set(&format!("mdi G21 G90 G1 {} F{:.0}", position, feed_mm_min))?;
+        self.set("wait done")
+    }
+
+    fn position(&mut self) -> Result<Option<Position>> {
+        match self.get_numbers("abs_act_pos")?[..] {
+            [x, y, z, ..] => Ok(Some(Position { x, y, z: Some(z) })),
+            _ => bail!("LinuxCNC didn't say where the toolhead is"),
+        }
+    }
+
+    fn joints(&mut self) -> Result<Option<Vec<f64>>> {
+        Ok(Some(self.get_numbers("joint_pos")?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::{collections::VecDeque, io::Read, net::TcpListener};
+
+    // A serial port that has these chunks waiting, and times out once they're read
+    struct CannedPort(VecDeque<&'static str>);
+
+    impl Read for CannedPort {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.pop_front() {
+                Some(chunk) => {
+                    buf[..chunk.len()].copy_from_slice(chunk.as_bytes());
+                    Ok(chunk.len())
+                }
+                None => Err(ErrorKind::TimedOut.into()),
+            }
+        }
+    }
+
+    fn serial_answer(chunks: &[&'static str]) -> Result<()> {
+        let mut reader = BufReader::new(CannedPort(chunks.iter().copied().collect()));
+        await_ok(&mut reader, "G1 X10 Y20 F3000", Instant::now())
+    }
+
+    #[test]
+    fn serial_ok_is_taken() {
+        assert!(serial_answer(&["ok\n"]).is_ok());
+        // Marlin with ADVANCED_OK and Smoothie add to it
+        assert!(serial_answer(&["ok N12 P15 B3\r\n"]).is_ok());
+    }
+
+    #[test]
+    fn serial_busy_and_echo_lines_are_waited_out() {
+        assert!(serial_answer(&["echo:busy: processing\n", "\n", "echo:busy: processing\n", "ok\n"]).is_ok());
+    }
+
+    #[test]
+    fn serial_line_split_by_the_read_timeout_is_joined() {
+        assert!(serial_answer(&["echo:busy: proc", "essing\no", "k\n"]).is_ok());
+    }
+
+    #[test]
+    fn serial_errors_are_reported_with_the_command() {
+        for reply in ["error:22\n", "ALARM:1\n", "!!\n"] {
+            let error = serial_answer(&[reply]).unwrap_err().to_string();
+            assert!(error.contains("G1 X10 Y20 F3000"), "{}", error);
+            assert!(error.contains(reply.trim()), "{}", error);
+        }
+    }
+
+    #[test]
+    fn serial_silence_times_out() {
+        let error = serial_answer(&["echo:busy: processing\n"]).unwrap_err().to_string();
+        assert!(error.contains("didn't answer"), "{}", error);
+    }
+
+    #[test]
+    fn serial_closed_port_fails() {
+        let mut reader = BufReader::new(&b"echo:start\n"[..]);
+        let error = await_ok(&mut reader, "G28", Instant::now()).unwrap_err().to_string();
+        assert!(error.contains("closed"), "{}", error);
+    }
+
+    fn moonraker_reply(code: u16, body: &str) -> Result<Value> {
+        let response = ureq::Response::new(code, "", body).unwrap();
+        answer(if code < 300 { Ok(response) } else { Err(ureq::Error::Status(code, response)) })
+    }
+
+    #[test]
+    fn moonraker_result_is_unwrapped() {
+        assert_eq!(moonraker_reply(200, r#"{"result": "ok"}"#).unwrap(), json!("ok"));
+    }
+
+    #[test]
+    fn moonraker_error_message_is_passed_on() {
+        let body = r#"{"error": {"code": 400, "message": "Must home axis first: 10.000 20.000 0.000 [0.000]"}}"#;
+        let error = moonraker_reply(400, body).unwrap_err().to_string();
+        assert_eq!(error, "Moonraker answered 400: Must home axis first: 10.000 20.000 0.000 [0.000]");
+        // Klipper not connected to Moonraker, with a body that isn't JSON
+        let error = moonraker_reply(503, "Service Unavailable").unwrap_err().to_string();
+        assert_eq!(error, "Moonraker answered 503: no message");
+    }
+
+    #[test]
+    fn moonraker_waits_for_klipper_to_be_ready() {
+        assert!(ready(&json!({"state": "ready", "state_message": "Printer is ready"})).is_ok());
+        let starting = json!({"state": "startup", "state_message": "Klipper is starting up\n"});
+        assert_eq!(ready(&starting).unwrap_err().to_string(), "Klipper is startup, not ready: Klipper is starting up");
+        assert!(ready(&json!({})).is_err());
+    }
+
+    #[test]
+    fn moonraker_toolhead_position_is_read() {
+        let status = json!({"status": {"toolhead": {"position": [10.5, 20.25, 3.0, 0.0]}}, "eventtime": 1.0});
+        assert_eq!(toolhead(&status).unwrap(), Position { x: 10.5, y: 20.25, z: Some(3.0) });
+        assert!(toolhead(&json!({"status": {"toolhead": {}}})).is_err());
+    }
+
+    #[test]
+    fn moonraker_that_never_answers_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let moonraker = Moonraker {
+            url: format!("http://{}", listener.local_addr().unwrap()),
+            agent: ureq::AgentBuilder::new().timeout(Duration::from_millis(200)).build(),
+        };
+        // Accepted but left unanswered, like Klipper stuck on a move
+        let _connection = thread::spawn(move || {
+            let _stream = listener.accept();
+            thread::sleep(Duration::from_secs(2));
+        });
+        let started = Instant::now();
+        let error = format!("{:#}", moonraker.script("G28").unwrap_err());
+        assert!(error.starts_with("Klipper turned down G28"), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
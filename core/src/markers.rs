@@ -0,0 +1,133 @@
+use crate::{
+    frame::{Frame, FrameBuffer},
+    lock::Locked,
+    target_print::parse_dictionary,
+};
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Point, Point2f, Rect, Scalar, Vector},
+    imgproc::{circle, line, put_text, rectangle, FONT_HERSHEY_SIMPLEX, LINE_8, LINE_AA},
+    objdetect::{get_predefined_dictionary, ArucoDetector, DetectorParameters, RefineParameters},
+    prelude::*,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct MarkerState {
+    enabled: bool,
+    // Built when the overlay is first switched on, the dictionary can't change afterwards
+    detector: Option<ArucoDetector>,
+    dictionary: String,
+    reading: Option<String>,
+}
+
+/// Finds ArUco markers in every preview frame and draws their outlines, corners and IDs, to
+/// check markers on the machine are readable before a calibration depends on them
+#[derive(Clone, Default)]
+pub struct MarkerOverlay(Arc<Mutex<MarkerState>>);
+
+impl MarkerOverlay {
+    pub fn new(dictionary: &str) -> Self {
+        let overlay = MarkerOverlay::default();
+        overlay.0.locked().dictionary = dictionary.to_string();
+        overlay
+    }
+
+    pub fn set_enabled(&self, enabled: bool) -> Result<()> {
+        let mut state = self.0.locked();
+        if enabled && state.detector.is_none() {
+            let dictionary = get_predefined_dictionary(parse_dictionary(&state.dictionary)?)?;
+            state.detector = Some(ArucoDetector::new(
+                &dictionary,
+                &DetectorParameters::default()?,
+                RefineParameters::new(10.0, 3.0, true)?,
+            )?);
+        }
+        state.enabled = enabled;
+        state.reading = None;
+        Ok(())
+    }
+
+    /// What the last frame contained, for the line under the preview
+    pub fn reading(&self) -> Option<String> {
+        self.0.locked().reading.clone()
+    }
+
+    /// Call with every preview frame after undistortion, so the outlines line up with it
+    pub fn apply(&self, frame: Frame) -> Result<Frame> {
+        let mut state = self.0.locked();
+        if !state.enabled {
+            return Ok(frame);
+        }
+        let Some(detector) = &state.detector else {
+            return Ok(frame);
+        };
+
+        let mut rgba = Mat::from_slice(frame.data.as_slice())?.reshape(4, frame.height)?.try_clone()?;
+        let mut corners = Vector::<Vector<Point2f>>::new();
+        let mut ids = Vector::<i32>::new();
+        let mut rejected = Vector::<Vector<Point2f>>::new();
+        detector.detect_markers(&*frame.gray_mat()?, &mut corners, &mut ids, &mut rejected)?;
+
+        // Candidates that looked like a marker but didn't decode, in red: usually glare,
+        // blur or a marker from another dictionary
+        let red = Scalar::new(255.0, 0.0, 0.0, 255.0);
+        for candidate in rejected.iter() {
+            draw_outline(&mut rgba, &candidate, red)?;
+        }
+        for (marker, id) in corners.iter().zip(ids.iter()) {
+            draw_marker(&mut rgba, &marker, id)?;
+        }
+
+        let mut found: Vec<i32> = ids.to_vec();
+        found.sort_unstable();
+        let listed = found.iter().map(|id| id.to_string()).collect::<Vec<_>>().join(", ");
+        state.reading = Some(match (found.len(), rejected.len()) {
+            (0, 0) => format!("No {} markers", state.dictionary),
+            (0, rejected) => format!("No {} markers, {} unreadable", state.dictionary, rejected),
+            (count, 0) => format!("{} markers: {}", count, listed),
+            (count, rejected) => format!("{} markers: {}, {} unreadable", count, listed, rejected),
+        });
+        Ok(frame.with_pixels(FrameBuffer::copy_of(rgba.data_bytes()?)))
+    }
+}
+
+fn to_point(p: Point2f) -> Point {
+    Point::new(p.x.round() as i32, p.y.round() as i32)
+}
+
+fn draw_outline(rgba: &mut Mat, corners: &Vector<Point2f>, color: Scalar) -> Result<()> {
+    let points: Vec<Point> = corners.iter().map(to_point).collect();
+    for (i, &p) in points.iter().enumerate() {
+        line(rgba, p, points[(i + 1) % points.len()], color, 1, LINE_AA, 0)?;
+    }
+    Ok(())
+}
+
+// Outline in green, corners as dots with the first (the marker's top-left) boxed, like
+// OpenCV's drawDetectedMarkers, and the ID in the middle
+fn draw_marker(rgba: &mut Mat, corners: &Vector<Point2f>, id: i32) -> Result<()> {
+    let green = Scalar::new(0.0, 255.0, 0.0, 255.0);
+    let corner_color = Scalar::new(0.0, 160.0, 255.0, 255.0);
+    draw_outline(rgba, corners, green)?;
+    let points: Vec<Point> = corners.iter().map(to_point).collect();
+    for &p in &points {
+        circle(rgba, p, 3, corner_color, -1, LINE_AA, 0)?;
+    }
+    if let Some(&first) = points.first() {
+        rectangle(rgba, Rect::new(first.x - 4, first.y - 4, 9, 9), corner_color, 1, LINE_8, 0)?;
+    }
+
+    let count = points.len().max(1) as i32;
+    let center = Point::new(
+        points.iter().map(|p| p.x).sum::<i32>() / count,
+        points.iter().map(|p| p.y).sum::<i32>() / count,
+    );
+    let label = id.to_string();
+    let origin = Point::new(center.x - 4 * label.len() as i32, center.y + 5);
+    // Dark outline first so the ID reads on both black and white cells
+    for (color, thickness) in [(Scalar::new(0.0, 0.0, 0.0, 255.0), 4), (Scalar::new(255.0, 255.0, 0.0, 255.0), 1)] {
+        put_text(rgba, &label, origin, FONT_HERSHEY_SIMPLEX, 0.5, color, thickness, LINE_AA, false)?;
+    }
+    Ok(())
+}
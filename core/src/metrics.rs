@@ -0,0 +1,309 @@
+use crate::{lock::Locked, profiles::CameraRole};
+use std::{
+    collections::VecDeque,
+    fmt::Write as _,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Timings of each stage kept for the figures, a few seconds' worth at camera rates
+pub const SAMPLES_KEPT: usize = 240;
+// The capture rate is the frames captured over this long
+const FPS_WINDOW: Duration = Duration::from_secs(2);
+
+/// Where a frame spends its time between the sensor and the screen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Reading a frame from the source
+    Capture,
+    /// Resizing, cropping, orienting and converting it to RGBA
+    Convert,
+    /// Waiting in a subscriber's queue until a consumer takes it
+    Transit,
+    /// Looking for a calibration target in it
+    Detect,
+    /// Turning it into the preview image, overlays included
+    Render,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 5] = [Stage::Capture, Stage::Convert, Stage::Transit, Stage::Detect, Stage::Render];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Stage::Capture => "capture",
+            Stage::Convert => "convert",
+            Stage::Transit => "transit",
+            Stage::Detect => "detect",
+            Stage::Render => "render",
+        }
+    }
+
+    /// Position in [`Stage::ALL`]
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// Figures of one stage over its last [`SAMPLES_KEPT`] timings
+#[derive(Debug, Clone, Copy)]
+pub struct StageTiming {
+    pub stage: Stage,
+    /// Timings since the app started, not only the ones kept, and their sum
+    pub count: u64,
+    pub total: Duration,
+    pub mean: Duration,
+    pub median: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+struct Samples {
+    recent: VecDeque<Duration>,
+    count: u64,
+    total: Duration,
+}
+
+const NO_SAMPLES: Samples = Samples {
+    recent: VecDeque::new(),
+    count: 0,
+    total: Duration::ZERO,
+};
+static STAGES: Mutex<[Samples; 5]> = Mutex::new([NO_SAMPLES; 5]);
+
+/// Adds a timing of `stage`, from whichever thread did the work
+pub fn record(stage: Stage, took: Duration) {
+    let mut stages = STAGES.locked();
+    let samples = &mut stages[stage.index()];
+    if samples.recent.len() >= SAMPLES_KEPT {
+        samples.recent.pop_front();
+    }
+    samples.recent.push_back(took);
+    samples.count += 1;
+    samples.total += took;
+}
+
+/// Records the time since `started`
+pub fn record_since(stage: Stage, started: Instant) {
+    record(stage, started.elapsed());
+}
+
+/// Every stage that has been timed at least once, in pipeline order
+pub fn timings() -> Vec<StageTiming> {
+    let stages = STAGES.locked();
+    Stage::ALL
+        .iter()
+        .filter_map(|&stage| {
+            let samples = &stages[stage.index()];
+            if samples.recent.is_empty() {
+                return None;
+            }
+            let mut sorted: Vec<Duration> = samples.recent.iter().copied().collect();
+            sorted.sort_unstable();
+            let p95 = sorted[(sorted.len() * 95 / 100).min(sorted.len() - 1)];
+            Some(StageTiming {
+                stage,
+                count: samples.count,
+                total: samples.total,
+                mean: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+                median: sorted[sorted.len() / 2],
+                p95,
+                max: sorted[sorted.len() - 1],
+            })
+        })
+        .collect()
+}
+
+// How the camera and calibration are doing, besides the stage timings
+struct Health {
+    captured: u64,
+    // When the frames of the last FPS_WINDOW were captured
+    recent: VecDeque<Instant>,
+    dropped: u64,
+    queue_depth: usize,
+    subscribers: usize,
+    recording: bool,
+    // By CameraRole::index
+    calibration_rms: [Option<f64>; 2],
+}
+
+static HEALTH: Mutex<Health> = Mutex::new(Health {
+    captured: 0,
+    recent: VecDeque::new(),
+    dropped: 0,
+    queue_depth: 0,
+    subscribers: 0,
+    recording: false,
+    calibration_rms: [None; 2],
+});
+
+/// Counts a frame the camera task published
+pub fn frame_captured(at: Instant) {
+    let mut health = HEALTH.locked();
+    health.captured += 1;
+    health.recent.push_back(at);
+    while health.recent.front().is_some_and(|&first| at.duration_since(first) > FPS_WINDOW) {
+        health.recent.pop_front();
+    }
+}
+
+/// Counts a frame a subscriber lost because its queue was full
+pub fn frame_dropped() {
+    HEALTH.locked().dropped += 1;
+}
+
+/// The frame queues after a publish: how many there are and the fullest one's length
+pub fn set_queues(subscribers: usize, deepest: usize) {
+    let mut health = HEALTH.locked();
+    health.subscribers = subscribers;
+    health.queue_depth = deepest;
+}
+
+pub fn set_recording(recording: bool) {
+    HEALTH.locked().recording = recording;
+}
+
+/// The RMS reprojection error of `role`'s latest calibration, None without one
+pub fn set_calibration_rms(role: CameraRole, rms: Option<f64>) {
+    HEALTH.locked().calibration_rms[role.index() as usize] = rms;
+}
+
+/// Frames per second the camera delivered lately, 0 when it stopped
+pub fn capture_fps() -> f64 {
+    let health = HEALTH.locked();
+    let (Some(&first), Some(&last)) = (health.recent.front(), health.recent.back()) else {
+        return 0.0;
+    };
+    if last.elapsed() > FPS_WINDOW || last == first {
+        return 0.0;
+    }
+    (health.recent.len() - 1) as f64 / last.duration_since(first).as_secs_f64()
+}
+
+/// The figures in the Prometheus text format: the timings as a summary per stage in seconds
+/// and their maximum as a gauge of its own, then the capture rate, frame losses, queues,
+/// recording and calibration errors
+pub fn prometheus() -> String {
+    let timings = timings();
+    let mut text = String::from(
+        "# HELP rustyrabbit_stage_seconds Time a frame spends in each pipeline stage\n\
+         # TYPE rustyrabbit_stage_seconds summary\n",
+    );
+    for timing in &timings {
+        let stage = timing.stage.label();
+        for (quantile, value) in [("0.5", timing.median), ("0.95", timing.p95)] {
+            let _ = writeln!(
+                text,
+                "rustyrabbit_stage_seconds{{stage=\"{}\",quantile=\"{}\"}} {}",
+                stage,
+                quantile,
+                value.as_secs_f64()
+            );
+        }
+        let _ = writeln!(text, "rustyrabbit_stage_seconds_sum{{stage=\"{}\"}} {}", stage, timing.total.as_secs_f64());
+        let _ = writeln!(text, "rustyrabbit_stage_seconds_count{{stage=\"{}\"}} {}", stage, timing.count);
+    }
+    // Quantiles stop short of 1, the slowest frame goes out on its own
+    text.push_str(
+        "# HELP rustyrabbit_stage_seconds_max Longest time of the recent frames in each pipeline stage\n\
+         # TYPE rustyrabbit_stage_seconds_max gauge\n",
+    );
+    for timing in &timings {
+        let stage = timing.stage.label();
+        let _ = writeln!(text, "rustyrabbit_stage_seconds_max{{stage=\"{}\"}} {}", stage, timing.max.as_secs_f64());
+    }
+    let fps = capture_fps();
+    let health = HEALTH.locked();
+    let mut metric = |name: &str, kind: &str, help: &str, value: f64| {
+        let _ = write!(text, "# HELP {0} {1}\n# TYPE {0} {2}\n{0} {3}\n", name, help, kind, value);
+    };
+    metric("rustyrabbit_capture_fps", "gauge", "Frames per second the camera delivered lately", fps);
+    metric("rustyrabbit_frames_captured_total", "counter", "Frames the camera delivered", health.captured as f64);
+    metric(
+        "rustyrabbit_frames_dropped_total",
+        "counter",
+        "Frames a consumer lost because it fell behind",
+        health.dropped as f64,
+    );
+    metric(
+        "rustyrabbit_frame_queue_depth",
+        "gauge",
+        "Frames waiting in the fullest consumer queue",
+        health.queue_depth as f64,
+    );
+    metric(
+        "rustyrabbit_frame_subscribers",
+        "gauge",
+        "Consumers taking the camera's frames",
+        health.subscribers as f64,
+    );
+    metric("rustyrabbit_recording", "gauge", "1 while frames are recorded", health.recording as u8 as f64);
+    let _ = write!(
+        text,
+        "# HELP rustyrabbit_calibration_rms_pixels RMS reprojection error of the latest calibration\n\
+         # TYPE rustyrabbit_calibration_rms_pixels gauge\n"
+    );
+    for role in [CameraRole::Top, CameraRole::Bottom] {
+        if let Some(rms) = health.calibration_rms[role.index() as usize] {
+            let name = match role {
+                CameraRole::Top => "top",
+                CameraRole::Bottom => "bottom",
+            };
+            let _ = writeln!(text, "rustyrabbit_calibration_rms_pixels{{role=\"{}\"}} {}", name, rms);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The figures are global, so everything about the text is checked in one test
+    #[test]
+    fn prometheus_text_is_well_formed() {
+        for millis in [10, 20, 30, 40] {
+            record(Stage::Detect, Duration::from_millis(millis));
+        }
+        set_calibration_rms(CameraRole::Top, Some(0.25));
+        set_calibration_rms(CameraRole::Bottom, None);
+        let text = prometheus();
+
+        for line in [
+            "rustyrabbit_stage_seconds{stage=\"detect\",quantile=\"0.5\"} 0.03",
+            "rustyrabbit_stage_seconds{stage=\"detect\",quantile=\"0.95\"} 0.04",
+            "rustyrabbit_stage_seconds_sum{stage=\"detect\"} 0.1",
+            "rustyrabbit_stage_seconds_count{stage=\"detect\"} 4",
+            "rustyrabbit_stage_seconds_max{stage=\"detect\"} 0.04",
+            "rustyrabbit_calibration_rms_pixels{role=\"top\"} 0.25",
+        ] {
+            assert!(text.lines().any(|exported| exported == line), "{} missing from\n{}", line, text);
+        }
+        assert!(!text.contains("quantile=\"1\""));
+        assert!(!text.contains("role=\"bottom\""));
+
+        // Every sample belongs to the family whose TYPE came last, every family is typed once
+        let mut families = Vec::new();
+        for line in text.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                assert!(help.split_once(' ').is_some_and(|(_, text)| !text.is_empty()), "{}", line);
+            } else if let Some(kind) = line.strip_prefix("# TYPE ") {
+                let (name, kind) = kind.split_once(' ').unwrap();
+                assert!(["counter", "gauge", "summary"].contains(&kind), "{}", line);
+                assert!(!families.contains(&name), "{} typed twice", name);
+                families.push(name);
+            } else {
+                let family = *families.last().expect("a sample before any TYPE");
+                let (series, value) = line.rsplit_once(' ').unwrap();
+                let name = series.split('{').next().unwrap();
+                let suffixed = ["", "_sum", "_count"].iter().any(|suffix| name == format!("{}{}", family, suffix));
+                assert!(suffixed, "{} under {}", name, family);
+                assert!(value.parse::<f64>().is_ok(), "{}", line);
+                if let Some(labels) = series.strip_prefix(name) {
+                    assert!(labels.is_empty() || (labels.starts_with('{') && labels.ends_with('}')), "{}", line);
+                }
+            }
+        }
+        assert!(families.contains(&"rustyrabbit_stage_seconds_max"));
+    }
+}
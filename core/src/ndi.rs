@@ -0,0 +1,225 @@
+use crate::{
+    frame::{Frame, FrameFeed},
+    lock::Locked,
+};
+use anyhow::{bail, Context, Result};
+use libloading::Library;
+use std::{
+    ffi::{c_char, c_void, CString},
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Condvar, Mutex, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// The NDI runtime, loaded when an output starts so the build needs no SDK
+#[cfg(windows)]
+const LIBRARIES: &[&str] = &["Processing.NDI.Lib.x64.dll"];
+#[cfg(target_os = "macos")]
+const LIBRARIES: &[&str] = &["libndi.dylib"];
+#[cfg(not(any(windows, target_os = "macos")))]
+const LIBRARIES: &[&str] = &["libndi.so.6", "libndi.so.5", "libndi.so"];
+// NDI_LIB_FOURCC('R', 'G', 'B', 'A') and NDIlib_frame_format_type_progressive
+const FOURCC_RGBA: u32 = u32::from_le_bytes(*b"RGBA");
+const FORMAT_PROGRESSIVE: i32 = 1;
+// NDIlib_send_timecode_synthesize
+const TIMECODE_SYNTHESIZE: i64 = i64::MAX;
+// Only shown by receivers, frames go out as they come
+const FRAME_RATE: (i32, i32) = (30, 1);
+// Longest a wait for the next frame lasts, how soon a stopped output notices
+const POLL: Duration = Duration::from_millis(50);
+
+// struct NDIlib_send_create_t
+#[repr(C)]
+struct SendCreate {
+    name: *const c_char,
+    groups: *const c_char,
+    clock_video: bool,
+    clock_audio: bool,
+}
+
+// struct NDIlib_video_frame_v2_t
+#[repr(C)]
+struct VideoFrame {
+    xres: i32,
+    yres: i32,
+    fourcc: u32,
+    frame_rate_n: i32,
+    frame_rate_d: i32,
+    picture_aspect_ratio: f32,
+    frame_format_type: i32,
+    timecode: i64,
+    data: *const u8,
+    line_stride_in_bytes: i32,
+    metadata: *const c_char,
+    timestamp: i64,
+}
+
+// An NDI sender, made and used on the output's thread
+struct Sender {
+    send_create: unsafe extern "C" fn(*const SendCreate) -> *mut c_void,
+    send_video: unsafe extern "C" fn(*mut c_void, *const VideoFrame),
+    send_destroy: unsafe extern "C" fn(*mut c_void),
+    instance: *mut c_void,
+    // Unloaded last, after the sender is destroyed
+    _library: Library,
+}
+
+// The instance is only used by the one thread that owns the sender
+unsafe impl Send for Sender {}
+
+impl Sender {
+    fn open(name: &str) -> Result<Self> {
+        let library = LIBRARIES
+            .iter()
+            // SAFETY: the NDI runtime's initialisers only set up the library
+            .find_map(|library| unsafe { Library::new(library).ok() })
+            .with_context(|| format!("Unable to load the NDI runtime ({}), is it installed?", LIBRARIES.join(", ")))?;
+        // SAFETY: the symbols are looked up by the names and signatures of Processing.NDI.Lib.h
+        let mut sender = unsafe {
+            let initialize = *library.get::<unsafe extern "C" fn() -> bool>(b"NDIlib_initialize\0")?;
+            if !initialize() {
+                bail!("NDI doesn't run on this CPU");
+            }
+            Sender {
+                send_create: *library.get(b"NDIlib_send_create\0")?,
+                send_video: *library.get(b"NDIlib_send_send_video_v2\0")?,
+                send_destroy: *library.get(b"NDIlib_send_destroy\0")?,
+                instance: ptr::null_mut(),
+                _library: library,
+            }
+        };
+        let name_c = CString::new(name)?;
+        let create = SendCreate {
+            name: name_c.as_ptr(),
+            groups: ptr::null(),
+            clock_video: false,
+            clock_audio: false,
+        };
+        // SAFETY: create and the name it points to outlive the call, NDI copies them
+        sender.instance = unsafe { (sender.send_create)(&create) };
+        if sender.instance.is_null() {
+            bail!("Unable to create the NDI source {:?}", name);
+        }
+        Ok(sender)
+    }
+
+    fn send(&self, frame: &Frame) {
+        let pixels = frame.data.as_slice();
+        let video = VideoFrame {
+            xres: frame.width,
+            yres: frame.height,
+            fourcc: FOURCC_RGBA,
+            frame_rate_n: FRAME_RATE.0,
+            frame_rate_d: FRAME_RATE.1,
+            picture_aspect_ratio: 0.0,
+            frame_format_type: FORMAT_PROGRESSIVE,
+            timecode: TIMECODE_SYNTHESIZE,
+            data: pixels.as_ptr(),
+            line_stride_in_bytes: frame.width * 4,
+            metadata: ptr::null(),
+            timestamp: 0,
+        };
+        // SAFETY: the pixels hold height rows of the stride, the synchronous send is done
+        // with them when it returns
+        unsafe { (self.send_video)(self.instance, &video) }
+    }
+}
+
+impl Drop for Sender {
+    fn drop(&mut self) {
+        // NDIlib_destroy is left out, another output may still be using the runtime
+        if !self.instance.is_null() {
+            // SAFETY: made by send_create and not used after this
+            unsafe { (self.send_destroy)(self.instance) }
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pending {
+    frame: Mutex<Option<Frame>>,
+    ready: Condvar,
+}
+
+/// Sends frames as an NDI source, for monitors and mixers on the network to pick up without
+/// any cabling. The NDI runtime is loaded when the output starts, and frames go out from a
+/// thread of its own until it is dropped.
+pub struct NdiOutput {
+    pending: Arc<Pending>,
+    stopped: Arc<AtomicBool>,
+    sender: Option<JoinHandle<()>>,
+}
+
+impl NdiOutput {
+    /// A source called `name` that sends what [`NdiOutput::publish`] hands it
+    pub fn start(name: &str) -> Result<Self> {
+        let pending = Arc::new(Pending::default());
+        let next = {
+            let pending = Arc::clone(&pending);
+            move || {
+                let mut frame = pending.frame.locked();
+                if frame.is_none() {
+                    frame = pending.ready.wait_timeout(frame, POLL).unwrap_or_else(PoisonError::into_inner).0;
+                }
+                frame.take()
+            }
+        };
+        Self::spawn(name, pending, next)
+    }
+
+    /// A source called `name` that sends every frame of the feed, through `prepare` first,
+    /// e.g. to draw overlays on it
+    pub fn follow(name: &str, frames: &FrameFeed, prepare: impl Fn(Frame) -> Frame + Send + 'static) -> Result<Self> {
+        let subscriber = frames.subscribe();
+        let next = move || subscriber.latest_timeout(POLL).map(&prepare);
+        Self::spawn(name, Arc::default(), next)
+    }
+
+    // Sends whatever `next` comes up with, None when there's nothing new
+    fn spawn(
+        name: &str,
+        pending: Arc<Pending>,
+        mut next: impl FnMut() -> Option<Frame> + Send + 'static,
+    ) -> Result<Self> {
+        let sender = Sender::open(name)?;
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    if let Some(frame) = next() {
+                        sender.send(&frame);
+                    }
+                }
+            })
+        };
+        log::info!("Sending the preview as the NDI source {:?}", name);
+        Ok(NdiOutput {
+            pending,
+            stopped,
+            sender: Some(thread),
+        })
+    }
+
+    /// Queues `frame` to go out next, in place of one that hasn't yet
+    pub fn publish(&self, frame: &Frame) {
+        *self.pending.frame.locked() = Some(frame.clone());
+        self.pending.ready.notify_one();
+    }
+}
+
+impl Drop for NdiOutput {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.pending.ready.notify_one();
+        if let Some(sender) = self.sender.take() {
+            if sender.join().is_err() {
+                log::error!("The NDI sender panicked");
+            }
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use crate::{
+    config,
+    error::DetectionError,
+    frame::Frame,
+    target::{CalibrationTarget, DetectedView},
+};
+use anyhow::{bail, Context, Result};
+use libloading::Library;
+use opencv::{
+    calib3d::draw_chessboard_corners,
+    core::{Mat, Point2f, Point3f, Size, Vector},
+};
+use std::{
+    env::consts::DLL_EXTENSION,
+    ffi::{c_char, c_void, CStr},
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+/// Folder in the config folder that detector plugins are loaded from
+pub const PLUGIN_DIR: &str = "plugins";
+/// Version of [`TargetPluginV1`], a plugin built against another one isn't loaded
+pub const ABI_VERSION: u32 = 1;
+// The function every plugin exports, taking nothing and returning its TargetPluginV1
+const ENTRY_POINT: &[u8] = b"rustyrabbit_target_plugin\0";
+// Most points a detection can report
+const MAX_POINTS: usize = 4096;
+
+/// What a plugin's `const TargetPluginV1 *rustyrabbit_target_plugin(void)` returns, for
+/// targets that can't be built in, e.g. an in-house fiducial format. Laid out as in C, so
+/// a plugin can be written in anything that builds a shared library. The struct and the
+/// name have to stay valid while the library is loaded. `detect` is called from several
+/// detection workers at once.
+#[repr(C)]
+pub struct TargetPluginV1 {
+    /// [`ABI_VERSION`]
+    pub abi_version: u32,
+    /// For the target list, UTF-8 and NUL terminated
+    pub name: *const c_char,
+    /// A target of `cols` x `rows` points `spacing_mm` apart, null when the plugin has none
+    /// of that size
+    pub create: unsafe extern "C" fn(cols: i32, rows: i32, spacing_mm: f64) -> *mut c_void,
+    pub destroy: unsafe extern "C" fn(target: *mut c_void),
+    /// Writes up to `capacity` points as x, y, z triples in mm and returns how many the
+    /// target has. A detection refers to them by their position in this list.
+    pub object_points: unsafe extern "C" fn(target: *mut c_void, xyz: *mut f32, capacity: usize) -> usize,
+    /// Looks for the target in a grey image of `width` x `height` with rows `stride` bytes
+    /// apart. Writes up to `capacity` points found as x, y pairs and the object point each
+    /// one is to `indices`, and returns how many: 0 when the target isn't in view, negative
+    /// on an error. Like the built-in targets, only a detection of every point is used.
+    pub detect: unsafe extern "C" fn(
+        target: *mut c_void,
+        gray: *const u8,
+        width: i32,
+        height: i32,
+        stride: usize,
+        xy: *mut f32,
+        indices: *mut u32,
+        capacity: usize,
+    ) -> i32,
+}
+
+/// A loaded plugin library, which makes targets of its kind
+pub struct TargetPlugin {
+    name: String,
+    path: PathBuf,
+    entry: *const TargetPluginV1,
+    // Unloaded last, once every target it made is gone
+    _library: Library,
+}
+
+// The entry points only ever read the struct the plugin made, which it keeps unchanged
+unsafe impl Send for TargetPlugin {}
+unsafe impl Sync for TargetPlugin {}
+
+impl TargetPlugin {
+    /// Loads the library at `path`. Its initialisers run here, only load plugins you trust.
+    pub fn load(path: &Path) -> Result<Self> {
+        // SAFETY: a plugin is native code that runs in the process, a broken one can do
+        // anything. The entry point's signature is checked by nothing but the ABI version.
+        unsafe {
+            let library = Library::new(path).with_context(|| format!("Unable to load {}", path.display()))?;
+            let entry = *library
+                .get::<unsafe extern "C" fn() -> *const TargetPluginV1>(ENTRY_POINT)
+                .with_context(|| format!("{} isn't a Rusty Rabbit plugin", path.display()))?;
+            let plugin = entry();
+            if plugin.is_null() {
+                bail!("{} returned no plugin", path.display());
+            }
+            if (*plugin).abi_version != ABI_VERSION {
+                bail!(
+                    "{} is built for plugin version {}, this Rusty Rabbit loads version {}",
+                    path.display(),
+                    (*plugin).abi_version,
+                    ABI_VERSION
+                );
+            }
+            if (*plugin).name.is_null() {
+                bail!("{} has no name", path.display());
+            }
+            let name = CStr::from_ptr((*plugin).name).to_string_lossy().into_owned();
+            Ok(TargetPlugin {
+                name,
+                path: path.to_path_buf(),
+                entry: plugin,
+                _library: library,
+            })
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// A target of `cols` x `rows` points `spacing_mm` apart
+    pub fn create(self: &Arc<Self>, cols: i32, rows: i32, spacing_mm: f64) -> Result<PluginTarget> {
+        if cols < 2 || rows < 2 || !spacing_mm.is_finite() || spacing_mm <= 0.0 {
+            return Err(DetectionError::InvalidTarget.into());
+        }
+        let entry = self.entry();
+        // SAFETY: within the plugin's contract, see TargetPluginV1
+        let handle = unsafe { (entry.create)(cols, rows, spacing_mm) };
+        if handle.is_null() {
+            bail!("The {} plugin has no {}x{} target", self.name, cols, rows);
+        }
+        let mut target = PluginTarget {
+            plugin: Arc::clone(self),
+            handle,
+            size: Size::new(cols, rows),
+            spacing_mm,
+            object_points: Vector::new(),
+        };
+        let mut xyz = vec![0.0f32; MAX_POINTS * 3];
+        // SAFETY: xyz has room for MAX_POINTS triples
+        let count = unsafe { (entry.object_points)(handle, xyz.as_mut_ptr(), MAX_POINTS) };
+        if count == 0 || count > MAX_POINTS {
+            bail!("The {} plugin's target has {} points, it needs 1 to {}", self.name, count, MAX_POINTS);
+        }
+        target.object_points = xyz[..count * 3].chunks_exact(3).map(|p| Point3f::new(p[0], p[1], p[2])).collect();
+        Ok(target)
+    }
+
+    fn entry(&self) -> &TargetPluginV1 {
+        // SAFETY: checked in load, and valid while the library is
+        unsafe { &*self.entry }
+    }
+}
+
+/// Every plugin in the [`PLUGIN_DIR`], by file name. Files that don't load are reported
+/// and left out.
+pub fn discover() -> Vec<Arc<TargetPlugin>> {
+    let dir = match config::config_dir() {
+        Ok(dir) => dir.join(PLUGIN_DIR),
+        Err(e) => {
+            log::warn!("Not loading plugins: {}", e);
+            return Vec::new();
+        }
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.extension().is_some_and(|extension| extension == DLL_EXTENSION))
+        .collect();
+    paths.sort();
+    paths
+        .iter()
+        .filter_map(|path| match TargetPlugin::load(path) {
+            Ok(plugin) => {
+                log::info!("Loaded the {} target plugin from {}", plugin.name, path.display());
+                Some(Arc::new(plugin))
+            }
+            Err(e) => {
+                log::warn!("Skipping plugin: {:?}", e);
+                None
+            }
+        })
+        .collect()
+}
+
+/// The plugin called `name` among the discovered ones
+pub fn find(name: &str) -> Result<Arc<TargetPlugin>> {
+    let plugins = discover();
+    let known = plugins.iter().map(|plugin| plugin.name.clone()).collect::<Vec<_>>().join(", ");
+    plugins.into_iter().find(|plugin| plugin.name == name).with_context(|| {
+        format!("No target plugin called {:?}, loaded are: {}", name, if known.is_empty() { "none" } else { &known })
+    })
+}
+
+/// A target made by a plugin. The plugin finds it, the rest of the calibration treats it
+/// like a built-in one.
+pub struct PluginTarget {
+    plugin: Arc<TargetPlugin>,
+    handle: *mut c_void,
+    size: Size,
+    spacing_mm: f64,
+    object_points: Vector<Point3f>,
+}
+
+// Plugins promise their targets can be detected from several threads at once
+unsafe impl Send for PluginTarget {}
+unsafe impl Sync for PluginTarget {}
+
+impl CalibrationTarget for PluginTarget {
+    fn describe(&self) -> String {
+        format!("{}x{} {}", self.size.width, self.size.height, self.plugin.name)
+    }
+
+    fn grid_size(&self) -> Size {
+        self.size
+    }
+
+    fn object_points(&self) -> Vector<Point3f> {
+        self.object_points.clone()
+    }
+
+    fn spacing(&self) -> f64 {
+        self.spacing_mm
+    }
+
+    fn detect(&self, frame: &Frame) -> Result<Option<DetectedView>> {
+        let gray = frame.gray()?;
+        let capacity = self.object_points.len();
+        let mut xy = vec![0.0f32; capacity * 2];
+        let mut indices = vec![0u32; capacity];
+        // SAFETY: gray holds height rows of width bytes, the outputs have room for capacity
+        // points
+        let found = unsafe {
+            (self.plugin.entry().detect)(
+                self.handle,
+                gray.as_ptr(),
+                frame.width,
+                frame.height,
+                frame.width as usize,
+                xy.as_mut_ptr(),
+                indices.as_mut_ptr(),
+                capacity,
+            )
+        };
+        let failed = |reason: String| DetectionError::Failed {
+            target: self.describe(),
+            reason,
+        };
+        if found < 0 {
+            return Err(failed(format!("the plugin returned error {}", found)).into());
+        }
+        // Guidance reads the outline off a complete grid, like the built-in targets only a
+        // full detection is used
+        if (found as usize) < capacity {
+            return Ok(None);
+        }
+        let mut ordered = vec![None; capacity];
+        for (point, &index) in xy.chunks_exact(2).zip(&indices) {
+            let slot = ordered
+                .get_mut(index as usize)
+                .ok_or_else(|| failed(format!("the plugin reported point {} of {}", index, capacity)))?;
+            if slot.replace(Point2f::new(point[0], point[1])).is_some() {
+                return Err(failed(format!("the plugin reported point {} twice", index)).into());
+            }
+        }
+        Ok(Some(DetectedView {
+            image_points: ordered.into_iter().flatten().collect(),
+            object_points: self.object_points(),
+        }))
+    }
+
+    fn draw_overlay(&self, rgba: &mut Mat, view: &DetectedView) -> Result<()> {
+        // The plugin's points needn't lie on a chess board, so they're marked without the
+        // lines joining up its rows
+        draw_chessboard_corners(rgba, self.size, &view.image_points, false)?;
+        Ok(())
+    }
+}
+
+impl Drop for PluginTarget {
+    fn drop(&mut self) {
+        // SAFETY: made by this plugin's create and not used after this
+        unsafe { (self.plugin.entry().destroy)(self.handle) }
+    }
+}
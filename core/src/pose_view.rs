@@ -0,0 +1,133 @@
+use anyhow::Result;
+use opencv::{
+    calib3d::rodrigues,
+    core::{Mat, Point, Scalar, Size, CV_8UC4},
+    imgproc::{line, put_text, FONT_HERSHEY_SIMPLEX, LINE_AA},
+    prelude::*,
+};
+
+const VIEW_SIZE: Size = Size { width: 480, height: 360 };
+pub const DEFAULT_AZIMUTH: f64 = 35.0;
+const ELEVATION: f64 = 25.0;
+
+/// A solved board pose in camera coordinates: Rodrigues rotation and translation, in mm
+/// like the object points
+#[derive(Debug, Clone, Copy)]
+pub struct BoardPose {
+    pub rvec: [f64; 3],
+    pub tvec: [f64; 3],
+    pub included: bool,
+}
+
+type Vec3 = [f64; 3];
+
+// Orbiting virtual camera looking at the middle of the scene
+struct Viewer {
+    center: Vec3,
+    distance: f64,
+    rotation: [[f64; 3]; 3],
+    focal: f64,
+}
+
+impl Viewer {
+    fn project(&self, p: Vec3) -> Option<Point> {
+        let d = [p[0] - self.center[0], p[1] - self.center[1], p[2] - self.center[2]];
+        let r = &self.rotation;
+        let x = r[0][0] * d[0] + r[0][1] * d[1] + r[0][2] * d[2];
+        let y = r[1][0] * d[0] + r[1][1] * d[1] + r[1][2] * d[2];
+        let z = r[2][0] * d[0] + r[2][1] * d[1] + r[2][2] * d[2] + self.distance;
+        if z <= self.distance * 0.05 {
+            return None;
+        }
+        Some(Point::new(
+            (self.focal * x / z + VIEW_SIZE.width as f64 / 2.0) as i32,
+            (self.focal * y / z + VIEW_SIZE.height as f64 / 2.0) as i32,
+        ))
+    }
+
+    fn line(&self, canvas: &mut Mat, a: Vec3, b: Vec3, color: Scalar) -> Result<()> {
+        if let (Some(a), Some(b)) = (self.project(a), self.project(b)) {
+            line(canvas, a, b, color, 1, LINE_AA, 0)?;
+        }
+        Ok(())
+    }
+}
+
+/// Draws the camera frustum and every board pose seen from `azimuth` degrees around the
+/// vertical axis, so clustered or too similar views stand out. `outline` is the rectangle the
+/// target's points span, in object point mm.
+pub fn render(
+    poses: &[BoardPose],
+    outline: [[f64; 2]; 4],
+    camera_matrix: &[[f64; 3]; 3],
+    frame_size: Size,
+    azimuth: f64,
+) -> Result<Mat> {
+    let mut canvas = Mat::new_size_with_default(VIEW_SIZE, CV_8UC4, Scalar::new(30.0, 30.0, 30.0, 255.0))?;
+
+    // Board outlines in camera coordinates, from the outline on the board's own plane
+    let mut boards = Vec::new();
+    for pose in poses {
+        let mut rotation = Mat::default();
+        rodrigues(&Mat::from_slice(&pose.rvec)?.try_clone()?, &mut rotation, &mut Mat::default())?;
+        // Only the first two columns matter for points on the board plane
+        let mut axes = [[0.0; 2]; 3];
+        for (i, row) in axes.iter_mut().enumerate() {
+            *row = [*rotation.at_2d::<f64>(i as i32, 0)?, *rotation.at_2d::<f64>(i as i32, 1)?];
+        }
+        let corners = outline.map(|[u, v]| [0, 1, 2].map(|i| pose.tvec[i] + axes[i][0] * u + axes[i][1] * v));
+        boards.push((corners, pose.included));
+    }
+
+    let depth = boards
+        .iter()
+        .flat_map(|(corners, _)| corners.iter().map(|p| p[2]))
+        .fold(1.0, f64::max);
+    let (azimuth, elevation) = (azimuth.to_radians(), ELEVATION.to_radians());
+    let (sa, ca, se, ce) = (azimuth.sin(), azimuth.cos(), elevation.sin(), elevation.cos());
+    // Yaw around the camera's y axis, then pitch to look down on the scene
+    let viewer = Viewer {
+        center: [0.0, 0.0, depth / 2.0],
+        distance: depth * 1.8,
+        rotation: [[ca, 0.0, -sa], [se * sa, ce, se * ca], [ce * sa, -se, ce * ca]],
+        focal: VIEW_SIZE.width as f64 * 0.9,
+    };
+
+    // Camera frustum out to a fifth of the scene depth
+    let frustum_depth = depth / 5.0;
+    let (fx, fy, cx, cy) = (camera_matrix[0][0], camera_matrix[1][1], camera_matrix[0][2], camera_matrix[1][2]);
+    let corner = |x: f64, y: f64| [(x - cx) / fx * frustum_depth, (y - cy) / fy * frustum_depth, frustum_depth];
+    let (w, h) = (frame_size.width as f64, frame_size.height as f64);
+    let frustum = [corner(0.0, 0.0), corner(w, 0.0), corner(w, h), corner(0.0, h)];
+    let camera_color = Scalar::new(255.0, 255.0, 255.0, 255.0);
+    for (i, &near) in frustum.iter().enumerate() {
+        viewer.line(&mut canvas, [0.0; 3], near, camera_color)?;
+        viewer.line(&mut canvas, near, frustum[(i + 1) % 4], camera_color)?;
+    }
+
+    for (index, (corners, included)) in boards.iter().enumerate() {
+        let color = if *included {
+            // Cycle through a few distinct colours so neighbouring views can be told apart
+            let palette = [
+                Scalar::new(255.0, 90.0, 90.0, 255.0),
+                Scalar::new(90.0, 220.0, 90.0, 255.0),
+                Scalar::new(90.0, 160.0, 255.0, 255.0),
+                Scalar::new(255.0, 200.0, 60.0, 255.0),
+                Scalar::new(220.0, 90.0, 255.0, 255.0),
+            ];
+            palette[index % palette.len()]
+        } else {
+            Scalar::new(110.0, 110.0, 110.0, 255.0)
+        };
+        for (i, &corner) in corners.iter().enumerate() {
+            viewer.line(&mut canvas, corner, corners[(i + 1) % 4], color)?;
+        }
+        // Diagonal so a board seen edge-on still reads as a plane
+        viewer.line(&mut canvas, corners[0], corners[2], color)?;
+        let middle = [0, 1, 2].map(|k| corners.iter().map(|p| p[k]).sum::<f64>() / 4.0);
+        if let Some(at) = viewer.project(middle) {
+            put_text(&mut canvas, &format!("{}", index + 1), at, FONT_HERSHEY_SIMPLEX, 0.4, color, 1, LINE_AA, false)?;
+        }
+    }
+    Ok(canvas)
+}
@@ -0,0 +1,114 @@
+use crate::{
+    acceleration,
+    frame::{Frame, FrameBuffer},
+};
+use anyhow::{bail, Result};
+use opencv::{
+    core::{add_weighted, lut, Mat, Size, BORDER_DEFAULT, CV_8UC1},
+    imgproc::{
+        adaptive_threshold, create_clahe, gaussian_blur, ADAPTIVE_THRESH_GAUSSIAN_C, COLOR_GRAY2RGBA, THRESH_BINARY,
+    },
+    photo::fast_nl_means_denoising,
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, OnceLock};
+
+// Neighbourhoods of non-local means denoising, OpenCV's recommended sizes
+const DENOISE_TEMPLATE: i32 = 7;
+const DENOISE_SEARCH: i32 = 21;
+
+/// One step of the chain the grey image goes through before a target is looked for in it,
+/// for boards the camera sees with too little contrast, e.g. under enclosure lighting.
+/// Set as `[[preprocess]]` tables in tuning.toml, in the order they run.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "step", rename_all = "snake_case")]
+pub enum Step {
+    /// Contrast limited adaptive histogram equalisation in tiles of a `tiles` x `tiles`
+    /// grid, evens out a board lit from one side. 2 to 4 is a gentle `clip_limit`.
+    Clahe { clip_limit: f64, tiles: i32 },
+    /// Below 1 brightens the shadows, above 1 darkens them
+    Gamma { gamma: f64 },
+    /// Non-local means, about 10 takes out sensor noise without softening edges. Slow on
+    /// large frames.
+    Denoise { strength: f32 },
+    /// Unsharp mask: adds `amount` times the detail finer than a blur of `sigma` pixels
+    Sharpen { amount: f64, sigma: f64 },
+    /// Black and white against the mean of a `block_size` neighbourhood, odd, less `offset`
+    Threshold { block_size: i32, offset: f64 },
+}
+
+impl Step {
+    fn apply(&self, src: &Mat, dst: &mut Mat) -> Result<()> {
+        match *self {
+            Step::Clahe { clip_limit, tiles } => {
+                if clip_limit <= 0.0 || tiles < 1 {
+                    bail!("CLAHE needs a clip limit above 0 and at least 1 tile");
+                }
+                create_clahe(clip_limit, Size::new(tiles, tiles))?.apply(src, dst)?;
+            }
+            Step::Gamma { gamma } => {
+                if !gamma.is_finite() || gamma <= 0.0 {
+                    bail!("Gamma {} isn't above 0", gamma);
+                }
+                let table: Vec<u8> =
+                    (0..=255).map(|level| (255.0 * (level as f64 / 255.0).powf(gamma)).round() as u8).collect();
+                lut(src, &Mat::from_slice(&table)?.try_clone()?, dst)?;
+            }
+            Step::Denoise { strength } => {
+                fast_nl_means_denoising(src, dst, strength, DENOISE_TEMPLATE, DENOISE_SEARCH)?;
+            }
+            Step::Sharpen { amount, sigma } => {
+                if sigma <= 0.0 {
+                    bail!("Sharpening needs a blur above 0 pixels");
+                }
+                let mut blurred = Mat::default();
+                gaussian_blur(src, &mut blurred, Size::new(0, 0), sigma, sigma, BORDER_DEFAULT)?;
+                add_weighted(src, 1.0 + amount, &blurred, -amount, 0.0, dst, -1)?;
+            }
+            Step::Threshold { block_size, offset } => {
+                if block_size < 3 || block_size % 2 == 0 {
+                    bail!("Threshold block size {} isn't odd and at least 3", block_size);
+                }
+                adaptive_threshold(src, dst, 255.0, ADAPTIVE_THRESH_GAUSSIAN_C, THRESH_BINARY, block_size, offset)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The 8 bit grey `gray` after every step in turn
+pub fn run(steps: &[Step], gray: &Mat) -> Result<Mat> {
+    let mut current = gray.try_clone()?;
+    for step in steps {
+        let mut next = Mat::default();
+        step.apply(&current, &mut next)?;
+        if next.typ() != CV_8UC1 {
+            bail!("{:?} didn't leave an 8 bit grey image", step);
+        }
+        current = next;
+    }
+    Ok(current)
+}
+
+/// The frame as a target detection sees it: the same pixels, with a grey copy that went
+/// through `steps`. The frame itself when there are none.
+pub fn for_detection(steps: &[Step], frame: &Frame) -> Result<Frame> {
+    if steps.is_empty() {
+        return Ok(frame.clone());
+    }
+    let processed = run(steps, &*frame.gray_mat()?)?;
+    Ok(Frame {
+        gray: Arc::new(OnceLock::from(FrameBuffer::copy_of(processed.data_bytes()?))),
+        ..frame.clone()
+    })
+}
+
+/// The frame with its pixels replaced by the grey image `steps` make, for the preview
+pub fn processed_view(steps: &[Step], frame: Frame) -> Result<Frame> {
+    let processed = run(steps, &*frame.gray_mat()?)?;
+    let mut rgba = Mat::default();
+    acceleration::convert_color(&processed, &mut rgba, COLOR_GRAY2RGBA)?;
+    let data = FrameBuffer::copy_of(rgba.data_bytes()?);
+    Ok(frame.with_pixels(data))
+}
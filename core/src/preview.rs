@@ -0,0 +1,265 @@
+use crate::{
+    acceleration::{self, RemapMaps},
+    frame::{Frame, FrameBuffer},
+    geometry::FrameGeometry,
+    lock::Locked,
+    profiles::Calibration,
+};
+use anyhow::{bail, Result};
+use opencv::{
+    calib3d::init_undistort_rectify_map,
+    core::{absdiff, add_weighted, bitwise_not_def, in_range, no_array, Mat, Point, Point2d, Rect, Scalar, Size, Vector},
+    imgproc::{get_text_size, put_text, rectangle, FONT_HERSHEY_SIMPLEX, LINE_8, LINE_AA},
+    prelude::*,
+};
+use std::sync::{Arc, Mutex};
+
+/// What a background task such as calibration drew over the last frame it looked at,
+/// laid over every frame of the preview until it draws again. The preview keeps the
+/// camera's rate however seldom the task gets to a frame.
+#[derive(Clone, Default)]
+pub struct PreviewOverlay(Arc<Mutex<Option<Layer>>>);
+
+struct Layer {
+    size: Size,
+    annotated: Mat,
+    // Where the annotated pixels differ from the frame they were drawn on
+    drawn: Mat,
+}
+
+impl PreviewOverlay {
+    /// `annotated` is RGBA `original` with the annotations drawn on it
+    pub fn show(&self, annotated: Mat, original: &Frame) -> Result<()> {
+        let pixels = Mat::from_slice(original.data.as_slice())?;
+        let mut difference = Mat::default();
+        absdiff(&annotated, &*pixels.reshape(4, original.height)?, &mut difference)?;
+        let (mut unchanged, mut drawn) = (Mat::default(), Mat::default());
+        in_range(&difference, &Scalar::all(0.0), &Scalar::all(0.0), &mut unchanged)?;
+        bitwise_not_def(&unchanged, &mut drawn)?;
+        *self.0.locked() = Some(Layer {
+            size: annotated.size()?,
+            annotated,
+            drawn,
+        });
+        Ok(())
+    }
+
+    /// Nothing drawn, e.g. before a calibration starts
+    pub fn clear(&self) {
+        *self.0.locked() = None;
+    }
+
+    /// `frame` with the annotations over it, unchanged without any or when they were drawn
+    /// on a frame of another size
+    pub fn apply(&self, frame: Frame) -> Result<Frame> {
+        let layer = self.0.locked();
+        let Some(layer) = layer.as_ref().filter(|layer| layer.size == Size::new(frame.width, frame.height)) else {
+            return Ok(frame);
+        };
+        let mut rgba = Mat::from_slice(frame.data.as_slice())?.reshape(4, frame.height)?.try_clone()?;
+        layer.annotated.copy_to_masked(&mut rgba, &layer.drawn)?;
+        let data = FrameBuffer::copy_of(rgba.data_bytes()?);
+        Ok(frame.with_pixels(data))
+    }
+}
+
+/// Large outlined text in the middle of an RGBA frame, e.g. a capture countdown
+pub fn draw_banner(rgba: &mut Mat, text: &str) -> Result<()> {
+    let size = rgba.size()?;
+    let scale = size.height as f64 / 160.0;
+    let thickness = (scale * 2.0).max(1.0) as i32;
+    let mut baseline = 0;
+    let text_size = get_text_size(text, FONT_HERSHEY_SIMPLEX, scale, thickness, &mut baseline)?;
+    let origin = Point::new((size.width - text_size.width) / 2, (size.height + text_size.height) / 2);
+    // Dark outline first so the text reads on both black and white squares
+    for (color, extra) in [(Scalar::new(0.0, 0.0, 0.0, 255.0), 4), (Scalar::new(255.0, 255.0, 255.0, 255.0), 0)] {
+        put_text(rgba, text, origin, FONT_HERSHEY_SIMPLEX, scale, color, thickness + extra, LINE_AA, false)?;
+    }
+    Ok(())
+}
+
+/// Brief confirmation over an RGBA frame: brightened, with a green border
+pub fn draw_flash(rgba: &mut Mat) -> Result<()> {
+    let white = Mat::new_size_with_default(rgba.size()?, rgba.typ(), Scalar::all(255.0))?;
+    let original = rgba.try_clone()?;
+    add_weighted(&original, 0.7, &white, 0.3, 0.0, rgba, -1)?;
+    let size = rgba.size()?;
+    let thickness = (size.height / 30).max(4);
+    rectangle(
+        rgba,
+        Rect::new(0, 0, size.width, size.height),
+        Scalar::new(0.0, 220.0, 0.0, 255.0),
+        thickness * 2,
+        LINE_8,
+        0,
+    )?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct UndistortState {
+    enabled: bool,
+    // Share of the width left of the comparison divider, raw there and undistorted right of
+    // it. Overrides `enabled` while set.
+    split: Option<f32>,
+    calibration: Option<Calibration>,
+    // Remap tables for the frame geometry they were built for
+    maps: Option<(FrameGeometry, RemapMaps)>,
+}
+
+/// Optionally straightens preview frames with the active camera's calibration
+#[derive(Clone, Default)]
+pub struct Undistort(Arc<Mutex<UndistortState>>);
+
+impl Undistort {
+    pub fn set_calibration(&self, calibration: Option<Calibration>) {
+        let mut state = self.0.locked();
+        state.calibration = calibration;
+        state.maps = None;
+    }
+
+    pub fn has_calibration(&self) -> bool {
+        self.0.locked().calibration.is_some()
+    }
+
+    /// Principal point of the active calibration, in sensor pixels
+    pub fn principal_point(&self) -> Option<Point2d> {
+        let state = self.0.locked();
+        let matrix = state.calibration.as_ref()?.camera_matrix;
+        Some(Point2d::new(matrix[0][2], matrix[1][2]))
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.locked().enabled = enabled;
+    }
+
+    pub fn set_split(&self, split: Option<f32>) {
+        self.0.locked().split = split.map(|split| split.clamp(0.0, 1.0));
+    }
+
+    /// Camera matrix and distortion of the active calibration for frames delivered with
+    /// `geometry`, None without a calibration that fits the camera
+    pub fn frame_intrinsics(&self, geometry: &FrameGeometry) -> Result<Option<(Mat, Mat)>> {
+        let state = self.0.locked();
+        let Some(calibration) = &state.calibration else {
+            return Ok(None);
+        };
+        if calibration.sensor_width != geometry.sensor_size.width || calibration.sensor_height != geometry.sensor_size.height {
+            return Ok(None);
+        }
+        Ok(Some((
+            geometry.to_frame_camera_matrix(&calibration.camera_matrix()?)?,
+            geometry.to_frame_dist_coeffs(&calibration.dist_coeffs()?)?,
+        )))
+    }
+
+    /// Where a point of the preview would be without lens distortion, in sensor pixels. The
+    /// point is taken as already undistorted while the preview is. None without a calibration
+    /// that fits the camera.
+    pub fn ideal_point(&self, point: Point2d, geometry: &FrameGeometry) -> Result<Option<Point2d>> {
+        let state = self.0.locked();
+        let Some(calibration) = &state.calibration else {
+            return Ok(None);
+        };
+        if calibration.sensor_width != geometry.sensor_size.width || calibration.sensor_height != geometry.sensor_size.height {
+            return Ok(None);
+        }
+        let sensor = geometry.to_sensor_point(point);
+        let undistorted = match state.split {
+            Some(split) => {
+                let width = geometry.orientation.output_size(geometry.scaled_size).width;
+                point.x >= split as f64 * width as f64
+            }
+            None => state.enabled,
+        };
+        if undistorted {
+            return Ok(Some(sensor));
+        }
+        let ideal = calibration.undistort_points(&Vector::<Point2d>::from_iter([sensor]), false)?;
+        Ok(Some(ideal.get(0)?))
+    }
+
+    pub fn apply(&self, frame: Frame) -> Result<Frame> {
+        let (enabled, split) = {
+            let state = self.0.locked();
+            (state.enabled, state.split)
+        };
+        if split.is_none() && !enabled {
+            return Ok(frame);
+        }
+        let Some(mut data) = self.correct(&frame)? else {
+            return Ok(frame);
+        };
+        // Raw pixels back in left of the divider, the divider itself is drawn by the UI
+        if let Some(split) = split {
+            let row = frame.width as usize * 4;
+            let raw = (split * frame.width as f32).round() as usize * 4;
+            for (corrected, original) in data.chunks_exact_mut(row).zip(frame.data.chunks_exact(row)) {
+                corrected[..raw].copy_from_slice(&original[..raw]);
+            }
+        }
+        Ok(frame.with_pixels(data))
+    }
+
+    /// Undistorted pixels of a frame whether or not the preview is undistorting, None without
+    /// a calibration
+    pub fn correct(&self, frame: &Frame) -> Result<Option<FrameBuffer>> {
+        let mut guard = self.0.locked();
+        let state = &mut *guard;
+        let Some(calibration) = &state.calibration else {
+            return Ok(None);
+        };
+
+        // The tables only depend on the geometry, so they are rebuilt after a camera
+        // switch or new calibration and reused for every frame in between
+        if state.maps.as_ref().map(|(geometry, _)| *geometry) != Some(frame.geometry) {
+            let (map_x, map_y) = match undistort_maps(calibration, frame) {
+                Ok(maps) => maps,
+                Err(e) => {
+                    // Don't keep failing on every frame
+                    state.enabled = false;
+                    state.split = None;
+                    return Err(e);
+                }
+            };
+            state.maps = Some((frame.geometry, RemapMaps::new(map_x, map_y)));
+        }
+        let (_, maps) = state.maps.as_mut().unwrap();
+
+        // Read in place, remap writes a new image anyway
+        let pixels = Mat::from_slice(frame.data.as_slice())?;
+        let rgba = pixels.reshape(4, frame.height)?;
+        let mut undistorted = Mat::default();
+        acceleration::remap_with(&rgba, &mut undistorted, maps)?;
+        Ok(Some(FrameBuffer::copy_of(undistorted.data_bytes()?)))
+    }
+}
+
+fn undistort_maps(calibration: &Calibration, frame: &Frame) -> Result<(Mat, Mat)> {
+    let geometry = &frame.geometry;
+    if calibration.sensor_width != geometry.sensor_size.width || calibration.sensor_height != geometry.sensor_size.height {
+        bail!(
+            "Calibration was made at {}x{} but the camera delivers {}x{}, recalibrate to undistort",
+            calibration.sensor_width,
+            calibration.sensor_height,
+            geometry.sensor_size.width,
+            geometry.sensor_size.height
+        );
+    }
+    let camera_matrix = geometry.to_frame_camera_matrix(&calibration.camera_matrix()?)?;
+    let dist_coeffs = geometry.to_frame_dist_coeffs(&calibration.dist_coeffs()?)?;
+
+    let mut map_x = Mat::default();
+    let mut map_y = Mat::default();
+    init_undistort_rectify_map(
+        &camera_matrix,
+        &dist_coeffs,
+        &no_array(),
+        &camera_matrix,
+        Size::new(frame.width, frame.height),
+        RemapMaps::map_type(),
+        &mut map_x,
+        &mut map_y,
+    )?;
+    Ok((map_x, map_y))
+}
@@ -0,0 +1,197 @@
+use crate::{
+    config,
+    geometry::{Flip, FrameGeometry, Orientation, Rotation},
+};
+use anyhow::{Context, Result};
+use opencv::{
+    calib3d::undistort_points,
+    core::{no_array, Mat, Point2d, Rect, Vector},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+use time::OffsetDateTime;
+
+const PROFILES_FILE: &str = "profiles.toml";
+
+/// The two cameras of a pick-and-place head
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum CameraRole {
+    // Looks down at the board from the head
+    #[default]
+    Top,
+    // Looks up at parts on the nozzle
+    Bottom,
+}
+
+impl CameraRole {
+    /// Same order as the role selector in the UI
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(CameraRole::Top),
+            1 => Some(CameraRole::Bottom),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> i32 {
+        match self {
+            CameraRole::Top => 0,
+            CameraRole::Bottom => 1,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            CameraRole::Top => "top camera",
+            CameraRole::Bottom => "bottom camera",
+        }
+    }
+}
+
+/// Intrinsics in full sensor coordinates, so they stay valid if the ROI or scale changes
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Calibration {
+    pub sensor_width: i32,
+    pub sensor_height: i32,
+    pub camera_matrix: [[f64; 3]; 3],
+    pub dist_coeffs: Vec<f64>,
+    /// RMS reprojection error in pixels
+    pub rms: f64,
+    pub calibrated_at: String,
+}
+
+impl Calibration {
+    /// From intrinsics solved on frames delivered with `geometry`
+    pub fn from_frames(geometry: &FrameGeometry, camera_matrix: &Mat, dist_coeffs: &Mat, rms: f64) -> Result<Self> {
+        let sensor_matrix = geometry.to_sensor_camera_matrix(camera_matrix)?;
+        let sensor_dist_coeffs = geometry.to_sensor_dist_coeffs(dist_coeffs)?;
+        let mut rows = [[0.0; 3]; 3];
+        for (r, row) in rows.iter_mut().enumerate() {
+            for (c, value) in row.iter_mut().enumerate() {
+                *value = *sensor_matrix.at_2d::<f64>(r as i32, c as i32)?;
+            }
+        }
+        Ok(Calibration {
+            sensor_width: geometry.sensor_size.width,
+            sensor_height: geometry.sensor_size.height,
+            camera_matrix: rows,
+            dist_coeffs: sensor_dist_coeffs.data_typed::<f64>()?.to_vec(),
+            rms,
+            calibrated_at: OffsetDateTime::now_utc().to_string(),
+        })
+    }
+
+    pub fn camera_matrix(&self) -> Result<Mat> {
+        Ok(Mat::from_slice_2d(&self.camera_matrix)?)
+    }
+
+    pub fn dist_coeffs(&self) -> Result<Mat> {
+        Ok(Mat::from_slice(&self.dist_coeffs)?.try_clone()?)
+    }
+
+    /// Where sensor pixels `points` would be without the lens distortion, in pixels of the
+    /// same camera, or `normalized` to coordinates on the plane one unit in front of it
+    pub fn undistort_points(&self, points: &Vector<Point2d>, normalized: bool) -> Result<Vector<Point2d>> {
+        let camera_matrix = self.camera_matrix()?;
+        let mut ideal = Vector::new();
+        let projection = if normalized { Mat::default() } else { camera_matrix.try_clone()? };
+        undistort_points(points, &mut ideal, &camera_matrix, &self.dist_coeffs()?, &no_array(), &projection)?;
+        Ok(ideal)
+    }
+
+    /// Horizontal, vertical and diagonal field of view of the full sensor, in degrees
+    pub fn field_of_view(&self) -> (f64, f64, f64) {
+        let half_x = self.sensor_width as f64 / (2.0 * self.camera_matrix[0][0]);
+        let half_y = self.sensor_height as f64 / (2.0 * self.camera_matrix[1][1]);
+        (
+            2.0 * half_x.atan().to_degrees(),
+            2.0 * half_y.atan().to_degrees(),
+            2.0 * half_x.hypot(half_y).atan().to_degrees(),
+        )
+    }
+
+    /// The result on its own as TOML, the way [`Calibration::export`] writes it
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    /// Writes the result on its own, for other tools
+    pub fn export(&self, path: &Path) -> Result<()> {
+        let text = self.to_toml()?;
+        fs::write(path, text).with_context(|| format!("Unable to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Which device plays a role and how its frames are prepared. Image settings such as
+/// exposure stay with the device in cameras.toml.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CameraProfile {
+    pub camera: Option<i32>,
+    pub flip: Option<Flip>,
+    pub rotate: Option<Rotation>,
+    /// X, Y, width, height in sensor pixels
+    pub roi: Option<[i32; 4]>,
+    pub scale: Option<f64>,
+    /// Machine datum (e.g. the nozzle axis) in sensor pixels, the reticle centre
+    pub datum: Option<[f64; 2]>,
+    /// Image scale at the working plane, for the reticle rings
+    pub mm_per_px: Option<f64>,
+    pub calibration: Option<Calibration>,
+}
+
+impl CameraProfile {
+    pub fn orientation(&self) -> Orientation {
+        Orientation {
+            flip: self.flip.unwrap_or_default(),
+            rotation: self.rotate.unwrap_or_default(),
+        }
+    }
+
+    pub fn roi(&self) -> Option<Rect> {
+        self.roi.map(|[x, y, width, height]| Rect::new(x, y, width, height))
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Profiles {
+    /// Role selected when the app was last closed
+    pub active: CameraRole,
+    pub top: CameraProfile,
+    pub bottom: CameraProfile,
+}
+
+impl Profiles {
+    pub fn get(&self, role: CameraRole) -> &CameraProfile {
+        match role {
+            CameraRole::Top => &self.top,
+            CameraRole::Bottom => &self.bottom,
+        }
+    }
+
+    pub fn get_mut(&mut self, role: CameraRole) -> &mut CameraProfile {
+        match role {
+            CameraRole::Top => &mut self.top,
+            CameraRole::Bottom => &mut self.bottom,
+        }
+    }
+}
+
+pub fn load() -> Result<Profiles> {
+    Ok(config::load(PROFILES_FILE)?.unwrap_or_default())
+}
+
+pub fn save(profiles: &Profiles) -> Result<()> {
+    Ok(config::save(PROFILES_FILE, profiles)?)
+}
+
+/// Re-reads the file so a calibration finishing in the background doesn't undo other edits
+pub fn update(change: impl FnOnce(&mut Profiles)) -> Result<()> {
+    let mut profiles = load()?;
+    change(&mut profiles);
+    save(&profiles)
+}
@@ -0,0 +1,340 @@
+use crate::{frame, lock::Locked};
+use anyhow::{anyhow, bail, Context, Result};
+use opencv::{
+    core::{Mat, Size},
+    prelude::*,
+    videoio::VideoWriter,
+};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    panic,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Condvar, Mutex, Once,
+    },
+    thread::{self, JoinHandle, ThreadId},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+
+/// Length of each video file of a recording. An MP4 is only playable once it is closed, so a
+/// crash or kill loses at most the file being written.
+pub const SEGMENT_LENGTH: Duration = Duration::from_secs(60);
+// Frames waiting for the writer before new ones are dropped, about a second of video
+const FRAMES_QUEUED: usize = 30;
+// How often the writer looks up from an empty queue to see whether it should stop
+const CHECK_INTERVAL: Duration = Duration::from_millis(100);
+// How long a panic waits for each recording's file to be closed before going on
+const FINALIZE_TIMEOUT: Duration = Duration::from_secs(3);
+
+// The recordings being written, for the panic hook to close
+static OPEN: Mutex<Vec<Arc<Finalizer>>> = Mutex::new(Vec::new());
+static PANIC_HOOK: Once = Once::new();
+
+/// A video of the delivered frames plus per-frame metadata in a CSV next to it. Frames are
+/// written on a thread of its own, so a slow disk drops frames from the recording instead of
+/// holding up the camera. The video is split into files of [`SEGMENT_LENGTH`], and a panic
+/// anywhere closes the file being written, so a panic that takes the process down leaves
+/// every file playable. If the app survives it, recording carries on in a new file.
+pub struct Recording {
+    frames: Option<SyncSender<QueuedFrame>>,
+    writer: Option<JoinHandle<Result<()>>>,
+    finalizer: Arc<Finalizer>,
+    path: PathBuf,
+    dropped: u64,
+}
+
+struct QueuedFrame {
+    bgr: Mat,
+    sequence: u64,
+    timestamp: OffsetDateTime,
+    exposure: Option<f64>,
+    source_id: Arc<str>,
+}
+
+// How the panic hook asks a writer to close the file it is writing and waits until it has
+struct Finalizer {
+    requested: AtomicBool,
+    closed: Mutex<Closed>,
+    on_closed: Condvar,
+    thread: Mutex<Option<ThreadId>>,
+}
+
+#[derive(Default)]
+struct Closed {
+    segments: u64,
+    writer_stopped: bool,
+}
+
+impl Finalizer {
+    fn segment_closed(&self) {
+        self.requested.store(false, Ordering::Relaxed);
+        self.closed.locked().segments += 1;
+        self.on_closed.notify_all();
+    }
+
+    fn writer_stopped(&self) {
+        self.closed.locked().writer_stopped = true;
+        self.on_closed.notify_all();
+    }
+
+    fn finalize(&self) {
+        // A panic on the writer thread itself closes the files as it unwinds
+        if *self.thread.locked() == Some(thread::current().id()) {
+            return;
+        }
+        let closed = self.closed.locked();
+        let before = closed.segments;
+        self.requested.store(true, Ordering::Relaxed);
+        let _ = self.on_closed.wait_timeout_while(closed, FINALIZE_TIMEOUT, |closed| {
+            closed.segments == before && !closed.writer_stopped
+        });
+    }
+}
+
+impl Recording {
+    /// Starts a new, timestamped recording in `dir`, never replacing an earlier one
+    #[tracing::instrument(name = "record", skip_all, fields(dir = %dir.display()))]
+    pub fn start(dir: &Path, frame_size: Size, fps: f64) -> Result<Self> {
+        fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+        let stem = format!("recording-{}", frame::file_timestamp(OffsetDateTime::now_utc()));
+        let (path, meta) = (0..100)
+            .find_map(|attempt| {
+                let name = if attempt == 0 { stem.clone() } else { format!("{}-{}", stem, attempt) };
+                let path = dir.join(format!("{}.mp4", name));
+                if path.exists() {
+                    return None;
+                }
+                // Claiming the CSV with create_new makes the check and the create one step
+                let meta = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(path.with_extension("csv"))
+                    .ok()?;
+                Some((path, meta))
+            })
+            .with_context(|| format!("No free recording name in {}", dir.display()))?;
+
+        let video = open_video(&path, frame_size, fps)?;
+        let mut meta = BufWriter::new(meta);
+        writeln!(meta, "sequence,timestamp,exposure,source,segment")?;
+        meta.flush()?;
+
+        install_panic_hook();
+        let finalizer = Arc::new(Finalizer {
+            requested: AtomicBool::new(false),
+            closed: Mutex::default(),
+            on_closed: Condvar::new(),
+            thread: Mutex::new(None),
+        });
+        OPEN.locked().push(Arc::clone(&finalizer));
+
+        let (frames, queued) = mpsc::sync_channel(FRAMES_QUEUED);
+        let writer = SegmentWriter {
+            first: path.clone(),
+            segment: 1,
+            started: Instant::now(),
+            video,
+            meta,
+            frame_size,
+            fps,
+        };
+        let finalizer_for_writer = Arc::clone(&finalizer);
+        let span = tracing::Span::current();
+        let writer = thread::Builder::new()
+            .name("rustyrabbit-recording".into())
+            .spawn(move || {
+                let _entered = span.enter();
+                *finalizer_for_writer.thread.locked() = Some(thread::current().id());
+                let written = writer.run(queued, &finalizer_for_writer);
+                finalizer_for_writer.writer_stopped();
+                written
+            })
+            .context("Unable to start the recording writer")?;
+
+        Ok(Recording {
+            frames: Some(frames),
+            writer: Some(writer),
+            finalizer,
+            path,
+            dropped: 0,
+        })
+    }
+
+    /// The first video file, later ones carry a part number after its name
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `bgr` is the delivered frame before conversion to RGBA. An error here is one the
+    /// writer ran into with an earlier frame.
+    pub fn write(
+        &mut self,
+        bgr: &Mat,
+        sequence: u64,
+        timestamp: OffsetDateTime,
+        exposure: Option<f64>,
+        source_id: &Arc<str>,
+    ) -> Result<()> {
+        let Some(frames) = &self.frames else {
+            bail!("{} is closed", self.path.display());
+        };
+        let queued = QueuedFrame {
+            bgr: bgr.try_clone()?,
+            sequence,
+            timestamp,
+            exposure,
+            source_id: Arc::clone(source_id),
+        };
+        match frames.try_send(queued) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+                Ok(())
+            }
+            // The writer only stops early on an error
+            Err(TrySendError::Disconnected(_)) => {
+                self.frames = None;
+                match self.join() {
+                    Err(e) => Err(e),
+                    Ok(()) => bail!("The writer of {} stopped", self.path.display()),
+                }
+            }
+        }
+    }
+
+    /// Writes out the frames still queued and closes the files
+    #[tracing::instrument(name = "record", skip_all, fields(path = %self.path.display()))]
+    pub fn finish(mut self) -> Result<()> {
+        self.frames = None;
+        if self.dropped > 0 {
+            log::warn!(
+                "{} frames were left out of {}, writing fell behind the camera",
+                self.dropped,
+                self.path.display()
+            );
+        }
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        match self.writer.take() {
+            Some(writer) => writer.join().map_err(|_| anyhow!("The writer of {} panicked", self.path.display()))?,
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for Recording {
+    // Dropped without finish, e.g. while unwinding: the writer still closes the files
+    fn drop(&mut self) {
+        self.frames = None;
+        let _ = self.join();
+        OPEN.locked().retain(|open| !Arc::ptr_eq(open, &self.finalizer));
+    }
+}
+
+// The files of one recording, owned by its writer thread
+struct SegmentWriter {
+    first: PathBuf,
+    segment: u32,
+    started: Instant,
+    video: VideoWriter,
+    meta: BufWriter<File>,
+    frame_size: Size,
+    fps: f64,
+}
+
+impl SegmentWriter {
+    fn run(mut self, queued: mpsc::Receiver<QueuedFrame>, finalizer: &Finalizer) -> Result<()> {
+        let written = loop {
+            if finalizer.requested.load(Ordering::Relaxed) {
+                if let Err(e) = self.next_segment() {
+                    break Err(e);
+                }
+                finalizer.segment_closed();
+            }
+            match queued.recv_timeout(CHECK_INTERVAL) {
+                Ok(frame) => {
+                    if let Err(e) = self.write(&frame) {
+                        break Err(e);
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            }
+        };
+        // Closed even after an error, so the frames before it stay playable
+        let closed = self.close();
+        written.and(closed)
+    }
+
+    fn write(&mut self, frame: &QueuedFrame) -> Result<()> {
+        if self.started.elapsed() >= SEGMENT_LENGTH {
+            self.next_segment()?;
+        }
+        self.video.write(&frame.bgr)?;
+        writeln!(
+            self.meta,
+            "{},{:.6},{},{},{}",
+            frame.sequence,
+            frame.timestamp.unix_timestamp_nanos() as f64 / 1e9,
+            frame.exposure.map(|e| e.to_string()).unwrap_or_default(),
+            frame.source_id,
+            self.segment
+        )?;
+        Ok(())
+    }
+
+    // Closes the current file and carries on in recording-<time>-part<n>.mp4
+    fn next_segment(&mut self) -> Result<()> {
+        self.segment += 1;
+        let stem = self.first.file_stem().unwrap_or_default().to_string_lossy();
+        let path = self.first.with_file_name(format!("{}-part{:03}.mp4", stem, self.segment));
+        let next = open_video(&path, self.frame_size, self.fps)?;
+        self.video.release()?;
+        self.video = next;
+        // Up to here the metadata matches closed, playable files
+        self.meta.flush()?;
+        self.started = Instant::now();
+        log::debug!("Recording continues in {}", path.display());
+        Ok(())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.video.release()?;
+        self.meta.flush()?;
+        Ok(())
+    }
+}
+
+fn open_video(path: &Path, frame_size: Size, fps: f64) -> Result<VideoWriter> {
+    let fourcc = VideoWriter::fourcc('m', 'p', '4', 'v')?;
+    let video = VideoWriter::new(&path.to_string_lossy(), fourcc, fps, frame_size, true)?;
+    if !video.is_opened()? {
+        bail!("Unable to write {}", path.display());
+    }
+    Ok(video)
+}
+
+// A panic on the main thread ends the process without unwinding the others, which would
+// leave the video being written without the index that makes it playable
+fn install_panic_hook() {
+    PANIC_HOOK.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            previous(info);
+            // The panic may have come from under the lock
+            let open = match OPEN.try_lock() {
+                Ok(open) => open.clone(),
+                Err(_) => return,
+            };
+            for finalizer in open {
+                finalizer.finalize();
+            }
+        }));
+    });
+}
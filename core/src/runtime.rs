@@ -0,0 +1,41 @@
+use anyhow::{anyhow, Result};
+use std::{sync::OnceLock, time::Duration};
+use tokio::{
+    runtime::{Builder, Runtime},
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_util::sync::CancellationToken;
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+static SHUTDOWN: OnceLock<CancellationToken> = OnceLock::new();
+
+/// The runtime the capture and calibration tasks run on, started on first use. Front ends
+/// without one of their own (the Slint event loop, a terminal loop) spawn onto this one and
+/// wait on it with `block_on` from outside any task.
+pub fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| {
+        Builder::new_multi_thread()
+            .enable_all()
+            .thread_name("rustyrabbit-worker")
+            .build()
+            .expect("Unable to start the task runtime")
+    })
+}
+
+/// Cancelled once, when the app shuts down. The camera task's and every calibration's
+/// tokens are children of this one, as are those of the detection workers under them, so
+/// cancelling it stops all of them at once without each being told on its own.
+pub fn shutdown_token() -> &'static CancellationToken {
+    SHUTDOWN.get_or_init(CancellationToken::new)
+}
+
+/// Waits up to `limit` for a task, from outside the runtime. A task that takes longer is
+/// left running detached and reported as an error, so shutting down never hangs on a stuck
+/// driver or a long solve. `what` names the task in the error.
+pub fn join_within<T>(task: JoinHandle<T>, limit: Duration, what: &str) -> Result<T> {
+    match runtime().block_on(timeout(limit, task)) {
+        Ok(joined) => joined.map_err(|e| anyhow!("The {} failed: {}", what, e)),
+        Err(_) => Err(anyhow!("The {} didn't finish within {} s", what, limit.as_secs())),
+    }
+}
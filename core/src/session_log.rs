@@ -0,0 +1,585 @@
+use crate::{
+    calibration::Button,
+    frame,
+    lock::Locked,
+    source::{FrameSource, SourceMetadata},
+    target::DetectedView,
+    target_print::TargetKind,
+};
+use anyhow::{bail, Context, Result};
+use opencv::{
+    core::{Mat, Vector},
+    imgcodecs::{imread, imwrite, IMREAD_COLOR, IMWRITE_PNG_COMPRESSION},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        mpsc::{self, SyncSender},
+        Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+use time::OffsetDateTime;
+
+/// The journal in a session folder, one JSON entry per line in the order things happened
+pub const JOURNAL_FILE: &str = "session.jsonl";
+const FRAMES_DIR: &str = "frames";
+// Frames waiting for the disk before capture waits too. Every frame is kept, a replay
+// that misses one isn't the same run.
+const FRAMES_QUEUED: usize = 60;
+// Fast rather than small, the frames have to keep up with the camera
+const PNG_COMPRESSION: i32 = 1;
+// Points further apart than this between a recorded and a replayed detection make it a
+// different result, in pixels
+const POINT_TOLERANCE: f32 = 1e-3;
+
+/// One line of the journal
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+pub enum Entry {
+    /// A frame as it went into the pipeline, cropped, scaled and oriented already, saved
+    /// losslessly as `file` next to the journal
+    Frame {
+        at_ms: u64,
+        sequence: u64,
+        unix_time: f64,
+        exposure: Option<f64>,
+        file: String,
+    },
+    Start {
+        at_ms: u64,
+        #[serde(flatten)]
+        started: Started,
+    },
+    Press { at_ms: u64, button: Button },
+    Delete { at_ms: u64, index: usize },
+    /// What the target detection found in the frame with this sequence number
+    Detection {
+        sequence: u64,
+        points: Option<Vec<[f32; 2]>>,
+        error: Option<String>,
+    },
+}
+
+/// How a calibration was started, enough to start it again the same way
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Started {
+    /// A built-in target, or None for the target of `plugin`
+    pub kind: Option<TargetKind>,
+    pub plugin: Option<String>,
+    pub cols: i32,
+    pub rows: i32,
+    pub square_mm: f64,
+    pub frames: usize,
+    pub manual: bool,
+    pub countdown_secs: u64,
+    pub stable_frames: usize,
+}
+
+struct Recorder {
+    dir: PathBuf,
+    started: Instant,
+    journal: BufWriter<File>,
+    frames: SyncSender<(Mat, PathBuf)>,
+    writer: JoinHandle<()>,
+    frames_logged: u64,
+}
+
+impl Recorder {
+    fn at_ms(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+
+    fn log(&mut self, entry: &Entry) {
+        let written = serde_json::to_string(entry)
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(writeln!(self.journal, "{}", line)?));
+        if let Err(e) = written {
+            log::error!("Unable to write to the session log in {}: {:?}", self.dir.display(), e);
+        }
+    }
+}
+
+// The session being logged, from capture, detection and the buttons alike
+static RECORDER: Mutex<Option<Recorder>> = Mutex::new(None);
+// The session being replayed, for comparing detections with
+static REPLAY: Mutex<Option<Comparison>> = Mutex::new(None);
+
+/// Logs the session until it is dropped
+pub struct SessionLog(PathBuf);
+
+impl SessionLog {
+    pub fn dir(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for SessionLog {
+    fn drop(&mut self) {
+        let Some(recorder) = RECORDER.locked().take() else {
+            return;
+        };
+        let Recorder {
+            mut journal,
+            frames,
+            writer,
+            frames_logged,
+            ..
+        } = recorder;
+        drop(frames);
+        if writer.join().is_err() {
+            log::error!("The session frame writer panicked");
+        }
+        if let Err(e) = journal.flush() {
+            log::error!("Unable to write the session log in {}: {:?}", self.0.display(), e);
+        }
+        log::info!("Session of {} frames saved to {}", frames_logged, self.0.display());
+    }
+}
+
+/// Logs every frame that goes into the pipeline, every calibration started, button pressed
+/// and detection made to a new session folder in `dir`, for [`Session`] to replay. Capture
+/// slows down to the disk's pace rather than leave frames out.
+pub fn start(dir: &Path) -> Result<SessionLog> {
+    let dir = dir.join(format!("session-{}", frame::file_timestamp(OffsetDateTime::now_utc())));
+    fs::create_dir_all(dir.join(FRAMES_DIR)).with_context(|| format!("Unable to create {}", dir.display()))?;
+    let journal_path = dir.join(JOURNAL_FILE);
+    let journal = File::create(&journal_path).with_context(|| format!("Unable to write {}", journal_path.display()))?;
+
+    let (frames, queued) = mpsc::sync_channel::<(Mat, PathBuf)>(FRAMES_QUEUED);
+    let writer = thread::Builder::new()
+        .name("rustyrabbit-session".into())
+        .spawn(move || {
+            let params = Vector::from_slice(&[IMWRITE_PNG_COMPRESSION, PNG_COMPRESSION]);
+            for (bgr, path) in queued {
+                match imwrite(&path.to_string_lossy(), &bgr, &params) {
+                    Ok(true) => {}
+                    Ok(false) => log::error!("Unable to write {}", path.display()),
+                    Err(e) => log::error!("Unable to write {}: {:?}", path.display(), e),
+                }
+            }
+        })
+        .context("Unable to start the session frame writer")?;
+
+    let mut recorder = RECORDER.locked();
+    if recorder.is_some() {
+        bail!("A session is logged already");
+    }
+    *recorder = Some(Recorder {
+        dir: dir.clone(),
+        started: Instant::now(),
+        journal: BufWriter::new(journal),
+        frames,
+        writer,
+        frames_logged: 0,
+    });
+    log::info!("Logging the session to {}", dir.display());
+    Ok(SessionLog(dir))
+}
+
+/// A frame about to be published, BGR
+pub fn frame(sequence: u64, timestamp: OffsetDateTime, exposure: Option<f64>, bgr: &Mat) {
+    let (frames, path) = {
+        let mut recorder = RECORDER.locked();
+        let Some(recorder) = recorder.as_mut() else {
+            return;
+        };
+        recorder.frames_logged += 1;
+        let file = format!("{}/{:08}.png", FRAMES_DIR, recorder.frames_logged);
+        let entry = Entry::Frame {
+            at_ms: recorder.at_ms(),
+            sequence,
+            unix_time: timestamp.unix_timestamp_nanos() as f64 / 1e9,
+            exposure,
+            file: file.clone(),
+        };
+        recorder.log(&entry);
+        (recorder.frames.clone(), recorder.dir.join(file))
+    };
+    // Waits for the writer outside the lock, detections and buttons are logged meanwhile
+    match bgr.try_clone() {
+        Ok(bgr) => {
+            let _ = frames.send((bgr, path));
+        }
+        Err(e) => log::error!("Unable to log frame {}: {:?}", sequence, e),
+    }
+}
+
+pub fn started(started: Started) {
+    if let Some(recorder) = RECORDER.locked().as_mut() {
+        let entry = Entry::Start {
+            at_ms: recorder.at_ms(),
+            started,
+        };
+        recorder.log(&entry);
+    }
+}
+
+pub fn pressed(button: Button) {
+    if let Some(recorder) = RECORDER.locked().as_mut() {
+        let entry = Entry::Press {
+            at_ms: recorder.at_ms(),
+            button,
+        };
+        recorder.log(&entry);
+    }
+}
+
+pub fn deleted(index: usize) {
+    if let Some(recorder) = RECORDER.locked().as_mut() {
+        let entry = Entry::Delete {
+            at_ms: recorder.at_ms(),
+            index,
+        };
+        recorder.log(&entry);
+    }
+}
+
+/// A detection finished. Logged while a session is, and compared with the recorded one
+/// while a session is replayed.
+pub fn detected(sequence: u64, result: &Result<Option<DetectedView>>) {
+    if !replaying() && RECORDER.locked().is_none() {
+        return;
+    }
+    let points = match result {
+        Ok(view) => Ok(view.as_ref().map(|view| view.image_points.iter().map(|p| [p.x, p.y]).collect())),
+        Err(e) => Err(e.to_string()),
+    };
+    if let Some(comparison) = REPLAY.locked().as_mut() {
+        comparison.compare(sequence, &points);
+    }
+    if let Some(recorder) = RECORDER.locked().as_mut() {
+        let (points, error) = match points {
+            Ok(points) => (points, None),
+            Err(e) => (None, Some(e)),
+        };
+        recorder.log(&Entry::Detection { sequence, points, error });
+    }
+}
+
+/// Whether a session is being replayed, which leaves stored calibrations alone
+pub fn replaying() -> bool {
+    REPLAY.locked().is_some()
+}
+
+/// A logged session, loaded for replaying
+pub struct Session {
+    dir: PathBuf,
+    entries: Vec<Entry>,
+}
+
+/// Something the user did, to do again once the replay has delivered `after_frames` frames
+pub struct Command<'a> {
+    pub after_frames: u64,
+    pub entry: &'a Entry,
+}
+
+impl Session {
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(JOURNAL_FILE);
+        let file = File::open(&path).with_context(|| format!("Unable to read {}", path.display()))?;
+        let mut entries = Vec::new();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.with_context(|| format!("Unable to read {}", path.display()))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            // The last line of a session that was killed may be cut short
+            match serde_json::from_str(&line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!("Ignoring line {} of {}: {}", number + 1, path.display(), e),
+            }
+        }
+        let session = Session {
+            dir: dir.to_path_buf(),
+            entries,
+        };
+        if session.frame_count() == 0 {
+            bail!("{} has no frames", path.display());
+        }
+        Ok(session)
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.entries.iter().filter(|entry| matches!(entry, Entry::Frame { .. })).count() as u64
+    }
+
+    /// The calibrations started and buttons pressed, in order
+    pub fn commands(&self) -> Vec<Command<'_>> {
+        let mut after_frames = 0;
+        let mut commands = Vec::new();
+        for entry in &self.entries {
+            match entry {
+                Entry::Frame { .. } => after_frames += 1,
+                Entry::Start { .. } | Entry::Press { .. } | Entry::Delete { .. } => {
+                    commands.push(Command { after_frames, entry })
+                }
+                Entry::Detection { .. } => {}
+            }
+        }
+        commands
+    }
+
+    /// Compares every detection from now on with the recorded one of the same frame. The
+    /// replayed frames are numbered from 1 in the order they were logged, whatever their
+    /// sequence numbers were.
+    pub fn start_comparing(&self) {
+        let mut positions = HashMap::new();
+        let mut expected = HashMap::new();
+        let mut position = 0;
+        for entry in &self.entries {
+            match entry {
+                Entry::Frame { sequence, .. } => {
+                    position += 1;
+                    positions.insert(*sequence, position);
+                }
+                Entry::Detection { sequence, points, error } => {
+                    // Sequence numbers start over when the camera is switched, the frame
+                    // logged last with it is the one that was detected
+                    if let Some(&position) = positions.get(sequence) {
+                        let recorded = match error {
+                            Some(error) => Err(error.clone()),
+                            None => Ok(points.clone()),
+                        };
+                        expected.insert(position, recorded);
+                    }
+                }
+                _ => {}
+            }
+        }
+        *REPLAY.locked() = Some(Comparison {
+            expected,
+            compared: 0,
+            differed: 0,
+        });
+    }
+
+    /// A source delivering the logged frames at the pace they were logged at
+    pub fn source(&self) -> Result<Box<dyn FrameSource>> {
+        Ok(Box::new(SessionSource::open(self)?))
+    }
+}
+
+/// Frames with a recorded detection that were detected again, and how many of those came
+/// out differently. None when no session is replayed.
+pub fn comparison() -> Option<(u64, u64)> {
+    REPLAY.locked().as_ref().map(|comparison| (comparison.compared, comparison.differed))
+}
+
+type Detected = Result<Option<Vec<[f32; 2]>>, String>;
+
+struct Comparison {
+    // By position of the frame in the session
+    expected: HashMap<u64, Detected>,
+    compared: u64,
+    differed: u64,
+}
+
+impl Comparison {
+    fn compare(&mut self, sequence: u64, replayed: &Detected) {
+        let Some(recorded) = self.expected.get(&sequence) else {
+            return;
+        };
+        self.compared += 1;
+        let same = match (recorded, replayed) {
+            (Ok(Some(recorded)), Ok(Some(replayed))) => {
+                recorded.len() == replayed.len()
+                    && recorded.iter().zip(replayed).all(|(a, b)| {
+                        (a[0] - b[0]).abs() <= POINT_TOLERANCE && (a[1] - b[1]).abs() <= POINT_TOLERANCE
+                    })
+            }
+            (Ok(None), Ok(None)) | (Err(_), Err(_)) => true,
+            _ => false,
+        };
+        if !same {
+            self.differed += 1;
+            log::warn!(
+                "Frame {}: the session found {}, the replay {}",
+                sequence,
+                describe(recorded),
+                describe(replayed)
+            );
+        }
+    }
+}
+
+fn describe(detected: &Detected) -> String {
+    match detected {
+        Ok(Some(points)) => format!("{} points", points.len()),
+        Ok(None) => "no target".to_string(),
+        Err(e) => format!("an error ({})", e),
+    }
+}
+
+// Plays the frames of a session back, paced like they were logged
+struct SessionSource {
+    frames: Vec<(u64, PathBuf)>,
+    next: usize,
+    started: Option<Instant>,
+    metadata: SourceMetadata,
+}
+
+impl SessionSource {
+    fn open(session: &Session) -> Result<Self> {
+        let frames: Vec<(u64, PathBuf)> = session
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                Entry::Frame { at_ms, file, .. } => Some((*at_ms, session.dir.join(file))),
+                _ => None,
+            })
+            .collect();
+        let first = load(&frames[0].1)?;
+        let span_ms = frames[frames.len() - 1].0.saturating_sub(frames[0].0);
+        let fps = if span_ms > 0 { (frames.len() - 1) as f64 * 1000.0 / span_ms as f64 } else { 1.0 };
+        Ok(SessionSource {
+            metadata: SourceMetadata {
+                size: first.size()?,
+                fps,
+                live: false,
+            },
+            frames,
+            next: 0,
+            started: None,
+        })
+    }
+}
+
+impl FrameSource for SessionSource {
+    fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        let Some((at_ms, path)) = self.frames.get(self.next) else {
+            return Ok(false);
+        };
+        let first_ms = self.frames[0].0;
+        let started = *self.started.get_or_insert_with(Instant::now);
+        let due = started + Duration::from_millis(at_ms.saturating_sub(first_ms));
+        if let Some(wait) = due.checked_duration_since(Instant::now()) {
+            thread::sleep(wait);
+        }
+        *frame = load(path)?;
+        self.next += 1;
+        Ok(true)
+    }
+
+    fn metadata(&self) -> Result<SourceMetadata> {
+        Ok(self.metadata)
+    }
+}
+
+fn load(path: &Path) -> Result<Mat> {
+    let image = imread(&path.to_string_lossy(), IMREAD_COLOR)?;
+    if image.empty() {
+        bail!("Unable to read {}", path.display());
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opencv::core::{Point2f, Scalar, CV_8UC3};
+
+    fn started() -> Started {
+        Started {
+            kind: Some(TargetKind::ChessBoard),
+            plugin: None,
+            cols: 9,
+            rows: 6,
+            square_mm: 25.0,
+            frames: 15,
+            manual: true,
+            countdown_secs: 3,
+            stable_frames: 10,
+        }
+    }
+
+    fn view(points: &[(f32, f32)]) -> DetectedView {
+        DetectedView {
+            image_points: points.iter().map(|&(x, y)| Point2f::new(x, y)).collect(),
+            object_points: Vector::new(),
+        }
+    }
+
+    // The recorder and the replay are global, so writing and reading back is one test
+    #[test]
+    fn a_logged_session_replays_as_it_was_logged() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("rustyrabbit-session-test-{}", std::process::id()));
+        let frames: Vec<Mat> = (0..3)
+            .map(|i| Mat::new_rows_cols_with_default(4, 6, CV_8UC3, Scalar::all(40.0 * i as f64)))
+            .collect::<opencv::Result<_>>()?;
+
+        let log = start(&dir)?;
+        let session_dir = log.dir().to_path_buf();
+        assert!(start(&dir).is_err(), "only one session is logged at a time");
+        super::started(started());
+        frame(7, OffsetDateTime::now_utc(), Some(8.0), &frames[0]);
+        detected(7, &Ok(Some(view(&[(1.0, 2.0), (3.5, 4.25)]))));
+        pressed(Button::Capture);
+        frame(8, OffsetDateTime::now_utc(), None, &frames[1]);
+        detected(8, &Ok(None));
+        deleted(0);
+        frame(9, OffsetDateTime::now_utc(), None, &frames[2]);
+        detected(9, &Err(anyhow::anyhow!("blurred")));
+        pressed(Button::Finish);
+        drop(log);
+        // A session that was killed mid-line
+        let mut journal = fs::OpenOptions::new().append(true).open(session_dir.join(JOURNAL_FILE))?;
+        write!(journal, "{{\"entry\":\"press\",\"at_")?;
+        drop(journal);
+
+        let session = Session::load(&session_dir)?;
+        assert_eq!(session.frame_count(), 3);
+        assert_eq!(session.entries.len(), 10);
+        assert!(matches!(&session.entries[0], Entry::Start { started: logged, .. } if *logged == started()));
+        assert!(matches!(
+            &session.entries[1],
+            Entry::Frame { sequence: 7, exposure: Some(exposure), .. } if *exposure == 8.0
+        ));
+        assert_eq!(
+            session.entries[2],
+            Entry::Detection {
+                sequence: 7,
+                points: Some(vec![[1.0, 2.0], [3.5, 4.25]]),
+                error: None
+            }
+        );
+        assert!(matches!(
+            &session.entries[8],
+            Entry::Detection { sequence: 9, points: None, error: Some(error) } if error == "blurred"
+        ));
+
+        let commands: Vec<_> =
+            session.commands().iter().map(|command| (command.after_frames, command.entry.clone())).collect();
+        assert_eq!(commands.len(), 4);
+        assert!(matches!(commands[0], (0, Entry::Start { .. })));
+        assert!(matches!(commands[1], (1, Entry::Press { button: Button::Capture, .. })));
+        assert!(matches!(commands[2], (2, Entry::Delete { index: 0, .. })));
+        assert!(matches!(commands[3], (3, Entry::Press { button: Button::Finish, .. })));
+
+        let mut source = session.source()?;
+        assert_eq!(source.metadata()?.size, frames[0].size()?);
+        let mut replayed = Mat::default();
+        for logged in &frames {
+            assert!(source.read(&mut replayed)?);
+            assert_eq!(replayed.data_bytes()?, logged.data_bytes()?);
+        }
+        assert!(!source.read(&mut replayed)?);
+
+        // Replayed frames are numbered from 1, one detection comes out differently
+        session.start_comparing();
+        detected(1, &Ok(Some(view(&[(1.0, 2.0), (3.5, 4.25)]))));
+        detected(2, &Ok(Some(view(&[(5.0, 5.0)]))));
+        detected(3, &Err(anyhow::anyhow!("still blurred")));
+        assert_eq!(comparison(), Some((3, 1)));
+        *REPLAY.locked() = None;
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+use crate::{
+    frame::{Frame, FrameFeed},
+    preview::Undistort,
+};
+use anyhow::{Context, Result};
+use memmap2::MmapMut;
+use std::{
+    fs::{self, File, OpenOptions},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{fence, AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+const MAGIC: &[u8; 8] = b"RRFRAME1";
+const VERSION: u32 = 1;
+const HEADER_SIZE: usize = 64;
+const SLOT_HEADER_SIZE: usize = 64;
+const FORMAT_RGBA8: u32 = 1;
+/// Frames kept, enough for a reader a few frames behind to finish the one it's on
+pub const SLOTS: usize = 4;
+// How often the feed is checked for a new frame
+const POLL: Duration = Duration::from_millis(2);
+
+/// Writes the feed's frames into a shared memory ring until dropped, for processes on the
+/// same machine that want the camera's frames without opening it themselves. The ring is a
+/// file, best put on a RAM disk such as /dev/shm, that readers map and read the pixels from
+/// in place.
+///
+/// All numbers are little endian. The file starts with a 64 byte header:
+///
+/// | Offset | Type     | Field                                                        |
+/// |--------|----------|--------------------------------------------------------------|
+/// | 0      | [u8; 8]  | `RRFRAME1`, zeros while the ring is being laid out again     |
+/// | 8      | u32      | Version, 1                                                   |
+/// | 12     | u32      | Number of slots                                              |
+/// | 16     | u64      | Bytes of pixels a slot holds                                 |
+/// | 24     | u64      | Frames written so far, the newest is in slot (n - 1) % slots |
+/// | 32     | u32      | Layout generation, bumped when the slots grow                |
+///
+/// The slots follow, each a 64 byte slot header and then the pixels:
+///
+/// | Offset | Type | Field                                                  |
+/// |--------|------|--------------------------------------------------------|
+/// | 0      | u64  | Write count, odd while the slot is being written       |
+/// | 8      | u64  | The frame's sequence number                            |
+/// | 16     | i64  | Capture time in nanoseconds since the Unix epoch       |
+/// | 24     | u32  | Width                                                  |
+/// | 28     | u32  | Height                                                 |
+/// | 32     | u32  | Bytes per row                                          |
+/// | 36     | u32  | Pixel format, 1 for RGBA8                              |
+/// | 40     | u32  | 1 when the lens distortion was taken out               |
+///
+/// A reader reads the write count, the pixels and the write count again, and keeps the
+/// pixels only when both counts are the same and even. It maps the file again when the
+/// generation changes. The file only ever grows, so an old mapping stays safe to read.
+pub struct SharedFrames {
+    path: PathBuf,
+    stopped: Arc<AtomicBool>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl SharedFrames {
+    /// Creates the ring file, replacing one left behind, and starts copying frames into it.
+    /// With `undistort` the frames are straightened with its calibration first where it fits.
+    pub fn start(path: &Path, frames: &FrameFeed, undistort: Option<Undistort>) -> Result<SharedFrames> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .with_context(|| format!("Unable to create {}", path.display()))?;
+        let mut ring = Ring {
+            file,
+            map: None,
+            slot_size: 0,
+            written: 0,
+            generation: 0,
+        };
+        let subscriber = frames.subscribe();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let (stopped, path) = (Arc::clone(&stopped), path.to_path_buf());
+            thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let Some(frame) = subscriber.latest() else {
+                        thread::sleep(POLL);
+                        continue;
+                    };
+                    let (frame, undistorted) = match &undistort {
+                        Some(undistort) => match undistort.correct(&frame) {
+                            Ok(Some(data)) => (frame.with_pixels(data), true),
+                            Ok(None) => (frame, false),
+                            Err(e) => {
+                                log::warn!("Sharing frames as they are, unable to undistort: {:?}", e);
+                                (frame, false)
+                            }
+                        },
+                        None => (frame, false),
+                    };
+                    if let Err(e) = ring.write(&frame, undistorted) {
+                        log::error!("Unable to share a frame in {}: {:?}", path.display(), e);
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            })
+        };
+        log::info!("Sharing frames in {}", path.display());
+        Ok(SharedFrames {
+            path: path.to_path_buf(),
+            stopped,
+            writer: Some(writer),
+        })
+    }
+}
+
+impl Drop for SharedFrames {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                log::error!("The shared frame writer panicked");
+            }
+        }
+        // Readers that still have it mapped keep their pages
+        if let Err(e) = fs::remove_file(&self.path) {
+            log::warn!("Unable to remove {}: {:?}", self.path.display(), e);
+        }
+    }
+}
+
+struct Ring {
+    file: File,
+    map: Option<MmapMut>,
+    slot_size: usize,
+    written: u64,
+    generation: u32,
+}
+
+impl Ring {
+    fn write(&mut self, frame: &Frame, undistorted: bool) -> Result<()> {
+        let pixels = frame.data.as_slice();
+        if self.map.is_none() || pixels.len() > self.slot_size {
+            self.lay_out(pixels.len())?;
+        }
+        let slot_size = self.slot_size;
+        let map = self.map.as_mut().unwrap();
+        let slot = (self.written % SLOTS as u64) as usize;
+        let start = HEADER_SIZE + slot * (SLOT_HEADER_SIZE + slot_size);
+        let writes = atomic_u64(map, start);
+        let count = writes.load(Ordering::Relaxed);
+        writes.store(count + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+
+        let header = &mut map[start + 8..start + SLOT_HEADER_SIZE];
+        let nanos = frame.timestamp.unix_timestamp_nanos() as i64;
+        header[0..8].copy_from_slice(&frame.sequence.to_le_bytes());
+        header[8..16].copy_from_slice(&nanos.to_le_bytes());
+        header[16..20].copy_from_slice(&(frame.width as u32).to_le_bytes());
+        header[20..24].copy_from_slice(&(frame.height as u32).to_le_bytes());
+        header[24..28].copy_from_slice(&(frame.width as u32 * 4).to_le_bytes());
+        header[28..32].copy_from_slice(&FORMAT_RGBA8.to_le_bytes());
+        header[32..36].copy_from_slice(&(undistorted as u32).to_le_bytes());
+        let data = start + SLOT_HEADER_SIZE;
+        map[data..data + pixels.len()].copy_from_slice(pixels);
+
+        atomic_u64(map, start).store(count + 2, Ordering::Release);
+        self.written += 1;
+        atomic_u64(map, 24).store(self.written, Ordering::Release);
+        Ok(())
+    }
+
+    // Grows the file for slots of `pixels` bytes. Readers see the magic go and come back
+    // with a new generation.
+    fn lay_out(&mut self, pixels: usize) -> Result<()> {
+        if let Some(map) = self.map.as_mut() {
+            map[0..8].fill(0);
+            map.flush()?;
+        }
+        let length = HEADER_SIZE + SLOTS * (SLOT_HEADER_SIZE + pixels);
+        if self.file.metadata()?.len() < length as u64 {
+            self.file.set_len(length as u64)?;
+        }
+        // SAFETY: nothing truncates the file while it is mapped, readers only read it
+        let mut map = unsafe { MmapMut::map_mut(&self.file)? };
+        map[..length].fill(0);
+        self.generation += 1;
+        self.slot_size = pixels;
+        self.written = 0;
+        map[8..12].copy_from_slice(&VERSION.to_le_bytes());
+        map[12..16].copy_from_slice(&(SLOTS as u32).to_le_bytes());
+        map[16..24].copy_from_slice(&(pixels as u64).to_le_bytes());
+        atomic_u32(&map, 32).store(self.generation, Ordering::Release);
+        map[0..8].copy_from_slice(MAGIC);
+        self.map = Some(map);
+        log::debug!("Laid out {} shared frame slots of {} bytes", SLOTS, pixels);
+        Ok(())
+    }
+}
+
+// Counters other processes read while they change
+fn atomic_u64(map: &MmapMut, offset: usize) -> &AtomicU64 {
+    assert!(offset % 8 == 0 && offset + 8 <= map.len());
+    // SAFETY: in bounds, and aligned as the map is page aligned; the borrow of the map
+    // keeps it mapped
+    unsafe { &*(map.as_ptr().add(offset) as *const AtomicU64) }
+}
+
+fn atomic_u32(map: &MmapMut, offset: usize) -> &AtomicU32 {
+    assert!(offset % 4 == 0 && offset + 4 <= map.len());
+    // SAFETY: as in atomic_u64
+    unsafe { &*(map.as_ptr().add(offset) as *const AtomicU32) }
+}
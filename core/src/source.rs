@@ -0,0 +1,591 @@
+use anyhow::{bail, Context, Result};
+#[cfg(feature = "aravis")]
+use crate::aravis_source::{AravisSettings, AravisSource};
+#[cfg(feature = "spinnaker")]
+use crate::spinnaker_source::{SpinnakerSettings, SpinnakerSource};
+use crate::{
+    bayer::{self, BayerConfig},
+    camera_settings::CameraSettings,
+    error::CameraError,
+    session_log::Session,
+    synthetic::{SyntheticConfig, SyntheticSource},
+    uvc::{self, XuControl},
+};
+use opencv::{
+    core::{Mat, Size},
+    imgcodecs::{imread, IMREAD_COLOR},
+    prelude::*,
+    videoio::{self, VideoCapture, VideoWriter},
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "bmp", "tif", "tiff"];
+
+// Used for the libcamera pipeline when the user doesn't ask for a mode
+const LIBCAMERA_DEFAULT_SIZE: (i32, i32) = (1280, 720);
+const LIBCAMERA_DEFAULT_FPS: i32 = 30;
+// V4L2 exposure modes as passed through CAP_PROP_AUTO_EXPOSURE
+const V4L2_EXPOSURE_MANUAL: f64 = 1.0;
+// Device indices looked at for attached cameras where they can't be listed
+#[cfg(not(target_os = "linux"))]
+const MAX_PROBED_CAMERAS: i32 = 10;
+
+/// OpenCV backends a device camera can be opened through, in the order they are tried when
+/// it keeps freezing: whichever OpenCV picks, then V4L2 and GStreamer by name
+pub const CAMERA_BACKENDS: [(i32, &str); 3] =
+    [(videoio::CAP_ANY, "the default backend"), (videoio::CAP_V4L2, "V4L2"), (videoio::CAP_GSTREAMER, "GStreamer")];
+
+/// Requested capture mode, anything left as None keeps the device default
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CaptureMode {
+    pub width: Option<i32>,
+    pub height: Option<i32>,
+    pub fps: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "clap", derive(clap::ValueEnum))]
+pub enum TriggerMode {
+    // Stream continuously
+    #[default]
+    FreeRunning,
+    // Acquire one frame per explicit trigger() call
+    Software,
+    // Acquire on the camera's external trigger input (Aravis and Spinnaker cameras only)
+    Hardware,
+}
+
+/// How to (re)open a source, kept around so the camera task can reconnect after an unplug
+#[derive(Debug, Clone)]
+pub enum SourceSpec {
+    Camera {
+        index: i32,
+        settings: CameraSettings,
+        xu_controls: Vec<XuControl>,
+        bayer: Option<BayerConfig>,
+    },
+    Libcamera {
+        mode: CaptureMode,
+    },
+    /// A network camera or stream server, anything OpenCV's FFmpeg or GStreamer backend
+    /// opens by URL (rtsp://, http:// MJPEG, ...)
+    Stream {
+        url: String,
+    },
+    ImageDir {
+        dir: PathBuf,
+        looping: bool,
+        fps: f64,
+    },
+    Video {
+        path: PathBuf,
+        looping: bool,
+    },
+    Synthetic(SyntheticConfig),
+    /// The frames of a session logged with [`crate::session_log`], for replaying it
+    Session {
+        dir: PathBuf,
+    },
+    #[cfg(feature = "aravis")]
+    Aravis {
+        device_id: Option<String>,
+        settings: AravisSettings,
+    },
+    /// A FLIR / Point Grey camera by serial number, the first one found without
+    #[cfg(feature = "spinnaker")]
+    Spinnaker {
+        serial: Option<String>,
+        settings: SpinnakerSettings,
+    },
+}
+
+impl SourceSpec {
+    pub fn open(&self) -> Result<Box<dyn FrameSource>> {
+        self.open_with_backend(videoio::CAP_ANY)
+    }
+
+    /// Opens a device camera through one of [`CAMERA_BACKENDS`], any other source as usual
+    pub fn open_with_backend(&self, backend: i32) -> Result<Box<dyn FrameSource>> {
+        Ok(match self {
+            SourceSpec::Camera { index, settings, xu_controls, bayer } => {
+                let source = CameraSource::open(*index, settings, *bayer, backend)?;
+                // Vendor controls (LED, HDR) that some board cameras only expose through XU units
+                if !xu_controls.is_empty() {
+                    uvc::apply_xu_controls(*index, xu_controls)?;
+                }
+                Box::new(source)
+            }
+            SourceSpec::Libcamera { mode } => Box::new(CameraSource::open_libcamera(*mode)?),
+            SourceSpec::Stream { url } => Box::new(CameraSource::open_stream(url)?),
+            SourceSpec::ImageDir { dir, looping, fps } => Box::new(ImageDirSource::open(dir, *looping, *fps)?),
+            SourceSpec::Video { path, looping } => Box::new(VideoFileSource::open(path, *looping)?),
+            SourceSpec::Synthetic(config) => Box::new(SyntheticSource::open(config.clone())?),
+            SourceSpec::Session { dir } => Session::load(dir)?.source()?,
+            #[cfg(feature = "aravis")]
+            SourceSpec::Aravis { device_id, settings } => Box::new(AravisSource::open(device_id.as_deref(), settings)?),
+            #[cfg(feature = "spinnaker")]
+            SourceSpec::Spinnaker { serial, settings } => Box::new(SpinnakerSource::open(serial.as_deref(), settings)?),
+        })
+    }
+
+    /// Whether opening through another of [`CAMERA_BACKENDS`] can make a difference
+    pub fn has_backends(&self) -> bool {
+        matches!(self, SourceSpec::Camera { .. })
+    }
+
+    pub fn supports_hardware_trigger(&self) -> bool {
+        #[cfg(feature = "aravis")]
+        if matches!(self, SourceSpec::Aravis { .. }) {
+            return true;
+        }
+        #[cfg(feature = "spinnaker")]
+        if matches!(self, SourceSpec::Spinnaker { .. }) {
+            return true;
+        }
+        false
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            SourceSpec::Camera { index, .. } => format!("Camera {}", index),
+            SourceSpec::Libcamera { .. } => "libcamera".to_string(),
+            SourceSpec::Stream { url } => format!("Stream {}", url),
+            SourceSpec::ImageDir { dir, .. } => format!("Images in {}", dir.display()),
+            SourceSpec::Video { path, .. } => format!("Video {}", path.display()),
+            SourceSpec::Synthetic(_) => "Synthetic camera".to_string(),
+            SourceSpec::Session { dir } => format!("Session in {}", dir.display()),
+            #[cfg(feature = "aravis")]
+            SourceSpec::Aravis { device_id, .. } => {
+                format!("Aravis {}", device_id.as_deref().unwrap_or("camera"))
+            }
+            #[cfg(feature = "spinnaker")]
+            SourceSpec::Spinnaker { serial, .. } => {
+                format!("Spinnaker {}", serial.as_deref().unwrap_or("camera"))
+            }
+        }
+    }
+}
+
+/// What a source reports about its frames
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SourceMetadata {
+    pub size: Size,
+    pub fps: f64,
+    /// Live devices can be unplugged and are reconnected, recorded and rendered sources can't
+    pub live: bool,
+}
+
+/// Image controls a source may have, set in the device's own units
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    Exposure,
+    Gain,
+    Focus,
+    WhiteBalance,
+}
+
+/// Anything the camera task can pull BGR frames from. Device cameras, network streams,
+/// files and the synthetic camera all look the same to the rest of the app behind this,
+/// and [`SourceSpec::open`] picks the implementation.
+pub trait FrameSource: Send {
+    /// Returns Ok(false) when no frame is available (e.g. the image folder or video has run out)
+    fn read(&mut self, frame: &mut Mat) -> Result<bool>;
+
+    fn metadata(&self) -> Result<SourceMetadata>;
+
+    /// Software trigger, the next read() returns a frame acquired after this call. The next
+    /// image of a folder, video or render is as fresh as it gets, so that's the default.
+    fn trigger(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Current exposure in the source's own units, if it reports one
+    fn exposure(&self) -> Option<f64> {
+        None
+    }
+
+    /// When the device took the last frame, in milliseconds on a clock of its own, if it
+    /// reports that. The same value twice means it handed out the same frame again.
+    fn device_timestamp(&self) -> Option<f64> {
+        None
+    }
+
+    /// Fixes a control at `value`, switching its automatic mode off first. Ok(false) when the
+    /// source has no such control.
+    fn set_control(&mut self, _control: Control, _value: f64) -> Result<bool> {
+        Ok(false)
+    }
+}
+
+/// Indices of the attached cameras [`SourceSpec::Camera`] can open. On Linux these are the
+/// capture nodes in sysfs, found without opening them, so a camera in use is listed too.
+#[cfg(target_os = "linux")]
+pub fn attached_cameras() -> Result<Vec<i32>> {
+    // Only there once a video driver has loaded
+    let Ok(entries) = fs::read_dir("/sys/class/video4linux") else {
+        return Ok(Vec::new());
+    };
+    let mut indices = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let Some(index) = name.to_str().and_then(|name| name.strip_prefix("video")?.parse().ok()) else {
+            continue;
+        };
+        // A UVC camera has a second node for its metadata, only the first one captures
+        let node = fs::read_to_string(entry.path().join("index")).unwrap_or_default();
+        if node.trim() == "0" {
+            indices.push(index);
+        }
+    }
+    indices.sort_unstable();
+    Ok(indices)
+}
+
+/// Indices of the attached cameras [`SourceSpec::Camera`] can open, by opening each in turn.
+/// A camera another program holds may be missing.
+#[cfg(not(target_os = "linux"))]
+pub fn attached_cameras() -> Result<Vec<i32>> {
+    let mut indices = Vec::new();
+    for index in 0..MAX_PROBED_CAMERAS {
+        if VideoCapture::new(index, videoio::CAP_ANY)?.is_opened()? {
+            indices.push(index);
+        }
+    }
+    Ok(indices)
+}
+
+/// A device or stream opened through OpenCV's VideoCapture (V4L2, GStreamer, FFmpeg, ...)
+pub struct CameraSource {
+    capture: VideoCapture,
+    // Set when the device delivers raw Bayer data that we debayer ourselves
+    bayer: Option<BayerConfig>,
+    raw: Mat,
+}
+
+impl CameraSource {
+    pub fn open(index: i32, settings: &CameraSettings, bayer: Option<BayerConfig>, backend: i32) -> Result<Self> {
+        let mode = settings.mode();
+        let mut capture = VideoCapture::new(index, backend)?;
+        if !capture.is_opened()? {
+            return Err(CameraError::NotFound {
+                device: format!("camera {}", index),
+            }
+            .into());
+        }
+        if let Some(config) = bayer {
+            // Ask for the raw mosaic and skip the backend's own conversion, the camera-side
+            // debayer sharpens edges and biases sub-pixel corner refinement
+            let [a, b, c, d] = config.pattern.fourcc();
+            capture.set(videoio::CAP_PROP_FOURCC, VideoWriter::fourcc(a, b, c, d)? as f64)?;
+            capture.set(videoio::CAP_PROP_CONVERT_RGB, 0.0)?;
+        }
+        if let Some(width) = mode.width {
+            capture.set(videoio::CAP_PROP_FRAME_WIDTH, width as f64)?;
+        }
+        if let Some(height) = mode.height {
+            capture.set(videoio::CAP_PROP_FRAME_HEIGHT, height as f64)?;
+        }
+        if let Some(fps) = mode.fps {
+            capture.set(videoio::CAP_PROP_FPS, fps as f64)?;
+        }
+        let mut source = CameraSource {
+            capture,
+            bayer,
+            raw: Mat::default(),
+        };
+        settings.apply_controls(&mut source)?;
+        Ok(source)
+    }
+
+    /// The Pi CSI cameras only show up through libcamera on newer OS images, so go
+    /// through GStreamer's libcamerasrc instead of V4L2. Needs OpenCV built with GStreamer.
+    pub fn open_libcamera(mode: CaptureMode) -> Result<Self> {
+        let pipeline = libcamera_pipeline(mode);
+        let capture = VideoCapture::from_file(&pipeline, videoio::CAP_GSTREAMER)?;
+        if !capture.is_opened()? {
+            return Err(CameraError::Backend {
+                device: "the libcamera camera".to_string(),
+                reason: format!(
+                    "is OpenCV built with GStreamer and gstreamer1.0-libcamera installed? Pipeline: {}",
+                    pipeline
+                ),
+            }
+            .into());
+        }
+        Ok(CameraSource {
+            capture,
+            bayer: None,
+            raw: Mat::default(),
+        })
+    }
+
+    pub fn open_stream(url: &str) -> Result<Self> {
+        let capture = VideoCapture::from_file(url, videoio::CAP_ANY)?;
+        if !capture.is_opened()? {
+            return Err(CameraError::Backend {
+                device: format!("stream {}", url),
+                reason: "check the URL, and that OpenCV was built with FFmpeg or GStreamer".to_string(),
+            }
+            .into());
+        }
+        Ok(CameraSource {
+            capture,
+            bayer: None,
+            raw: Mat::default(),
+        })
+    }
+}
+
+impl FrameSource for CameraSource {
+    fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        let Some(config) = self.bayer else {
+            return Ok(self.capture.read(frame)? && frame.size()?.width > 0);
+        };
+        if !self.capture.read(&mut self.raw)? || self.raw.empty() {
+            return Ok(false);
+        }
+        let height = self.capture.get(videoio::CAP_PROP_FRAME_HEIGHT)? as i32;
+        bayer::demosaic(&self.raw, height, config, frame)?;
+        Ok(true)
+    }
+
+    fn metadata(&self) -> Result<SourceMetadata> {
+        Ok(SourceMetadata {
+            size: Size::new(
+                self.capture.get(videoio::CAP_PROP_FRAME_WIDTH)? as i32,
+                self.capture.get(videoio::CAP_PROP_FRAME_HEIGHT)? as i32,
+            ),
+            fps: self.capture.get(videoio::CAP_PROP_FPS)?,
+            live: true,
+        })
+    }
+
+    // UVC cameras can't be triggered, so drop whatever the driver queued before the trigger
+    fn trigger(&mut self) -> Result<()> {
+        self.capture.grab()?;
+        Ok(())
+    }
+
+    fn exposure(&self) -> Option<f64> {
+        self.capture.get(videoio::CAP_PROP_EXPOSURE).ok()
+    }
+
+    // V4L2 passes on the driver's buffer timestamp, backends without one report 0
+    fn device_timestamp(&self) -> Option<f64> {
+        self.capture.get(videoio::CAP_PROP_POS_MSEC).ok().filter(|&msec| msec > 0.0)
+    }
+
+    fn set_control(&mut self, control: Control, value: f64) -> Result<bool> {
+        // The driver ignores a fixed value while the matching automatic control is on
+        let automatic = match control {
+            Control::Exposure => Some((videoio::CAP_PROP_AUTO_EXPOSURE, V4L2_EXPOSURE_MANUAL)),
+            Control::Gain => None,
+            Control::Focus => Some((videoio::CAP_PROP_AUTOFOCUS, 0.0)),
+            Control::WhiteBalance => Some((videoio::CAP_PROP_AUTO_WB, 0.0)),
+        };
+        if let Some((automatic, off)) = automatic {
+            self.capture.set(automatic, off)?;
+        }
+        let property = match control {
+            Control::Exposure => videoio::CAP_PROP_EXPOSURE,
+            Control::Gain => videoio::CAP_PROP_GAIN,
+            Control::Focus => videoio::CAP_PROP_FOCUS,
+            Control::WhiteBalance => videoio::CAP_PROP_WB_TEMPERATURE,
+        };
+        Ok(self.capture.set(property, value)?)
+    }
+}
+
+/// Plays a folder of still images back in file name order, like a very slow camera
+pub struct ImageDirSource {
+    paths: Vec<PathBuf>,
+    next: usize,
+    looping: bool,
+    fps: f64,
+    size: Size,
+    last_read: Option<Instant>,
+}
+
+impl ImageDirSource {
+    pub fn open(dir: &Path, looping: bool, fps: f64) -> Result<Self> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+            .with_context(|| format!("Unable to read image directory {}", dir.display()))?
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| is_image(path))
+            .collect();
+        paths.sort();
+
+        let first = paths
+            .first()
+            .with_context(|| format!("No images found in {}", dir.display()))?;
+        let size = load_image(first)?.size()?;
+
+        Ok(ImageDirSource {
+            paths,
+            next: 0,
+            looping,
+            fps: if fps > 0.0 { fps } else { 1.0 },
+            size,
+            last_read: None,
+        })
+    }
+}
+
+impl FrameSource for ImageDirSource {
+    fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        loop {
+            if self.next >= self.paths.len() {
+                if !self.looping {
+                    return Ok(false);
+                }
+                self.next = 0;
+            }
+
+            // Pace playback so the preview and calibration see a steady stream
+            let interval = Duration::from_secs_f64(1.0 / self.fps);
+            if let Some(last) = self.last_read {
+                let elapsed = last.elapsed();
+                if elapsed < interval {
+                    thread::sleep(interval - elapsed);
+                }
+            }
+            self.last_read = Some(Instant::now());
+
+            let path = &self.paths[self.next];
+            self.next += 1;
+
+            let image = load_image(path)?;
+            let size = image.size()?;
+            if size != self.size {
+                // The rest of the pipeline assumes a fixed frame size
+                log::warn!(
+                    "Skipping {}: size {}x{} does not match {}x{}",
+                    path.display(),
+                    size.width,
+                    size.height,
+                    self.size.width,
+                    self.size.height
+                );
+                continue;
+            }
+
+            *frame = image;
+            return Ok(true);
+        }
+    }
+
+    fn metadata(&self) -> Result<SourceMetadata> {
+        Ok(SourceMetadata {
+            size: self.size,
+            fps: self.fps,
+            live: false,
+        })
+    }
+}
+
+/// Plays a video file back at its own frame rate, for calibrating from a recording
+pub struct VideoFileSource {
+    capture: VideoCapture,
+    path: PathBuf,
+    looping: bool,
+    fps: f64,
+    size: Size,
+    last_read: Option<Instant>,
+}
+
+impl VideoFileSource {
+    pub fn open(path: &Path, looping: bool) -> Result<Self> {
+        let name = path
+            .to_str()
+            .with_context(|| format!("Non UTF-8 video path {}", path.display()))?;
+        let capture = VideoCapture::from_file(name, videoio::CAP_ANY)?;
+        if !capture.is_opened()? {
+            bail!("Unable to open video {}", path.display());
+        }
+        let size = Size::new(
+            capture.get(videoio::CAP_PROP_FRAME_WIDTH)? as i32,
+            capture.get(videoio::CAP_PROP_FRAME_HEIGHT)? as i32,
+        );
+        if size.width <= 0 || size.height <= 0 {
+            bail!("Unable to read the frame size of {}", path.display());
+        }
+        // Some containers don't say, play those like a typical camera
+        let fps = capture.get(videoio::CAP_PROP_FPS)?;
+        Ok(VideoFileSource {
+            capture,
+            path: path.to_path_buf(),
+            looping,
+            fps: if fps > 0.0 && fps.is_finite() { fps } else { 30.0 },
+            size,
+            last_read: None,
+        })
+    }
+}
+
+impl FrameSource for VideoFileSource {
+    fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        // Decoding runs far ahead of real time otherwise, and every frame would queue up
+        let interval = Duration::from_secs_f64(1.0 / self.fps);
+        if let Some(last) = self.last_read {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                thread::sleep(interval - elapsed);
+            }
+        }
+        self.last_read = Some(Instant::now());
+
+        if self.capture.read(frame)? && !frame.empty() {
+            return Ok(true);
+        }
+        if !self.looping {
+            return Ok(false);
+        }
+        if !self.capture.set(videoio::CAP_PROP_POS_FRAMES, 0.0)? {
+            bail!("Unable to rewind {}", self.path.display());
+        }
+        Ok(self.capture.read(frame)? && !frame.empty())
+    }
+
+    fn metadata(&self) -> Result<SourceMetadata> {
+        Ok(SourceMetadata {
+            size: self.size,
+            fps: self.fps,
+            live: false,
+        })
+    }
+}
+
+fn libcamera_pipeline(mode: CaptureMode) -> String {
+    let width = mode.width.unwrap_or(LIBCAMERA_DEFAULT_SIZE.0);
+    let height = mode.height.unwrap_or(LIBCAMERA_DEFAULT_SIZE.1);
+    let fps = mode.fps.unwrap_or(LIBCAMERA_DEFAULT_FPS);
+    // appsink only keeps the newest buffer so a slow consumer never sees stale frames
+    format!(
+        "libcamerasrc ! video/x-raw,width={},height={},framerate={}/1 ! videoconvert ! video/x-raw,format=BGR ! appsink drop=true max-buffers=1",
+        width, height, fps
+    )
+}
+
+fn is_image(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| IMAGE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+fn load_image(path: &Path) -> Result<Mat> {
+    let name = path
+        .to_str()
+        .with_context(|| format!("Non UTF-8 image path {}", path.display()))?;
+    let image = imread(name, IMREAD_COLOR)?;
+    if image.empty() {
+        bail!("Unable to decode image {}", path.display());
+    }
+    Ok(image)
+}
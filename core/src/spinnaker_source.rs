@@ -0,0 +1,472 @@
+use crate::{
+    bayer::{self, BayerConfig, BayerPattern, Demosaic},
+    error::CameraError,
+    source::{Control, FrameSource, SourceMetadata},
+};
+use anyhow::{bail, Context, Result};
+use libloading::Library;
+use opencv::{
+    core::{Mat, Size, CV_8UC1, CV_8UC3},
+    imgproc::{cvt_color, COLOR_GRAY2BGR, COLOR_RGB2BGR},
+    prelude::*,
+};
+use std::{
+    ffi::{c_char, c_void, CString},
+    ptr,
+};
+
+// Spinnaker's C library, loaded when a camera is opened so the build needs no SDK
+#[cfg(windows)]
+const LIBRARY: &str = "SpinnakerC_v140.dll";
+#[cfg(not(windows))]
+const LIBRARY: &str = "libSpinnaker_C.so";
+const GRAB_TIMEOUT_MS: u64 = 200;
+// SPINNAKER_ERR_SUCCESS and SPINNAKER_ERR_TIMEOUT from SpinnakerDefsC.h
+const SUCCESS: i32 = 0;
+const TIMEOUT: i32 = -1011;
+
+/// Camera features set on opening, anything left as None keeps the camera's current value
+#[derive(Debug, Default, Clone)]
+pub struct SpinnakerSettings {
+    pub exposure_us: Option<f64>,
+    pub gain_db: Option<f64>,
+    /// A PixelFormat entry, e.g. Mono8 or BayerRG8
+    pub pixel_format: Option<String>,
+    /// Software, or the input line a hardware trigger comes in on, e.g. Line0
+    pub trigger_source: Option<String>,
+    pub demosaic: Demosaic,
+}
+
+type Handle = *mut c_void;
+
+// The few entry points of the Spinnaker C API that capture needs, as in SpinnakerC.h and
+// SpinnakerGenApiC.h. Every one returns a spinError.
+struct Api {
+    system_get_instance: unsafe extern "C" fn(*mut Handle) -> i32,
+    system_release_instance: unsafe extern "C" fn(Handle) -> i32,
+    system_get_cameras: unsafe extern "C" fn(Handle, Handle) -> i32,
+    camera_list_create_empty: unsafe extern "C" fn(*mut Handle) -> i32,
+    camera_list_get_size: unsafe extern "C" fn(Handle, *mut usize) -> i32,
+    camera_list_get: unsafe extern "C" fn(Handle, usize, *mut Handle) -> i32,
+    camera_list_get_by_serial: unsafe extern "C" fn(Handle, *const c_char, *mut Handle) -> i32,
+    camera_list_clear: unsafe extern "C" fn(Handle) -> i32,
+    camera_list_destroy: unsafe extern "C" fn(Handle) -> i32,
+    camera_init: unsafe extern "C" fn(Handle) -> i32,
+    camera_deinit: unsafe extern "C" fn(Handle) -> i32,
+    camera_release: unsafe extern "C" fn(Handle) -> i32,
+    camera_get_node_map: unsafe extern "C" fn(Handle, *mut Handle) -> i32,
+    camera_begin_acquisition: unsafe extern "C" fn(Handle) -> i32,
+    camera_end_acquisition: unsafe extern "C" fn(Handle) -> i32,
+    camera_get_next_image_ex: unsafe extern "C" fn(Handle, u64, *mut Handle) -> i32,
+    image_is_incomplete: unsafe extern "C" fn(Handle, *mut u8) -> i32,
+    image_get_width: unsafe extern "C" fn(Handle, *mut usize) -> i32,
+    image_get_height: unsafe extern "C" fn(Handle, *mut usize) -> i32,
+    image_get_stride: unsafe extern "C" fn(Handle, *mut usize) -> i32,
+    image_get_data: unsafe extern "C" fn(Handle, *mut *mut c_void) -> i32,
+    image_get_time_stamp: unsafe extern "C" fn(Handle, *mut i64) -> i32,
+    image_release: unsafe extern "C" fn(Handle) -> i32,
+    node_map_get_node: unsafe extern "C" fn(Handle, *const c_char, *mut Handle) -> i32,
+    enumeration_get_entry_by_name: unsafe extern "C" fn(Handle, *const c_char, *mut Handle) -> i32,
+    enumeration_get_current_entry: unsafe extern "C" fn(Handle, *mut Handle) -> i32,
+    enumeration_entry_get_int_value: unsafe extern "C" fn(Handle, *mut i64) -> i32,
+    enumeration_entry_get_symbolic: unsafe extern "C" fn(Handle, *mut c_char, *mut usize) -> i32,
+    enumeration_set_int_value: unsafe extern "C" fn(Handle, i64) -> i32,
+    float_get_value: unsafe extern "C" fn(Handle, *mut f64) -> i32,
+    float_set_value: unsafe extern "C" fn(Handle, f64) -> i32,
+    integer_get_value: unsafe extern "C" fn(Handle, *mut i64) -> i32,
+    string_get_value: unsafe extern "C" fn(Handle, *mut c_char, *mut usize) -> i32,
+    command_execute: unsafe extern "C" fn(Handle) -> i32,
+    // Unloaded last, after every handle is released
+    _library: Library,
+}
+
+impl Api {
+    fn load() -> Result<Self> {
+        // SAFETY: the symbols are looked up by the names and signatures of the Spinnaker C API,
+        // whose initialisers only set up the SDK
+        unsafe {
+            let library = Library::new(LIBRARY)
+                .with_context(|| format!("Unable to load {}, is the Spinnaker SDK installed?", LIBRARY))?;
+            macro_rules! symbol {
+                ($name:literal) => {
+                    *library
+                        .get(concat!($name, "\0").as_bytes())
+                        .with_context(|| format!("{} has no {}", LIBRARY, $name))?
+                };
+            }
+            Ok(Api {
+                system_get_instance: symbol!("spinSystemGetInstance"),
+                system_release_instance: symbol!("spinSystemReleaseInstance"),
+                system_get_cameras: symbol!("spinSystemGetCameras"),
+                camera_list_create_empty: symbol!("spinCameraListCreateEmpty"),
+                camera_list_get_size: symbol!("spinCameraListGetSize"),
+                camera_list_get: symbol!("spinCameraListGet"),
+                camera_list_get_by_serial: symbol!("spinCameraListGetBySerial"),
+                camera_list_clear: symbol!("spinCameraListClear"),
+                camera_list_destroy: symbol!("spinCameraListDestroy"),
+                camera_init: symbol!("spinCameraInit"),
+                camera_deinit: symbol!("spinCameraDeInit"),
+                camera_release: symbol!("spinCameraRelease"),
+                camera_get_node_map: symbol!("spinCameraGetNodeMap"),
+                camera_begin_acquisition: symbol!("spinCameraBeginAcquisition"),
+                camera_end_acquisition: symbol!("spinCameraEndAcquisition"),
+                camera_get_next_image_ex: symbol!("spinCameraGetNextImageEx"),
+                image_is_incomplete: symbol!("spinImageIsIncomplete"),
+                image_get_width: symbol!("spinImageGetWidth"),
+                image_get_height: symbol!("spinImageGetHeight"),
+                image_get_stride: symbol!("spinImageGetStride"),
+                image_get_data: symbol!("spinImageGetData"),
+                image_get_time_stamp: symbol!("spinImageGetTimeStamp"),
+                image_release: symbol!("spinImageRelease"),
+                node_map_get_node: symbol!("spinNodeMapGetNode"),
+                enumeration_get_entry_by_name: symbol!("spinEnumerationGetEntryByName"),
+                enumeration_get_current_entry: symbol!("spinEnumerationGetCurrentEntry"),
+                enumeration_entry_get_int_value: symbol!("spinEnumerationEntryGetIntValue"),
+                enumeration_entry_get_symbolic: symbol!("spinEnumerationEntryGetSymbolic"),
+                enumeration_set_int_value: symbol!("spinEnumerationSetIntValue"),
+                float_get_value: symbol!("spinFloatGetValue"),
+                float_set_value: symbol!("spinFloatSetValue"),
+                integer_get_value: symbol!("spinIntegerGetValue"),
+                string_get_value: symbol!("spinStringGetValue"),
+                command_execute: symbol!("spinCommandExecute"),
+                _library: library,
+            })
+        }
+    }
+}
+
+fn check(what: &str, error: i32) -> Result<()> {
+    if error != SUCCESS {
+        bail!("Spinnaker {} failed with error {}", what, error);
+    }
+    Ok(())
+}
+
+/// FLIR / Point Grey camera (Blackfly, Chameleon, Oryx, ...) driven through the Spinnaker
+/// SDK, for the exposure, gain, trigger and pixel format control OpenCV's generic capture
+/// doesn't give these cameras. Picked by serial number, or the first one found.
+pub struct SpinnakerSource {
+    camera: Handle,
+    nodes: Handle,
+    system: Handle,
+    size: Size,
+    fps: f64,
+    pixel_format: String,
+    demosaic: Demosaic,
+    timestamp: Option<f64>,
+    api: Api,
+}
+
+// The handles are only used from the camera task that owns the source, Spinnaker allows
+// that from any thread
+unsafe impl Send for SpinnakerSource {}
+
+impl SpinnakerSource {
+    pub fn open(serial: Option<&str>, settings: &SpinnakerSettings) -> Result<Self> {
+        let api = Api::load()?;
+        let mut system = ptr::null_mut();
+        // SAFETY: see Api, every out pointer is a live local
+        unsafe { check("SystemGetInstance", (api.system_get_instance)(&mut system))? };
+        let mut source = SpinnakerSource {
+            camera: ptr::null_mut(),
+            nodes: ptr::null_mut(),
+            system,
+            size: Size::default(),
+            fps: 30.0,
+            pixel_format: String::new(),
+            demosaic: settings.demosaic,
+            timestamp: None,
+            api,
+        };
+        // Dropping the source on an error releases as much as was set up
+        source.camera = source.find_camera(serial)?;
+        let (api, camera) = (&source.api, source.camera);
+        // SAFETY: as above
+        unsafe {
+            check("CameraInit", (api.camera_init)(camera))?;
+            check("CameraGetNodeMap", (api.camera_get_node_map)(camera, &mut source.nodes))?;
+        }
+
+        source.set_entry("AcquisitionMode", "Continuous")?;
+        if let Some(format) = &settings.pixel_format {
+            source.set_entry("PixelFormat", format)?;
+        }
+        if let Some(exposure) = settings.exposure_us {
+            source.set_control(Control::Exposure, exposure)?;
+        }
+        if let Some(gain) = settings.gain_db {
+            source.set_control(Control::Gain, gain)?;
+        }
+        // The trigger source can only be changed with the trigger off
+        source.set_entry("TriggerMode", "Off")?;
+        if let Some(trigger) = &settings.trigger_source {
+            source.set_entry("TriggerSelector", "FrameStart")?;
+            source.set_entry("TriggerSource", trigger)?;
+            source.set_entry("TriggerMode", "On")?;
+        }
+
+        source.size = Size::new(source.integer("Width")? as i32, source.integer("Height")? as i32);
+        source.fps = source.float("AcquisitionResultingFrameRate").or_else(|_| source.float("AcquisitionFrameRate"))?;
+        source.pixel_format = source.entry("PixelFormat")?;
+        // SAFETY: as above
+        unsafe { check("CameraBeginAcquisition", (source.api.camera_begin_acquisition)(source.camera))? };
+
+        log::info!(
+            "Spinnaker camera: {} {}, {}x{} {} @ {:.1} FPS",
+            source.string("DeviceModelName").unwrap_or_default(),
+            source.string("DeviceSerialNumber").unwrap_or_default(),
+            source.size.width,
+            source.size.height,
+            source.pixel_format,
+            source.fps
+        );
+        Ok(source)
+    }
+
+    fn find_camera(&self, serial: Option<&str>) -> Result<Handle> {
+        let api = &self.api;
+        let mut list = ptr::null_mut();
+        let mut camera = ptr::null_mut();
+        // SAFETY: see Api; the list is destroyed before returning, the camera keeps a
+        // reference of its own
+        unsafe {
+            check("CameraListCreateEmpty", (api.camera_list_create_empty)(&mut list))?;
+            let found = (|| -> Result<()> {
+                check("SystemGetCameras", (api.system_get_cameras)(self.system, list))?;
+                let mut count = 0;
+                check("CameraListGetSize", (api.camera_list_get_size)(list, &mut count))?;
+                if count == 0 {
+                    return Err(CameraError::NotFound {
+                        device: "a Spinnaker camera".to_string(),
+                    }
+                    .into());
+                }
+                match serial {
+                    Some(serial) => {
+                        let serial_c = CString::new(serial)?;
+                        let error = (api.camera_list_get_by_serial)(list, serial_c.as_ptr(), &mut camera);
+                        if error != SUCCESS || camera.is_null() {
+                            bail!("No Spinnaker camera with serial number {} among the {} found", serial, count);
+                        }
+                    }
+                    None => check("CameraListGet", (api.camera_list_get)(list, 0, &mut camera))?,
+                }
+                Ok(())
+            })();
+            (api.camera_list_clear)(list);
+            (api.camera_list_destroy)(list);
+            found.map(|_| camera)
+        }
+    }
+
+    fn node(&self, name: &str) -> Result<Handle> {
+        let name_c = CString::new(name)?;
+        let mut node = ptr::null_mut();
+        // SAFETY: see Api
+        let error = unsafe { (self.api.node_map_get_node)(self.nodes, name_c.as_ptr(), &mut node) };
+        if error != SUCCESS || node.is_null() {
+            bail!("The Spinnaker camera has no {} feature", name);
+        }
+        Ok(node)
+    }
+
+    // Sets an enumeration feature to the entry of that name
+    fn set_entry(&self, name: &str, entry: &str) -> Result<()> {
+        let node = self.node(name)?;
+        let entry_c = CString::new(entry)?;
+        let (mut handle, mut value) = (ptr::null_mut(), 0);
+        // SAFETY: see Api
+        unsafe {
+            let error = (self.api.enumeration_get_entry_by_name)(node, entry_c.as_ptr(), &mut handle);
+            if error != SUCCESS || handle.is_null() {
+                bail!("The Spinnaker camera's {} can't be {}", name, entry);
+            }
+            check("EnumerationEntryGetIntValue", (self.api.enumeration_entry_get_int_value)(handle, &mut value))?;
+            check(&format!("setting {} to {}", name, entry), (self.api.enumeration_set_int_value)(node, value))
+        }
+    }
+
+    // The current entry of an enumeration feature
+    fn entry(&self, name: &str) -> Result<String> {
+        let node = self.node(name)?;
+        let mut entry = ptr::null_mut();
+        // SAFETY: see Api
+        unsafe { check("EnumerationGetCurrentEntry", (self.api.enumeration_get_current_entry)(node, &mut entry))? };
+        // SAFETY: text passes a buffer of the length it says
+        self.text(|buffer, length| unsafe { (self.api.enumeration_entry_get_symbolic)(entry, buffer, length) })
+    }
+
+    fn string(&self, name: &str) -> Result<String> {
+        let node = self.node(name)?;
+        // SAFETY: as in entry
+        self.text(|buffer, length| unsafe { (self.api.string_get_value)(node, buffer, length) })
+    }
+
+    // Reads a string out through `get`, which takes a buffer and its length in and out
+    fn text(&self, get: impl Fn(*mut c_char, *mut usize) -> i32) -> Result<String> {
+        let mut buffer = vec![0u8; 256];
+        let mut length = buffer.len();
+        check("reading a string", get(buffer.as_mut_ptr() as *mut c_char, &mut length))?;
+        buffer.truncate(length.min(buffer.len()));
+        let text = String::from_utf8_lossy(&buffer);
+        Ok(text.trim_end_matches('\0').to_string())
+    }
+
+    fn float(&self, name: &str) -> Result<f64> {
+        let node = self.node(name)?;
+        let mut value = 0.0;
+        // SAFETY: see Api
+        unsafe { check(&format!("reading {}", name), (self.api.float_get_value)(node, &mut value))? };
+        Ok(value)
+    }
+
+    fn set_float(&self, name: &str, value: f64) -> Result<()> {
+        let node = self.node(name)?;
+        // SAFETY: see Api
+        unsafe { check(&format!("setting {} to {}", name, value), (self.api.float_set_value)(node, value)) }
+    }
+
+    fn integer(&self, name: &str) -> Result<i64> {
+        let node = self.node(name)?;
+        let mut value = 0;
+        // SAFETY: see Api
+        unsafe { check(&format!("reading {}", name), (self.api.integer_get_value)(node, &mut value))? };
+        Ok(value)
+    }
+
+    fn convert(&self, image: Handle, frame: &mut Mat) -> Result<()> {
+        let api = &self.api;
+        let (mut width, mut height, mut stride, mut data) = (0, 0, 0, ptr::null_mut());
+        // SAFETY: see Api
+        unsafe {
+            check("ImageGetWidth", (api.image_get_width)(image, &mut width))?;
+            check("ImageGetHeight", (api.image_get_height)(image, &mut height))?;
+            check("ImageGetStride", (api.image_get_stride)(image, &mut stride))?;
+            check("ImageGetData", (api.image_get_data)(image, &mut data))?;
+        }
+        let bayer = |pattern| {
+            Some(BayerConfig {
+                pattern,
+                algorithm: self.demosaic,
+            })
+        };
+        let (channels, conversion, bayer) = match self.pixel_format.as_str() {
+            "Mono8" => (1, Some(COLOR_GRAY2BGR), None),
+            "BayerRG8" => (1, None, bayer(BayerPattern::Rggb)),
+            "BayerBG8" => (1, None, bayer(BayerPattern::Bggr)),
+            "BayerGR8" => (1, None, bayer(BayerPattern::Grbg)),
+            "BayerGB8" => (1, None, bayer(BayerPattern::Gbrg)),
+            "RGB8" | "RGB8Packed" => (3, Some(COLOR_RGB2BGR), None),
+            "BGR8" | "BGR8Packed" => (3, None, None),
+            other => {
+                return Err(CameraError::UnsupportedFormat {
+                    device: "The Spinnaker camera".to_string(),
+                    format: other.to_string(),
+                }
+                .into())
+            }
+        };
+        if data.is_null() || stride < width * channels {
+            bail!("The Spinnaker camera delivered an image without {}x{} pixels", width, height);
+        }
+        let kind = if channels == 1 { CV_8UC1 } else { CV_8UC3 };
+        // SAFETY: the image holds height rows of stride bytes until it is released, after the
+        // pixels are converted into the frame
+        let raw = unsafe { Mat::new_rows_cols_with_data_unsafe(height as i32, width as i32, kind, data, stride)? };
+        match (conversion, bayer) {
+            (_, Some(config)) => bayer::demosaic(&raw, height as i32, config, frame)?,
+            (Some(code), None) => cvt_color(&raw, frame, code, 0)?,
+            (None, None) => raw.copy_to(frame)?,
+        }
+        Ok(())
+    }
+}
+
+impl FrameSource for SpinnakerSource {
+    fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        let api = &self.api;
+        let mut image = ptr::null_mut();
+        // SAFETY: see Api
+        let error = unsafe { (api.camera_get_next_image_ex)(self.camera, GRAB_TIMEOUT_MS, &mut image) };
+        if error == TIMEOUT {
+            return Ok(false);
+        }
+        check("CameraGetNextImage", error)?;
+        let (mut incomplete, mut nanos) = (0, 0);
+        // SAFETY: see Api
+        let result = unsafe { check("ImageIsIncomplete", (api.image_is_incomplete)(image, &mut incomplete)) }
+            .and_then(|_| match incomplete {
+                0 => self.convert(image, frame).map(|_| true),
+                _ => Ok(false),
+            });
+        // SAFETY: see Api
+        if unsafe { (api.image_get_time_stamp)(image, &mut nanos) } == SUCCESS {
+            self.timestamp = Some(nanos as f64 / 1e6);
+        }
+        // Hand the image back to the stream whatever happened
+        // SAFETY: not used after this
+        unsafe { (api.image_release)(image) };
+        result
+    }
+
+    fn metadata(&self) -> Result<SourceMetadata> {
+        Ok(SourceMetadata {
+            size: self.size,
+            fps: self.fps,
+            live: true,
+        })
+    }
+
+    fn trigger(&mut self) -> Result<()> {
+        let node = self.node("TriggerSoftware")?;
+        // SAFETY: see Api
+        unsafe { check("TriggerSoftware", (self.api.command_execute)(node)) }
+    }
+
+    // Exposure time in microseconds
+    fn exposure(&self) -> Option<f64> {
+        self.float("ExposureTime").ok()
+    }
+
+    fn device_timestamp(&self) -> Option<f64> {
+        self.timestamp
+    }
+
+    // SFNC feature names, in the camera's units (exposure in microseconds, gain in dB)
+    fn set_control(&mut self, control: Control, value: f64) -> Result<bool> {
+        match control {
+            Control::Exposure => {
+                self.set_entry("ExposureAuto", "Off")?;
+                self.set_float("ExposureTime", value)?;
+            }
+            Control::Gain => {
+                self.set_entry("GainAuto", "Off")?;
+                self.set_float("Gain", value)?;
+            }
+            Control::Focus | Control::WhiteBalance => return Ok(false),
+        }
+        Ok(true)
+    }
+}
+
+impl Drop for SpinnakerSource {
+    fn drop(&mut self) {
+        let api = &self.api;
+        // SAFETY: see Api, each handle is released once and after everything made from it
+        unsafe {
+            if !self.camera.is_null() {
+                if !self.nodes.is_null() {
+                    // Fails harmlessly when acquisition never started
+                    (api.camera_end_acquisition)(self.camera);
+                    if let Err(e) = check("CameraDeInit", (api.camera_deinit)(self.camera)) {
+                        log::error!("Error closing the Spinnaker camera: {:?}", e);
+                    }
+                }
+                (api.camera_release)(self.camera);
+            }
+            if let Err(e) = check("SystemReleaseInstance", (api.system_release_instance)(self.system)) {
+                log::error!("Error releasing the Spinnaker system: {:?}", e);
+            }
+        }
+    }
+}
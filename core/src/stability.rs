@@ -0,0 +1,138 @@
+use opencv::core::{Point2f, Vector};
+
+/// Mean corner movement between consecutive frames that still counts as holding still,
+/// in delivered pixels, unless tuned otherwise
+pub const MAX_CORNER_MOTION_PX: f32 = 1.0;
+
+/// Waits for the board to sit still for a number of consecutive frames, so automatic
+/// capture doesn't take motion blurred views
+pub struct StabilityGate {
+    required_frames: usize,
+    max_motion_px: f32,
+    previous: Option<Vector<Point2f>>,
+    stable_frames: usize,
+}
+
+impl StabilityGate {
+    pub fn new(required_frames: usize) -> Self {
+        StabilityGate {
+            required_frames,
+            max_motion_px: MAX_CORNER_MOTION_PX,
+            previous: None,
+            stable_frames: 0,
+        }
+    }
+
+    /// Feed every frame, None when no board was found. Returns true once the board has
+    /// been still long enough.
+    pub fn update(&mut self, corners: Option<&Vector<Point2f>>) -> bool {
+        let Some(corners) = corners else {
+            self.reset();
+            return false;
+        };
+        let still = self
+            .previous
+            .as_ref()
+            .and_then(|previous| mean_motion(previous, corners))
+            .is_some_and(|motion| motion <= self.max_motion_px);
+        self.stable_frames = if still { self.stable_frames + 1 } else { 0 };
+        self.previous = Some(corners.clone());
+        self.stable_frames >= self.required_frames
+    }
+
+    /// Changes the thresholds between frames, frames already counted as still stay counted
+    pub fn tune(&mut self, required_frames: usize, max_motion_px: f32) {
+        self.required_frames = required_frames;
+        self.max_motion_px = max_motion_px;
+    }
+
+    pub fn reset(&mut self) {
+        self.previous = None;
+        self.stable_frames = 0;
+    }
+}
+
+fn mean_motion(a: &Vector<Point2f>, b: &Vector<Point2f>) -> Option<f32> {
+    if a.len() != b.len() || a.is_empty() {
+        return None;
+    }
+    let total: f32 = a
+        .iter()
+        .zip(b.iter())
+        .map(|(p, q)| ((p.x - q.x).powi(2) + (p.y - q.y).powi(2)).sqrt())
+        .sum();
+    Some(total / a.len() as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A 2x2 grid moved right by dx
+    fn corners(dx: f32) -> Vector<Point2f> {
+        [(0.0, 0.0), (10.0, 0.0), (0.0, 10.0), (10.0, 10.0)]
+            .into_iter()
+            .map(|(x, y)| Point2f::new(x + dx, y))
+            .collect()
+    }
+
+    #[test]
+    fn opens_after_the_required_still_frames() {
+        let mut gate = StabilityGate::new(3);
+        // The first frame has nothing to compare with
+        assert!(!gate.update(Some(&corners(0.0))));
+        assert!(!gate.update(Some(&corners(0.0))));
+        assert!(!gate.update(Some(&corners(0.0))));
+        assert!(gate.update(Some(&corners(0.0))));
+        assert!(gate.update(Some(&corners(0.0))));
+    }
+
+    #[test]
+    fn motion_exactly_at_the_threshold_counts_as_still() {
+        let mut gate = StabilityGate::new(2);
+        gate.update(Some(&corners(0.0)));
+        assert!(!gate.update(Some(&corners(MAX_CORNER_MOTION_PX))));
+        assert!(gate.update(Some(&corners(2.0 * MAX_CORNER_MOTION_PX))));
+    }
+
+    #[test]
+    fn motion_just_over_the_threshold_starts_over() {
+        let mut gate = StabilityGate::new(2);
+        gate.update(Some(&corners(0.0)));
+        gate.update(Some(&corners(0.0)));
+        assert!(!gate.update(Some(&corners(MAX_CORNER_MOTION_PX + 0.01))));
+        assert!(!gate.update(Some(&corners(MAX_CORNER_MOTION_PX + 0.01))));
+        assert!(gate.update(Some(&corners(MAX_CORNER_MOTION_PX + 0.01))));
+    }
+
+    #[test]
+    fn losing_the_board_starts_over() {
+        let mut gate = StabilityGate::new(1);
+        gate.update(Some(&corners(0.0)));
+        assert!(gate.update(Some(&corners(0.0))));
+        assert!(!gate.update(None));
+        assert!(!gate.update(Some(&corners(0.0))));
+        assert!(gate.update(Some(&corners(0.0))));
+    }
+
+    #[test]
+    fn a_different_corner_count_is_not_still() {
+        let mut gate = StabilityGate::new(1);
+        gate.update(Some(&corners(0.0)));
+        let fewer: Vector<Point2f> = corners(0.0).iter().take(3).collect();
+        assert!(!gate.update(Some(&fewer)));
+        assert!(!gate.update(Some(&Vector::new())));
+        assert!(!gate.update(Some(&Vector::new())));
+    }
+
+    #[test]
+    fn tuning_keeps_the_frames_counted() {
+        let mut gate = StabilityGate::new(5);
+        gate.update(Some(&corners(0.0)));
+        gate.update(Some(&corners(0.5)));
+        gate.update(Some(&corners(1.0)));
+        gate.tune(2, 0.25);
+        assert!(gate.update(Some(&corners(1.25))));
+        assert!(!gate.update(Some(&corners(1.75))));
+    }
+}
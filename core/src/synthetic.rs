@@ -0,0 +1,248 @@
+use crate::source::{FrameSource, SourceMetadata};
+use anyhow::Result;
+use opencv::{
+    calib3d::undistort_points,
+    core::{self, no_array, Mat, Point2f, Rect, Scalar, Size, Vector, BORDER_CONSTANT, CV_16SC3, CV_32FC1, CV_8U, CV_8UC3},
+    imgproc::{gaussian_blur, rectangle, remap, warp_perspective, FILLED, INTER_LINEAR, LINE_8},
+    prelude::*,
+};
+use std::{
+    f64::consts::PI,
+    thread,
+    time::{Duration, Instant},
+};
+
+// Texture resolution of one board square
+const SQUARE_PX: i32 = 40;
+const BACKGROUND: f64 = 128.0;
+
+#[derive(Debug, Clone)]
+pub struct SyntheticConfig {
+    pub size: Size,
+    pub fps: f64,
+    /// Inner corners, as entered in the calibration form
+    pub board_cols: i32,
+    pub board_rows: i32,
+    /// Standard deviation of additive Gaussian noise, in grey levels
+    pub noise: f64,
+    /// Gaussian blur sigma in pixels
+    pub blur: f64,
+    /// Radial distortion baked into the rendered images
+    pub k1: f64,
+    pub k2: f64,
+}
+
+/// Renders a chessboard moving in front of a pinhole camera with known intrinsics, so the
+/// pipeline can be developed without hardware and calibration results checked against truth
+pub struct SyntheticSource {
+    config: SyntheticConfig,
+    texture: Mat,
+    camera_matrix: [[f64; 3]; 3],
+    // Remap tables that add lens distortion to the ideal render, None without distortion
+    distortion_maps: Option<(Mat, Mat)>,
+    // Camera distance that makes the board fill about half the view
+    distance: f64,
+    frame_index: u64,
+    last_read: Option<Instant>,
+    ideal: Mat,
+    distorted: Mat,
+    blurred: Mat,
+}
+
+impl SyntheticSource {
+    pub fn open(config: SyntheticConfig) -> Result<Self> {
+        let width = config.size.width as f64;
+        let height = config.size.height as f64;
+        let focal = 0.9 * width;
+        let camera_matrix = [[focal, 0.0, width / 2.0], [0.0, focal, height / 2.0], [0.0, 0.0, 1.0]];
+
+        let texture = board_texture(config.board_cols, config.board_rows)?;
+        let distortion_maps = if config.k1 != 0.0 || config.k2 != 0.0 {
+            Some(distortion_maps(config.size, &camera_matrix, config.k1, config.k2)?)
+        } else {
+            None
+        };
+        let board_width = (config.board_cols + 1) as f64;
+        let distance = focal * board_width / (0.5 * width);
+
+        log::info!(
+            "Synthetic camera: {}x{}, fx = fy = {:.1}, cx = {:.1}, cy = {:.1}, k1 = {}, k2 = {}",
+            config.size.width, config.size.height, focal, camera_matrix[0][2], camera_matrix[1][2], config.k1, config.k2
+        );
+
+        Ok(SyntheticSource {
+            config,
+            texture,
+            camera_matrix,
+            distortion_maps,
+            distance,
+            frame_index: 0,
+            last_read: None,
+            ideal: Mat::default(),
+            distorted: Mat::default(),
+            blurred: Mat::default(),
+        })
+    }
+}
+
+impl FrameSource for SyntheticSource {
+    fn read(&mut self, frame: &mut Mat) -> Result<bool> {
+        // Pace output like a real camera
+        let interval = Duration::from_secs_f64(1.0 / self.config.fps);
+        if let Some(last) = self.last_read {
+            let elapsed = last.elapsed();
+            if elapsed < interval {
+                thread::sleep(interval - elapsed);
+            }
+        }
+        self.last_read = Some(Instant::now());
+
+        let t = self.frame_index as f64 / self.config.fps;
+        self.frame_index += 1;
+
+        let homography = Mat::from_slice_2d(&self.homography(t))?;
+        warp_perspective(
+            &self.texture,
+            &mut self.ideal,
+            &homography,
+            self.config.size,
+            INTER_LINEAR,
+            BORDER_CONSTANT,
+            Scalar::all(BACKGROUND),
+        )?;
+
+        let mut current = &self.ideal;
+        if let Some((map_x, map_y)) = &self.distortion_maps {
+            remap(current, &mut self.distorted, map_x, map_y, INTER_LINEAR, BORDER_CONSTANT, Scalar::all(BACKGROUND))?;
+            current = &self.distorted;
+        }
+        if self.config.blur > 0.0 {
+            gaussian_blur(current, &mut self.blurred, Size::new(0, 0), self.config.blur, self.config.blur, core::BORDER_DEFAULT)?;
+            current = &self.blurred;
+        }
+        if self.config.noise > 0.0 {
+            let mut noise = Mat::new_size_with_default(self.config.size, CV_16SC3, Scalar::all(0.0))?;
+            core::randn(&mut noise, &Scalar::all(0.0), &Scalar::all(self.config.noise))?;
+            core::add(current, &noise, frame, &no_array(), CV_8U)?;
+        } else {
+            current.copy_to(frame)?;
+        }
+        Ok(true)
+    }
+
+    fn metadata(&self) -> Result<SourceMetadata> {
+        Ok(SourceMetadata {
+            size: self.config.size,
+            fps: self.config.fps,
+            live: false,
+        })
+    }
+}
+
+impl SyntheticSource {
+    // Board pose at time t: slow tilts about both axes while drifting around the view
+    fn homography(&self, t: f64) -> [[f64; 3]; 3] {
+        let tilt_x = 0.5 * (2.0 * PI * t / 7.0).sin();
+        let tilt_y = 0.5 * (2.0 * PI * t / 11.0).sin();
+        let spin = 0.3 * (2.0 * PI * t / 13.0).sin();
+        let rotation = matmul(&matmul(&rot_z(spin), &rot_y(tilt_y)), &rot_x(tilt_x));
+
+        let range = self.distance * 0.25;
+        let translation = [
+            range * (2.0 * PI * t / 5.0).sin(),
+            0.6 * range * (2.0 * PI * t / 3.0).cos(),
+            self.distance * (1.0 + 0.2 * (2.0 * PI * t / 9.0).sin()),
+        ];
+
+        // Board units are squares, centred on the board so rotations pivot around the middle
+        let centre = [
+            (self.config.board_cols + 1) as f64 / 2.0,
+            (self.config.board_rows + 1) as f64 / 2.0,
+        ];
+        let offset = [
+            translation[0] - rotation[0][0] * centre[0] - rotation[0][1] * centre[1],
+            translation[1] - rotation[1][0] * centre[0] - rotation[1][1] * centre[1],
+            translation[2] - rotation[2][0] * centre[0] - rotation[2][1] * centre[1],
+        ];
+        let board_to_camera = [
+            [rotation[0][0], rotation[0][1], offset[0]],
+            [rotation[1][0], rotation[1][1], offset[1]],
+            [rotation[2][0], rotation[2][1], offset[2]],
+        ];
+
+        // Texture pixels to board squares (the texture has a one square white margin)
+        let s = SQUARE_PX as f64;
+        let texture_to_board = [[1.0 / s, 0.0, -1.0], [0.0, 1.0 / s, -1.0], [0.0, 0.0, 1.0]];
+
+        matmul(&matmul(&self.camera_matrix, &board_to_camera), &texture_to_board)
+    }
+}
+
+fn board_texture(cols: i32, rows: i32) -> Result<Mat> {
+    let squares_x = cols + 1;
+    let squares_y = rows + 1;
+    let size = Size::new((squares_x + 2) * SQUARE_PX, (squares_y + 2) * SQUARE_PX);
+    let mut texture = Mat::new_size_with_default(size, CV_8UC3, Scalar::all(255.0))?;
+    for y in 0..squares_y {
+        for x in 0..squares_x {
+            if (x + y) % 2 == 0 {
+                rectangle(
+                    &mut texture,
+                    Rect::new((x + 1) * SQUARE_PX, (y + 1) * SQUARE_PX, SQUARE_PX, SQUARE_PX),
+                    Scalar::all(0.0),
+                    FILLED,
+                    LINE_8,
+                    0,
+                )?;
+            }
+        }
+    }
+    Ok(texture)
+}
+
+// For every distorted output pixel find where it lands in the ideal pinhole image
+fn distortion_maps(size: Size, camera_matrix: &[[f64; 3]; 3], k1: f64, k2: f64) -> Result<(Mat, Mat)> {
+    let k = Mat::from_slice_2d(camera_matrix)?;
+    let dist_coeffs = Mat::from_slice(&[k1, k2, 0.0, 0.0, 0.0])?;
+
+    let pixels: Vector<Point2f> = (0..size.height)
+        .flat_map(|y| (0..size.width).map(move |x| Point2f::new(x as f32, y as f32)))
+        .collect();
+    let mut ideal: Vector<Point2f> = Vector::new();
+    undistort_points(&pixels, &mut ideal, &k, &dist_coeffs, &no_array(), &k)?;
+
+    let mut map_x = Mat::new_size_with_default(size, CV_32FC1, Scalar::all(0.0))?;
+    let mut map_y = Mat::new_size_with_default(size, CV_32FC1, Scalar::all(0.0))?;
+    for (i, point) in ideal.iter().enumerate() {
+        let row = i as i32 / size.width;
+        let col = i as i32 % size.width;
+        *map_x.at_2d_mut::<f32>(row, col)? = point.x;
+        *map_y.at_2d_mut::<f32>(row, col)? = point.y;
+    }
+    Ok((map_x, map_y))
+}
+
+fn matmul(a: &[[f64; 3]; 3], b: &[[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+fn rot_x(angle: f64) -> [[f64; 3]; 3] {
+    let (s, c) = angle.sin_cos();
+    [[1.0, 0.0, 0.0], [0.0, c, -s], [0.0, s, c]]
+}
+
+fn rot_y(angle: f64) -> [[f64; 3]; 3] {
+    let (s, c) = angle.sin_cos();
+    [[c, 0.0, s], [0.0, 1.0, 0.0], [-s, 0.0, c]]
+}
+
+fn rot_z(angle: f64) -> [[f64; 3]; 3] {
+    let (s, c) = angle.sin_cos();
+    [[c, -s, 0.0], [s, c, 0.0], [0.0, 0.0, 1.0]]
+}
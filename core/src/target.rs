@@ -0,0 +1,294 @@
+use crate::{
+    acceleration,
+    error::DetectionError,
+    frame::Frame,
+    lock::Locked,
+    target_print::{parse_dictionary, TargetKind, TargetSpec, MARKER_RATIO},
+};
+use anyhow::Result;
+use opencv::{
+    calib3d::{
+        draw_chessboard_corners, find_chessboard_corners, find_circles_grid_1, CALIB_CB_ADAPTIVE_THRESH,
+        CALIB_CB_ASYMMETRIC_GRID, CALIB_CB_NORMALIZE_IMAGE,
+    },
+    core::{Mat, Point2f, Point3f, Size, TermCriteria, TermCriteria_Type, Vector},
+    features2d::SimpleBlobDetector,
+    imgproc::{corner_sub_pix, INTER_AREA},
+    objdetect::{get_predefined_dictionary, CharucoBoard, CharucoDetector},
+    prelude::*,
+};
+use std::sync::Mutex;
+
+/// Where a target was found in one frame
+#[derive(Debug, Clone)]
+pub struct DetectedView {
+    /// Frame pixels, as refined as the target allows
+    pub image_points: Vector<Point2f>,
+    /// The same points on the target in mm, in the same order
+    pub object_points: Vector<Point3f>,
+}
+
+/// A printed pattern the calibration can find in frames. The chess board, the asymmetric
+/// circle grid and ChArUco boards are built in; anything else, e.g. a vendor's own dot
+/// code, implements this and is handed to [`crate::calibration::run_calibration`].
+pub trait CalibrationTarget: Send + Sync {
+    /// For the log and the status line, e.g. "9x6 chess board"
+    fn describe(&self) -> String;
+
+    /// Points per row and rows. Detections of the whole target list their points row by row,
+    /// like OpenCV's chess board corners, which pose guidance relies on.
+    fn grid_size(&self) -> Size;
+
+    /// Every point of the target in mm on its surface, in the order a complete detection
+    /// lists them
+    fn object_points(&self) -> Vector<Point3f>;
+
+    /// Distance between neighbouring points in mm, the length of the board's drawn axes
+    fn spacing(&self) -> f64;
+
+    /// Finds the target in an RGBA frame, None when it isn't (fully) in view. Called for every
+    /// frame while calibrating, so it should keep up with the camera.
+    fn detect(&self, frame: &Frame) -> Result<Option<DetectedView>>;
+
+    /// Marks a detection on an RGBA image, the preview or a view's thumbnail
+    fn draw_overlay(&self, rgba: &mut Mat, view: &DetectedView) -> Result<()> {
+        draw_chessboard_corners(rgba, self.grid_size(), &view.image_points, true)?;
+        Ok(())
+    }
+}
+
+/// One of the built-in targets, as set on the Calibration tab or the command line
+pub fn from_spec(spec: &TargetSpec) -> Result<Box<dyn CalibrationTarget>> {
+    if spec.rows < 2 || spec.cols < 2 || !spec.square_mm.is_finite() || spec.square_mm <= 0.0 {
+        return Err(DetectionError::InvalidTarget.into());
+    }
+    Ok(match &spec.kind {
+        TargetKind::ChessBoard => Box::new(ChessBoard::new(spec.cols, spec.rows, spec.square_mm)),
+        TargetKind::CircleGrid => Box::new(CircleGrid::new(spec.cols, spec.rows, spec.square_mm)),
+        TargetKind::Charuco { dictionary } => {
+            Box::new(Charuco::new(spec.cols, spec.rows, spec.square_mm, dictionary)?)
+        }
+    })
+}
+
+/// Longest side a chess board is searched for at unless set otherwise. The corners found
+/// there are refined in the full frame, which is where the accuracy comes from, so searching
+/// a 4K frame costs about what a 720p one does.
+pub const DETECTION_SIZE: i32 = 1280;
+
+/// Inner corners of a chess board, refined to subpixel positions
+pub struct ChessBoard {
+    // Inner corners as (columns, rows)
+    size: Size,
+    square_mm: f64,
+    detection_size: i32,
+}
+
+impl ChessBoard {
+    pub fn new(cols: i32, rows: i32, square_mm: f64) -> Self {
+        ChessBoard {
+            size: Size::new(cols, rows),
+            square_mm,
+            detection_size: DETECTION_SIZE,
+        }
+    }
+
+    /// Searches frames shrunk to `longest_side` pixels, 0 searches them at full size. Smaller
+    /// is faster but a board far away may have too few pixels per square left to be found.
+    pub fn with_detection_size(mut self, longest_side: i32) -> Self {
+        self.detection_size = longest_side;
+        self
+    }
+}
+
+impl CalibrationTarget for ChessBoard {
+    fn describe(&self) -> String {
+        format!("{}x{} chess board", self.size.width, self.size.height)
+    }
+
+    fn grid_size(&self) -> Size {
+        self.size
+    }
+
+    // Points run (row, col, 0)
+    fn object_points(&self) -> Vector<Point3f> {
+        let square = self.square_mm as f32;
+        let (cols, rows) = (self.size.width, self.size.height);
+        (0..rows)
+            .flat_map(|row| (0..cols).map(move |col| Point3f::new(row as f32 * square, col as f32 * square, 0.)))
+            .collect()
+    }
+
+    fn spacing(&self) -> f64 {
+        self.square_mm
+    }
+
+    fn detect(&self, frame: &Frame) -> Result<Option<DetectedView>> {
+        let gray = frame.gray_mat()?; // Converted once per frame, shared with focus and markers
+        let longest = frame.width.max(frame.height);
+        let scale = if self.detection_size > 0 && longest > self.detection_size {
+            longest as f64 / self.detection_size as f64
+        } else {
+            1.0
+        };
+        let mut corners = Vector::<Point2f>::new();
+        let flags = CALIB_CB_ADAPTIVE_THRESH | CALIB_CB_NORMALIZE_IMAGE;
+        let found = if scale > 1.0 {
+            let mut small = Mat::default();
+            let small_size = Size::new(
+                (frame.width as f64 / scale).round() as i32,
+                (frame.height as f64 / scale).round() as i32,
+            );
+            acceleration::resize_to(&gray, &mut small, small_size, INTER_AREA)?;
+            let found = find_chessboard_corners(&small, self.size, &mut corners, flags)?;
+            // Back to full frame pixels, whose centres sit half a pixel in
+            let (x_scale, y_scale) =
+                (frame.width as f32 / small_size.width as f32, frame.height as f32 / small_size.height as f32);
+            corners = corners
+                .iter()
+                .map(|corner| Point2f::new((corner.x + 0.5) * x_scale - 0.5, (corner.y + 0.5) * y_scale - 0.5))
+                .collect();
+            found
+        } else {
+            find_chessboard_corners(&*gray, self.size, &mut corners, flags)?
+        };
+        if !found {
+            return Ok(None);
+        }
+        // Refined on every frame, so the stability check also sees the steadier positions.
+        // Corners found on a shrunk frame can be off by the shrink factor, the window has
+        // to reach that far.
+        let term_criteria = TermCriteria::new(
+            TermCriteria_Type::COUNT as i32 | TermCriteria_Type::EPS as i32,
+            30,
+            0.1,
+        )?;
+        let window = 11.max((scale * 5.0).ceil() as i32);
+        corner_sub_pix(&*gray, &mut corners, Size::new(window, window), Size::new(-1, -1), term_criteria)?;
+        Ok(Some(DetectedView {
+            image_points: corners,
+            object_points: self.object_points(),
+        }))
+    }
+}
+
+/// OpenCV's asymmetric circle grid, every other row shifted by half the spacing. Circle
+/// centres come from blob detection and are already subpixel.
+pub struct CircleGrid {
+    // Circles per row and rows
+    size: Size,
+    spacing_mm: f64,
+}
+
+impl CircleGrid {
+    pub fn new(cols: i32, rows: i32, spacing_mm: f64) -> Self {
+        CircleGrid {
+            size: Size::new(cols, rows),
+            spacing_mm,
+        }
+    }
+}
+
+impl CalibrationTarget for CircleGrid {
+    fn describe(&self) -> String {
+        format!("{}x{} asymmetric circle grid", self.size.width, self.size.height)
+    }
+
+    fn grid_size(&self) -> Size {
+        self.size
+    }
+
+    // Same layout as the printed sheet: (2 * col + row % 2) * s, row * s
+    fn object_points(&self) -> Vector<Point3f> {
+        let s = self.spacing_mm as f32;
+        let (cols, rows) = (self.size.width, self.size.height);
+        (0..rows)
+            .flat_map(|row| {
+                (0..cols).map(move |col| Point3f::new((2 * col + row % 2) as f32 * s, row as f32 * s, 0.))
+            })
+            .collect()
+    }
+
+    fn spacing(&self) -> f64 {
+        self.spacing_mm
+    }
+
+    fn detect(&self, frame: &Frame) -> Result<Option<DetectedView>> {
+        let gray = frame.gray_mat()?;
+        let mut centers = Vector::<Point2f>::new();
+        let blobs = SimpleBlobDetector::create_def()?;
+        if !find_circles_grid_1(&*gray, self.size, &mut centers, CALIB_CB_ASYMMETRIC_GRID, &blobs.into())? {
+            return Ok(None);
+        }
+        Ok(Some(DetectedView {
+            image_points: centers,
+            object_points: self.object_points(),
+        }))
+    }
+}
+
+/// A ChArUco board: chess board corners identified by the markers between them, so a
+/// view is never mistaken for the board turned around. Only views of every corner are
+/// taken, pose guidance needs the whole grid.
+pub struct Charuco {
+    // Inner corners as (columns, rows), one less than the squares each way
+    size: Size,
+    square_mm: f64,
+    // Ordered by corner id, row by row
+    corners: Vec<Point3f>,
+    // OpenCV's detector isn't Sync
+    detector: Mutex<CharucoDetector>,
+}
+
+impl Charuco {
+    pub fn new(cols: i32, rows: i32, square_mm: f64, dictionary: &str) -> Result<Self> {
+        let dictionary = get_predefined_dictionary(parse_dictionary(dictionary)?)?;
+        let board = CharucoBoard::new_def(
+            Size::new(cols + 1, rows + 1),
+            square_mm as f32,
+            (square_mm * MARKER_RATIO) as f32,
+            &dictionary,
+        )?;
+        Ok(Charuco {
+            size: Size::new(cols, rows),
+            square_mm,
+            corners: board.get_chessboard_corners()?.to_vec(),
+            detector: Mutex::new(CharucoDetector::new_def(&board)?),
+        })
+    }
+}
+
+impl CalibrationTarget for Charuco {
+    fn describe(&self) -> String {
+        format!("{}x{} ChArUco board", self.size.width + 1, self.size.height + 1)
+    }
+
+    fn grid_size(&self) -> Size {
+        self.size
+    }
+
+    fn object_points(&self) -> Vector<Point3f> {
+        Vector::from_slice(&self.corners)
+    }
+
+    fn spacing(&self) -> f64 {
+        self.square_mm
+    }
+
+    fn detect(&self, frame: &Frame) -> Result<Option<DetectedView>> {
+        let gray = frame.gray_mat()?;
+        let mut corners = Vector::<Point2f>::new();
+        let mut ids = Vector::<i32>::new();
+        self.detector.locked().detect_board_def(&*gray, &mut corners, &mut ids)?;
+        if ids.len() != self.corners.len() {
+            return Ok(None);
+        }
+        // The detector lists corners in the order it found them, the grid needs them by id
+        let mut found: Vec<(i32, Point2f)> = ids.iter().zip(corners.iter()).collect();
+        found.sort_by_key(|(id, _)| *id);
+        Ok(Some(DetectedView {
+            image_points: found.into_iter().map(|(_, corner)| corner).collect(),
+            object_points: self.object_points(),
+        }))
+    }
+}
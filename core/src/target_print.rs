@@ -0,0 +1,375 @@
+use crate::error::DetectionError;
+use anyhow::{bail, Context, Result};
+use opencv::{
+    core::{Mat, Point, Rect, Scalar, Vector, CV_8UC1},
+    imgcodecs::{imwrite, IMWRITE_PNG_COMPRESSION},
+    imgproc::{circle, line, put_text, rectangle, FILLED, FONT_HERSHEY_SIMPLEX, LINE_8, LINE_AA},
+    objdetect::{generate_image_marker, get_predefined_dictionary, PredefinedDictionaryType},
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+};
+
+// White space around the target, enough for printers that can't print to the edge
+const MARGIN_MM: f64 = 15.0;
+// Room below the target for the scale bar and the description
+const FOOTER_MM: f64 = 22.0;
+const SCALE_BAR_MM: f64 = 50.0;
+const TEXT_MM: f64 = 3.5;
+/// ArUco markers fill this much of their ChArUco square
+pub const MARKER_RATIO: f64 = 0.75;
+const PNG_DPI: f64 = 300.0;
+const MM_PER_INCH: f64 = 25.4;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum TargetKind {
+    ChessBoard,
+    // OpenCV's asymmetric circle grid, every other row shifted by half the spacing
+    CircleGrid,
+    Charuco { dictionary: String },
+}
+
+/// Same numbers as the Calibration tab: inner corners (circles for the grid) and the
+/// square side (circle spacing) in mm
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TargetSpec {
+    pub kind: TargetKind,
+    pub rows: i32,
+    pub cols: i32,
+    pub square_mm: f64,
+}
+
+// Black shapes on white paper, in mm from the top-left corner of the page
+enum Shape {
+    Rect { x: f64, y: f64, w: f64, h: f64 },
+    Circle { x: f64, y: f64, r: f64 },
+    Line { x1: f64, y1: f64, x2: f64, y2: f64, width: f64 },
+    Text { x: f64, y: f64, text: String },
+}
+
+struct Sheet {
+    width: f64,
+    height: f64,
+    shapes: Vec<Shape>,
+}
+
+impl TargetSpec {
+    fn describe(&self) -> String {
+        match &self.kind {
+            TargetKind::ChessBoard => format!(
+                "Chess board, {}x{} inner corners, {} mm squares",
+                self.cols, self.rows, self.square_mm
+            ),
+            TargetKind::CircleGrid => format!(
+                "Asymmetric circle grid, {}x{} circles, {} mm spacing",
+                self.cols, self.rows, self.square_mm
+            ),
+            TargetKind::Charuco { dictionary } => format!(
+                "ChArUco, {}x{} squares of {} mm, {} mm {} markers",
+                self.cols + 1,
+                self.rows + 1,
+                self.square_mm,
+                self.square_mm * MARKER_RATIO,
+                dictionary
+            ),
+        }
+    }
+
+    fn file_stem(&self) -> String {
+        let kind = match self.kind {
+            TargetKind::ChessBoard => "chessboard",
+            TargetKind::CircleGrid => "circles",
+            TargetKind::Charuco { .. } => "charuco",
+        };
+        format!("target-{}-{}x{}-{}mm", kind, self.cols, self.rows, self.square_mm)
+    }
+
+    fn sheet(&self) -> Result<Sheet> {
+        if self.rows < 2 || self.cols < 2 || !self.square_mm.is_finite() || self.square_mm <= 0.0 {
+            return Err(DetectionError::InvalidTarget.into());
+        }
+        let s = self.square_mm;
+        let mut shapes = Vec::new();
+        let (width, height) = match &self.kind {
+            TargetKind::ChessBoard => {
+                let (cols, rows) = (self.cols + 1, self.rows + 1);
+                for row in 0..rows {
+                    for col in (0..cols).filter(|col| (row + col) % 2 == 0) {
+                        shapes.push(Shape::Rect {
+                            x: MARGIN_MM + col as f64 * s,
+                            y: MARGIN_MM + row as f64 * s,
+                            w: s,
+                            h: s,
+                        });
+                    }
+                }
+                (cols as f64 * s, rows as f64 * s)
+            }
+            TargetKind::CircleGrid => {
+                // Same layout as OpenCV's object points: (2 * col + row % 2) * s, row * s
+                let r = s / 2.0;
+                for row in 0..self.rows {
+                    for col in 0..self.cols {
+                        shapes.push(Shape::Circle {
+                            x: MARGIN_MM + r + (2 * col + row % 2) as f64 * s,
+                            y: MARGIN_MM + r + row as f64 * s,
+                            r: r * 0.8,
+                        });
+                    }
+                }
+                ((2 * self.cols) as f64 * s, self.rows as f64 * s)
+            }
+            TargetKind::Charuco { dictionary } => {
+                // OpenCV 4.6+ layout: black top-left square, markers in the white squares
+                // numbered row by row
+                let dictionary = get_predefined_dictionary(parse_dictionary(dictionary)?)?;
+                let bits = dictionary.marker_size();
+                let (cols, rows) = (self.cols + 1, self.rows + 1);
+                let marker = s * MARKER_RATIO;
+                let cell = marker / (bits + 2) as f64;
+                let mut id = 0;
+                for row in 0..rows {
+                    for col in 0..cols {
+                        let (x, y) = (MARGIN_MM + col as f64 * s, MARGIN_MM + row as f64 * s);
+                        if (row + col) % 2 == 0 {
+                            shapes.push(Shape::Rect { x, y, w: s, h: s });
+                            continue;
+                        }
+                        // One pixel per bit, including the black border
+                        let mut image = Mat::default();
+                        generate_image_marker(&dictionary, id, bits + 2, &mut image, 1)?;
+                        id += 1;
+                        let offset = (s - marker) / 2.0;
+                        for by in 0..bits + 2 {
+                            for bx in 0..bits + 2 {
+                                if *image.at_2d::<u8>(by, bx)? < 128 {
+                                    shapes.push(Shape::Rect {
+                                        x: x + offset + bx as f64 * cell,
+                                        y: y + offset + by as f64 * cell,
+                                        w: cell,
+                                        h: cell,
+                                    });
+                                }
+                            }
+                        }
+                    }
+                }
+                (cols as f64 * s, rows as f64 * s)
+            }
+        };
+
+        // Scale bar with 10 mm ticks, to check the printer didn't fit the page
+        let bar_y = MARGIN_MM + height + 8.0;
+        let thin = 0.3;
+        shapes.push(Shape::Line { x1: MARGIN_MM, y1: bar_y, x2: MARGIN_MM + SCALE_BAR_MM, y2: bar_y, width: 0.6 });
+        for tick in 0..=(SCALE_BAR_MM / 10.0) as i32 {
+            let x = MARGIN_MM + tick as f64 * 10.0;
+            shapes.push(Shape::Line { x1: x, y1: bar_y - 2.0, x2: x, y2: bar_y, width: thin });
+        }
+        shapes.push(Shape::Text {
+            x: MARGIN_MM + SCALE_BAR_MM + 3.0,
+            y: bar_y,
+            text: format!("{} mm", SCALE_BAR_MM),
+        });
+        shapes.push(Shape::Text {
+            x: MARGIN_MM,
+            y: bar_y + 7.0,
+            text: format!("{}. Print at 100% scale and check the bar.", self.describe()),
+        });
+
+        Ok(Sheet {
+            width: width.max(SCALE_BAR_MM + 20.0) + 2.0 * MARGIN_MM,
+            height: height + MARGIN_MM + FOOTER_MM,
+            shapes,
+        })
+    }
+}
+
+pub fn parse_dictionary(name: &str) -> Result<PredefinedDictionaryType> {
+    use PredefinedDictionaryType::*;
+    Ok(match name.trim().to_uppercase().as_str() {
+        "DICT_4X4_50" => DICT_4X4_50,
+        "DICT_4X4_100" => DICT_4X4_100,
+        "DICT_4X4_250" => DICT_4X4_250,
+        "DICT_4X4_1000" => DICT_4X4_1000,
+        "DICT_5X5_50" => DICT_5X5_50,
+        "DICT_5X5_100" => DICT_5X5_100,
+        "DICT_5X5_250" => DICT_5X5_250,
+        "DICT_5X5_1000" => DICT_5X5_1000,
+        "DICT_6X6_50" => DICT_6X6_50,
+        "DICT_6X6_100" => DICT_6X6_100,
+        "DICT_6X6_250" => DICT_6X6_250,
+        "DICT_6X6_1000" => DICT_6X6_1000,
+        "DICT_7X7_50" => DICT_7X7_50,
+        "DICT_7X7_100" => DICT_7X7_100,
+        "DICT_7X7_250" => DICT_7X7_250,
+        "DICT_7X7_1000" => DICT_7X7_1000,
+        "DICT_ARUCO_ORIGINAL" => DICT_ARUCO_ORIGINAL,
+        _ => return Err(DetectionError::UnknownDictionary(name.to_string()).into()),
+    })
+}
+
+/// Writes the target as SVG, PDF and PNG into `dir`, returns the PDF's path
+pub fn write(spec: &TargetSpec, dir: &Path) -> Result<PathBuf> {
+    let sheet = spec.sheet()?;
+    fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+    let stem = dir.join(spec.file_stem());
+
+    let svg = stem.with_extension("svg");
+    fs::write(&svg, to_svg(&sheet)).with_context(|| format!("Unable to write {}", svg.display()))?;
+    let pdf = stem.with_extension("pdf");
+    fs::write(&pdf, to_pdf(&sheet)).with_context(|| format!("Unable to write {}", pdf.display()))?;
+    let png = stem.with_extension("png");
+    let params = Vector::from_slice(&[IMWRITE_PNG_COMPRESSION, 9]);
+    if !imwrite(&png.to_string_lossy(), &to_raster(&sheet, PNG_DPI)?, &params)? {
+        bail!("Unable to write {}", png.display());
+    }
+    Ok(pdf)
+}
+
+/// The target in grey at `dpi` as it comes out of the printer, e.g. to time detection on
+pub fn raster(spec: &TargetSpec, dpi: f64) -> Result<Mat> {
+    to_raster(&spec.sheet()?, dpi)
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+// Sized in mm so printing at 100% gives the real dimensions
+fn to_svg(sheet: &Sheet) -> String {
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{w}mm\" height=\"{h}mm\" viewBox=\"0 0 {w} {h}\">\n\
+         <rect width=\"{w}\" height=\"{h}\" fill=\"white\"/>\n",
+        w = sheet.width,
+        h = sheet.height
+    );
+    for shape in &sheet.shapes {
+        let _ = match shape {
+            Shape::Rect { x, y, w, h } => writeln!(svg, "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"/>", x, y, w, h),
+            Shape::Circle { x, y, r } => writeln!(svg, "<circle cx=\"{}\" cy=\"{}\" r=\"{}\"/>", x, y, r),
+            Shape::Line { x1, y1, x2, y2, width } => writeln!(
+                svg,
+                "<line x1=\"{}\" y1=\"{}\" x2=\"{}\" y2=\"{}\" stroke=\"black\" stroke-width=\"{}\"/>",
+                x1, y1, x2, y2, width
+            ),
+            Shape::Text { x, y, text } => writeln!(
+                svg,
+                "<text x=\"{}\" y=\"{}\" font-family=\"sans-serif\" font-size=\"{}\">{}</text>",
+                x,
+                y,
+                TEXT_MM,
+                escape_xml(text)
+            ),
+        };
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+// A single page PDF in vector form, the page is exactly the sheet size
+fn to_pdf(sheet: &Sheet) -> Vec<u8> {
+    let pt = |mm: f64| mm * 72.0 / MM_PER_INCH;
+    // PDF measures up from the bottom of the page
+    let flip = |y: f64| pt(sheet.height - y);
+    let mut content = String::from("0 g 0 G\n");
+    for shape in &sheet.shapes {
+        let _ = match shape {
+            Shape::Rect { x, y, w, h } => {
+                writeln!(content, "{:.3} {:.3} {:.3} {:.3} re f", pt(*x), flip(y + h), pt(*w), pt(*h))
+            }
+            Shape::Circle { x, y, r } => {
+                // Four Bezier quarters
+                let (cx, cy, r) = (pt(*x), flip(*y), pt(*r));
+                let k = r * 0.552_284_75;
+                writeln!(
+                    content,
+                    "{:.3} {:.3} m {:.3} {:.3} {:.3} {:.3} {:.3} {:.3} c {:.3} {:.3} {:.3} {:.3} {:.3} {:.3} c \
+                     {:.3} {:.3} {:.3} {:.3} {:.3} {:.3} c {:.3} {:.3} {:.3} {:.3} {:.3} {:.3} c f",
+                    cx + r, cy,
+                    cx + r, cy + k, cx + k, cy + r, cx, cy + r,
+                    cx - k, cy + r, cx - r, cy + k, cx - r, cy,
+                    cx - r, cy - k, cx - k, cy - r, cx, cy - r,
+                    cx + k, cy - r, cx + r, cy - k, cx + r, cy
+                )
+            }
+            Shape::Line { x1, y1, x2, y2, width } => writeln!(
+                content,
+                "{:.3} w {:.3} {:.3} m {:.3} {:.3} l S",
+                pt(*width),
+                pt(*x1),
+                flip(*y1),
+                pt(*x2),
+                flip(*y2)
+            ),
+            Shape::Text { x, y, text } => {
+                let text = text.replace('\\', "\\\\").replace('(', "\\(").replace(')', "\\)");
+                writeln!(content, "BT /F1 {:.2} Tf {:.3} {:.3} Td ({}) Tj ET", pt(TEXT_MM), pt(*x), flip(*y), text)
+            }
+        };
+    }
+
+    let objects = [
+        "<< /Type /Catalog /Pages 2 0 R >>".to_string(),
+        "<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_string(),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {:.3} {:.3}] /Contents 4 0 R \
+             /Resources << /Font << /F1 5 0 R >> >> >>",
+            pt(sheet.width),
+            pt(sheet.height)
+        ),
+        format!("<< /Length {} >>\nstream\n{}endstream", content.len(), content),
+        "<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica >>".to_string(),
+    ];
+    let mut pdf = String::from("%PDF-1.4\n");
+    let mut offsets = Vec::new();
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        let _ = write!(pdf, "{} 0 obj\n{}\nendobj\n", index + 1, object);
+    }
+    let xref = pdf.len();
+    let _ = write!(pdf, "xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1);
+    for offset in offsets {
+        let _ = writeln!(pdf, "{:010} 00000 n ", offset);
+    }
+    let _ = write!(
+        pdf,
+        "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n",
+        objects.len() + 1,
+        xref
+    );
+    pdf.into_bytes()
+}
+
+fn to_raster(sheet: &Sheet, dpi: f64) -> Result<Mat> {
+    let px = |mm: f64| (mm * dpi / MM_PER_INCH).round() as i32;
+    let mut image = Mat::new_rows_cols_with_default(px(sheet.height), px(sheet.width), CV_8UC1, Scalar::all(255.0))?;
+    let black = Scalar::all(0.0);
+    for shape in &sheet.shapes {
+        match shape {
+            Shape::Rect { x, y, w, h } => {
+                // From rounded edges, so neighbouring squares meet without gaps
+                let (x0, y0) = (px(*x), px(*y));
+                let rect = Rect::new(x0, y0, px(x + w) - x0, px(y + h) - y0);
+                rectangle(&mut image, rect, black, FILLED, LINE_8, 0)?;
+            }
+            Shape::Circle { x, y, r } => {
+                circle(&mut image, Point::new(px(*x), px(*y)), px(*r), black, FILLED, LINE_AA, 0)?;
+            }
+            Shape::Line { x1, y1, x2, y2, width } => {
+                let thickness = px(*width).max(1);
+                line(&mut image, Point::new(px(*x1), px(*y1)), Point::new(px(*x2), px(*y2)), black, thickness, LINE_8, 0)?;
+            }
+            Shape::Text { x, y, text } => {
+                // Hershey glyphs are about 22 px tall at scale 1
+                let scale = px(TEXT_MM) as f64 / 22.0;
+                put_text(&mut image, text, Point::new(px(*x), px(*y)), FONT_HERSHEY_SIMPLEX, scale, black, 2, LINE_AA, false)?;
+            }
+        }
+    }
+    Ok(image)
+}
@@ -0,0 +1,134 @@
+use crate::{config, preprocess::Step, runtime};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::watch, time::interval};
+
+const TUNING_FILE: &str = "tuning.toml";
+// How often the file is looked at for changes
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+// Written when there is no file yet, so the settings can be found
+const TEMPLATE: &str = "\
+# Changes to this file apply while Rusty Rabbit runs, no restart needed. A setting left
+# out keeps the value from the command line or the window.
+
+# Frames the board has to hold still before automatic capture takes a view
+# stable_frames = 5
+# Mean corner movement between frames that still counts as holding still, in pixels
+# max_corner_motion_px = 1.0
+# Seconds between automatic captures
+# countdown_secs = 3
+
+# Overlays drawn over the preview while calibrating
+# coverage_overlay = true
+# board_axes_overlay = true
+
+# Where recordings and snapshots are written from now on
+# record_dir = \"/home/me/recordings\"
+# snapshot_dir = \"/home/me/snapshots\"
+
+# Steps the grey image goes through before the target is looked for, in this order, for
+# boards with too little contrast. \"Preprocessed\" under the preview shows the result
+# outside a calibration. Tables go last in the file, keep the settings above them.
+# [[preprocess]]
+# step = \"clahe\"
+# clip_limit = 3.0
+# tiles = 8
+# [[preprocess]]
+# step = \"gamma\"
+# gamma = 0.8
+# [[preprocess]]
+# step = \"denoise\"
+# strength = 10.0
+# [[preprocess]]
+# step = \"sharpen\"
+# amount = 1.0
+# sigma = 2.0
+# [[preprocess]]
+# step = \"threshold\"
+# block_size = 31
+# offset = 5.0
+";
+
+/// Settings read from tuning.toml in the config folder and applied while the app runs, for
+/// tuning capture and detection without restarting the camera. Anything left out keeps
+/// the value from the command line or the window.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Tuning {
+    pub stable_frames: Option<usize>,
+    pub max_corner_motion_px: Option<f32>,
+    pub countdown_secs: Option<u64>,
+    pub coverage_overlay: Option<bool>,
+    pub board_axes_overlay: Option<bool>,
+    pub record_dir: Option<PathBuf>,
+    pub snapshot_dir: Option<PathBuf>,
+    pub preprocess: Vec<Step>,
+}
+
+/// The tuning as it is now and whenever the file changes. The file is read once here and
+/// then watched on the shared runtime until it shuts down; one that doesn't parse is
+/// reported and the settings before it stay in effect.
+pub fn watch() -> watch::Receiver<Tuning> {
+    let path = config::config_dir().map(|dir| dir.join(TUNING_FILE));
+    if let Ok(path) = &path {
+        if !path.exists() {
+            if let Err(e) = fs::write(path, TEMPLATE) {
+                log::warn!("Unable to write {}: {}", path.display(), e);
+            }
+        }
+    }
+    let (sender, receiver) = watch::channel(load().unwrap_or_default());
+    match path {
+        Ok(path) => {
+            runtime::runtime().spawn(follow(path, sender));
+        }
+        Err(e) => log::warn!("Not watching {} for changes: {}", TUNING_FILE, e),
+    }
+    receiver
+}
+
+fn load() -> Option<Tuning> {
+    match config::load(TUNING_FILE) {
+        Ok(tuning) => Some(tuning.unwrap_or_default()),
+        Err(e) => {
+            log::warn!("Ignoring {}: {}", TUNING_FILE, e);
+            None
+        }
+    }
+}
+
+async fn follow(path: PathBuf, sender: watch::Sender<Tuning>) {
+    let modified = |path: &PathBuf| fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    let mut last_modified: Option<SystemTime> = modified(&path);
+    let mut ticks = interval(POLL_INTERVAL);
+    let shutdown = runtime::shutdown_token();
+    loop {
+        tokio::select! {
+            _ = shutdown.cancelled() => return,
+            _ = ticks.tick() => {}
+        }
+        if sender.is_closed() {
+            return;
+        }
+        let now_modified = modified(&path);
+        if now_modified == last_modified {
+            continue;
+        }
+        last_modified = now_modified;
+        let Some(tuning) = load() else {
+            continue;
+        };
+        let changed = sender.send_if_modified(|current| {
+            let changed = *current != tuning;
+            *current = tuning;
+            changed
+        });
+        if changed {
+            log::info!("Applied the changes to {}", path.display());
+        }
+    }
+}
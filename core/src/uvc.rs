@@ -0,0 +1,182 @@
+use anyhow::{bail, Result};
+use std::{fmt, str::FromStr};
+
+// UVC request codes from the UVC 1.5 spec, table A-8
+#[cfg(target_os = "linux")]
+const UVC_SET_CUR: u8 = 0x01;
+#[cfg(target_os = "linux")]
+const UVC_GET_LEN: u8 = 0x85;
+
+/// A vendor specific extension unit (XU) control value, written as UNIT:SELECTOR=HEXBYTES,
+/// e.g. 4:2=01 to switch the LED ring on an ELP board camera
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XuControl {
+    pub unit: u8,
+    pub selector: u8,
+    pub value: Vec<u8>,
+}
+
+impl FromStr for XuControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let usage = || format!("expected UNIT:SELECTOR=HEXBYTES, got '{}'", s);
+        let (address, value) = s.split_once('=').ok_or_else(usage)?;
+        let (unit, selector) = address.split_once(':').ok_or_else(usage)?;
+        let unit = unit.trim().parse().map_err(|_| usage())?;
+        let selector = selector.trim().parse().map_err(|_| usage())?;
+
+        let hex: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+        let hex = hex.trim_start_matches("0x");
+        // Checked byte by byte first, so a sign or a multibyte character can't get in
+        if hex.is_empty() || hex.len() % 2 != 0 || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(usage());
+        }
+        let value = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| usage())?;
+
+        Ok(XuControl { unit, selector, value })
+    }
+}
+
+impl fmt::Display for XuControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}=", self.unit, self.selector)?;
+        for byte in &self.value {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod ioctl {
+    use anyhow::{bail, Context, Result};
+    use std::{fs::File, os::fd::AsRawFd};
+
+    // struct uvc_xu_control_query from linux/uvcvideo.h
+    #[repr(C)]
+    struct UvcXuControlQuery {
+        unit: u8,
+        selector: u8,
+        query: u8,
+        size: u16,
+        data: *mut u8,
+    }
+
+    // _IOWR('u', 0x21, struct uvc_xu_control_query)
+    const UVCIOC_CTRL_QUERY: libc::c_ulong = (3 << 30)
+        | ((std::mem::size_of::<UvcXuControlQuery>() as libc::c_ulong) << 16)
+        | ((b'u' as libc::c_ulong) << 8)
+        | 0x21;
+
+    pub fn open(device_index: i32) -> Result<File> {
+        let path = format!("/dev/video{}", device_index);
+        File::options()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Unable to open {} for UVC extension controls", path))
+    }
+
+    pub fn query(device: &File, unit: u8, selector: u8, query: u8, data: &mut [u8]) -> Result<()> {
+        let mut request = UvcXuControlQuery {
+            unit,
+            selector,
+            query,
+            size: data.len() as u16,
+            data: data.as_mut_ptr(),
+        };
+        // SAFETY: request points at a live buffer of request.size bytes for the whole call
+        let ret = unsafe { libc::ioctl(device.as_raw_fd(), UVCIOC_CTRL_QUERY as _, &mut request) };
+        if ret < 0 {
+            bail!(
+                "UVC XU query {:#x} on unit {} selector {} failed: {}",
+                query,
+                unit,
+                selector,
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn control_len(device: &std::fs::File, unit: u8, selector: u8) -> Result<usize> {
+    let mut len = [0u8; 2];
+    ioctl::query(device, unit, selector, UVC_GET_LEN, &mut len)?;
+    Ok(u16::from_le_bytes(len) as usize)
+}
+
+/// Writes each control to /dev/video<device_index>
+#[cfg(target_os = "linux")]
+pub fn apply_xu_controls(device_index: i32, controls: &[XuControl]) -> Result<()> {
+    let device = ioctl::open(device_index)?;
+    for control in controls {
+        let len = control_len(&device, control.unit, control.selector)?;
+        if len != control.value.len() {
+            bail!("UVC XU control {} expects {} bytes, got {}", control, len, control.value.len());
+        }
+        let mut value = control.value.clone();
+        ioctl::query(&device, control.unit, control.selector, UVC_SET_CUR, &mut value)?;
+        log::info!("Set UVC XU control {}", control);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply_xu_controls(_device_index: i32, controls: &[XuControl]) -> Result<()> {
+    if !controls.is_empty() {
+        bail!("UVC extension unit controls are only supported on Linux");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_unit_selector_and_bytes() {
+        let control: XuControl = "4:2=01".parse().unwrap();
+        assert_eq!(control, XuControl { unit: 4, selector: 2, value: vec![0x01] });
+        let control: XuControl = " 10 : 3 = 0x01 FF 7a".parse().unwrap();
+        assert_eq!(control, XuControl { unit: 10, selector: 3, value: vec![0x01, 0xff, 0x7a] });
+    }
+
+    #[test]
+    fn displays_as_it_is_written() {
+        let control: XuControl = "4:2=0x01FF".parse().unwrap();
+        assert_eq!(control.to_string(), "4:2=01ff");
+        assert_eq!(control.to_string().parse::<XuControl>().unwrap(), control);
+    }
+
+    #[test]
+    fn rejects_malformed_selectors() {
+        for malformed in [
+            "",
+            "4:2",
+            "4=01",
+            ":2=01",
+            "4:=01",
+            "256:2=01",
+            "4:-1=01",
+            "4:2:1=01",
+            "four:2=01",
+            "4:2=",
+            "4:2=0x",
+            "4:2=1",
+            "4:2=012",
+            "4:2=0g",
+            "4:2=+1",
+            "4:2=aé1",
+        ] {
+            let error = malformed.parse::<XuControl>().unwrap_err();
+            assert!(error.contains("UNIT:SELECTOR=HEXBYTES"), "{:?}: {}", malformed, error);
+        }
+    }
+}
@@ -0,0 +1,227 @@
+use crate::{
+    acceleration,
+    frame::{Frame, FrameFeed},
+    preview::Undistort,
+};
+use anyhow::{bail, Context, Result};
+use opencv::{
+    core::{Mat, Size},
+    imgproc::{COLOR_RGBA2YUV_I420, INTER_LINEAR},
+    prelude::*,
+};
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+// How often the feed is checked for a new frame
+const POLL: Duration = Duration::from_millis(2);
+
+/// Writes the feed's frames, undistorted where the calibration fits, to a v4l2loopback
+/// device until dropped, so any video application can open the corrected stream as a
+/// camera. The device's size is set from the first frame and later frames are scaled to
+/// it: a reader that has the device open keeps its format.
+pub struct VirtualCamera {
+    stopped: Arc<AtomicBool>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl VirtualCamera {
+    pub fn start(device: &Path, frames: &FrameFeed, undistort: Undistort) -> Result<VirtualCamera> {
+        if !cfg!(target_os = "linux") {
+            bail!("Virtual cameras need v4l2loopback, which is only on Linux");
+        }
+        let file = File::options()
+            .write(true)
+            .open(device)
+            .with_context(|| format!("Unable to open {}, is v4l2loopback loaded?", device.display()))?;
+        let mut output = Output {
+            file,
+            device: device.to_path_buf(),
+            size: None,
+        };
+        let subscriber = frames.subscribe();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let writer = {
+            let stopped = Arc::clone(&stopped);
+            thread::spawn(move || {
+                let mut warned = false;
+                while !stopped.load(Ordering::Relaxed) {
+                    let Some(frame) = subscriber.latest() else {
+                        thread::sleep(POLL);
+                        continue;
+                    };
+                    let frame = match undistort.correct(&frame) {
+                        Ok(Some(data)) => frame.with_pixels(data),
+                        Ok(None) => frame,
+                        Err(e) => {
+                            // Every frame would fail the same way
+                            if !warned {
+                                log::warn!("The virtual camera shows the frames as they are: {:?}", e);
+                                warned = true;
+                            }
+                            frame
+                        }
+                    };
+                    if let Err(e) = output.write(&frame) {
+                        log::error!("Unable to write to {}: {:?}", output.device.display(), e);
+                        thread::sleep(Duration::from_secs(1));
+                    }
+                }
+            })
+        };
+        log::info!("Writing the undistorted frames to {}", device.display());
+        Ok(VirtualCamera {
+            stopped,
+            writer: Some(writer),
+        })
+    }
+}
+
+impl Drop for VirtualCamera {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        if let Some(writer) = self.writer.take() {
+            if writer.join().is_err() {
+                log::error!("The virtual camera writer panicked");
+            }
+        }
+    }
+}
+
+struct Output {
+    file: File,
+    device: PathBuf,
+    size: Option<Size>,
+}
+
+impl Output {
+    fn write(&mut self, frame: &Frame) -> Result<()> {
+        let size = match self.size {
+            Some(size) => size,
+            None => {
+                // I420 halves both directions for the chroma
+                let size = Size::new(frame.width & !1, frame.height & !1);
+                let device = self.device.display();
+                v4l2::set_format(&self.file, size)
+                    .with_context(|| format!("Unable to set {} to {}x{}", device, size.width, size.height))?;
+                log::info!("{} is {}x{}", device, size.width, size.height);
+                self.size = Some(size);
+                size
+            }
+        };
+        let pixels = Mat::from_slice(frame.data.as_slice())?;
+        let rgba = pixels.reshape(4, frame.height)?;
+        let mut scaled = Mat::default();
+        let rgba = if frame.width != size.width || frame.height != size.height {
+            acceleration::resize_to(&rgba, &mut scaled, size, INTER_LINEAR)?;
+            &scaled
+        } else {
+            &*rgba
+        };
+        let mut yuv = Mat::default();
+        acceleration::convert_color(rgba, &mut yuv, COLOR_RGBA2YUV_I420)?;
+        // v4l2loopback takes one whole frame per write
+        self.file.write_all(yuv.data_bytes()?)?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod v4l2 {
+    use anyhow::{bail, Result};
+    use opencv::core::Size;
+    use std::{fs::File, os::fd::AsRawFd};
+
+    // From linux/videodev2.h
+    const V4L2_BUF_TYPE_VIDEO_OUTPUT: u32 = 2;
+    const V4L2_FIELD_NONE: u32 = 1;
+    const V4L2_COLORSPACE_SMPTE170M: u32 = 1;
+    // v4l2_fourcc('Y', 'U', '1', '2'), planar I420
+    const V4L2_PIX_FMT_YUV420: u32 = u32::from_le_bytes(*b"YU12");
+
+    // struct v4l2_pix_format
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct PixFormat {
+        width: u32,
+        height: u32,
+        pixelformat: u32,
+        field: u32,
+        bytesperline: u32,
+        sizeimage: u32,
+        colorspace: u32,
+        private: u32,
+        flags: u32,
+        ycbcr_enc: u32,
+        quantization: u32,
+        xfer_func: u32,
+    }
+
+    // The union in struct v4l2_format: 200 bytes, aligned like the pointers in some members
+    #[repr(C)]
+    union FormatData {
+        pix: PixFormat,
+        raw: [u8; 200],
+        _align: [*const libc::c_void; 0],
+    }
+
+    // struct v4l2_format
+    #[repr(C)]
+    struct Format {
+        kind: u32,
+        fmt: FormatData,
+    }
+
+    // _IOWR('V', 5, struct v4l2_format)
+    const VIDIOC_S_FMT: libc::c_ulong = (3 << 30)
+        | ((std::mem::size_of::<Format>() as libc::c_ulong) << 16)
+        | ((b'V' as libc::c_ulong) << 8)
+        | 5;
+
+    pub fn set_format(device: &File, size: Size) -> Result<()> {
+        let (width, height) = (size.width as u32, size.height as u32);
+        let mut format = Format {
+            kind: V4L2_BUF_TYPE_VIDEO_OUTPUT,
+            fmt: FormatData { raw: [0; 200] },
+        };
+        format.fmt.pix = PixFormat {
+            width,
+            height,
+            pixelformat: V4L2_PIX_FMT_YUV420,
+            field: V4L2_FIELD_NONE,
+            bytesperline: width,
+            sizeimage: width * height * 3 / 2,
+            colorspace: V4L2_COLORSPACE_SMPTE170M,
+            private: 0,
+            flags: 0,
+            ycbcr_enc: 0,
+            quantization: 0,
+            xfer_func: 0,
+        };
+        // SAFETY: format is a live struct v4l2_format for the whole call
+        let ret = unsafe { libc::ioctl(device.as_raw_fd(), VIDIOC_S_FMT as _, &mut format) };
+        if ret < 0 {
+            bail!("VIDIOC_S_FMT failed: {}", std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod v4l2 {
+    use anyhow::{bail, Result};
+    use opencv::core::Size;
+    use std::fs::File;
+
+    pub fn set_format(_device: &File, _size: Size) -> Result<()> {
+        bail!("Virtual cameras are only supported on Linux");
+    }
+}
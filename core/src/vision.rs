@@ -0,0 +1,170 @@
+use crate::{
+    frame::Frame,
+    geometry::FrameGeometry,
+    profiles::{Calibration, CameraProfile},
+};
+use anyhow::Result;
+use opencv::{
+    core::{Point, Point2d, Point2f, Size, Vector, BORDER_DEFAULT},
+    imgproc::{
+        arc_length, bounding_rect, contour_area, find_contours, gaussian_blur, min_area_rect, min_enclosing_circle,
+        threshold, CHAIN_APPROX_NONE, RETR_EXTERNAL, RETR_LIST, THRESH_BINARY, THRESH_BINARY_INV, THRESH_OTSU,
+    },
+    prelude::*,
+};
+use std::f64::consts::PI;
+
+// How far a mark's diameter may be off the one asked for and still count as the fiducial
+const DIAMETER_TOLERANCE: f64 = 0.25;
+// 1 for a perfect circle, a square scores 0.785
+const MIN_CIRCULARITY: f64 = 0.8;
+// Smallest share of the frame a blob has to cover to be part of the part, below is dust
+const MIN_PART_AREA: f64 = 0.0005;
+
+/// A round fiducial mark, as OpenPnP locates them on boards
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fiducial {
+    /// Centre in the frame's pixels
+    pub frame_center: Point2d,
+    /// Centre in sensor pixels, without the lens distortion when the calibration fits
+    pub center: Point2d,
+    /// In the frame's pixels
+    pub diameter: f64,
+}
+
+/// Where a part on the nozzle is and how it is turned, as bottom vision needs it to place
+/// the part square. Sensor pixels, without the lens distortion when the calibration fits.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PartAlignment {
+    pub center: Point2d,
+    /// Degrees, counterclockwise in the image, between -45 and 45
+    pub angle: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+/// Frame pixels to sensor pixels, straightened with the calibration when it was taken at
+/// the frame's sensor size
+pub fn to_sensor(
+    geometry: &FrameGeometry,
+    calibration: Option<&Calibration>,
+    points: &[Point2d],
+) -> Result<Vec<Point2d>> {
+    let sensor: Vector<Point2d> = points.iter().map(|&point| geometry.to_sensor_point(point)).collect();
+    let size = geometry.sensor_size;
+    let fits = |calibration: &&Calibration| {
+        calibration.sensor_width == size.width && calibration.sensor_height == size.height
+    };
+    match calibration.filter(fits) {
+        Some(calibration) if !sensor.is_empty() => Ok(calibration.undistort_points(&sensor, false)?.to_vec()),
+        _ => Ok(sensor.to_vec()),
+    }
+}
+
+/// How far a sensor pixel is from the profile's machine datum in mm, along the sensor's
+/// axes like the reticle. None until both the datum and the image scale are set.
+pub fn offset_mm(profile: &CameraProfile, sensor: Point2d) -> Option<Point2d> {
+    let [x, y] = profile.datum?;
+    let mm_per_px = profile.mm_per_px?;
+    Some(Point2d::new((sensor.x - x) * mm_per_px, (sensor.y - y) * mm_per_px))
+}
+
+/// The round mark closest to the middle of the frame with a diameter of about `diameter`
+/// frame pixels, light on dark or dark on light
+pub fn locate_fiducial(frame: &Frame, calibration: Option<&Calibration>, diameter: f64) -> Result<Option<Fiducial>> {
+    let gray = smoothed(frame)?;
+    let middle = Point2d::new(frame.width as f64 / 2.0, frame.height as f64 / 2.0);
+    let mut best: Option<(f64, Point2d, f64)> = None;
+    for polarity in [THRESH_BINARY, THRESH_BINARY_INV] {
+        let mut binary = Mat::default();
+        threshold(&gray, &mut binary, 0.0, 255.0, polarity | THRESH_OTSU)?;
+        let mut contours = Vector::<Vector<Point>>::new();
+        find_contours(&binary, &mut contours, RETR_LIST, CHAIN_APPROX_NONE, Point::new(0, 0))?;
+        for contour in &contours {
+            let perimeter = arc_length(&contour, true)?;
+            if perimeter <= 0.0 {
+                continue;
+            }
+            let circularity = 4.0 * PI * contour_area(&contour, false)? / (perimeter * perimeter);
+            let (mut center, mut radius) = (Point2f::default(), 0.0f32);
+            min_enclosing_circle(&contour, &mut center, &mut radius)?;
+            let found = 2.0 * radius as f64;
+            if circularity < MIN_CIRCULARITY || (found - diameter).abs() > DIAMETER_TOLERANCE * diameter {
+                continue;
+            }
+            let center = Point2d::new(center.x as f64, center.y as f64);
+            let distance = (center.x - middle.x).hypot(center.y - middle.y);
+            let closer = match best {
+                Some((closest, ..)) => distance < closest,
+                None => true,
+            };
+            if closer {
+                best = Some((distance, center, found));
+            }
+        }
+    }
+    let Some((_, frame_center, diameter)) = best else {
+        return Ok(None);
+    };
+    let center = to_sensor(&frame.geometry, calibration, &[frame_center])?[0];
+    Ok(Some(Fiducial {
+        frame_center,
+        center,
+        diameter,
+    }))
+}
+
+/// The part on the nozzle as the smallest rectangle around everything lit against the dark
+/// background, leaving out blobs that touch the frame's edge such as the nozzle holder
+pub fn align_part(frame: &Frame, calibration: Option<&Calibration>) -> Result<Option<PartAlignment>> {
+    let gray = smoothed(frame)?;
+    let mut binary = Mat::default();
+    threshold(&gray, &mut binary, 0.0, 255.0, THRESH_BINARY | THRESH_OTSU)?;
+    let mut contours = Vector::<Vector<Point>>::new();
+    find_contours(&binary, &mut contours, RETR_EXTERNAL, CHAIN_APPROX_NONE, Point::new(0, 0))?;
+    let min_area = MIN_PART_AREA * frame.width as f64 * frame.height as f64;
+    let mut points = Vec::new();
+    for contour in &contours {
+        let bounds = bounding_rect(&contour)?;
+        let touches_edge = bounds.x <= 0
+            || bounds.y <= 0
+            || bounds.x + bounds.width >= frame.width
+            || bounds.y + bounds.height >= frame.height;
+        if touches_edge || contour_area(&contour, false)? < min_area {
+            continue;
+        }
+        points.extend(contour.iter().map(|point| Point2d::new(point.x as f64, point.y as f64)));
+    }
+    if points.is_empty() {
+        return Ok(None);
+    }
+    // Fitted in sensor pixels, so the distortion and any rotation of the frame are taken out first
+    let sensor: Vector<Point2f> = to_sensor(&frame.geometry, calibration, &points)?
+        .into_iter()
+        .map(|point| Point2f::new(point.x as f32, point.y as f32))
+        .collect();
+    let rect = min_area_rect(&sensor)?;
+    // OpenCV's angle is clockwise in image coordinates, y pointing down
+    let (mut angle, mut width, mut height) = (-rect.angle as f64, rect.size.width as f64, rect.size.height as f64);
+    while angle <= -45.0 {
+        angle += 90.0;
+        (width, height) = (height, width);
+    }
+    while angle > 45.0 {
+        angle -= 90.0;
+        (width, height) = (height, width);
+    }
+    Ok(Some(PartAlignment {
+        center: Point2d::new(rect.center.x as f64, rect.center.y as f64),
+        angle,
+        width,
+        height,
+    }))
+}
+
+// The grey frame with sensor noise blurred away, so edges give clean contours
+fn smoothed(frame: &Frame) -> Result<Mat> {
+    let mut blurred = Mat::default();
+    gaussian_blur(&*frame.gray_mat()?, &mut blurred, Size::new(5, 5), 0.0, 0.0, BORDER_DEFAULT)?;
+    Ok(blurred)
+}
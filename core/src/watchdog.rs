@@ -0,0 +1,62 @@
+use anyhow::Result;
+use opencv::{core::Mat, prelude::*};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    time::{Duration, Instant},
+};
+
+/// How long a camera may hand out the same frame before it counts as frozen. Long enough
+/// for a dark, noiseless scene at a low frame rate not to trip it.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+// Every this many bytes of a frame go into its fingerprint, enough to catch sensor noise
+const FINGERPRINT_STRIDE: usize = 61;
+
+/// Tells when a live camera keeps returning frames that aren't new: the device timestamp
+/// stands still, or the pixels are the same to the byte, which a real sensor never manages.
+/// Some UVC cameras get into that state after hours of streaming without reporting an error.
+#[derive(Debug, Default)]
+pub struct Watchdog {
+    last_timestamp: Option<f64>,
+    last_fingerprint: Option<u64>,
+    // When the frames stopped changing
+    unchanged_since: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Call with every frame read, true once they haven't changed for [`STALL_TIMEOUT`]
+    pub fn stalled(&mut self, device_timestamp: Option<f64>, bgr: &Mat) -> Result<bool> {
+        let fingerprint = fingerprint(bgr)?;
+        let repeated = match (device_timestamp, self.last_timestamp) {
+            (Some(timestamp), Some(last)) => timestamp == last,
+            _ => self.last_fingerprint == Some(fingerprint),
+        };
+        self.last_timestamp = device_timestamp;
+        self.last_fingerprint = Some(fingerprint);
+        if !repeated {
+            self.unchanged_since = None;
+            return Ok(false);
+        }
+        let since = *self.unchanged_since.get_or_insert_with(Instant::now);
+        Ok(since.elapsed() >= STALL_TIMEOUT)
+    }
+
+    /// Starts over, e.g. after the camera was reopened
+    pub fn reset(&mut self) {
+        *self = Watchdog::default();
+    }
+}
+
+fn fingerprint(bgr: &Mat) -> Result<u64> {
+    let mut hasher = DefaultHasher::new();
+    // Frames read from a capture always are continuous, a view into one gets copied
+    let copied;
+    let bgr = if bgr.is_continuous() {
+        bgr
+    } else {
+        copied = bgr.try_clone()?;
+        &copied
+    };
+    bgr.data_bytes()?.iter().step_by(FINGERPRINT_STRIDE).for_each(|byte| byte.hash(&mut hasher));
+    Ok(hasher.finish())
+}
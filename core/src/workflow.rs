@@ -0,0 +1,273 @@
+use crate::{
+    error::WorkflowError,
+    events::{Event, EventBus},
+    lock::Locked,
+};
+use std::sync::{Arc, Mutex};
+
+/// The steps of calibrating a camera, in order
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WorkflowState {
+    /// No calibration started, or the last one was cancelled or failed
+    #[default]
+    Idle,
+    /// A target and view count were accepted and capture is about to start
+    Configured,
+    /// Collecting views of the target
+    Capturing,
+    /// The solver is running, on new views or again on the ones still ticked
+    Solving,
+    /// Solved, with per view errors to look through before anything is kept
+    Reviewed,
+    /// The result is stored with the camera's profile
+    Saved,
+}
+
+impl WorkflowState {
+    pub const ALL: [WorkflowState; 6] = [
+        WorkflowState::Idle,
+        WorkflowState::Configured,
+        WorkflowState::Capturing,
+        WorkflowState::Solving,
+        WorkflowState::Reviewed,
+        WorkflowState::Saved,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            WorkflowState::Idle => "idle",
+            WorkflowState::Configured => "configured",
+            WorkflowState::Capturing => "capturing",
+            WorkflowState::Solving => "solving",
+            WorkflowState::Reviewed => "reviewed",
+            WorkflowState::Saved => "saved",
+        }
+    }
+
+    /// Position in [`WorkflowState::ALL`], for front ends that show the states as a list
+    pub fn index(self) -> usize {
+        WorkflowState::ALL.iter().position(|&state| state == self).unwrap_or(0)
+    }
+
+    /// A calibration or a solve owns the camera's views, nothing else may start
+    pub fn is_busy(self) -> bool {
+        matches!(self, WorkflowState::Configured | WorkflowState::Capturing | WorkflowState::Solving)
+    }
+
+    /// Whether the workflow may go from this state straight to `next`
+    pub fn can_become(self, next: WorkflowState) -> bool {
+        use WorkflowState::*;
+        matches!(
+            (self, next),
+            (Idle | Reviewed | Saved, Configured)
+                | (Configured, Capturing)
+                | (Capturing, Solving)
+                // Solving again with views left out
+                | (Reviewed | Saved, Solving)
+                | (Solving, Reviewed)
+                | (Reviewed, Saved)
+                // Cancelled or failed, back to where it started with the views it had then
+                | (Configured | Capturing | Solving, Idle | Reviewed | Saved)
+        )
+    }
+}
+
+/// The calibration workflow of a front end's camera, shared by the front end and the
+/// calibration task. Every change goes out as [`Event::WorkflowChanged`]. Starting a
+/// calibration or a solve while another one is running fails instead of the two fighting
+/// over the same views.
+#[derive(Clone)]
+pub struct Workflow {
+    states: Arc<Mutex<States>>,
+    events: EventBus,
+}
+
+#[derive(Default)]
+struct States {
+    current: WorkflowState,
+    // Where the running calibration or solve started, to go back to if it doesn't finish
+    started_from: WorkflowState,
+}
+
+impl Workflow {
+    pub fn new(events: EventBus) -> Self {
+        Workflow {
+            states: Arc::default(),
+            events,
+        }
+    }
+
+    pub fn state(&self) -> WorkflowState {
+        self.states.locked().current
+    }
+
+    /// Starts a calibration (Configured) or a re-solve (Solving). Fails while one is running
+    /// already, which is what keeps two of them off the same camera.
+    pub fn start(&self, next: WorkflowState) -> Result<(), WorkflowError> {
+        let mut states = self.states.locked();
+        if states.current.is_busy() {
+            return Err(WorkflowError::Busy(states.current));
+        }
+        let started_from = states.current;
+        self.change(&mut states, next)?;
+        states.started_from = started_from;
+        Ok(())
+    }
+
+    /// Moves on to `next`, or fails and leaves the state as it was when `next` can't follow it
+    pub fn advance(&self, next: WorkflowState) -> Result<(), WorkflowError> {
+        self.change(&mut self.states.locked(), next)
+    }
+
+    /// For the end of a calibration or solve however it ended: leaves a finished state
+    /// alone, and goes back to where it started from one where work was still under way
+    pub fn settle(&self) {
+        let mut states = self.states.locked();
+        if states.current.is_busy() {
+            let started_from = states.started_from;
+            if let Err(e) = self.change(&mut states, started_from) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    fn change(&self, states: &mut States, next: WorkflowState) -> Result<(), WorkflowError> {
+        let current = states.current;
+        if !current.can_become(next) {
+            return Err(WorkflowError::NotAllowed {
+                from: current,
+                to: next,
+            });
+        }
+        states.current = next;
+        // Published under the lock, so subscribers see the changes in the order they happened
+        self.events.publish(Event::WorkflowChanged(next));
+        log::debug!("Calibration workflow: {} -> {}", current.label(), next.label());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workflow() -> Workflow {
+        Workflow::new(EventBus::default())
+    }
+
+    // Idle through a capture and a solve to a saved result
+    fn saved() -> Workflow {
+        let workflow = workflow();
+        workflow.start(WorkflowState::Configured).unwrap();
+        for next in [WorkflowState::Capturing, WorkflowState::Solving, WorkflowState::Reviewed, WorkflowState::Saved] {
+            workflow.advance(next).unwrap();
+        }
+        workflow
+    }
+
+    #[test]
+    fn runs_through_every_state_in_order() {
+        let events = EventBus::default();
+        let mut subscriber = events.subscribe();
+        let workflow = Workflow::new(events);
+        assert_eq!(workflow.state(), WorkflowState::Idle);
+        workflow.start(WorkflowState::Configured).unwrap();
+        for next in [WorkflowState::Capturing, WorkflowState::Solving, WorkflowState::Reviewed, WorkflowState::Saved] {
+            workflow.advance(next).unwrap();
+        }
+        assert_eq!(workflow.state(), WorkflowState::Saved);
+        for expected in &WorkflowState::ALL[1..] {
+            match subscriber.blocking_recv() {
+                Some(Event::WorkflowChanged(state)) => assert_eq!(state, *expected),
+                _ => panic!("expected the change to {}", expected.label()),
+            }
+        }
+    }
+
+    #[test]
+    fn solving_needs_a_capture_first() {
+        let workflow = workflow();
+        assert!(matches!(
+            workflow.start(WorkflowState::Solving),
+            Err(WorkflowError::NotAllowed { from: WorkflowState::Idle, to: WorkflowState::Solving })
+        ));
+        workflow.start(WorkflowState::Configured).unwrap();
+        assert!(matches!(
+            workflow.advance(WorkflowState::Solving),
+            Err(WorkflowError::NotAllowed { from: WorkflowState::Configured, to: WorkflowState::Solving })
+        ));
+        assert_eq!(workflow.state(), WorkflowState::Configured);
+    }
+
+    #[test]
+    fn nothing_starts_while_calibrating() {
+        let workflow = workflow();
+        workflow.start(WorkflowState::Configured).unwrap();
+        assert!(matches!(
+            workflow.start(WorkflowState::Configured),
+            Err(WorkflowError::Busy(WorkflowState::Configured))
+        ));
+        workflow.advance(WorkflowState::Capturing).unwrap();
+        assert!(matches!(
+            workflow.start(WorkflowState::Configured),
+            Err(WorkflowError::Busy(WorkflowState::Capturing))
+        ));
+        assert!(matches!(
+            workflow.advance(WorkflowState::Configured),
+            Err(WorkflowError::NotAllowed { from: WorkflowState::Capturing, to: WorkflowState::Configured })
+        ));
+        assert_eq!(workflow.state(), WorkflowState::Capturing);
+    }
+
+    #[test]
+    fn nothing_is_saved_before_review() {
+        let workflow = workflow();
+        workflow.start(WorkflowState::Configured).unwrap();
+        workflow.advance(WorkflowState::Capturing).unwrap();
+        assert!(workflow.advance(WorkflowState::Saved).is_err());
+        workflow.advance(WorkflowState::Solving).unwrap();
+        assert!(workflow.advance(WorkflowState::Saved).is_err());
+        assert_eq!(workflow.state(), WorkflowState::Solving);
+    }
+
+    #[test]
+    fn a_finished_result_can_be_solved_again_or_recalibrated() {
+        let workflow = saved();
+        workflow.start(WorkflowState::Solving).unwrap();
+        workflow.advance(WorkflowState::Reviewed).unwrap();
+        workflow.start(WorkflowState::Configured).unwrap();
+        assert_eq!(workflow.state(), WorkflowState::Configured);
+    }
+
+    #[test]
+    fn settle_goes_back_to_where_an_unfinished_run_started() {
+        let workflow = workflow();
+        workflow.start(WorkflowState::Configured).unwrap();
+        workflow.advance(WorkflowState::Capturing).unwrap();
+        workflow.settle();
+        assert_eq!(workflow.state(), WorkflowState::Idle);
+
+        let workflow = saved();
+        workflow.start(WorkflowState::Solving).unwrap();
+        workflow.settle();
+        assert_eq!(workflow.state(), WorkflowState::Saved);
+    }
+
+    #[test]
+    fn settle_keeps_a_finished_run() {
+        let workflow = saved();
+        workflow.start(WorkflowState::Solving).unwrap();
+        workflow.advance(WorkflowState::Reviewed).unwrap();
+        workflow.settle();
+        assert_eq!(workflow.state(), WorkflowState::Reviewed);
+    }
+
+    #[test]
+    fn only_running_states_are_busy() {
+        let busy: Vec<_> = WorkflowState::ALL.into_iter().filter(|state| state.is_busy()).collect();
+        assert_eq!(busy, [WorkflowState::Configured, WorkflowState::Capturing, WorkflowState::Solving]);
+        for state in WorkflowState::ALL {
+            assert_eq!(WorkflowState::ALL[state.index()], state);
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// Python bindings to the core: open a source and read frames, find a target in them, solve
+// a calibration from the views found and keep it with the camera profiles, for test scripts
+// that check calibrations from pytest. Build and install with `maturin develop` in python/.
+use numpy::{PyArray1, PyArray3, PyArrayMethods, PyReadonlyArrayDyn, PyUntypedArrayMethods};
+use opencv::{
+    core::{Mat, Point2d, Size, Vector},
+    imgproc::{COLOR_BGR2RGBA, COLOR_GRAY2RGBA, COLOR_RGB2RGBA},
+    prelude::*,
+};
+use pyo3::{exceptions::PyValueError, prelude::*};
+use rustyrabbit_core::{
+    acceleration,
+    calibration,
+    camera_settings::CameraSettings,
+    frame::{Frame, FrameBuffer},
+    geometry::FrameGeometry,
+    profiles::{self, CameraRole, Profiles},
+    source::{FrameSource, SourceSpec},
+    target::{self, CalibrationTarget, DetectedView},
+    target_print::{TargetKind, TargetSpec},
+};
+use std::path::PathBuf;
+
+fn role(name: &str) -> PyResult<CameraRole> {
+    match name {
+        "top" => Ok(CameraRole::Top),
+        "bottom" => Ok(CameraRole::Bottom),
+        _ => Err(PyValueError::new_err(format!("{:?} isn't a camera role, use \"top\" or \"bottom\"", name))),
+    }
+}
+
+/// An RGBA image, as read from a camera or made from a NumPy array
+#[pyclass(name = "Frame", module = "rustyrabbit")]
+#[derive(Clone)]
+struct PyFrame(Frame);
+
+#[pymethods]
+impl PyFrame {
+    /// From a uint8 array of height x width (grey), height x width x 3 (RGB) or
+    /// height x width x 4 (RGBA)
+    #[staticmethod]
+    fn from_numpy(image: PyReadonlyArrayDyn<'_, u8>) -> PyResult<Self> {
+        let (height, width, channels) = match *image.shape() {
+            [height, width] => (height, width, 1),
+            [height, width, channels @ (3 | 4)] => (height, width, channels),
+            _ => return Err(PyValueError::new_err("Expected a height x width (x 3 or 4) uint8 array")),
+        };
+        let pixels = image.as_slice()?;
+        let frame = to_frame(pixels, width as i32, height as i32, channels as i32)?;
+        Ok(PyFrame(frame))
+    }
+
+    /// The pixels as a height x width x 4 uint8 array
+    fn to_numpy<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyArray3<u8>>> {
+        let frame = &self.0;
+        let dims = [frame.height as usize, frame.width as usize, 4];
+        PyArray1::from_slice_bound(py, frame.data.as_slice()).reshape(dims)
+    }
+
+    #[getter]
+    fn width(&self) -> i32 {
+        self.0.width
+    }
+
+    #[getter]
+    fn height(&self) -> i32 {
+        self.0.height
+    }
+
+    /// Counts up from 1 for every frame a camera reads, 0 for an array
+    #[getter]
+    fn sequence(&self) -> u64 {
+        self.0.sequence
+    }
+}
+
+fn to_frame(pixels: &[u8], width: i32, height: i32, channels: i32) -> anyhow::Result<Frame> {
+    if channels == 4 {
+        return Ok(Frame::from_rgba(FrameBuffer::copy_of(pixels), width, height));
+    }
+    let image = Mat::from_slice(pixels)?;
+    let image = image.reshape(channels, height)?;
+    let code = if channels == 1 { COLOR_GRAY2RGBA } else { COLOR_RGB2RGBA };
+    let mut rgba = Mat::default();
+    acceleration::convert_color(&image, &mut rgba, code)?;
+    Ok(Frame::from_rgba(FrameBuffer::copy_of(rgba.data_bytes()?), width, height))
+}
+
+/// A camera, network stream, folder of stills or video file, read one frame at a time.
+/// Unlike the app nothing is cropped, scaled or turned.
+#[pyclass(name = "Camera", module = "rustyrabbit")]
+struct PyCamera {
+    source: Box<dyn FrameSource>,
+    bgr: Mat,
+    sequence: u64,
+}
+
+impl PyCamera {
+    fn open(spec: SourceSpec) -> PyResult<Self> {
+        Ok(PyCamera {
+            source: spec.open()?,
+            bgr: Mat::default(),
+            sequence: 0,
+        })
+    }
+}
+
+#[pymethods]
+impl PyCamera {
+    /// The camera at this device index, with the settings it has
+    #[new]
+    #[pyo3(signature = (index = 0))]
+    fn new(index: i32) -> PyResult<Self> {
+        PyCamera::open(SourceSpec::Camera {
+            index,
+            settings: CameraSettings::default(),
+            xu_controls: Vec::new(),
+            bayer: None,
+        })
+    }
+
+    /// Anything OpenCV opens by URL, such as rtsp:// or an MJPEG http:// stream
+    #[staticmethod]
+    fn stream(url: String) -> PyResult<Self> {
+        PyCamera::open(SourceSpec::Stream { url })
+    }
+
+    /// The images in a folder in name order, at `fps`
+    #[staticmethod]
+    #[pyo3(signature = (dir, fps = 1000.0))]
+    fn images(dir: PathBuf, fps: f64) -> PyResult<Self> {
+        PyCamera::open(SourceSpec::ImageDir {
+            dir,
+            looping: false,
+            fps,
+        })
+    }
+
+    #[staticmethod]
+    fn video(path: PathBuf) -> PyResult<Self> {
+        PyCamera::open(SourceSpec::Video { path, looping: false })
+    }
+
+    /// The next frame, None when the source has none (any more)
+    fn read(&mut self, py: Python<'_>) -> PyResult<Option<PyFrame>> {
+        let (source, bgr) = (&mut self.source, &mut self.bgr);
+        if !py.allow_threads(|| source.read(bgr))? {
+            return Ok(None);
+        }
+        let mut frame = from_bgr(&self.bgr)?;
+        self.sequence += 1;
+        frame.sequence = self.sequence;
+        frame.exposure = self.source.exposure();
+        Ok(Some(PyFrame(frame)))
+    }
+}
+
+fn from_bgr(bgr: &Mat) -> anyhow::Result<Frame> {
+    let mut rgba = Mat::default();
+    acceleration::convert_color(bgr, &mut rgba, COLOR_BGR2RGBA)?;
+    let size = rgba.size()?;
+    Ok(Frame::from_rgba(FrameBuffer::copy_of(rgba.data_bytes()?), size.width, size.height))
+}
+
+/// Where a target was found in a frame
+#[pyclass(name = "Detection", module = "rustyrabbit")]
+#[derive(Clone)]
+struct PyDetection(DetectedView);
+
+#[pymethods]
+impl PyDetection {
+    /// (x, y) in frame pixels
+    #[getter]
+    fn image_points(&self) -> Vec<(f32, f32)> {
+        self.0.image_points.iter().map(|point| (point.x, point.y)).collect()
+    }
+
+    /// (x, y, z) on the target in mm, in the same order
+    #[getter]
+    fn object_points(&self) -> Vec<(f32, f32, f32)> {
+        self.0.object_points.iter().map(|point| (point.x, point.y, point.z)).collect()
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.image_points.len()
+    }
+}
+
+/// A calibration target, sized like on the command line: inner corners (circles for the
+/// grid) and the square side (circle spacing) in mm
+#[pyclass(name = "Target", module = "rustyrabbit")]
+struct PyTarget(Box<dyn CalibrationTarget>);
+
+impl PyTarget {
+    fn from_spec(kind: TargetKind, cols: i32, rows: i32, square_mm: f64) -> PyResult<Self> {
+        let spec = TargetSpec {
+            kind,
+            rows,
+            cols,
+            square_mm,
+        };
+        Ok(PyTarget(target::from_spec(&spec)?))
+    }
+}
+
+#[pymethods]
+impl PyTarget {
+    #[staticmethod]
+    fn chess_board(cols: i32, rows: i32, square_mm: f64) -> PyResult<Self> {
+        PyTarget::from_spec(TargetKind::ChessBoard, cols, rows, square_mm)
+    }
+
+    #[staticmethod]
+    fn circle_grid(cols: i32, rows: i32, spacing_mm: f64) -> PyResult<Self> {
+        PyTarget::from_spec(TargetKind::CircleGrid, cols, rows, spacing_mm)
+    }
+
+    #[staticmethod]
+    #[pyo3(signature = (cols, rows, square_mm, dictionary = "DICT_6X6_250".to_string()))]
+    fn charuco(cols: i32, rows: i32, square_mm: f64, dictionary: String) -> PyResult<Self> {
+        PyTarget::from_spec(TargetKind::Charuco { dictionary }, cols, rows, square_mm)
+    }
+
+    /// The target in the frame, None when it isn't (fully) in view
+    fn detect(&self, py: Python<'_>, frame: &PyFrame) -> PyResult<Option<PyDetection>> {
+        let (target, frame) = (&self.0, &frame.0);
+        Ok(py.allow_threads(|| target.detect(frame))?.map(PyDetection))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Target({})", self.0.describe())
+    }
+}
+
+/// Intrinsics in full sensor pixels
+#[pyclass(name = "Calibration", module = "rustyrabbit")]
+#[derive(Clone)]
+struct PyCalibration(profiles::Calibration);
+
+#[pymethods]
+impl PyCalibration {
+    #[staticmethod]
+    fn from_json(text: &str) -> PyResult<Self> {
+        Ok(PyCalibration(serde_json::from_str(text).map_err(anyhow::Error::from)?))
+    }
+
+    fn to_json(&self) -> PyResult<String> {
+        Ok(serde_json::to_string_pretty(&self.0).map_err(anyhow::Error::from)?)
+    }
+
+    fn to_toml(&self) -> PyResult<String> {
+        Ok(self.0.to_toml()?)
+    }
+
+    #[getter]
+    fn sensor_width(&self) -> i32 {
+        self.0.sensor_width
+    }
+
+    #[getter]
+    fn sensor_height(&self) -> i32 {
+        self.0.sensor_height
+    }
+
+    /// 3x3, row by row
+    #[getter]
+    fn camera_matrix(&self) -> [[f64; 3]; 3] {
+        self.0.camera_matrix
+    }
+
+    /// OpenCV's order: k1, k2, p1, p2, k3 and on
+    #[getter]
+    fn dist_coeffs(&self) -> Vec<f64> {
+        self.0.dist_coeffs.clone()
+    }
+
+    /// RMS reprojection error in pixels
+    #[getter]
+    fn rms(&self) -> f64 {
+        self.0.rms
+    }
+
+    #[getter]
+    fn calibrated_at(&self) -> String {
+        self.0.calibrated_at.clone()
+    }
+
+    /// Horizontal, vertical and diagonal, in degrees
+    #[getter]
+    fn field_of_view(&self) -> (f64, f64, f64) {
+        self.0.field_of_view()
+    }
+
+    /// Where sensor pixels would be without the lens distortion, in pixels or `normalized`
+    /// to the plane one unit in front of the camera
+    #[pyo3(signature = (points, normalized = false))]
+    fn undistort_points(&self, points: Vec<(f64, f64)>, normalized: bool) -> PyResult<Vec<(f64, f64)>> {
+        if points.is_empty() {
+            return Ok(Vec::new());
+        }
+        let points: Vector<Point2d> = points.into_iter().map(|(x, y)| Point2d::new(x, y)).collect();
+        let ideal = self.0.undistort_points(&points, normalized)?;
+        Ok(ideal.iter().map(|point| (point.x, point.y)).collect())
+    }
+
+    fn __repr__(&self) -> String {
+        let calibration = &self.0;
+        format!(
+            "Calibration({}x{}, rms={:.3} px, calibrated_at={:?})",
+            calibration.sensor_width, calibration.sensor_height, calibration.rms, calibration.calibrated_at
+        )
+    }
+}
+
+/// Solves the intrinsics from detections in frames of one size, without saving them
+#[pyfunction]
+fn calibrate(detections: Vec<PyDetection>, width: i32, height: i32) -> PyResult<PyCalibration> {
+    let views: Vec<DetectedView> = detections.into_iter().map(|detection| detection.0).collect();
+    let geometry = FrameGeometry::full_sensor(Size::new(width, height));
+    Ok(PyCalibration(calibration::solve_views(&views, &geometry)?))
+}
+
+/// The camera profiles as the app stores them, as JSON
+#[pyfunction]
+fn load_profiles() -> PyResult<String> {
+    Ok(serde_json::to_string_pretty(&profiles::load()?).map_err(anyhow::Error::from)?)
+}
+
+/// Replaces the stored camera profiles with these, JSON as load_profiles gives them
+#[pyfunction]
+fn save_profiles(text: &str) -> PyResult<()> {
+    let stored: Profiles = serde_json::from_str(text).map_err(anyhow::Error::from)?;
+    Ok(profiles::save(&stored)?)
+}
+
+/// The stored calibration of the "top" or "bottom" camera, None before its first
+#[pyfunction]
+fn load_calibration(camera: &str) -> PyResult<Option<PyCalibration>> {
+    let role = role(camera)?;
+    Ok(profiles::load()?.get(role).calibration.clone().map(PyCalibration))
+}
+
+/// Stores a calibration for the "top" or "bottom" camera, as a calibration in the app does
+#[pyfunction]
+fn save_calibration(camera: &str, calibration: &PyCalibration) -> PyResult<()> {
+    let role = role(camera)?;
+    let calibration = calibration.0.clone();
+    if calibration.sensor_width <= 0 || calibration.sensor_height <= 0 {
+        return Err(PyValueError::new_err("A calibration needs the sensor size it was taken at"));
+    }
+    Ok(profiles::update(|profiles| profiles.get_mut(role).calibration = Some(calibration))?)
+}
+
+#[pymodule]
+fn rustyrabbit(module: &Bound<'_, PyModule>) -> PyResult<()> {
+    module.add_class::<PyFrame>()?;
+    module.add_class::<PyCamera>()?;
+    module.add_class::<PyDetection>()?;
+    module.add_class::<PyTarget>()?;
+    module.add_class::<PyCalibration>()?;
+    module.add_function(wrap_pyfunction!(calibrate, module)?)?;
+    module.add_function(wrap_pyfunction!(load_profiles, module)?)?;
+    module.add_function(wrap_pyfunction!(save_profiles, module)?)?;
+    module.add_function(wrap_pyfunction!(load_calibration, module)?)?;
+    module.add_function(wrap_pyfunction!(save_calibration, module)?)?;
+    Ok(())
+}
@@ -0,0 +1,403 @@
+use crate::{
+    cli::CAMERA_INDEX,
+    formats::FileFormat,
+    headless::{CalibrationRequest, HeadlessApp},
+};
+use anyhow::Result;
+use clap::ValueEnum;
+use opencv::core::Point2d;
+use rustyrabbit_core::{
+    calibration::Button,
+    camera_settings,
+    frame::Frame,
+    profiles::{self, CameraProfile, CameraRole},
+    source, vision,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::{
+    io::{Cursor, Read},
+    time::Duration,
+};
+use tiny_http::{Header, Method, Request, Response, StatusCode};
+
+// Largest request body read, a calibration request is a few dozen bytes
+const MAX_BODY: u64 = 64 * 1024;
+// Longest a look at the scene waits for the camera, several frames even at a low rate
+const FRESH_FRAME_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Board and capture settings posted to /api/calibration, anything left out keeps the
+// command line value
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StartRequest {
+    cols: Option<i32>,
+    rows: Option<i32>,
+    square_mm: Option<f64>,
+    frames: Option<usize>,
+    manual: Option<bool>,
+    countdown: Option<u64>,
+}
+
+impl StartRequest {
+    fn apply(self, defaults: &CalibrationRequest) -> Result<CalibrationRequest> {
+        let mut request = defaults.clone();
+        request.board.0 = self.cols.unwrap_or(request.board.0);
+        request.board.1 = self.rows.unwrap_or(request.board.1);
+        request.square_mm = self.square_mm.unwrap_or(request.square_mm);
+        request.frames = self.frames.unwrap_or(request.frames);
+        request.manual = self.manual.unwrap_or(request.manual);
+        request.countdown = self.countdown.unwrap_or(request.countdown);
+        request.check()?;
+        Ok(request)
+    }
+}
+
+// What OpenPnP asks /api/vision/ to look for. Fiducials are looked for with the top camera
+// and parts with the bottom one unless `camera` says otherwise.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct VisionRequest {
+    camera: Option<CameraRole>,
+    // The fiducial's size, in mm with the camera's image scale or straight in frame pixels
+    diameter_mm: Option<f64>,
+    diameter_px: Option<f64>,
+}
+
+// An answer with its status code, JSON unless it's a downloaded file
+struct Answer {
+    status: u16,
+    body: String,
+    content_type: &'static str,
+    file_name: Option<String>,
+}
+
+impl Answer {
+    fn json(status: u16, value: Value) -> Self {
+        Answer {
+            status,
+            body: value.to_string(),
+            content_type: "application/json",
+            file_name: None,
+        }
+    }
+
+    fn ok(value: Value) -> Self {
+        Answer::json(200, value)
+    }
+
+    fn error(status: u16, message: impl ToString) -> Self {
+        Answer::json(status, json!({ "error": message.to_string() }))
+    }
+
+    fn into_response(self) -> Response<Cursor<Vec<u8>>> {
+        let mut response = Response::from_string(self.body)
+            .with_status_code(StatusCode(self.status))
+            .with_header(header("Content-Type", self.content_type))
+            .with_header(header("Cache-Control", "no-store"));
+        if let Some(name) = self.file_name {
+            let disposition = format!("attachment; filename=\"{}\"", name);
+            response = response.with_header(header("Content-Disposition", &disposition));
+        }
+        response
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+// Answers a request under /api/, the JSON interface for driving calibrations and asking for
+// fiducial and part locates from another program, a production line's orchestrator or an
+// OpenPnP script. Every answer is JSON, errors as
+// {"error": "..."} with a 4xx or 500 status. With a token, requests without it are turned down.
+pub fn handle(
+    app: &HeadlessApp,
+    defaults: &CalibrationRequest,
+    token: Option<&str>,
+    mut request: Request,
+) -> Result<()> {
+    let answer = if !authorized(&request, token) {
+        Answer::error(401, "Missing or wrong API token, send it as Authorization: Bearer TOKEN")
+    } else {
+        route(app, defaults, &mut request).unwrap_or_else(|e| {
+            log::warn!("Error answering {} {}: {:?}", request.method(), request.url(), e);
+            Answer::error(500, e)
+        })
+    };
+    request.respond(answer.into_response())?;
+    Ok(())
+}
+
+fn authorized(request: &Request, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let given = request
+        .headers()
+        .iter()
+        .find(|header| header.field.equiv("Authorization"))
+        .and_then(|header| header.value.as_str().strip_prefix("Bearer "));
+    // Every byte is compared, so the time taken doesn't tell how much of a guess was right
+    given.is_some_and(|given| {
+        given.len() == token.len() && given.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    })
+}
+
+fn route(app: &HeadlessApp, defaults: &CalibrationRequest, request: &mut Request) -> Result<Answer> {
+    let url = request.url().to_string();
+    let path = url.split_once('?').map_or(url.as_str(), |(path, _)| path);
+    let segments: Vec<&str> = path.trim_start_matches("/api/").trim_end_matches('/').split('/').collect();
+    let method = request.method().clone();
+    Ok(match (&method, segments.as_slice()) {
+        (Method::Get, ["cameras"]) => cameras(app)?,
+        (Method::Post, ["cameras", role]) => match parse_role(role) {
+            Ok(role) => select_camera(app, role),
+            Err(answer) => answer,
+        },
+        (Method::Get, ["cameras", role, file]) => match parse_role(role) {
+            Ok(role) => download(role, file)?,
+            Err(answer) => answer,
+        },
+        (Method::Get, ["calibration"]) => Answer::ok(progress(app)),
+        (Method::Post, ["calibration"]) => {
+            let mut body = String::new();
+            request.as_reader().take(MAX_BODY).read_to_string(&mut body)?;
+            start(app, defaults, &body)
+        }
+        (Method::Post, ["calibration", action]) => match *action {
+            "capture" => press(app, Button::Capture),
+            "undo" => press(app, Button::Undo),
+            "finish" => press(app, Button::Finish),
+            "cancel" => press(app, Button::Cancel),
+            _ => Answer::error(404, format!("No calibration action {:?}", action)),
+        },
+        (Method::Get, ["calibration", "result"]) => result(app),
+        (Method::Post, ["vision", task @ ("fiducial" | "part")]) => {
+            let mut body = String::new();
+            request.as_reader().take(MAX_BODY).read_to_string(&mut body)?;
+            match parse_body::<VisionRequest>(&body) {
+                Ok(vision) if *task == "fiducial" => fiducial(app, vision)?,
+                Ok(vision) => part(app, vision)?,
+                Err(answer) => answer,
+            }
+        }
+        _ => Answer::error(404, format!("No {} {}", method, path)),
+    })
+}
+
+// As in the paths and in profiles.toml
+fn role_name(role: CameraRole) -> &'static str {
+    match role {
+        CameraRole::Top => "top",
+        CameraRole::Bottom => "bottom",
+    }
+}
+
+fn parse_role(name: &str) -> Result<CameraRole, Answer> {
+    CameraRole::from_str(name, true).map_err(|_| Answer::error(404, format!("No camera {:?}, use top or bottom", name)))
+}
+
+// The cameras attached to the machine with the roles each plays, and both roles with their
+// device and whether it has a calibration
+fn cameras(app: &HeadlessApp) -> Result<Answer> {
+    let profiles = profiles::load()?;
+    let active = app.role();
+    let roles = [CameraRole::Top, CameraRole::Bottom];
+    let device = |role: CameraRole| profiles.get(role).camera.unwrap_or(CAMERA_INDEX);
+    let attached: Vec<Value> = source::attached_cameras()?
+        .into_iter()
+        .map(|index| {
+            let playing: Vec<CameraRole> = roles.into_iter().filter(|&role| device(role) == index).collect();
+            json!({
+                "index": index,
+                "name": camera_settings::device_key(index),
+                "roles": playing,
+            })
+        })
+        .collect();
+    let cameras: Vec<Value> = roles
+        .into_iter()
+        .map(|role| {
+            let profile = profiles.get(role);
+            json!({
+                "role": role,
+                "label": role.label(),
+                "active": role == active,
+                "device": device(role),
+                "calibrated": profile.calibration.is_some(),
+                "calibrated_at": profile.calibration.as_ref().map(|calibration| &calibration.calibrated_at),
+                "rms": profile.calibration.as_ref().map(|calibration| calibration.rms),
+            })
+        })
+        .collect();
+    Ok(Answer::ok(json!({ "attached": attached, "roles": cameras })))
+}
+
+fn select_camera(app: &HeadlessApp, role: CameraRole) -> Answer {
+    match app.use_camera(role) {
+        Ok(()) => Answer::ok(json!({ "role": role })),
+        Err(e) => Answer::error(409, e),
+    }
+}
+
+fn progress(app: &HeadlessApp) -> Value {
+    let role = app.role();
+    let state = app.report().state();
+    json!({
+        "role": role,
+        "calibrating": state.calibrating,
+        "workflow": state.workflow.label(),
+        "captured": state.captured,
+        "required": state.required,
+        "status": state.status,
+    })
+}
+
+// An empty body is all defaults
+fn parse_body<T: DeserializeOwned + Default>(body: &str) -> Result<T, Answer> {
+    if body.trim().is_empty() {
+        return Ok(T::default());
+    }
+    serde_json::from_str(body).map_err(|e| Answer::error(400, e))
+}
+
+// An empty body calibrates with the command line's settings
+fn start(app: &HeadlessApp, defaults: &CalibrationRequest, body: &str) -> Answer {
+    let request = match parse_body::<StartRequest>(body).and_then(|settings| {
+        settings.apply(defaults).map_err(|e| Answer::error(400, e))
+    }) {
+        Ok(request) => request,
+        Err(answer) => return answer,
+    };
+    match app.start_calibration(&request) {
+        Ok(()) => Answer::json(202, progress(app)),
+        Err(e) => Answer::error(409, e),
+    }
+}
+
+fn press(app: &HeadlessApp, button: Button) -> Answer {
+    if !app.report().state().calibrating {
+        return Answer::error(409, "No calibration is running");
+    }
+    app.controls.press(button);
+    Answer::ok(progress(app))
+}
+
+// The last solve of the active camera with the error of each view
+fn result(app: &HeadlessApp) -> Answer {
+    let role = app.role();
+    let state = app.report().state();
+    let Some(calibration) = &state.calibration else {
+        return Answer::error(404, format!("The {} has no calibration yet", role.label()));
+    };
+    let (horizontal, vertical, diagonal) = calibration.field_of_view();
+    let views: Vec<Value> = state
+        .views
+        .iter()
+        .map(|(frame, error)| json!({ "frame": frame, "error": error }))
+        .collect();
+    Answer::ok(json!({
+        "role": role,
+        "calibration": calibration,
+        "field_of_view": { "horizontal": horizontal, "vertical": vertical, "diagonal": diagonal },
+        "views": views,
+    }))
+}
+
+// The stored calibration of a camera as calibration.json, .yaml, .xml, .py or .toml
+fn download(role: CameraRole, file: &str) -> Result<Answer> {
+    let format = match file.strip_prefix("calibration.").and_then(FileFormat::from_extension) {
+        Some(format) => format,
+        None => {
+            let message = format!("No file {:?}, use calibration{}", file, FileFormat::EXTENSIONS);
+            return Ok(Answer::error(404, message));
+        }
+    };
+    let Some(calibration) = profiles::load()?.get(role).calibration.clone() else {
+        return Ok(Answer::error(404, format!("The {} has no calibration yet", role.label())));
+    };
+    Ok(Answer {
+        status: 200,
+        body: format.render(&calibration)?,
+        content_type: format.content_type(),
+        file_name: Some(format!("{}-calibration.{}", role_name(role), format.extension())),
+    })
+}
+
+fn point(point: Point2d) -> Value {
+    json!({ "x": point.x, "y": point.y })
+}
+
+// A frame taken after the request with the camera of `role` and its profile, or the
+// answer why there is none
+fn look(app: &HeadlessApp, role: CameraRole) -> Result<Result<(CameraProfile, Frame), Answer>> {
+    if let Err(e) = app.use_camera(role) {
+        return Ok(Err(Answer::error(409, e)));
+    }
+    let profile = profiles::load()?.get(role).clone();
+    Ok(match app.fresh_frame(FRESH_FRAME_TIMEOUT) {
+        Ok(frame) => Ok((profile, frame)),
+        Err(e) => Err(Answer::error(503, e)),
+    })
+}
+
+// The round mark nearest the middle of a frame taken after the request, in frame and sensor
+// pixels and in mm from the camera's datum once it and the image scale are set
+fn fiducial(app: &HeadlessApp, request: VisionRequest) -> Result<Answer> {
+    let role = request.camera.unwrap_or(CameraRole::Top);
+    let (profile, frame) = match look(app, role)? {
+        Ok(look) => look,
+        Err(answer) => return Ok(answer),
+    };
+    // Image scale is per sensor pixel, a scaled frame's pixels are larger
+    let frame_px_per_mm = |mm_per_px: f64| (frame.geometry.scale_x + frame.geometry.scale_y) / 2.0 / mm_per_px;
+    let diameter = match (request.diameter_px, request.diameter_mm, profile.mm_per_px) {
+        (Some(diameter), ..) => diameter,
+        (None, Some(diameter), Some(mm_per_px)) => diameter * frame_px_per_mm(mm_per_px),
+        (None, Some(_), None) => {
+            let message = format!("The {} has no image scale, give diameter_px instead", role.label());
+            return Ok(Answer::error(400, message));
+        }
+        (None, None, _) => return Ok(Answer::error(400, "Give the fiducial's diameter_mm or diameter_px")),
+    };
+    let found = vision::locate_fiducial(&frame, profile.calibration.as_ref(), diameter)?;
+    Ok(Answer::ok(match found {
+        Some(fiducial) => json!({
+            "found": true,
+            "camera": role,
+            "frame": frame.sequence,
+            "pixel": point(fiducial.frame_center),
+            "sensor": point(fiducial.center),
+            "diameter_px": fiducial.diameter,
+            "offset_mm": vision::offset_mm(&profile, fiducial.center).map(point),
+        }),
+        None => json!({ "found": false, "camera": role, "frame": frame.sequence }),
+    }))
+}
+
+// Bottom vision: where the part on the nozzle is and how far it is turned, in sensor pixels
+// and in mm from the datum
+fn part(app: &HeadlessApp, request: VisionRequest) -> Result<Answer> {
+    let role = request.camera.unwrap_or(CameraRole::Bottom);
+    let (profile, frame) = match look(app, role)? {
+        Ok(look) => look,
+        Err(answer) => return Ok(answer),
+    };
+    let found = vision::align_part(&frame, profile.calibration.as_ref())?;
+    Ok(Answer::ok(match found {
+        Some(part) => json!({
+            "found": true,
+            "camera": role,
+            "frame": frame.sequence,
+            "sensor": point(part.center),
+            "angle_deg": part.angle,
+            "size_px": { "width": part.width, "height": part.height },
+            "offset_mm": vision::offset_mm(&profile, part.center).map(point),
+            "size_mm": profile.mm_per_px.map(|mm_per_px| {
+                json!({ "width": part.width * mm_per_px, "height": part.height * mm_per_px })
+            }),
+        }),
+        None => json!({ "found": false, "camera": role, "frame": frame.sequence }),
+    }))
+}
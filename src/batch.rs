@@ -0,0 +1,256 @@
+use crate::{
+    cli::Args,
+    formats::FileFormat,
+    headless::{CalibrationRequest, HeadlessApp},
+};
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use rustyrabbit_core::{
+    calibration::{Button, MIN_CALIBRATION_FRAMES},
+    frame::FrameFeed,
+    machine::{CapturePlan, Machine, Position},
+    profiles::{Calibration, CameraRole},
+};
+use std::{
+    fs,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often the calibration is checked on
+const POLL: Duration = Duration::from_millis(30);
+// An image folder or video that delivers nothing for this long has run out. Longer than
+// any sensible playback interval.
+const PLAYBACK_IDLE: Duration = Duration::from_secs(5);
+// How long a Capture press at a plan position has to turn into a view
+const VIEW_TIMEOUT: Duration = Duration::from_secs(3);
+// Presses at one position before it is skipped, a press is dropped while the target isn't found
+const CAPTURE_ATTEMPTS: usize = 3;
+// How far the toolhead may end up from a plan position, in mm
+const POSITION_TOLERANCE: f64 = 0.05;
+
+// What --output is written as, from its extension
+fn output_format(path: &Path) -> Result<FileFormat> {
+    let extension = path.extension().and_then(|ext| ext.to_str()).unwrap_or("");
+    match FileFormat::from_extension(extension) {
+        Some(format) => Ok(format),
+        None => bail!("Unknown output format for {}, use {}", path.display(), FileFormat::EXTENSIONS),
+    }
+}
+
+// Captures and solves a calibration straight away with the board given on the command line,
+// then writes the result to --output. The progress goes to the log, and the exit status
+// says whether it worked.
+pub fn run(
+    args: Arc<Args>,
+    role: CameraRole,
+    frames: FrameFeed,
+) -> Result<()> {
+    let Some(output) = args.output.clone() else {
+        bail!("--headless needs an --output file");
+    };
+    // Before capturing, a typo shouldn't cost a whole run
+    let format = output_format(&output)?;
+    let mut request = CalibrationRequest::from_args(&args);
+    let timeout = args.timeout.map(Duration::from_secs);
+    let playback = args.is_playback();
+    let poses_path = args.poses.clone();
+    let mut plan = match &args.capture_plan {
+        Some(path) => Some(open_plan(path, &mut request)?),
+        None => None,
+    };
+
+    let app = HeadlessApp::start(args, role, frames)?;
+    let mut poses = Vec::new();
+    let result = calibrate(&app, &request, timeout, playback, plan.as_mut(), &mut poses);
+    app.stop()?;
+    let calibration = result?;
+
+    let text = format.render(&calibration)?;
+    fs::write(&output, text).with_context(|| format!("Unable to write {}", output.display()))?;
+    log::info!("Wrote the {} calibration to {}", role.label(), output.display());
+    if let Some(path) = poses_path {
+        let text = serde_json::to_string_pretty(&poses)?;
+        fs::write(&path, text).with_context(|| format!("Unable to write {}", path.display()))?;
+        log::info!("Wrote the machine poses of {} views to {}", poses.len(), path.display());
+    }
+    Ok(())
+}
+
+// Where the machine was for a view of a capture plan, for hand-eye calibration
+#[derive(Serialize)]
+struct ViewPose {
+    // Counting from 1 in the order captured, as the calibration's views are
+    view: usize,
+    planned: Position,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    actual: Option<Position>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    joints: Option<Vec<f64>>,
+}
+
+// Reads the plan and connects to its machine. The calibration then takes one view per
+// position, on the plan's Capture presses only.
+fn open_plan(path: &Path, request: &mut CalibrationRequest) -> Result<(CapturePlan, Box<dyn Machine>)> {
+    let plan = CapturePlan::load(path)?;
+    if plan.positions.len() < MIN_CALIBRATION_FRAMES {
+        bail!(
+            "{} has {} positions, a calibration needs at least {}",
+            path.display(),
+            plan.positions.len(),
+            MIN_CALIBRATION_FRAMES
+        );
+    }
+    let mut machine = plan.connect()?;
+    if plan.home {
+        log::info!("Homing the machine");
+        machine.home()?;
+    }
+    request.frames = plan.positions.len();
+    request.manual = true;
+    Ok((plan, machine))
+}
+
+fn calibrate(
+    app: &HeadlessApp,
+    request: &CalibrationRequest,
+    timeout: Option<Duration>,
+    playback: bool,
+    plan: Option<&mut (CapturePlan, Box<dyn Machine>)>,
+    poses: &mut Vec<ViewPose>,
+) -> Result<Calibration> {
+    // Only a result from this run counts, not the one already stored for the camera
+    app.report().state().calibration = None;
+    app.start_calibration(request)?;
+
+    let started = Instant::now();
+    let mut last_frame = Instant::now();
+    let mut finishing = false;
+    if let Some((plan, machine)) = plan {
+        let moved = capture_along(app, plan, machine.as_mut(), poses);
+        if let Err(e) = moved {
+            app.controls.press(Button::Cancel);
+            return Err(e);
+        }
+        let captured = app.report().state().captured;
+        if captured < MIN_CALIBRATION_FRAMES {
+            app.controls.press(Button::Cancel);
+            bail!("Only {} views were captured, a calibration needs at least {}", captured, MIN_CALIBRATION_FRAMES);
+        }
+        if captured < request.frames {
+            log::info!("Solving with the {} views captured", captured);
+            app.controls.press(Button::Finish);
+            finishing = true;
+        }
+    }
+    let mut captured = 0;
+    while app.report().state().calibrating {
+        thread::sleep(POLL);
+        if app.next_frame().is_some() {
+            last_frame = Instant::now();
+        }
+        let now_captured = app.report().state().captured;
+        if now_captured != captured {
+            captured = now_captured;
+            log::info!("Captured view {} of {}", captured, request.frames);
+        }
+
+        let ended = if playback && last_frame.elapsed() >= PLAYBACK_IDLE {
+            "The input ran out"
+        } else if timeout.is_some_and(|timeout| started.elapsed() >= timeout) {
+            "Timed out"
+        } else {
+            continue;
+        };
+        if captured < MIN_CALIBRATION_FRAMES {
+            app.controls.press(Button::Cancel);
+            bail!(
+                "{} after {} views, a calibration needs at least {}",
+                ended,
+                captured,
+                MIN_CALIBRATION_FRAMES
+            );
+        }
+        if !finishing {
+            log::info!("{} after {} views, solving with those", ended, captured);
+            app.controls.press(Button::Finish);
+            finishing = true;
+        }
+    }
+
+    let state = app.report().state();
+    match &state.calibration {
+        Some(calibration) => Ok(calibration.clone()),
+        None => bail!("The calibration didn't finish: {}", state.status),
+    }
+}
+
+// Moves to each position of the plan in turn and captures a view there, skipping the
+// positions the target can't be found from
+fn capture_along(
+    app: &HeadlessApp,
+    plan: &CapturePlan,
+    machine: &mut dyn Machine,
+    poses: &mut Vec<ViewPose>,
+) -> Result<()> {
+    let total = plan.positions.len();
+    for (index, &position) in plan.positions.iter().enumerate() {
+        if !app.report().state().calibrating {
+            break;
+        }
+        log::info!("Moving to position {} of {}: {}", index + 1, total, position);
+        machine
+            .move_to(position, plan.feed_mm_min)
+            .with_context(|| format!("Unable to move to {}", position))?;
+        let actual = check_position(machine, position)?;
+        let joints = machine.joints()?;
+        thread::sleep(plan.settle());
+        if capture_here(app) {
+            poses.push(ViewPose {
+                view: app.report().state().captured,
+                planned: position,
+                actual,
+                joints,
+            });
+        } else {
+            log::warn!("No view at position {} ({}), the target wasn't found", index + 1, position);
+        }
+    }
+    Ok(())
+}
+
+// Where the toolhead really went, for controllers that can say
+fn check_position(machine: &mut dyn Machine, planned: Position) -> Result<Option<Position>> {
+    let Some(actual) = machine.position()? else {
+        return Ok(None);
+    };
+    let off_z = match (planned.z, actual.z) {
+        (Some(planned), Some(actual)) => (planned - actual).abs(),
+        _ => 0.0,
+    };
+    let off = (planned.x - actual.x).hypot(planned.y - actual.y).max(off_z);
+    if off > POSITION_TOLERANCE {
+        log::warn!("The toolhead is at {}, {:.3} mm from {}", actual, off, planned);
+    } else {
+        log::debug!("The toolhead is at {}", actual);
+    }
+    Ok(Some(actual))
+}
+
+fn capture_here(app: &HeadlessApp) -> bool {
+    for _ in 0..CAPTURE_ATTEMPTS {
+        let before = app.report().state().captured;
+        app.controls.press(Button::Capture);
+        let pressed = Instant::now();
+        while pressed.elapsed() < VIEW_TIMEOUT {
+            thread::sleep(POLL);
+            let state = app.report().state();
+            if !state.calibrating || state.captured > before {
+                return state.captured > before;
+            }
+        }
+    }
+    false
+}
@@ -0,0 +1,639 @@
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use opencv::core::{Rect, Size};
+use rustyrabbit_core::{
+    acceleration::Backend,
+    bayer::{BayerConfig, BayerPattern, Demosaic},
+    camera_settings::{self, CameraSettings},
+    detection::{DetectionConfig, QueuePolicy, DEFAULT_WORKERS},
+    geometry::{parse_rect, Flip, Rotation},
+    profiles::{CameraProfile, CameraRole},
+    source::{CaptureMode, SourceSpec, TriggerMode},
+    synthetic::SyntheticConfig,
+    uvc::XuControl,
+};
+use std::{env, path::PathBuf};
+
+pub const CAMERA_INDEX: i32 = 0;
+const DEFAULT_STABLE_FRAMES: usize = 5;
+const DEFAULT_COUNTDOWN: u64 = 3;
+
+#[derive(Parser, Debug)]
+#[command(version, about = "Rabbit Pick & Place camera calibration")]
+pub struct Args {
+    /// Start with the top (downward looking) or bottom (upward looking) camera profile.
+    /// Defaults to the one used last
+    #[arg(long, value_enum)]
+    pub role: Option<CameraRole>,
+
+    /// Index of the camera to open. Remembered for the role
+    #[arg(long)]
+    pub camera: Option<i32>,
+
+    /// Set a vendor UVC extension unit control on the camera as UNIT:SELECTOR=HEXBYTES
+    /// (Linux only, repeatable), e.g. --uvc-xu 4:2=01
+    #[arg(long = "uvc-xu", value_name = "UNIT:SELECTOR=HEX")]
+    pub uvc_xu: Vec<XuControl>,
+
+    /// Capture the raw Bayer mosaic with this layout and debayer in-process
+    #[arg(long, value_enum, value_name = "PATTERN")]
+    pub bayer: Option<BayerPattern>,
+
+    /// Demosaic algorithm for raw Bayer frames (--bayer or Aravis and Spinnaker Bayer pixel formats)
+    #[arg(long, value_enum, default_value_t = Demosaic::Bilinear)]
+    pub demosaic: Demosaic,
+
+    /// Capture from a Raspberry Pi CSI camera through libcamera (GStreamer libcamerasrc)
+    #[arg(long, conflicts_with = "playback")]
+    pub libcamera: bool,
+
+    /// Capture from a network camera or stream URL (rtsp://, http:// MJPEG, ...)
+    #[arg(long, value_name = "URL", conflicts_with_all = ["playback", "libcamera", "synthetic"])]
+    pub stream: Option<String>,
+
+    /// Open a GigE Vision / USB3 Vision camera through Aravis, optionally by device ID
+    #[cfg(feature = "aravis")]
+    #[arg(long, value_name = "DEVICE_ID", num_args = 0..=1, default_missing_value = "", group = "genicam", conflicts_with_all = ["playback", "libcamera", "stream"])]
+    pub aravis: Option<String>,
+
+    /// Open a FLIR / Point Grey camera (Blackfly, ...) through the Spinnaker SDK, optionally
+    /// by serial number
+    #[cfg(feature = "spinnaker")]
+    #[arg(long, value_name = "SERIAL", num_args = 0..=1, default_missing_value = "", group = "genicam", conflicts_with_all = ["playback", "libcamera", "stream"])]
+    pub spinnaker: Option<String>,
+
+    /// Exposure time in microseconds (Aravis and Spinnaker cameras)
+    #[cfg(any(feature = "aravis", feature = "spinnaker"))]
+    #[arg(long, requires = "genicam")]
+    pub exposure_us: Option<f64>,
+
+    /// Gain in dB (Aravis and Spinnaker cameras)
+    #[cfg(any(feature = "aravis", feature = "spinnaker"))]
+    #[arg(long, requires = "genicam")]
+    pub gain_db: Option<f64>,
+
+    /// GenICam pixel format, e.g. Mono8 or BayerRG8 (Aravis and Spinnaker cameras)
+    #[cfg(any(feature = "aravis", feature = "spinnaker"))]
+    #[arg(long, requires = "genicam")]
+    pub pixel_format: Option<String>,
+
+    /// Input line used by --trigger-mode hardware (Aravis and Spinnaker cameras, Line0 is the
+    /// opto-isolated input of a Blackfly)
+    #[cfg(any(feature = "aravis", feature = "spinnaker"))]
+    #[arg(long, default_value = "Line1", requires = "genicam")]
+    pub trigger_source: String,
+
+    /// Free-run, or only acquire frames on a software trigger (the Trigger button) or
+    /// the camera's hardware trigger input
+    #[arg(long, value_enum, default_value_t = TriggerMode::FreeRunning)]
+    pub trigger_mode: TriggerMode,
+
+    /// Exposure in the driver's units (switches auto exposure off). Remembered per camera
+    #[arg(long)]
+    pub exposure: Option<f64>,
+
+    /// Gain in the driver's units. Remembered per camera
+    #[arg(long)]
+    pub gain: Option<f64>,
+
+    /// Focus position in the driver's units (switches autofocus off). Remembered per camera
+    #[arg(long)]
+    pub focus: Option<f64>,
+
+    /// White balance temperature in Kelvin (switches auto white balance off). Remembered per camera
+    #[arg(long)]
+    pub white_balance: Option<f64>,
+
+    /// Requested capture width in pixels
+    #[arg(long)]
+    pub width: Option<i32>,
+
+    /// Requested capture height in pixels
+    #[arg(long)]
+    pub height: Option<i32>,
+
+    /// Requested capture rate in frames per second
+    #[arg(long)]
+    pub fps: Option<i32>,
+
+    /// Only pass this region of the sensor image through detection, preview and
+    /// recording, as X,Y,WIDTH,HEIGHT in sensor pixels. Remembered for the role
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_rect)]
+    pub roi: Option<Rect>,
+
+    /// Downscale frames right after capture (0 < SCALE <= 1), e.g. 0.5 for a Pi 4 preview.
+    /// Remembered for the role
+    #[arg(long, value_parser = parse_scale)]
+    pub scale: Option<f64>,
+
+    /// Mirror frames, e.g. for a camera looking up through the machine bed. Remembered for the role
+    #[arg(long, value_enum)]
+    pub flip: Option<Flip>,
+
+    /// Rotate frames clockwise by this many degrees, after flipping. Remembered for the role
+    #[arg(long, value_enum)]
+    pub rotate: Option<Rotation>,
+
+    /// Centre the reticle on this machine datum (e.g. the nozzle axis) as X,Y in sensor pixels
+    /// instead of the calibrated principal point. Remembered for the role
+    #[arg(long, value_name = "X,Y", value_parser = parse_point)]
+    pub datum: Option<(f64, f64)>,
+
+    /// Image scale at the working plane in millimetres per sensor pixel, draws mm rings
+    /// around the reticle. Remembered for the role
+    #[arg(long)]
+    pub mm_per_px: Option<f64>,
+
+    /// Render a moving virtual chessboard with known intrinsics instead of using a camera
+    #[arg(long, conflicts_with_all = ["playback", "libcamera"])]
+    pub synthetic: bool,
+
+    /// Inner corners of the synthetic board as COLSxROWS
+    #[arg(long, default_value = "9x6", value_parser = parse_board, requires = "synthetic")]
+    pub synthetic_board: (i32, i32),
+
+    /// Gaussian noise added to synthetic frames, in grey levels
+    #[arg(long, default_value_t = 0.0, requires = "synthetic")]
+    pub synthetic_noise: f64,
+
+    /// Gaussian blur sigma applied to synthetic frames, in pixels
+    #[arg(long, default_value_t = 0.0, requires = "synthetic")]
+    pub synthetic_blur: f64,
+
+    /// Radial distortion k1 baked into synthetic frames
+    #[arg(long, default_value_t = 0.0, requires = "synthetic", allow_hyphen_values = true)]
+    pub synthetic_k1: f64,
+
+    /// Radial distortion k2 baked into synthetic frames
+    #[arg(long, default_value_t = 0.0, requires = "synthetic", allow_hyphen_values = true)]
+    pub synthetic_k2: f64,
+
+    /// Read frames from a folder of still images instead of a camera
+    #[arg(long, value_name = "DIR", group = "playback")]
+    pub image_dir: Option<PathBuf>,
+
+    /// Play a video file back at its own frame rate instead of using a camera
+    #[arg(long, value_name = "FILE", group = "playback")]
+    pub video: Option<PathBuf>,
+
+    /// Log every frame, calibration, button press and detection of this run to a new session
+    /// folder in DIR, to be replayed with --replay. Capture slows down to the disk's pace
+    #[arg(long, value_name = "DIR", conflicts_with = "replay")]
+    pub record_session: Option<PathBuf>,
+
+    /// Share the live frames with other processes on this machine in a shared memory ring at
+    /// FILE, such as /dev/shm/rustyrabbit. The layout is in the README
+    #[arg(long, value_name = "FILE")]
+    pub shared_frames: Option<PathBuf>,
+
+    /// Take the lens distortion out of the shared frames with the camera's stored calibration
+    #[arg(long, requires = "shared_frames")]
+    pub shared_frames_undistorted: bool,
+
+    /// Write the frames, straightened with the camera's stored calibration, to a v4l2loopback
+    /// DEVICE such as /dev/video10, for applications that can't undistort themselves (Linux)
+    #[arg(long, value_name = "DEVICE")]
+    pub virtual_camera: Option<PathBuf>,
+
+    /// Publish the preview, overlays and all, as an NDI source of this NAME for monitoring
+    /// displays on the network. Needs the NDI runtime installed
+    #[arg(long, value_name = "NAME")]
+    pub ndi: Option<String>,
+
+    /// Serve Prometheus metrics on ADDRESS at /metrics, e.g. 0.0.0.0:9464: capture rate,
+    /// dropped frames, stage timings, queue depths, recording and the calibration errors.
+    /// Needs a build with --features metrics
+    #[cfg(feature = "metrics")]
+    #[arg(long, value_name = "ADDRESS")]
+    pub metrics: Option<String>,
+
+    /// Replay a session folder logged with --record-session through the pipeline without a
+    /// window, compare every detection with the logged one and exit. Leaves stored calibrations
+    /// alone
+    #[arg(long, value_name = "DIR", group = "playback")]
+    pub replay: Option<PathBuf>,
+
+    /// Start again from the beginning once the folder or video is exhausted
+    #[arg(long = "loop", requires = "playback")]
+    pub loop_images: bool,
+
+    /// Playback rate for --image-dir, in frames per second
+    #[arg(long, default_value_t = 5.0)]
+    pub image_fps: f64,
+
+    /// ArUco dictionary the Markers overlay looks for
+    #[arg(long, value_name = "DICT", default_value = "DICT_6X6_250")]
+    pub marker_dictionary: String,
+
+    /// Folder for stills saved with the Snapshot button (or the S key)
+    #[arg(long, value_name = "DIR", default_value = "snapshots")]
+    pub snapshot_dir: PathBuf,
+
+    /// Folder for recordings, each one gets a new timestamped file and never replaces another.
+    /// Defaults to the folder used last, or the working directory
+    #[arg(long, value_name = "DIR")]
+    pub record_dir: Option<PathBuf>,
+
+    /// Start recording as soon as the camera is open
+    #[arg(long)]
+    pub record: bool,
+
+    /// Also write the log to a file in DIR that starts over every day, the last week is kept.
+    /// RUST_LOG sets the levels, e.g. RUST_LOG=rustyrabbit=debug
+    #[arg(long, value_name = "DIR")]
+    pub log_dir: Option<PathBuf>,
+
+    /// Consecutive frames the board has to hold still before automatic calibration capture
+    /// takes a view (0 accepts every detection). Defaults to 5, or 0 with --image-dir and --synthetic
+    #[arg(long)]
+    pub stable_frames: Option<usize>,
+
+    /// Calibration target detections running at once, on threads of their own so a slow one
+    /// never holds up the camera or the preview
+    #[arg(long, value_name = "N", default_value_t = DEFAULT_WORKERS)]
+    pub detection_workers: usize,
+
+    /// Which frames target detection takes while it is slower than the camera: the newest one,
+    /// or every frame on a single worker. Defaults to every with --image-dir and --video
+    #[arg(long, value_enum, value_name = "POLICY")]
+    pub detection_queue: Option<QueuePolicy>,
+
+    /// Look for the calibration target in at most this many frames a second, leaving the cores to
+    /// the camera and the preview, which keep the camera's rate. Only while taking the newest frame
+    #[arg(long, value_name = "FPS", value_parser = parse_rate)]
+    pub detection_fps: Option<f64>,
+
+    /// Convert, resize and undistort frames with OpenCL on the GPU when there is a device,
+    /// falling back to the CPU if it fails. Try --benchmark-opencl first, it doesn't help everywhere
+    #[arg(long, conflicts_with = "cuda")]
+    pub opencl: bool,
+
+    /// Convert, resize and undistort frames on an NVIDIA GPU, e.g. a Jetson, falling back to
+    /// the CPU if it fails. Needs a build with the cuda feature
+    #[arg(long)]
+    pub cuda: bool,
+
+    /// Time frame conversion on the CPU, with OpenCL and with CUDA at the camera size set with --width
+    /// and --height (1920x1080 without), print which is faster and exit
+    #[arg(long)]
+    pub benchmark_opencl: bool,
+
+    /// Threads OpenCV splits each frame's conversion and resize across. Defaults to one per core
+    #[arg(long, value_name = "N")]
+    pub cv_threads: Option<i32>,
+
+    /// Don't ring the terminal bell when a calibration view is accepted
+    #[arg(long)]
+    pub no_capture_beep: bool,
+
+    /// Don't flash the preview when a calibration view is accepted
+    #[arg(long)]
+    pub no_capture_flash: bool,
+
+    /// Run in the terminal instead of opening a window, e.g. over SSH on a machine controller
+    /// without a display server. Needs a build with --features tui
+    #[cfg(feature = "tui")]
+    #[arg(long, group = "front_end", conflicts_with = "kiosk")]
+    pub tui: bool,
+
+    /// Serve the preview and calibration controls to a browser on ADDRESS instead of opening a window,
+    /// and a JSON API for other programs under /api/. Only this computer can connect unless
+    /// ADDRESS says otherwise, e.g. 0.0.0.0:8080. Needs a build with --features web
+    #[cfg(feature = "web")]
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        num_args = 0..=1,
+        default_missing_value = "127.0.0.1:8080",
+        group = "front_end",
+        conflicts_with = "kiosk"
+    )]
+    pub web: Option<String>,
+
+    /// Turn down requests to the JSON API of --web that don't send "Authorization: Bearer TOKEN",
+    /// for a server other computers can reach
+    #[cfg(feature = "web")]
+    #[arg(long, value_name = "TOKEN", requires = "web")]
+    pub api_token: Option<String>,
+
+    /// Serve the calibration service of proto/rustyrabbit.proto over gRPC on ADDRESS instead of
+    /// opening a window, for factory software. Needs a build with --features grpc.
+    #[arg(
+        long,
+        value_name = "ADDRESS",
+        num_args = 0..=1,
+        default_missing_value = "0.0.0.0:50051",
+        group = "front_end",
+        conflicts_with = "kiosk"
+    )]
+    pub grpc: Option<String>,
+
+    /// Run as a ROS 2 node instead of opening a window, publishing image_raw and camera_info and
+    /// offering the calibration as services. Needs a build with --features ros
+    #[arg(long, group = "front_end", conflicts_with = "kiosk")]
+    pub ros: bool,
+
+    // Everything from --ros-args on, which ROS 2 launch files append for the node
+    #[arg(skip)]
+    pub ros_args: Vec<String>,
+
+    /// Calibrate straight away without any interface and write the result to --output, for
+    /// scripts and machines without a display
+    #[arg(long, group = "front_end", conflicts_with_all = ["kiosk", "manual"], requires = "output")]
+    pub headless: bool,
+
+    /// Run a Rhai script against the camera and a machine instead of opening a window, for
+    /// calibration routines of your own: move, capture, find, compute, repeat. Needs a build with
+    /// --features script
+    #[cfg(feature = "script")]
+    #[arg(long, value_name = "FILE", group = "front_end", conflicts_with = "kiosk")]
+    pub script: Option<PathBuf>,
+
+    /// Where --headless writes the calibration, in the format of the extension: .json,
+    /// .yaml/.yml (OpenCV FileStorage), .xml (OpenPnP), .py (NumPy) or .toml
+    #[arg(long, value_name = "FILE", requires = "headless")]
+    pub output: Option<PathBuf>,
+
+    /// Give up a --headless calibration that hasn't finished after this many seconds
+    #[arg(long, value_name = "SECS", requires = "headless")]
+    pub timeout: Option<u64>,
+
+    /// TOML file with a serial port, Moonraker address or linuxcncrsh host and machine positions:
+    /// --headless moves the camera to each one and captures a view there, for a target fixed to the bed
+    #[arg(long, value_name = "FILE", requires = "headless")]
+    pub capture_plan: Option<PathBuf>,
+
+    /// Where --capture-plan writes the machine position and joint positions of each view, JSON
+    /// for hand-eye calibration
+    #[arg(long, value_name = "FILE", requires = "capture_plan")]
+    pub poses: Option<PathBuf>,
+
+    /// Inner corners of the chess board as COLSxROWS (terminal, browser and headless modes)
+    #[arg(long, default_value = "9x6", value_parser = parse_board)]
+    pub board: (i32, i32),
+
+    /// Chess board square size in mm (terminal, browser and headless modes)
+    #[arg(long, default_value_t = 25.0, value_parser = parse_length)]
+    pub square_mm: f64,
+
+    /// Calibrate with the target of the detector plugin of this name instead of a chess board,
+    /// --board and --square-mm giving its size (terminal, browser and headless modes)
+    #[arg(long, value_name = "NAME")]
+    pub target_plugin: Option<String>,
+
+    /// ONNX chess board corner model to fall back to in frames the classical detector gives
+    /// up on, e.g. under glare or heavy blur (terminal, browser and headless modes, needs the
+    /// onnx feature)
+    #[arg(long, value_name = "FILE", conflicts_with = "target_plugin")]
+    pub corner_model: Option<PathBuf>,
+
+    /// Views to capture (terminal, browser and headless modes)
+    #[arg(long, default_value_t = 15)]
+    pub frames: usize,
+
+    /// Capture views with the space bar instead of automatically (terminal and browser interfaces)
+    #[arg(long)]
+    pub manual: bool,
+
+    /// Seconds between automatic captures (terminal, browser and headless modes). Defaults to
+    /// 3, or 0 with --image-dir and --synthetic
+    #[arg(long)]
+    pub countdown: Option<u64>,
+
+    /// Fullscreen without window decorations and with large touch controls, for a panel mounted on the machine
+    #[arg(long)]
+    pub kiosk: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Time capture from the camera, frame conversion, target detection at several resolutions and
+    /// the solver on this machine, and print the timings as JSON
+    Bench {
+        /// Frames, detections and solves each stage is timed over
+        #[arg(long, default_value_t = 50)]
+        rounds: u32,
+    },
+}
+
+fn parse_scale(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(scale) if scale > 0.0 && scale <= 1.0 => Ok(scale),
+        _ => Err(format!("expected a scale between 0 and 1, got '{}'", s)),
+    }
+}
+
+fn parse_rate(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok(rate),
+        _ => Err(format!("expected frames per second above 0, got '{}'", s)),
+    }
+}
+
+fn parse_length(s: &str) -> Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(length) if length > 0.0 && length.is_finite() => Ok(length),
+        _ => Err(format!("expected a length in mm, got '{}'", s)),
+    }
+}
+
+fn parse_point(s: &str) -> Result<(f64, f64), String> {
+    let usage = || format!("expected X,Y, got '{}'", s);
+    let (x, y) = s.split_once(',').ok_or_else(usage)?;
+    match (x.trim().parse(), y.trim().parse()) {
+        (Ok(x), Ok(y)) => Ok((x, y)),
+        _ => Err(usage()),
+    }
+}
+
+fn parse_board(s: &str) -> Result<(i32, i32), String> {
+    let usage = || format!("expected COLSxROWS, got '{}'", s);
+    let (cols, rows) = s.split_once(['x', 'X']).ok_or_else(usage)?;
+    match (cols.trim().parse(), rows.trim().parse()) {
+        (Ok(cols), Ok(rows)) if cols > 1 && rows > 1 => Ok((cols, rows)),
+        _ => Err(usage()),
+    }
+}
+
+impl Args {
+    // The command line up to --ros-args, the rest is kept for the ROS 2 node
+    pub fn from_command_line() -> Self {
+        let mut own: Vec<String> = env::args().collect();
+        let ros_args = match own.iter().position(|arg| arg == "--ros-args") {
+            Some(start) => own.split_off(start),
+            None => Vec::new(),
+        };
+        let mut args = Args::parse_from(own);
+        // Without the node nothing would take them, and they may hide a typo in our own options
+        if !args.ros && !ros_args.is_empty() {
+            Args::command()
+                .error(ErrorKind::ArgumentConflict, "--ros-args only goes to the ROS 2 node, add --ros to run it")
+                .exit();
+        }
+        args.ros_args = ros_args;
+        args
+    }
+
+    pub fn stable_frames(&self) -> usize {
+        // Every still in an image folder is a different pose and the synthetic board never
+        // stops, there is nothing to wait for
+        let default = if self.image_dir.is_some() || self.synthetic { 0 } else { DEFAULT_STABLE_FRAMES };
+        self.stable_frames.unwrap_or(default)
+    }
+
+    pub fn countdown(&self) -> u64 {
+        // Waiting would skip most of an image folder, one still after another
+        let default = if self.image_dir.is_some() || self.synthetic { 0 } else { DEFAULT_COUNTDOWN };
+        self.countdown.unwrap_or(default)
+    }
+
+    pub fn detection(&self) -> DetectionConfig {
+        // Every still and video frame is a pose of its own, none is worth skipping
+        let default = if self.is_playback() { QueuePolicy::Every } else { QueuePolicy::Latest };
+        DetectionConfig {
+            workers: self.detection_workers.max(1),
+            policy: self.detection_queue.unwrap_or(default),
+            max_fps: self.detection_fps,
+        }
+    }
+
+    pub fn acceleration(&self) -> Backend {
+        if self.cuda {
+            Backend::Cuda
+        } else if self.opencl {
+            Backend::OpenCl
+        } else {
+            Backend::Cpu
+        }
+    }
+
+    // Frames come from a folder or file that runs out, rather than a camera
+    pub fn is_playback(&self) -> bool {
+        self.image_dir.is_some() || self.video.is_some() || self.replay.is_some()
+    }
+
+    // Writes the per-role options given on this command line into the profile, returns
+    // whether anything changed
+    pub fn apply_to_profile(&self, profile: &mut CameraProfile) -> bool {
+        let before = profile.clone();
+        profile.camera = self.camera.or(profile.camera);
+        profile.flip = self.flip.or(profile.flip);
+        profile.rotate = self.rotate.or(profile.rotate);
+        profile.roi = self.roi.map(|roi| [roi.x, roi.y, roi.width, roi.height]).or(profile.roi);
+        profile.scale = self.scale.or(profile.scale);
+        profile.datum = self.datum.map(|(x, y)| [x, y]).or(profile.datum);
+        profile.mm_per_px = self.mm_per_px.or(profile.mm_per_px);
+        *profile != before
+    }
+
+    // Camera settings given on this command line, to be merged over the stored ones
+    pub fn camera_settings(&self) -> CameraSettings {
+        CameraSettings {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+            exposure: self.exposure,
+            gain: self.gain,
+            focus: self.focus,
+            white_balance: self.white_balance,
+        }
+    }
+
+    // The GenICam TriggerSource for --trigger-mode, None to free-run
+    #[cfg(any(feature = "aravis", feature = "spinnaker"))]
+    fn trigger_source(&self) -> Option<String> {
+        match self.trigger_mode {
+            TriggerMode::FreeRunning => None,
+            TriggerMode::Software => Some("Software".to_string()),
+            TriggerMode::Hardware => Some(self.trigger_source.clone()),
+        }
+    }
+
+    // How to open the camera for `profile`, with `overrides` merged over its stored settings
+    pub fn source_spec(&self, profile: &CameraProfile, overrides: &CameraSettings) -> SourceSpec {
+        let mode = CaptureMode {
+            width: self.width,
+            height: self.height,
+            fps: self.fps,
+        };
+
+        if let Some(dir) = &self.replay {
+            return SourceSpec::Session { dir: dir.clone() };
+        }
+        if let Some(dir) = &self.image_dir {
+            return SourceSpec::ImageDir {
+                dir: dir.clone(),
+                looping: self.loop_images,
+                fps: self.image_fps,
+            };
+        }
+        if let Some(path) = &self.video {
+            return SourceSpec::Video {
+                path: path.clone(),
+                looping: self.loop_images,
+            };
+        }
+        if let Some(url) = &self.stream {
+            return SourceSpec::Stream { url: url.clone() };
+        }
+        if self.libcamera {
+            return SourceSpec::Libcamera { mode };
+        }
+        if self.synthetic {
+            let (board_cols, board_rows) = self.synthetic_board;
+            return SourceSpec::Synthetic(SyntheticConfig {
+                size: Size::new(self.width.unwrap_or(1280), self.height.unwrap_or(720)),
+                fps: self.fps.unwrap_or(30) as f64,
+                board_cols,
+                board_rows,
+                noise: self.synthetic_noise,
+                blur: self.synthetic_blur,
+                k1: self.synthetic_k1,
+                k2: self.synthetic_k2,
+            });
+        }
+        #[cfg(feature = "aravis")]
+        if let Some(device_id) = &self.aravis {
+            return SourceSpec::Aravis {
+                device_id: Some(device_id.clone()).filter(|id| !id.is_empty()),
+                settings: rustyrabbit_core::aravis_source::AravisSettings {
+                    exposure_us: self.exposure_us,
+                    gain_db: self.gain_db,
+                    pixel_format: self.pixel_format.clone(),
+                    trigger_source: self.trigger_source(),
+                    demosaic: self.demosaic,
+                },
+            };
+        }
+        #[cfg(feature = "spinnaker")]
+        if let Some(serial) = &self.spinnaker {
+            return SourceSpec::Spinnaker {
+                serial: Some(serial.clone()).filter(|serial| !serial.is_empty()),
+                settings: rustyrabbit_core::spinnaker_source::SpinnakerSettings {
+                    exposure_us: self.exposure_us,
+                    gain_db: self.gain_db,
+                    pixel_format: self.pixel_format.clone(),
+                    trigger_source: self.trigger_source(),
+                    demosaic: self.demosaic,
+                },
+            };
+        }
+        // Reapply whatever was tuned for this camera last time
+        let index = profile.camera.unwrap_or(CAMERA_INDEX);
+        let stored = camera_settings::load(&camera_settings::device_key(index)).unwrap_or_else(|e| {
+            log::warn!("Ignoring stored camera settings: {:?}", e);
+            None
+        });
+        SourceSpec::Camera {
+            index,
+            settings: stored.unwrap_or_default().merged_with(overrides),
+            xu_controls: self.uvc_xu.clone(),
+            bayer: self.bayer.map(|pattern| BayerConfig {
+                pattern,
+                algorithm: self.demosaic,
+            }),
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use anyhow::{anyhow, Result};
+use rustyrabbit_core::lock::Locked;
+use std::sync::{Arc, Mutex};
+
+// The system clipboard, opened on first use. On X11 and Wayland the text is served by this
+// process, so the handle is kept for as long as the app runs.
+#[derive(Clone, Default)]
+pub struct SystemClipboard(Arc<Mutex<Option<arboard::Clipboard>>>);
+
+impl SystemClipboard {
+    pub fn copy(&self, text: String) -> Result<()> {
+        let mut clipboard = self.0.locked();
+        if clipboard.is_none() {
+            *clipboard = Some(arboard::Clipboard::new().map_err(|e| anyhow!("No clipboard: {}", e))?);
+        }
+        clipboard
+            .as_mut()
+            .unwrap()
+            .set_text(text)
+            .map_err(|e| anyhow!("Unable to copy: {}", e))
+    }
+}
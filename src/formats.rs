@@ -0,0 +1,128 @@
+use anyhow::Result;
+use rustyrabbit_core::profiles::Calibration;
+
+// How the intrinsics are written for other tools, pasted from the Calibration tab (in the
+// order of its list) or saved by --headless
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultFormat {
+    NumPy,
+    OpenCvYaml,
+    OpenPnp,
+}
+
+impl ResultFormat {
+    #[cfg(feature = "gui")]
+    pub fn from_index(index: i32) -> Option<Self> {
+        match index {
+            0 => Some(ResultFormat::NumPy),
+            1 => Some(ResultFormat::OpenCvYaml),
+            2 => Some(ResultFormat::OpenPnp),
+            _ => None,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ResultFormat::NumPy => "NumPy",
+            ResultFormat::OpenCvYaml => "OpenCV YAML",
+            ResultFormat::OpenPnp => "OpenPnP",
+        }
+    }
+}
+
+// Camera matrix and distortion coefficients in full sensor pixels. `{:?}` prints the
+// shortest text that reads back as the same f64 (with a ".0" on whole numbers), nothing is
+// rounded away.
+pub fn format(calibration: &Calibration, format: ResultFormat) -> String {
+    let m = calibration.camera_matrix;
+    let matrix: Vec<String> = m.iter().flatten().map(|v| format!("{:?}", v)).collect();
+    let dist: Vec<String> = calibration.dist_coeffs.iter().map(|v| format!("{:?}", v)).collect();
+    match format {
+        ResultFormat::NumPy => {
+            let rows: Vec<String> = matrix.chunks(3).map(|row| format!("    [{}],", row.join(", "))).collect();
+            format!(
+                "# {}x{} sensor, RMS {:.4} px\ncamera_matrix = np.array([\n{}\n])\ndist_coeffs = np.array([{}])\n",
+                calibration.sensor_width,
+                calibration.sensor_height,
+                calibration.rms,
+                rows.join("\n"),
+                dist.join(", ")
+            )
+        }
+        // The layout cv::FileStorage writes and reads
+        ResultFormat::OpenCvYaml => format!(
+            "image_width: {}\nimage_height: {}\ncamera_matrix: !!opencv-matrix\n   rows: 3\n   cols: 3\n   dt: d\n   \
+             data: [ {} ]\ndistortion_coefficients: !!opencv-matrix\n   rows: 1\n   cols: {}\n   dt: d\n   \
+             data: [ {} ]\navg_reprojection_error: {:?}\n",
+            calibration.sensor_width,
+            calibration.sensor_height,
+            matrix.join(", "),
+            dist.len(),
+            dist.join(", "),
+            calibration.rms
+        ),
+        // The lens calibration element of a camera in OpenPnP's machine.xml
+        ResultFormat::OpenPnp => format!(
+            "<calibration enabled=\"true\">\n   <camera-matrix length=\"9\">{}</camera-matrix>\n   \
+             <distortion-coefficients length=\"{}\">{}</distortion-coefficients>\n</calibration>\n",
+            matrix.join(", "),
+            dist.len(),
+            dist.join(", ")
+        ),
+    }
+}
+
+// A calibration as a file on its own, as --headless writes it and the browser API downloads it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileFormat {
+    Json,
+    Toml,
+    Text(ResultFormat),
+}
+
+impl FileFormat {
+    pub const EXTENSIONS: &'static str = ".json, .yaml, .yml, .xml, .py or .toml";
+
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        Some(match extension.to_ascii_lowercase().as_str() {
+            "json" => FileFormat::Json,
+            "toml" => FileFormat::Toml,
+            "yaml" | "yml" => FileFormat::Text(ResultFormat::OpenCvYaml),
+            "xml" => FileFormat::Text(ResultFormat::OpenPnp),
+            "py" => FileFormat::Text(ResultFormat::NumPy),
+            _ => return None,
+        })
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            FileFormat::Json => "json",
+            FileFormat::Toml => "toml",
+            FileFormat::Text(ResultFormat::OpenCvYaml) => "yaml",
+            FileFormat::Text(ResultFormat::OpenPnp) => "xml",
+            FileFormat::Text(ResultFormat::NumPy) => "py",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            FileFormat::Json => "application/json",
+            FileFormat::Toml => "application/toml",
+            FileFormat::Text(ResultFormat::OpenCvYaml) => "application/yaml",
+            FileFormat::Text(ResultFormat::OpenPnp) => "application/xml",
+            FileFormat::Text(ResultFormat::NumPy) => "text/x-python",
+        }
+    }
+
+    pub fn render(self, calibration: &Calibration) -> Result<String> {
+        Ok(match self {
+            FileFormat::Json => serde_json::to_string_pretty(calibration)? + "\n",
+            FileFormat::Toml => calibration.to_toml()?,
+            // A complete file that cv::FileStorage opens, not just the pasted fragment
+            FileFormat::Text(ResultFormat::OpenCvYaml) => {
+                format!("%YAML:1.0\n---\n{}", format(calibration, ResultFormat::OpenCvYaml))
+            }
+            FileFormat::Text(result_format) => format(calibration, result_format),
+        })
+    }
+}
@@ -0,0 +1,84 @@
+use rustyrabbit_core::{frame::Frame, lock::Locked};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+// How often the numbers are refreshed
+const WINDOW: Duration = Duration::from_secs(1);
+
+struct StatsState {
+    started: Instant,
+    // First and latest frame shown in this window: sequence number and capture time
+    first: Option<(u64, Instant)>,
+    last: Option<(u64, Instant)>,
+    shown: u32,
+    // Frames the preview never got to show, from gaps in the sequence numbers. Counted
+    // since the camera started.
+    dropped: u64,
+    latency_ms: f64,
+}
+
+impl Default for StatsState {
+    fn default() -> Self {
+        StatsState {
+            started: Instant::now(),
+            first: None,
+            last: None,
+            shown: 0,
+            dropped: 0,
+            latency_ms: 0.0,
+        }
+    }
+}
+
+// Achieved camera and preview frame rates, to tell whether lag comes from capture or display
+#[derive(Clone, Default)]
+pub struct FrameStats(Arc<Mutex<StatsState>>);
+
+impl FrameStats {
+    // Call with every frame the preview shows
+    pub fn record(&self, frame: &Frame) {
+        let mut state = self.0.locked();
+        match state.last {
+            // A new capture thread starts counting from 1 again
+            Some((sequence, _)) if frame.sequence <= sequence => {
+                let started = state.started;
+                *state = StatsState { started, ..Default::default() };
+            }
+            Some((sequence, _)) => state.dropped += frame.sequence - sequence - 1,
+            None => {}
+        }
+        if state.first.is_none() {
+            state.first = Some((frame.sequence, frame.captured_at));
+        }
+        state.last = Some((frame.sequence, frame.captured_at));
+        state.shown += 1;
+        state.latency_ms = frame.age_ms();
+    }
+
+    // Once per window, the text for the preview
+    pub fn reading(&self) -> Option<String> {
+        let mut state = self.0.locked();
+        let elapsed = state.started.elapsed();
+        if elapsed < WINDOW {
+            return None;
+        }
+        let preview_fps = state.shown as f64 / elapsed.as_secs_f64();
+        let camera_fps = match (state.first, state.last) {
+            (Some((first, first_at)), Some((last, last_at))) if last > first => {
+                (last - first) as f64 / last_at.duration_since(first_at).as_secs_f64().max(1e-6)
+            }
+            _ => 0.0,
+        };
+        let text = format!(
+            "Camera {:.1} fps, preview {:.1} fps, {} dropped, {:.0} ms behind",
+            camera_fps, preview_fps, state.dropped, state.latency_ms
+        );
+        // Keep counting drops across windows, the rates start over
+        state.started = Instant::now();
+        state.first = state.last;
+        state.shown = 0;
+        Some(text)
+    }
+}
@@ -0,0 +1,179 @@
+use crate::{
+    cli::Args,
+    headless::{CalibrationRequest, HeadlessApp},
+};
+use anyhow::{bail, Context, Result};
+use opencv::core::{Point2d, Vector};
+use rustyrabbit_core::{
+    frame::FrameFeed,
+    profiles::{self, CameraRole},
+    runtime::runtime,
+};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{sync::mpsc, task::spawn_blocking, time::sleep};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::{transport::Server, Request, Response, Status};
+
+mod proto {
+    tonic::include_proto!("rustyrabbit.v1");
+}
+
+use proto::{
+    calibration_server::{Calibration, CalibrationServer},
+    CalibrationStatus, GetIntrinsicsRequest, Intrinsics, Point, StartCalibrationRequest, StreamStatusRequest,
+    UndistortPointsRequest, UndistortPointsResponse,
+};
+
+// How often a status stream looks for a change
+const STATUS_POLL: Duration = Duration::from_millis(100);
+// Changes a slow client can fall behind by before the stream waits for it
+const STATUS_QUEUE: usize = 16;
+
+// Serves proto/rustyrabbit.proto, the calibration and its results for factory software
+// written in anything gRPC has a code generator for
+pub fn run(args: Arc<Args>, role: CameraRole, frames: FrameFeed) -> Result<()> {
+    let Some(address) = args.grpc.clone() else {
+        bail!("No address to serve on");
+    };
+    let address: SocketAddr = address.parse().with_context(|| format!("{:?} isn't an address and port", address))?;
+    let service = CalibrationService {
+        defaults: CalibrationRequest::from_args(&args),
+        app: Arc::new(HeadlessApp::start(args, role, frames)?),
+    };
+    let app = Arc::clone(&service.app);
+    log::info!("Serving the gRPC calibration service on {}", address);
+    let served = runtime().block_on(Server::builder().add_service(CalibrationServer::new(service)).serve(address));
+    app.stop()?;
+    served.with_context(|| format!("Unable to serve on {}", address))
+}
+
+struct CalibrationService {
+    app: Arc<HeadlessApp>,
+    defaults: CalibrationRequest,
+}
+
+impl CalibrationService {
+    fn role(&self, camera: proto::CameraRole) -> CameraRole {
+        match camera {
+            proto::CameraRole::Unspecified => self.app.role(),
+            proto::CameraRole::Top => CameraRole::Top,
+            proto::CameraRole::Bottom => CameraRole::Bottom,
+        }
+    }
+
+    fn stored_calibration(&self, camera: proto::CameraRole) -> Result<(CameraRole, profiles::Calibration), Status> {
+        let role = self.role(camera);
+        let profiles = profiles::load().map_err(internal)?;
+        match profiles.get(role).calibration.clone() {
+            Some(calibration) => Ok((role, calibration)),
+            None => Err(Status::not_found(format!("The {} has no calibration yet", role.label()))),
+        }
+    }
+}
+
+fn internal(e: anyhow::Error) -> Status {
+    Status::internal(format!("{:#}", e))
+}
+
+fn proto_role(role: CameraRole) -> proto::CameraRole {
+    match role {
+        CameraRole::Top => proto::CameraRole::Top,
+        CameraRole::Bottom => proto::CameraRole::Bottom,
+    }
+}
+
+fn status(app: &HeadlessApp) -> CalibrationStatus {
+    let role = app.role();
+    let state = app.report().state();
+    CalibrationStatus {
+        camera: proto_role(role) as i32,
+        calibrating: state.calibrating,
+        workflow: state.workflow.label().to_string(),
+        captured: state.captured as u32,
+        required: state.required as u32,
+        status: state.status.clone(),
+    }
+}
+
+#[tonic::async_trait]
+impl Calibration for CalibrationService {
+    async fn start_calibration(
+        &self,
+        request: Request<StartCalibrationRequest>,
+    ) -> Result<Response<CalibrationStatus>, Status> {
+        let settings = request.into_inner();
+        let role = self.role(settings.camera());
+        let mut calibration = self.defaults.clone();
+        calibration.board.0 = settings.cols.unwrap_or(calibration.board.0);
+        calibration.board.1 = settings.rows.unwrap_or(calibration.board.1);
+        calibration.square_mm = settings.square_mm.unwrap_or(calibration.square_mm);
+        calibration.frames = settings.frames.map_or(calibration.frames, |frames| frames as usize);
+        calibration.manual = settings.manual.unwrap_or(calibration.manual);
+        calibration.countdown = settings.countdown_secs.map_or(calibration.countdown, u64::from);
+        calibration.check().map_err(|e| Status::invalid_argument(e.to_string()))?;
+
+        // Switching cameras and starting wait on the camera and the runtime, off the workers
+        let app = Arc::clone(&self.app);
+        spawn_blocking(move || {
+            app.use_camera(role).map_err(|e| Status::failed_precondition(e.to_string()))?;
+            app.start_calibration(&calibration).map_err(|e| Status::failed_precondition(e.to_string()))?;
+            Ok(Response::new(status(&app)))
+        })
+        .await
+        .map_err(|e| Status::internal(e.to_string()))?
+    }
+
+    type StreamStatusStream = ReceiverStream<Result<CalibrationStatus, Status>>;
+
+    async fn stream_status(
+        &self,
+        _request: Request<StreamStatusRequest>,
+    ) -> Result<Response<Self::StreamStatusStream>, Status> {
+        let (sender, receiver) = mpsc::channel(STATUS_QUEUE);
+        let app = Arc::clone(&self.app);
+        runtime().spawn(async move {
+            let mut sent = None;
+            loop {
+                let now = status(&app);
+                if sent.as_ref() != Some(&now) {
+                    if sender.send(Ok(now.clone())).await.is_err() {
+                        // The client hung up
+                        return;
+                    }
+                    sent = Some(now);
+                }
+                sleep(STATUS_POLL).await;
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_intrinsics(&self, request: Request<GetIntrinsicsRequest>) -> Result<Response<Intrinsics>, Status> {
+        let (role, calibration) = self.stored_calibration(request.into_inner().camera())?;
+        Ok(Response::new(Intrinsics {
+            camera: proto_role(role) as i32,
+            sensor_width: calibration.sensor_width as u32,
+            sensor_height: calibration.sensor_height as u32,
+            camera_matrix: calibration.camera_matrix.iter().flatten().copied().collect(),
+            dist_coeffs: calibration.dist_coeffs,
+            rms: calibration.rms,
+            calibrated_at: calibration.calibrated_at,
+        }))
+    }
+
+    async fn undistort_points(
+        &self,
+        request: Request<UndistortPointsRequest>,
+    ) -> Result<Response<UndistortPointsResponse>, Status> {
+        let request = request.into_inner();
+        let (_, calibration) = self.stored_calibration(request.camera())?;
+        if request.points.is_empty() {
+            return Ok(Response::new(UndistortPointsResponse { points: Vec::new() }));
+        }
+        let points: Vector<Point2d> = request.points.iter().map(|point| Point2d::new(point.x, point.y)).collect();
+        let ideal = calibration.undistort_points(&points, request.normalized).map_err(internal)?;
+        Ok(Response::new(UndistortPointsResponse {
+            points: ideal.iter().map(|point| Point { x: point.x, y: point.y }).collect(),
+        }))
+    }
+}
@@ -0,0 +1,793 @@
+use crate::{
+    cli::Args,
+    clipboard::SystemClipboard,
+    formats::{self, ResultFormat},
+    frame_stats::FrameStats,
+    histogram::Histogram,
+    inspector::PixelInspector,
+    measure::Measurement,
+    reticle::Reticle,
+    settings,
+    shortcuts::{self, Action},
+    snapshot::Snapshot,
+    status_log, targets, ui, LogLine, MainWindow,
+};
+use anyhow::{anyhow, Result};
+use rustyrabbit_core::{
+    calibration::{self, Button, CalibrationControls, CalibrationSession, SharedViews, MIN_CALIBRATION_FRAMES},
+    events::{Event, EventBus},
+    focus::FocusMeter,
+    frame::{Frame, FrameFeed},
+    lock::Locked,
+    markers::MarkerOverlay,
+    metrics::{self, Stage},
+    ndi::NdiOutput,
+    plugin,
+    preprocess,
+    preview::{PreviewOverlay, Undistort},
+    profiles::{self, CameraRole, Profiles},
+    runtime::runtime,
+    session_log::{self, Started},
+    source::TriggerMode,
+    target::{self, CalibrationTarget},
+    target_print::{self, TargetKind, TargetSpec},
+    tuning,
+    workflow::{Workflow, WorkflowState},
+};
+use slint::{ComponentHandle, Image, Model, ModelRc, Timer, TimerMode, VecModel};
+use std::{
+    cell::RefCell,
+    env,
+    path::{Path, PathBuf},
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often the log panel, focus and frame statistics are brought up to date. The preview
+// itself is redrawn with every new frame.
+const STATUS_REFRESH: Duration = Duration::from_millis(100);
+
+// The translations named by RUSTYRABBIT_LANG_DIR, else the lang folder next to the program,
+// else the source tree's when it runs from target/<profile>
+fn translations_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("RUSTYRABBIT_LANG_DIR") {
+        return Some(dir.into());
+    }
+    let exe = env::current_exe().ok()?;
+    let program_dir = exe.parent()?;
+    let mut candidates = vec![program_dir.join("lang")];
+    if let Some(target) = program_dir.parent().filter(|dir| dir.file_name().is_some_and(|name| name == "target")) {
+        candidates.extend(target.parent().map(|source| source.join("lang")));
+    }
+    candidates.into_iter().find(|dir| dir.is_dir())
+}
+
+// The main window, with the preview, calibration and results tabs
+pub fn run(
+    args: Arc<Args>,
+    camera_profiles: Profiles,
+    frames: FrameFeed,
+) -> Result<()> {
+    let role = camera_profiles.active;
+    let active_role = Arc::new(Mutex::new(role));
+
+    // UI text in the language of LANGUAGE/LANG, from lang/<locale>/LC_MESSAGES/rustyrabbit.mo
+    match translations_dir() {
+        Some(dir) => slint::init_translations!(dir),
+        None => log::debug!("No lang folder found, the interface is in English"),
+    }
+
+    // Initialize Slint window
+    let window = MainWindow::new()?;
+    if args.kiosk {
+        window.set_kiosk(true);
+        window.window().set_fullscreen(true);
+    }
+    let window_clone_for_callback = window.as_weak(); // Clone for use in calibration callback
+    let window_clone_for_render = window.as_weak(); // Clone for use in render closure
+    let window_clone_for_switch = window.as_weak(); // Clone for camera role switching
+    let overlay = PreviewOverlay::default(); // Detection results drawn over the preview
+    let undistort = Undistort::default(); // Optional lens correction of the preview
+    let focus = FocusMeter::default(); // Sharpness readout for focusing by hand
+    let histogram = Histogram::default(); // Exposure check drawn over the preview
+    let reticle = Reticle::default(); // Crosshair for lining up with the nozzle
+    let inspector = PixelInspector::default(); // Pixel values under the pointer
+    let measurement = Measurement::default(); // Distance between two clicked points
+    let frame_stats = FrameStats::default(); // Achieved frame rates and drops
+    let markers = MarkerOverlay::new(&args.marker_dictionary); // ArUco IDs over the preview
+    let tuning = tuning::watch(); // Thresholds, overlays and folders that can change while running
+    let ndi = args.ndi.as_deref().map(NdiOutput::start).transpose()?; // The preview for monitors on the network
+    let snapshot_dir = tuning.borrow().snapshot_dir.clone().unwrap_or_else(|| args.snapshot_dir.clone());
+    let snapshot = Snapshot::new(snapshot_dir); // Stills of the live feed
+    let events = EventBus::default(); // Camera and calibration progress, shown in the window
+    ui::follow(window.as_weak(), events.subscribe());
+    let workflow = Workflow::new(events.clone());
+
+    // Window layout, target fields and overlays as they were left last time
+    let ui_settings = settings::load();
+
+    // Folder of the recording in progress, carried over when switching cameras
+    let default_record_dir = tuning
+        .borrow()
+        .record_dir
+        .clone()
+        .or_else(|| args.record_dir.clone())
+        .or_else(|| ui_settings.record_dir.clone())
+        .unwrap_or_else(|| PathBuf::from("."));
+    let record_dir = Arc::new(Mutex::new(args.record.then(|| default_record_dir.clone())));
+    window.set_record_dir(default_record_dir.to_string_lossy().to_string().into());
+
+    // Start capturing from the selected camera. Without one the window still opens, the
+    // preview says what went wrong and offers to try again.
+    let capture = match crate::start_capture(
+        &args,
+        camera_profiles.get(role),
+        &args.camera_settings(),
+        record_dir.locked().clone(),
+        frames.clone(),
+        events.clone(),
+    ) {
+        Ok((capture, _)) => Some(capture),
+        Err(e) => {
+            let status = format!("Unable to open the {}: {}", role.label(), e);
+            log::error!("{}", status);
+            window.set_camera_connected(false);
+            window.set_camera_status(status.into());
+            None
+        }
+    };
+    let capture = Arc::new(Mutex::new(capture));
+    window.set_camera_role(role.index());
+    undistort.set_calibration(camera_profiles.get(role).calibration.clone());
+    window.set_undistort_available(undistort.has_calibration());
+    ui::publish_result(&window.as_weak(), role, camera_profiles.get(role).calibration.as_ref());
+    reticle.set_profile(camera_profiles.get(role));
+
+    let target_plugins = plugin::discover();
+    let plugin_names: Vec<slint::SharedString> = target_plugins.iter().map(|plugin| plugin.name().into()).collect();
+    window.set_target_plugins(ModelRc::new(VecModel::from(plugin_names)));
+
+    let frames_for_callback = frames.clone(); // Clone for callback use
+    let active_role_for_callback = Arc::clone(&active_role);
+    let overlay_for_callback = overlay.clone();
+    let undistort_for_callback = undistort.clone();
+    let focus_for_callback = focus.clone();
+    let args_for_callback = Arc::clone(&args);
+    let events_for_callback = events.clone();
+    let workflow_for_callback = workflow.clone();
+    let tuning_for_callback = tuning.clone();
+    let calibration_views = SharedViews::default(); // Views of the last calibration, for re-solving
+    let calibration_views_for_callback = Arc::clone(&calibration_views);
+    let controls = CalibrationControls::default();
+    let controls_for_callback = controls.clone();
+    // The running or last calibration task, waited for when the window closes
+    let calibration_task = Arc::new(Mutex::new(None));
+    let calibration_task_for_callback = Arc::clone(&calibration_task);
+    let controls_for_finish = controls.clone();
+    window.on_finish_calibration(move || controls_for_finish.press(Button::Finish));
+    let controls_for_capture = controls.clone();
+    window.on_capture_view(move || controls_for_capture.press(Button::Capture));
+    let controls_for_undo = controls.clone();
+    window.on_undo_capture(move || controls_for_undo.press(Button::Undo));
+    let controls_for_delete = controls.clone();
+    window.on_delete_capture(move |index| controls_for_delete.delete(index as usize));
+    let controls_for_cancel = controls.clone();
+    window.on_cancel_calibration(move || controls_for_cancel.press(Button::Cancel));
+
+    window.on_calibration_wrapper_callback(
+        move |selected_calibration,
+              grid_rows,
+              grid_cols,
+              _loc_x,
+              _loc_y,
+              required_frames,
+              manual_capture,
+              countdown,
+              square_size,
+              dictionary| {
+            let show_error = |status: String| {
+                log::error!("{}", status);
+                if let Some(window) = window_clone_for_callback.upgrade() {
+                    window.set_status(status.into());
+                }
+            };
+            // Same order as the calibration type list, None for a detector plugin's target
+            let kind = match selected_calibration {
+                0 => Some(TargetKind::ChessBoard),
+                1 => Some(TargetKind::CircleGrid),
+                2 => Some(TargetKind::Charuco {
+                    dictionary: dictionary.to_string(),
+                }),
+                3 => None,
+                _ => {
+                    log::error!("Unknown calibration type selected: {}", selected_calibration);
+                    return;
+                }
+            };
+
+            let square_size = match square_size.trim().parse::<f64>() {
+                Ok(size) if size > 0.0 && size.is_finite() => size,
+                _ => return show_error(format!("Square size {:?} is not a length in mm", square_size.as_str())),
+            };
+            let spec = kind.map(|kind| TargetSpec {
+                kind,
+                rows: grid_rows,
+                cols: grid_cols,
+                square_mm: square_size,
+            });
+            let (made, plugin_name): (Result<Arc<dyn CalibrationTarget>>, Option<String>) = match &spec {
+                Some(spec) => (target::from_spec(spec).map(Arc::from), None),
+                None => match window_clone_for_callback
+                    .upgrade()
+                    .and_then(|window| target_plugins.get(window.get_target_plugin() as usize).cloned())
+                {
+                    Some(plugin) => {
+                        let made = plugin.create(grid_cols, grid_rows, square_size);
+                        let name = Some(plugin.name().to_string());
+                        (made.map(|target| Arc::new(target) as Arc<dyn CalibrationTarget>), name)
+                    }
+                    None => {
+                        let folder = plugin::PLUGIN_DIR;
+                        return show_error(format!("Unable to calibrate: no detector plugin in the {} folder", folder));
+                    }
+                },
+            };
+            let target: Arc<dyn CalibrationTarget> = match made {
+                Ok(target) => target,
+                Err(e) => return show_error(format!("Unable to calibrate: {}", e)),
+            };
+
+            if let Err(e) = workflow_for_callback.start(WorkflowState::Configured) {
+                return show_error(e.to_string());
+            }
+            log::info!(
+                "Calibration started with rows: {}, cols: {}, square: {} mm, frames: {}",
+                grid_rows, grid_cols, square_size, required_frames
+            );
+
+            // Calibrate in a task on the shared runtime to avoid blocking the UI
+            controls_for_callback.reset();
+            let session = CalibrationSession {
+                frames: frames_for_callback.clone(),
+                role: *active_role_for_callback.locked(),
+                overlay: overlay_for_callback.clone(),
+                undistort: undistort_for_callback.clone(),
+                focus: focus_for_callback.clone(),
+                controls: controls_for_callback.clone(),
+                manual_capture,
+                stable_frames: args_for_callback.stable_frames(),
+                countdown: Duration::from_secs(countdown.max(0) as u64),
+                confirm_beep: !args_for_callback.no_capture_beep,
+                confirm_flash: !args_for_callback.no_capture_flash,
+                detection: args_for_callback.detection(),
+                tuning: tuning_for_callback.clone(),
+                views: Arc::clone(&calibration_views_for_callback),
+                events: events_for_callback.clone(),
+                workflow: workflow_for_callback.clone(),
+            };
+            let required_frames = (required_frames as usize).max(MIN_CALIBRATION_FRAMES);
+            session_log::started(Started {
+                kind: spec.map(|spec| spec.kind),
+                plugin: plugin_name,
+                cols: grid_cols,
+                rows: grid_rows,
+                square_mm: square_size,
+                frames: required_frames,
+                manual: manual_capture,
+                countdown_secs: session.countdown.as_secs(),
+                stable_frames: session.stable_frames,
+            });
+            let task = runtime().spawn(async move {
+                session.set_calibrating(true);
+                if let Err(e) = calibration::run_calibration(target, required_frames, &session).await {
+                    log::error!("Error during calibration: {:?}", e);
+                    session.events.publish(Event::Error(format!("Calibration failed: {}", e)));
+                }
+                session.set_calibrating(false);
+            });
+            *calibration_task_for_callback.locked() = Some(task);
+        },
+    );
+
+    // Target presets fill in the board dimensions, new ones are saved from the same panel
+    targets::publish(&window, &targets::load());
+    let window_clone_for_presets = window.as_weak();
+    window.on_save_target_preset(move |name, kind, rows, cols, square, dictionary| {
+        let Some(window) = window_clone_for_presets.upgrade() else {
+            return;
+        };
+        let saved = square
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow!("Square size {:?} is not a length in mm", square.as_str()))
+            .and_then(|square_mm| {
+                targets::save_custom(targets::Preset {
+                    name: name.trim().to_string(),
+                    kind,
+                    rows,
+                    cols,
+                    square_mm,
+                    dictionary: dictionary.trim().to_string(),
+                })
+            });
+        let status = match saved {
+            Ok(()) => {
+                targets::publish(&window, &targets::load());
+                format!("Saved target preset {}", name.trim())
+            }
+            Err(e) => format!("Unable to save the preset: {}", e),
+        };
+        log::info!("{}", status);
+        window.set_status(status.into());
+    });
+
+    let window_clone_for_print = window.as_weak();
+    window.on_print_target(move |kind, rows, cols, square, dictionary| {
+        let kind = match kind {
+            0 => TargetKind::ChessBoard,
+            1 => TargetKind::CircleGrid,
+            2 => TargetKind::Charuco {
+                dictionary: dictionary.to_string(),
+            },
+            _ => {
+                if let Some(window) = window_clone_for_print.upgrade() {
+                    window.set_status("Detector plugin targets are printed with their own tools".into());
+                }
+                return;
+            }
+        };
+        let written = square
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| anyhow!("Square size {:?} is not a length in mm", square.as_str()))
+            .and_then(|square_mm| {
+                let spec = TargetSpec {
+                    kind,
+                    rows,
+                    cols,
+                    square_mm,
+                };
+                target_print::write(&spec, Path::new("."))
+            });
+        let status = match written {
+            Ok(path) => {
+                log::info!("Wrote printable target {} (with SVG and PNG)", path.display());
+                format!("Wrote {} with SVG and PNG copies, print at 100% scale", path.display())
+            }
+            Err(e) => {
+                log::error!("Error writing the target: {:?}", e);
+                format!("Unable to write the target: {}", e)
+            }
+        };
+        if let Some(window) = window_clone_for_print.upgrade() {
+            window.set_status(status.into());
+        }
+    });
+
+    // Per-view table: leave views out and solve again without recapturing
+    let calibration_views_for_toggle = Arc::clone(&calibration_views);
+    window.on_calibration_view_toggled(move |index, included| {
+        calibration::set_view_included(&calibration_views_for_toggle, index as usize, included);
+    });
+    let calibration_views_for_poses = Arc::clone(&calibration_views);
+    let window_clone_for_poses = window.as_weak();
+    window.on_pose_view_rotated(move |azimuth| {
+        if let Some(image) = ui::rotate_poses(&calibration_views_for_poses, azimuth as f64) {
+            if let Some(window) = window_clone_for_poses.upgrade() {
+                window.set_pose_image(image);
+            }
+        }
+    });
+    let undistort_for_resolve = undistort.clone();
+    let controls_for_resolve = controls.clone();
+    let events_for_resolve = events.clone();
+    let calibration_task_for_resolve = Arc::clone(&calibration_task);
+    let workflow_for_resolve = workflow.clone();
+    window.on_resolve_calibration(move || {
+        let views = Arc::clone(&calibration_views);
+        let undistort = undistort_for_resolve.clone();
+        let controls = controls_for_resolve.clone();
+        let events = events_for_resolve.clone();
+        let workflow = workflow_for_resolve.clone();
+        if let Err(e) = workflow.start(WorkflowState::Solving) {
+            log::warn!("{}", e);
+            events.publish(Event::Error(e.to_string()));
+            return;
+        }
+        // OpenCV's solver blocks, so it gets one of the runtime's blocking threads, and is
+        // waited for like a calibration when the window closes
+        let task = runtime().spawn_blocking(move || {
+            controls.reset();
+            events.publish(Event::CalibratingChanged(true));
+            if let Err(e) = calibration::resolve(&views, &undistort, &controls, &events, &workflow) {
+                let status = format!("Unable to calibrate: {}", e);
+                log::error!("{}", status);
+                events.publish(Event::Error(status));
+            }
+            events.publish(Event::CalibratingChanged(false));
+        });
+        *calibration_task_for_resolve.locked() = Some(task);
+    });
+
+    // Write the active camera's calibration next to the recordings
+    let active_role_for_export = Arc::clone(&active_role);
+    let window_clone_for_export = window.as_weak();
+    window.on_export_calibration(move || {
+        let role = *active_role_for_export.locked();
+        let path = PathBuf::from(format!("calibration-{}.toml", role.label().replace(' ', "-")));
+        let result = profiles::load().and_then(|profiles| match &profiles.get(role).calibration {
+            Some(calibration) => calibration.export(&path),
+            None => Err(anyhow!("The {} is not calibrated yet", role.label())),
+        });
+        let status = match result {
+            Ok(()) => {
+                log::info!("Exported the {} calibration to {}", role.label(), path.display());
+                format!("Exported to {}", path.display())
+            }
+            Err(e) => {
+                log::error!("Error exporting calibration: {:?}", e);
+                format!("Unable to export: {}", e)
+            }
+        };
+        if let Some(window) = window_clone_for_export.upgrade() {
+            window.set_status(status.into());
+        }
+    });
+
+    // Paste-ready intrinsics of the active camera for other tools
+    let active_role_for_copy = Arc::clone(&active_role);
+    let window_clone_for_copy = window.as_weak();
+    let system_clipboard = SystemClipboard::default();
+    window.on_copy_calibration(move |format| {
+        let role = *active_role_for_copy.locked();
+        let format = ResultFormat::from_index(format).unwrap_or(ResultFormat::NumPy);
+        let result = profiles::load().and_then(|profiles| match &profiles.get(role).calibration {
+            Some(calibration) => system_clipboard.copy(formats::format(calibration, format)),
+            None => Err(anyhow!("The {} is not calibrated yet", role.label())),
+        });
+        let status = match result {
+            Ok(()) => format!("Copied the {} calibration as {}", role.label(), format.label()),
+            Err(e) => {
+                log::error!("Error copying calibration: {:?}", e);
+                format!("Unable to copy: {}", e)
+            }
+        };
+        if let Some(window) = window_clone_for_copy.upgrade() {
+            window.set_status(status.into());
+        }
+    });
+
+    // Software trigger: the camera task acquires one frame per press
+    window.set_trigger_enabled(args.trigger_mode == TriggerMode::Software);
+    let capture_for_trigger = Arc::clone(&capture);
+    window.on_software_trigger(move || {
+        if let Some(capture) = capture_for_trigger.locked().as_ref() {
+            capture.trigger();
+        }
+    });
+
+    let capture_for_record = Arc::clone(&capture);
+    let record_dir_for_toggle = Arc::clone(&record_dir);
+    window.on_recording_toggled(move |on, dir| {
+        let dir = on.then(|| PathBuf::from(dir.as_str()));
+        *record_dir_for_toggle.locked() = dir.clone();
+        if let Some(capture) = capture_for_record.locked().as_ref() {
+            capture.record(dir);
+        }
+    });
+
+    // tuning.toml edited while running: new output folders apply to the next snapshot, and
+    // a recording under way carries on in the new folder
+    let mut tuning_for_dirs = tuning.clone();
+    let capture_for_tuning = Arc::clone(&capture);
+    let record_dir_for_tuning = Arc::clone(&record_dir);
+    let snapshot_for_tuning = snapshot.clone();
+    let window_clone_for_tuning = window.as_weak();
+    runtime().spawn(async move {
+        while tuning_for_dirs.changed().await.is_ok() {
+            let tuning = tuning_for_dirs.borrow_and_update().clone();
+            if let Some(dir) = tuning.snapshot_dir {
+                snapshot_for_tuning.set_dir(dir);
+            }
+            let Some(dir) = tuning.record_dir else {
+                continue;
+            };
+            let mut recording = record_dir_for_tuning.locked();
+            if recording.as_ref().is_some_and(|current| *current != dir) {
+                *recording = Some(dir.clone());
+                if let Some(capture) = capture_for_tuning.locked().as_ref() {
+                    capture.record(Some(dir.clone()));
+                }
+            }
+            drop(recording);
+            let text = dir.to_string_lossy().to_string();
+            let _ = window_clone_for_tuning.upgrade_in_event_loop(move |window| window.set_record_dir(text.into()));
+        }
+    });
+
+    // Opens the camera for a role, from the top/bottom selector and the no camera screen.
+    // Reopening a camera can take a moment so do it off the UI thread.
+    let capture_for_switch = Arc::clone(&capture);
+    let args_for_switch = Arc::clone(&args);
+    let undistort_for_switch = undistort.clone();
+    let reticle_for_switch = reticle.clone();
+    let events_for_switch = events.clone();
+    let active_role_for_switch = Arc::clone(&active_role);
+    let active_role_for_retry = Arc::clone(&active_role);
+    let open_camera = Rc::new(move |role: CameraRole| {
+        let capture = Arc::clone(&capture_for_switch);
+        let active_role = Arc::clone(&active_role);
+        let args = Arc::clone(&args_for_switch);
+        let undistort = undistort_for_switch.clone();
+        let reticle = reticle_for_switch.clone();
+        let record_dir = record_dir.locked().clone();
+        let frames = frames.clone();
+        let events = events_for_switch.clone();
+        let window = window_clone_for_switch.clone();
+        thread::spawn(move || {
+            match crate::switch_camera(role, &args, &capture, &active_role, record_dir, frames, events) {
+                Ok(profile) => {
+                    undistort.set_calibration(profile.calibration.clone());
+                    reticle.set_profile(&profile);
+                    let available = undistort.has_calibration();
+                    // Nothing to compare against without a calibration
+                    if !available {
+                        undistort.set_split(None);
+                    }
+                    let _ = window.upgrade_in_event_loop(move |win| {
+                        win.set_undistort_available(available);
+                        if !available {
+                            win.set_comparing(false);
+                        }
+                    });
+                    ui::publish_result(&window, role, profile.calibration.as_ref());
+                }
+                Err(e) => {
+                    let status = format!("Unable to open the {}: {}", role.label(), e);
+                    log::error!("{}", status);
+                    let _ = window.upgrade_in_event_loop(move |win| {
+                        win.set_camera_connected(false);
+                        win.set_camera_status(status.into());
+                    });
+                }
+            }
+        });
+    });
+    let open_for_switch = Rc::clone(&open_camera);
+    window.on_switch_camera(move |index| {
+        let Some(role) = CameraRole::from_index(index) else {
+            return;
+        };
+        if *active_role_for_switch.locked() != role {
+            open_for_switch(role);
+        }
+    });
+    // Opens the active camera again, after it failed to open or went away
+    window.on_retry_camera(move || open_camera(*active_role_for_retry.locked()));
+
+    let snapshot_for_button = snapshot.clone();
+    window.on_take_snapshot(move || snapshot_for_button.request());
+
+    let focus_for_reset = focus.clone();
+    window.on_reset_focus_peak(move || focus_for_reset.reset_peak());
+
+    // Keyboard shortcuts go through the same callbacks as the buttons
+    let keymap = shortcuts::load().keymap();
+    let window_clone_for_keys = window.as_weak();
+    window.on_shortcut(move |text| {
+        let (Some(win), Some(action)) = (window_clone_for_keys.upgrade(), keymap.action(&text)) else {
+            return false;
+        };
+        match action {
+            Action::Capture if win.get_calibrating() => win.invoke_capture_view(),
+            Action::Cancel if win.get_calibrating() => win.invoke_cancel_calibration(),
+            Action::Snapshot => win.invoke_take_snapshot(),
+            Action::Undistort if win.get_undistort_available() => {
+                let on = !win.get_undistorting();
+                win.set_undistorting(on);
+                win.invoke_undistort_toggled(on);
+            }
+            Action::SwitchCamera => {
+                let next = (win.get_camera_role() + 1) % 2;
+                win.set_camera_role(next);
+                win.invoke_switch_camera(next);
+            }
+            _ => return false,
+        }
+        true
+    });
+
+    // Log panel, newest message on top
+    let log_lines = Rc::new(VecModel::<LogLine>::default());
+    window.set_log_lines(log_lines.clone().into());
+
+    // A new frame redraws the preview straight away, at the camera's rate and whatever
+    // detection does. One redraw is queued at a time, a busy window skips to the newest frame.
+    let wake = frames.subscribe();
+    let redraw_queued = Arc::new(AtomicBool::new(false));
+    let window_for_wake = window.as_weak();
+    runtime().spawn(async move {
+        loop {
+            wake.recv().await;
+            wake.latest();
+            if redraw_queued.swap(true, Ordering::AcqRel) {
+                continue;
+            }
+            let queued = Arc::clone(&redraw_queued);
+            let redraw = window_for_wake.upgrade_in_event_loop(move |window| {
+                queued.store(false, Ordering::Release);
+                window.set_frame(window.get_frame() + 1);
+            });
+            if redraw.is_err() {
+                return; // The event loop is gone
+            }
+        }
+    });
+
+    // Set up a timer to update the status around the preview
+    let focus_for_timer = focus.clone();
+    let frame_stats_for_timer = frame_stats.clone();
+    let markers_for_timer = markers.clone();
+    let timer = Timer::default();
+    timer.start(
+        TimerMode::Repeated,
+        STATUS_REFRESH,
+        move || {
+            if let Some(window) = window_clone_for_render.upgrade() {
+                for entry in status_log::drain() {
+                    let level = match entry.level {
+                        log::Level::Error => 2,
+                        log::Level::Warn => 1,
+                        _ => 0,
+                    };
+                    log_lines.insert(0, LogLine { level, text: entry.text.into() });
+                    if log_lines.row_count() > status_log::MAX_ENTRIES {
+                        log_lines.remove(status_log::MAX_ENTRIES);
+                    }
+                }
+                if let Some((level, peak, text)) = focus_for_timer.reading() {
+                    window.set_focus_level(level);
+                    window.set_focus_peak(peak);
+                    window.set_focus_text(text.into());
+                }
+                if let Some(text) = markers_for_timer.reading() {
+                    window.set_marker_info(text.into());
+                }
+                if let Some(text) = frame_stats_for_timer.reading() {
+                    window.set_frame_stats(text.into());
+                    window.set_stage_timings(ui::stage_timings());
+                }
+            }
+        },
+    );
+
+    // Raw or undistorted preview
+    let undistort_for_toggle = undistort.clone();
+    let measurement_for_undistort = measurement.clone();
+    window.on_undistort_toggled(move |enabled| {
+        undistort_for_toggle.set_enabled(enabled);
+        measurement_for_undistort.clear();
+    });
+    // Raw and undistorted side by side
+    let undistort_for_compare = undistort.clone();
+    let measurement_for_compare = measurement.clone();
+    window.on_compare_toggled(move |enabled, position| {
+        undistort_for_compare.set_split(enabled.then_some(position));
+        measurement_for_compare.clear();
+    });
+    let undistort_for_split = undistort.clone();
+    window.on_split_moved(move |position| undistort_for_split.set_split(Some(position)));
+    let histogram_for_toggle = histogram.clone();
+    window.on_histogram_toggled(move |enabled| histogram_for_toggle.set_enabled(enabled));
+    let reticle_for_toggle = reticle.clone();
+    window.on_reticle_toggled(move |enabled| reticle_for_toggle.set_enabled(enabled));
+    let inspector_for_toggle = inspector.clone();
+    window.on_inspector_toggled(move |enabled| inspector_for_toggle.set_enabled(enabled));
+    let inspector_for_pointer = inspector.clone();
+    let reticle_for_pointer = reticle.clone();
+    let window_clone_for_inspector = window.as_weak();
+    window.on_inspect_pixel(move |u, v| {
+        if let Some(window) = window_clone_for_inspector.upgrade() {
+            let text = inspector_for_pointer.describe(u, v, &reticle_for_pointer);
+            window.set_pixel_info(text.into());
+        }
+    });
+    let measurement_for_toggle = measurement.clone();
+    window.on_measure_toggled(move |enabled| measurement_for_toggle.set_enabled(enabled));
+    let measurement_for_pointer = measurement.clone();
+    let undistort_for_measure = undistort.clone();
+    let reticle_for_measure = reticle.clone();
+    let window_clone_for_measure = window.as_weak();
+    window.on_measure_point(move |u, v| {
+        let text = measurement_for_pointer
+            .click(u, v, &undistort_for_measure, reticle_for_measure.mm_per_px())
+            .unwrap_or_else(|e| format!("Unable to measure: {}", e));
+        if let Some(window) = window_clone_for_measure.upgrade() {
+            window.set_measure_info(text.into());
+        }
+    });
+    let markers_for_toggle = markers.clone();
+    let window_clone_for_markers = window.as_weak();
+    window.on_markers_toggled(move |enabled| {
+        if let Err(e) = markers_for_toggle.set_enabled(enabled) {
+            log::error!("Unable to detect markers: {:?}", e);
+            if let Some(window) = window_clone_for_markers.upgrade() {
+                window.set_marker_info(format!("Unable to detect markers: {}", e).into());
+            }
+        }
+    });
+
+    // The preview's own view of the camera feed
+    let preview = frames.subscribe();
+    let window_for_preview = window.as_weak();
+    let tuning_for_preview = tuning.clone();
+    // What the window shows goes out over NDI too, when asked to
+    let publish = move |frame: &Frame| {
+        if let Some(ndi) = &ndi {
+            ndi.publish(frame);
+        }
+    };
+    // None when there is no frame newer than the one shown
+    let render = move || -> Result<Option<Image>> {
+        let Some(frame) = preview.latest() else {
+            return Ok(None);
+        };
+        log::debug!("Preview of frame {} after {:.1} ms", frame.sequence, frame.age_ms());
+        frame_stats.record(&frame);
+        // A running calibration's annotations of the last frame it looked at, over this one
+        if window_for_preview.upgrade().is_some_and(|window| window.get_calibrating()) {
+            let frame = overlay.apply(frame)?;
+            snapshot.take_if_requested(&frame, &undistort);
+            let frame = reticle.apply(frame, undistort.principal_point())?;
+            let frame = histogram.apply(frame)?;
+            publish(&frame);
+            return Ok(Some(ui::to_image(&frame)));
+        }
+        focus.measure(&frame, None)?;
+        snapshot.take_if_requested(&frame, &undistort);
+        // What detection will look at, to tune the steps in tuning.toml by
+        let frame = if window_for_preview.upgrade().is_some_and(|window| window.get_preprocessed_shown()) {
+            preprocess::processed_view(&tuning_for_preview.borrow().preprocess, frame)?
+        } else {
+            frame
+        };
+        let frame = undistort.apply(frame)?;
+        inspector.keep(&frame);
+        let frame = markers.apply(frame)?;
+        let frame = measurement.apply(frame)?;
+        let frame = reticle.apply(frame, undistort.principal_point())?;
+        let frame = histogram.apply(frame)?;
+        publish(&frame);
+        Ok(Some(ui::to_image(&frame)))
+    };
+
+    // Handle rendering of images in Slint window, the last image stays up until there is a
+    // newer frame
+    let shown = RefCell::new(Image::default());
+    window.on_render_image(move |_frame| {
+        let rendering = Instant::now();
+        match render() {
+            Ok(Some(image)) => {
+                metrics::record_since(Stage::Render, rendering);
+                *shown.borrow_mut() = image;
+            }
+            Ok(None) => {}
+            Err(err) => log::error!("Error rendering image: {:?}", err),
+        }
+        shown.borrow().clone()
+    });
+
+    settings::restore(&window, &ui_settings);
+    let result = window.run();
+    settings::save(&settings::from_window(&window, &ui_settings));
+
+    // Everything is wound down even when the event loop failed, and its error comes first
+    let shutdown = crate::shut_down(calibration_task.locked().take(), capture.locked().take());
+    result?;
+    shutdown
+}
@@ -0,0 +1,373 @@
+use crate::cli::Args;
+use anyhow::{bail, Result};
+use rustyrabbit_core::{
+    calibration::{self, CalibrationControls, CalibrationSession, SharedViews},
+    capture::CaptureHandle,
+    events::{Event, EventBus, EventSubscriber},
+    focus::FocusMeter,
+    frame::{Frame, FrameFeed, FrameSubscriber},
+    lock::Locked,
+    ndi::NdiOutput,
+    plugin::{self, TargetPlugin},
+    preview::{PreviewOverlay, Undistort},
+    profiles::{self, Calibration, CameraRole},
+    runtime::runtime,
+    session_log::{self, Started},
+    target::{self, CalibrationTarget},
+    target_print::{TargetKind, TargetSpec},
+    tuning::{self, Tuning},
+    workflow::{Workflow, WorkflowState},
+};
+#[cfg(feature = "onnx")]
+use rustyrabbit_core::corner_model::{CornerModel, WithCornerModel};
+use std::{
+    sync::{Arc, Mutex, MutexGuard},
+    thread,
+    time::Duration,
+};
+use tokio::{sync::watch, task::JoinHandle};
+
+// A target and how to capture it
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalibrationRequest {
+    // A chess board unless a replayed session used another target
+    pub kind: TargetKind,
+    // Inner corners as (columns, rows)
+    pub board: (i32, i32),
+    pub square_mm: f64,
+    pub frames: usize,
+    pub manual: bool,
+    // Seconds between automatic captures
+    pub countdown: u64,
+    pub stable_frames: usize,
+}
+
+impl CalibrationRequest {
+    pub fn from_args(args: &Args) -> Self {
+        CalibrationRequest {
+            kind: TargetKind::ChessBoard,
+            board: args.board,
+            square_mm: args.square_mm,
+            frames: args.frames.max(calibration::MIN_CALIBRATION_FRAMES),
+            manual: args.manual,
+            countdown: args.countdown(),
+            stable_frames: args.stable_frames(),
+        }
+    }
+
+    // For settings that came from a browser or another program rather than clap
+    pub fn check(&self) -> Result<()> {
+        if self.board.0 < 2 || self.board.1 < 2 {
+            bail!("The board needs at least 2x2 inner corners");
+        }
+        if self.square_mm <= 0.0 || !self.square_mm.is_finite() {
+            bail!("Square size {} is not a length in mm", self.square_mm);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct ReportState {
+    pub calibrating: bool,
+    pub workflow: WorkflowState,
+    pub captured: usize,
+    pub required: usize,
+    pub status: String,
+    // Frame number and reprojection error of each view of the last solve
+    pub views: Vec<(u64, Option<f64>)>,
+    pub calibration: Option<Calibration>,
+}
+
+// Calibration progress and results, read back by whichever interface shows them
+#[derive(Clone, Default)]
+pub struct SharedReport(Arc<Mutex<ReportState>>);
+
+impl SharedReport {
+    pub fn state(&self) -> MutexGuard<'_, ReportState> {
+        self.0.locked()
+    }
+
+    // Keeps the state up to date on a thread of its own until the app goes away
+    fn follow(&self, mut events: EventSubscriber) {
+        let report = self.clone();
+        thread::spawn(move || {
+            while let Some(event) = events.blocking_recv() {
+                report.apply(event);
+            }
+        });
+    }
+
+    fn apply(&self, event: Event) {
+        let mut state = self.state();
+        match event {
+            Event::CalibratingChanged(calibrating) => state.calibrating = calibrating,
+            Event::WorkflowChanged(workflow) => state.workflow = workflow,
+            Event::CalibrationProgress {
+                captured,
+                required,
+                status,
+            } => {
+                state.captured = captured;
+                state.required = required;
+                state.status = status;
+            }
+            Event::Status(status) | Event::Error(status) => state.status = status,
+            Event::Solved { views, calibration } => {
+                state.views = views.errors();
+                state.calibration = Some(calibration);
+            }
+            // Camera and recording changes already go to the log, which these interfaces
+            // show, and none of them shows the views' images
+            Event::CameraStateChanged { .. }
+            | Event::RecordingChanged(_)
+            | Event::ViewsCleared
+            | Event::ViewAccepted(_)
+            | Event::ViewRemoved(_) => {}
+        }
+    }
+}
+
+// The camera and calibration pipeline without the main window, for the terminal and browser
+// interfaces. Uses the same pieces the window does.
+pub struct HeadlessApp {
+    args: Arc<Args>,
+    capture: Mutex<Option<CaptureHandle>>,
+    active_role: Mutex<CameraRole>,
+    frames: FrameFeed,
+    preview: FrameSubscriber,
+    overlay: PreviewOverlay,
+    undistort: Undistort,
+    pub controls: CalibrationControls,
+    // The running or last calibration task, waited for on stop
+    calibration: Mutex<Option<JoinHandle<()>>>,
+    views: SharedViews,
+    events: EventBus,
+    workflow: Workflow,
+    // Thresholds and overlays from tuning.toml, as it is now
+    tuning: watch::Receiver<Tuning>,
+    report: SharedReport,
+    // Makes the targets when --target-plugin is set, chess boards are calibrated without
+    target_plugin: Option<Arc<TargetPlugin>>,
+    // The annotated frames for monitors on the network, with --ndi
+    _ndi: Option<NdiOutput>,
+    // Finds the chess boards the classical detector misses, with --corner-model
+    #[cfg(feature = "onnx")]
+    corner_model: Option<Arc<CornerModel>>,
+}
+
+impl HeadlessApp {
+    pub fn start(args: Arc<Args>, role: CameraRole, frames: FrameFeed) -> Result<Self> {
+        let events = EventBus::default();
+        let report = SharedReport::default();
+        report.follow(events.subscribe());
+        let target_plugin = args.target_plugin.as_deref().map(plugin::find).transpose()?;
+        #[cfg(feature = "onnx")]
+        let corner_model = args.corner_model.as_deref().map(CornerModel::load).transpose()?.map(Arc::new);
+        let profile = profiles::load()?.get(role).clone();
+        let (capture, _) = crate::start_capture(
+            &args,
+            &profile,
+            &args.camera_settings(),
+            None,
+            frames.clone(),
+            events.clone(),
+        )?;
+        let overlay = PreviewOverlay::default();
+        let ndi = match &args.ndi {
+            Some(name) => {
+                let (report, overlay) = (report.clone(), overlay.clone());
+                let prepare = move |frame: Frame| annotate(&report, &overlay, &frame).unwrap_or(frame);
+                Some(NdiOutput::follow(name, &frames, prepare)?)
+            }
+            None => None,
+        };
+        let app = HeadlessApp {
+            args,
+            capture: Mutex::new(Some(capture)),
+            active_role: Mutex::new(role),
+            preview: frames.subscribe(),
+            frames,
+            overlay,
+            undistort: Undistort::default(),
+            controls: CalibrationControls::default(),
+            calibration: Mutex::new(None),
+            views: SharedViews::default(),
+            workflow: Workflow::new(events.clone()),
+            tuning: tuning::watch(),
+            events,
+            report,
+            target_plugin,
+            _ndi: ndi,
+            #[cfg(feature = "onnx")]
+            corner_model,
+        };
+        app.use_calibration(profile.calibration);
+        Ok(app)
+    }
+
+    pub fn role(&self) -> CameraRole {
+        *self.active_role.locked()
+    }
+
+    pub fn report(&self) -> &SharedReport {
+        &self.report
+    }
+
+    fn use_calibration(&self, calibration: Option<Calibration>) {
+        self.undistort.set_calibration(calibration.clone());
+        let mut state = self.report.state();
+        state.views.clear();
+        state.calibration = calibration;
+    }
+
+    // The newest frame to show, with a running calibration's annotations of the last frame
+    // it looked at over it. Frames published between two calls are skipped.
+    pub fn next_frame(&self) -> Option<Frame> {
+        let frame = self.preview.latest()?;
+        Some(self.annotate(&frame).unwrap_or(frame))
+    }
+
+    // The newest frame as the camera delivered it, for a viewer that asked for no overlays
+    pub fn next_raw_frame(&self) -> Option<Frame> {
+        self.preview.latest()
+    }
+
+    // The frame with a running calibration's annotations, None when nothing is drawn
+    pub fn annotate(&self, frame: &Frame) -> Option<Frame> {
+        annotate(&self.report, &self.overlay, frame)
+    }
+
+    // Between the top and bottom camera, not while calibrating
+    pub fn switch_camera(&self) {
+        if self.workflow.state().is_busy() {
+            return;
+        }
+        let role = match self.role() {
+            CameraRole::Top => CameraRole::Bottom,
+            CameraRole::Bottom => CameraRole::Top,
+        };
+        match crate::switch_camera(
+            role,
+            &self.args,
+            &self.capture,
+            &self.active_role,
+            None,
+            self.frames.clone(),
+            self.events.clone(),
+        ) {
+            Ok(profile) => self.use_calibration(profile.calibration),
+            Err(e) => log::error!("Error switching camera: {:?}", e),
+        }
+    }
+
+    // Switches to `role` unless it's in use already. Turned down while calibrating.
+    pub fn use_camera(&self, role: CameraRole) -> Result<()> {
+        if self.role() != role {
+            self.switch_camera();
+        }
+        if self.role() != role {
+            bail!("Unable to switch to the {} now", role.label());
+        }
+        Ok(())
+    }
+
+    // The first frame captured after the call, for a look at the scene as it is now rather
+    // than as it was while the machine was still moving
+    pub fn fresh_frame(&self, limit: Duration) -> Result<Frame> {
+        match self.frames.subscribe().recv_timeout(limit) {
+            Some(frame) => Ok(frame),
+            None => bail!("The {} delivered no frame within {} ms", self.role().label(), limit.as_millis()),
+        }
+    }
+
+    // Turned down while a calibration is running already, or when the target can't be made
+    pub fn start_calibration(&self, request: &CalibrationRequest) -> Result<()> {
+        let (cols, rows) = request.board;
+        let required_frames = request.frames.max(calibration::MIN_CALIBRATION_FRAMES);
+        let square_size = request.square_mm;
+        let spec = TargetSpec {
+            kind: request.kind.clone(),
+            rows,
+            cols,
+            square_mm: square_size,
+        };
+        let target: Arc<dyn CalibrationTarget> = match &self.target_plugin {
+            Some(plugin) => Arc::new(plugin.create(cols, rows, square_size)?),
+            None => Arc::from(target::from_spec(&spec)?),
+        };
+        #[cfg(feature = "onnx")]
+        let target: Arc<dyn CalibrationTarget> = match &self.corner_model {
+            Some(model) if self.target_plugin.is_none() && spec.kind == TargetKind::ChessBoard => {
+                Arc::new(WithCornerModel::new(target, Arc::clone(model)))
+            }
+            _ => target,
+        };
+        self.workflow.start(WorkflowState::Configured)?;
+        session_log::started(Started {
+            kind: self.target_plugin.is_none().then_some(spec.kind),
+            plugin: self.target_plugin.as_ref().map(|plugin| plugin.name().to_string()),
+            cols,
+            rows,
+            square_mm: square_size,
+            frames: required_frames,
+            manual: request.manual,
+            countdown_secs: request.countdown,
+            stable_frames: request.stable_frames,
+        });
+        log::info!(
+            "Calibration started with a {}, square: {} mm, frames: {}",
+            target.describe(), square_size, required_frames
+        );
+        self.controls.reset();
+        let session = CalibrationSession {
+            frames: self.frames.clone(),
+            role: self.role(),
+            overlay: self.overlay.clone(),
+            undistort: self.undistort.clone(),
+            focus: FocusMeter::default(),
+            controls: self.controls.clone(),
+            manual_capture: request.manual,
+            stable_frames: request.stable_frames,
+            countdown: Duration::from_secs(request.countdown),
+            confirm_beep: !self.args.no_capture_beep,
+            confirm_flash: !self.args.no_capture_flash,
+            detection: self.args.detection(),
+            tuning: self.tuning.clone(),
+            views: Arc::clone(&self.views),
+            events: self.events.clone(),
+            workflow: self.workflow.clone(),
+        };
+        // Marked before the task starts so frames are left to it from now on, the event
+        // arrives a moment later
+        self.report.state().calibrating = true;
+        session.set_calibrating(true);
+        let task = runtime().spawn(async move {
+            if let Err(e) = calibration::run_calibration(target, required_frames, &session).await {
+                log::error!("Error during calibration: {:?}", e);
+                session.events.publish(Event::Error(format!("Calibration failed: {}", e)));
+            }
+            session.set_calibrating(false);
+        });
+        *self.calibration.locked() = Some(task);
+        Ok(())
+    }
+
+    // Cancels a running calibration and waits for it and the camera to wind down
+    pub fn stop(&self) -> Result<()> {
+        crate::shut_down(self.calibration.locked().take(), self.capture.locked().take())
+    }
+}
+
+fn annotate(report: &SharedReport, overlay: &PreviewOverlay, frame: &Frame) -> Option<Frame> {
+    if !report.state().calibrating {
+        return None;
+    }
+    match overlay.apply(frame.clone()) {
+        Ok(annotated) => Some(annotated),
+        Err(e) => {
+            log::warn!("Unable to draw the calibration over the preview: {:?}", e);
+            None
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Point, Rect, Scalar},
+    imgproc::{cvt_color, line, put_text, rectangle, COLOR_RGBA2GRAY, FILLED, FONT_HERSHEY_SIMPLEX, LINE_8, LINE_AA},
+    prelude::*,
+};
+use rustyrabbit_core::frame::{Frame, FrameBuffer};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+// Grey levels at or beyond these count as crushed shadows / blown highlights
+const SHADOW_CLIP: usize = 2;
+const HIGHLIGHT_CLIP: usize = 253;
+// Share of the image that has to be clipped before the indicator lights up
+const CLIP_WARNING: f64 = 0.001;
+const PANEL_WIDTH: i32 = 256;
+const PANEL_HEIGHT: i32 = 100;
+const MARGIN: i32 = 10;
+
+// Luminance histogram drawn into the corner of the preview, for setting exposure before capturing
+#[derive(Clone, Default)]
+pub struct Histogram(Arc<AtomicBool>);
+
+impl Histogram {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn apply(&self, frame: Frame) -> Result<Frame> {
+        if !self.0.load(Ordering::Relaxed) {
+            return Ok(frame);
+        }
+        let mut rgba = Mat::from_slice(frame.data.as_slice())?.reshape(4, frame.height)?.try_clone()?;
+        draw(&mut rgba)?;
+        Ok(frame.with_pixels(FrameBuffer::copy_of(rgba.data_bytes()?)))
+    }
+}
+
+fn draw(rgba: &mut Mat) -> Result<()> {
+    let mut gray = Mat::default();
+    cvt_color(&*rgba, &mut gray, COLOR_RGBA2GRAY, 0)?;
+    let mut bins = [0u64; 256];
+    for &value in gray.data_bytes()? {
+        bins[value as usize] += 1;
+    }
+    let total = gray.total() as f64;
+    let shadows = bins[..=SHADOW_CLIP].iter().sum::<u64>() as f64 / total;
+    let highlights = bins[HIGHLIGHT_CLIP..].iter().sum::<u64>() as f64 / total;
+
+    // Bottom left corner, shrunk to fit small frames
+    let width = PANEL_WIDTH.min(rgba.cols() - 2 * MARGIN);
+    let height = PANEL_HEIGHT.min(rgba.rows() / 3);
+    if width < 32 || height < 16 {
+        return Ok(());
+    }
+    let panel = Rect::new(MARGIN, rgba.rows() - MARGIN - height, width, height);
+    rectangle(rgba, panel, Scalar::new(0.0, 0.0, 0.0, 255.0), FILLED, LINE_8, 0)?;
+
+    // Scaled to the tallest unclipped bin, a spike at either end would flatten the rest
+    let tallest = bins[SHADOW_CLIP + 1..HIGHLIGHT_CLIP].iter().copied().max().unwrap_or(0).max(1) as f64;
+    let bar_color = Scalar::new(230.0, 230.0, 230.0, 255.0);
+    for x in 0..width {
+        let bin = (x * 256 / width) as usize;
+        let bar = ((bins[bin] as f64 / tallest).min(1.0) * (height - 2) as f64) as i32;
+        if bar > 0 {
+            let px = panel.x + x;
+            let bottom = panel.y + height - 1;
+            line(rgba, Point::new(px, bottom), Point::new(px, bottom - bar), bar_color, 1, LINE_8, 0)?;
+        }
+    }
+
+    // Clipping indicators at either end of the panel
+    let indicator = 6;
+    if shadows > CLIP_WARNING {
+        let rect = Rect::new(panel.x, panel.y, indicator, height);
+        rectangle(rgba, rect, Scalar::new(0.0, 120.0, 255.0, 255.0), FILLED, LINE_8, 0)?;
+    }
+    if highlights > CLIP_WARNING {
+        let rect = Rect::new(panel.x + width - indicator, panel.y, indicator, height);
+        rectangle(rgba, rect, Scalar::new(255.0, 0.0, 0.0, 255.0), FILLED, LINE_8, 0)?;
+        let text = format!("{:.1}% blown", highlights * 100.0);
+        put_text(
+            rgba,
+            &text,
+            Point::new(panel.x + 2 * indicator, panel.y + 14),
+            FONT_HERSHEY_SIMPLEX,
+            0.4,
+            Scalar::new(255.0, 80.0, 80.0, 255.0),
+            1,
+            LINE_AA,
+            false,
+        )?;
+    }
+    Ok(())
+}
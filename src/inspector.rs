@@ -0,0 +1,55 @@
+use crate::reticle::Reticle;
+use opencv::core::Point2d;
+use rustyrabbit_core::{frame::Frame, lock::Locked};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+// Reads back pixel values of the preview under the pointer, for debugging detection thresholds
+#[derive(Clone, Default)]
+pub struct PixelInspector {
+    enabled: Arc<AtomicBool>,
+    // Last preview frame, before any overlays were drawn onto it
+    frame: Arc<Mutex<Option<Frame>>>,
+}
+
+impl PixelInspector {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+        if !enabled {
+            *self.frame.locked() = None;
+        }
+    }
+
+    // Copying every frame isn't free, so only while inspecting
+    pub fn keep(&self, frame: &Frame) {
+        if self.enabled.load(Ordering::Relaxed) {
+            *self.frame.locked() = Some(frame.clone());
+        }
+    }
+
+    // `u` and `v` run from 0 to 1 across the frame
+    pub fn describe(&self, u: f32, v: f32, reticle: &Reticle) -> String {
+        let guard = self.frame.locked();
+        let Some(frame) = guard.as_ref() else {
+            return String::new();
+        };
+        let x = ((u * frame.width as f32) as i32).clamp(0, frame.width - 1);
+        let y = ((v * frame.height as f32) as i32).clamp(0, frame.height - 1);
+        let offset = ((y * frame.width + x) * 4) as usize;
+        let [r, g, b] = [frame.data[offset], frame.data[offset + 1], frame.data[offset + 2]];
+        // Same weights as OpenCV's RGB to gray conversion
+        let gray = (0.299 * r as f64 + 0.587 * g as f64 + 0.114 * b as f64).round();
+
+        let sensor = frame.geometry.to_sensor_point(Point2d::new(x as f64, y as f64));
+        let mut text = format!(
+            "x {} y {} (sensor {:.1}, {:.1})  RGB {} {} {}  gray {}",
+            x, y, sensor.x, sensor.y, r, g, b, gray
+        );
+        if let Some((dx, dy)) = reticle.offset_mm(sensor) {
+            text += &format!("  {:+.3}, {:+.3} mm from datum", dx, dy);
+        }
+        text
+    }
+}
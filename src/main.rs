@@ -1,320 +1,311 @@
+#[cfg(feature = "web")]
+mod api;
+mod batch;
+mod cli;
+#[cfg(feature = "gui")]
+mod clipboard;
+mod formats;
+#[cfg(feature = "gui")]
+mod frame_stats;
+#[cfg(feature = "grpc")]
+mod grpc;
+#[cfg(feature = "gui")]
+mod gui;
+mod headless;
+#[cfg(feature = "gui")]
+mod histogram;
+#[cfg(feature = "gui")]
+mod inspector;
+#[cfg(feature = "gui")]
+mod measure;
+#[cfg(feature = "metrics")]
+mod prometheus;
+mod replay;
+#[cfg(feature = "gui")]
+mod reticle;
+#[cfg(feature = "ros")]
+mod ros;
+#[cfg(feature = "script")]
+mod script;
+#[cfg(feature = "gui")]
+mod settings;
+#[cfg(feature = "gui")]
+mod shortcuts;
+#[cfg(feature = "gui")]
+mod snapshot;
+mod status_log;
+#[cfg(feature = "gui")]
+mod targets;
+#[cfg(feature = "tui")]
+mod tui;
+#[cfg(feature = "gui")]
+mod ui;
+#[cfg(feature = "web")]
+mod web;
+
 use anyhow::Result;
-use opencv::{
-    calib3d::{calibrate_camera, find_chessboard_corners, CALIB_CB_ADAPTIVE_THRESH, CALIB_CB_NORMALIZE_IMAGE},
-    core::{Mat, MatTraitConst, Point2f, Point3f, Size, TermCriteria, TermCriteria_Type, Vector, CV_32F},
-    highgui::{destroy_all_windows, imshow, wait_key},
-    imgproc::{corner_sub_pix, cvt_color, COLOR_BGR2GRAY, COLOR_BGR2RGBA},
-    prelude::*,
-    videoio::{self, VideoCapture, VideoCaptureTrait, VideoWriter, VideoWriterTrait},
+use cli::{Args, Command};
+use opencv::core::Size;
+use rustyrabbit_core::{
+    acceleration,
+    bench,
+    calibration::CANCEL_TIMEOUT,
+    camera_settings::{self, CameraSettings},
+    capture::{self, CaptureConfig, CaptureHandle},
+    error::CameraError,
+    events::EventBus,
+    frame::FrameFeed,
+    lock::Locked,
+    metrics,
+    preview::Undistort,
+    profiles::{self, CameraProfile, CameraRole},
+    runtime,
+    session_log,
+    shared_frames::SharedFrames,
+    source::{SourceSpec, TriggerMode},
+    virtual_camera::VirtualCamera,
 };
-use slint::{Image, SharedString, Timer, TimerMode};
 use std::{
-    io::{stderr, Write},
-    sync::{
-        mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
-    },
-    thread,
-    thread::{spawn, JoinHandle},
-    time::Duration,
+    path::PathBuf,
+    sync::{Arc, Mutex},
 };
+use tokio::task::JoinHandle;
+
+// Rounds of --benchmark-opencl, enough for a steady average
+const BENCHMARK_FRAMES: u32 = 100;
 
 // Import your Slint UI file
+#[cfg(feature = "gui")]
 slint::include_modules!();
 
-const CAMERA_INDEX: i32 = 0;
-
-#[derive(Debug)]
-enum CalibrationType {
-    ChessBoard,
-    CircleGrid,
-    RabbitPAruco,
-}
-
 fn main() -> Result<()> {
-    env_logger::init();
+    let args = Arc::new(Args::from_command_line());
+    let _log_file = status_log::init(args.log_dir.as_deref())?;
 
-    let (frame_sender, frame_receiver) = channel();
-    let (exit_sender, exit_receiver) = channel();
-
-    // Wrap frame_receiver in Arc<Mutex<Receiver<T>>>
-    let frame_receiver = Arc::new(Mutex::new(frame_receiver));
-
-    // Initialize camera
-    let camera = VideoCapture::new(CAMERA_INDEX, videoio::CAP_ANY)?;
-    if !camera.is_opened()? {
-        panic!("Unable to open default camera!");
+    if args.benchmark_opencl {
+        let frame_size = Size::new(args.width.unwrap_or(1920), args.height.unwrap_or(1080));
+        println!("{}", acceleration::benchmark(frame_size, BENCHMARK_FRAMES)?.summary());
+        return Ok(());
+    }
+    #[cfg(not(feature = "cuda"))]
+    if args.cuda {
+        anyhow::bail!("Built without CUDA, rebuild with --features cuda");
+    }
+    #[cfg(not(feature = "onnx"))]
+    if args.corner_model.is_some() {
+        anyhow::bail!("Built without ONNX Runtime, rebuild with --features onnx");
+    }
+    acceleration::set_backend(args.acceleration());
+    if let Some(threads) = args.cv_threads {
+        acceleration::set_threads(threads)?;
     }
 
-    // Get camera parameters
-    let frame_width = camera.get(videoio::CAP_PROP_FRAME_WIDTH)? as i32;
-    let frame_height = camera.get(videoio::CAP_PROP_FRAME_HEIGHT)? as i32;
-    let fps = camera.get(videoio::CAP_PROP_FPS)?;
-    println!(
-        "Camera: width {}, height {}, FPS: {}",
-        frame_width, frame_height, fps
-    );
-
-    // Initialize Slint window
-    let window = MainWindow::new()?;
-    let window_clone_for_callback = window.as_weak(); // Clone for use in calibration callback
-    let window_clone_for_render = window.as_weak(); // Clone for use in render closure
-
-    let frame_receiver_for_callback = Arc::clone(&frame_receiver); // Clone for callback use
-    window.on_calibration_wrapper_callback(move |selected_calibration, grid_rows, grid_cols, loc_x, loc_y| {
-        // Convert integer to enum
-        let calibration_type = match selected_calibration {
-            0 => CalibrationType::ChessBoard,
-            1 => CalibrationType::CircleGrid,
-            2 => CalibrationType::RabbitPAruco,
-            _ => {
-                eprintln!("Unknown calibration type selected: {}", selected_calibration);
-                stderr().flush().unwrap();
-                return;
-            }
-        };
-
-        eprintln!(
-            "Calibration started with type: {:?}, rows: {}, cols: {}, loc_x: {}, loc_y: {}",
-            calibration_type, grid_rows, grid_cols, loc_x, loc_y
-        );
-        stderr().flush().unwrap();
-
-        // Perform calibration in a separate thread to avoid blocking the UI
-        let window_clone = window_clone_for_callback.clone(); // Clone for use in this thread
-        let frame_receiver = Arc::clone(&frame_receiver_for_callback); // Clone again for thread use
-        thread::spawn(move || {
-            match calibration_type {
-                CalibrationType::ChessBoard => {
-                    if let Err(e) = start_chessboard_calibration(grid_rows, grid_cols, &frame_receiver, frame_width, frame_height, window_clone) {
-                        eprintln!("Error during calibration: {:?}", e);
-                    }
-                }
-                CalibrationType::CircleGrid => {
-                    if let Err(e) = start_circle_grid_calibration(grid_rows, grid_cols) {
-                        eprintln!("Error during calibration: {:?}", e);
-                    }
-                }
-                CalibrationType::RabbitPAruco => {
-                    if let Err(e) = start_aruco_calibration(loc_x, loc_y) {
-                        eprintln!("Error during calibration: {:?}", e);
-                    }
-                }
-            }
-        });
+    // Pick the camera role, anything set for it on the command line is remembered
+    let mut camera_profiles = profiles::load().unwrap_or_else(|e| {
+        log::warn!("Ignoring stored camera profiles: {:?}", e);
+        Default::default()
     });
+    let role = args.role.unwrap_or(camera_profiles.active);
+    if args.apply_to_profile(camera_profiles.get_mut(role)) || camera_profiles.active != role {
+        camera_profiles.active = role;
+        if let Err(e) = profiles::save(&camera_profiles) {
+            log::error!("Error saving camera profiles: {:?}", e);
+        }
+    }
+    log::info!("Using the {}", role.label());
+
+    // Reads from the profile's camera, then times the rest of the pipeline on made up frames.
+    // Runs before the session log and any outputs start, so a benchmark opens no ports and
+    // writes no session files.
+    if let Some(Command::Bench { rounds }) = &args.command {
+        let source = args.source_spec(camera_profiles.get(role), &args.camera_settings());
+        println!("{}", serde_json::to_string_pretty(&bench::run(&source, *rounds)?)?);
+        return Ok(());
+    }
 
-    // Set up a timer to update frames in the Slint window
-    let timer = Timer::default();
-    timer.start(
-        TimerMode::Repeated,
-        Duration::from_secs_f32(1.0 / (fps + 10.0) as f32), // Adjusting for smoother video display
-        move || {
-            if let Some(window) = window_clone_for_render.upgrade() {
-                window.set_frame(window.get_frame() + 1);
-            }
-        },
-    );
+    // Written out when main returns, whichever front end ran
+    let _session_log = args.record_session.as_deref().map(session_log::start).transpose()?;
 
-    // Start the camera thread to handle capturing frames
-    let camera_thread = start_camera_thread(
-        frame_sender,
-        exit_receiver,
-        camera,
-        frame_width as f64,
-        frame_height as f64,
-        fps,
-    )?;
+    // Every frame of the camera task, for the preview and calibration alike
+    let frames = FrameFeed::default();
 
-    // Use the Arc<Mutex<Receiver>> in the render closure
-    let frame_receiver_render = Arc::clone(&frame_receiver);
-    let render = move || -> Result<Image> {
-        let receiver = frame_receiver_render.lock().unwrap();
-        if let Ok(frame_rgba) = receiver.try_recv() {
-            let frame_data: Vec<u8> = frame_rgba; // Use directly
-            let image = Image::from_rgba8(slint::SharedPixelBuffer::clone_from_slice(
-                frame_data.as_slice(),
-                frame_width as u32,
-                frame_height as u32,
-            ));
-            Ok(image)
-        } else {
-            Ok(Image::default())
+    for role in [CameraRole::Top, CameraRole::Bottom] {
+        let rms = camera_profiles.get(role).calibration.as_ref().map(|calibration| calibration.rms);
+        metrics::set_calibration_rms(role, rms);
+    }
+    #[cfg(feature = "metrics")]
+    if let Some(address) = &args.metrics {
+        prometheus::serve(address)?;
+    }
+
+    // Shared until main returns, whichever front end ran
+    let _shared_frames = match &args.shared_frames {
+        Some(path) => {
+            let undistort = args.shared_frames_undistorted.then(|| {
+                let undistort = Undistort::default();
+                undistort.set_calibration(camera_profiles.get(role).calibration.clone());
+                undistort
+            });
+            Some(SharedFrames::start(path, &frames, undistort)?)
         }
+        None => None,
+    };
+    let _virtual_camera = match &args.virtual_camera {
+        Some(device) => {
+            let Some(calibration) = camera_profiles.get(role).calibration.clone() else {
+                anyhow::bail!("--virtual-camera needs a calibration of the {} to undistort with", role.label());
+            };
+            let undistort = Undistort::default();
+            undistort.set_calibration(Some(calibration));
+            Some(VirtualCamera::start(device, &frames, undistort)?)
+        }
+        None => None,
     };
 
-    // Handle rendering of images in Slint window
-    window.on_render_image(move |_frame| {
-        render().unwrap_or_else(|err| {
-            eprintln!("Error rendering image: {:?}", err);
-            Image::default()
-        })
-    });
-
-    window.run()?;
+    if args.replay.is_some() {
+        return replay::run(args, role, frames);
+    }
+    // No window at all, for machines without a display server
+    #[cfg(feature = "tui")]
+    if args.tui {
+        return tui::run(args, role, frames);
+    }
+    #[cfg(feature = "web")]
+    if args.web.is_some() {
+        return web::run(args, role, frames);
+    }
+    if args.ros {
+        #[cfg(not(feature = "ros"))]
+        anyhow::bail!("Built without ROS 2, rebuild with --features ros in a sourced ROS 2 workspace");
+        #[cfg(feature = "ros")]
+        return ros::run(args, role, frames);
+    }
+    if args.grpc.is_some() {
+        #[cfg(not(feature = "grpc"))]
+        anyhow::bail!("Built without gRPC, rebuild with --features grpc");
+        #[cfg(feature = "grpc")]
+        return grpc::run(args, role, frames);
+    }
+    #[cfg(feature = "script")]
+    if args.script.is_some() {
+        return script::run(args, role, frames);
+    }
+    if args.headless {
+        return batch::run(args, role, frames);
+    }
 
-    exit_sender.send(())?;
-    camera_thread.join().unwrap()?;
-    println!("Camera stopped and resources released");
-    destroy_all_windows()?; // Close all OpenCV windows
-    Ok(())
+    #[cfg(not(feature = "gui"))]
+    anyhow::bail!("Built without the window, use --headless or another interface built in");
+    #[cfg(feature = "gui")]
+    gui::run(args, camera_profiles, frames)
 }
 
-fn start_camera_thread(
-    frame_sender: Sender<Vec<u8>>,
-    exit_receiver: Receiver<()>,
-    mut camera: VideoCapture,
-    frame_width: f64,
-    frame_height: f64,
-    fps: f64,
-) -> Result<JoinHandle<Result<()>>> {
-    Ok(spawn(move || -> Result<()> {
-        let fourcc = VideoWriter::fourcc('m', 'p', '4', 'v')?;
-        let mut out = VideoWriter::new(
-            "output.mp4",
-            fourcc,
-            fps,
-            Size::new(frame_width as i32, frame_height as i32),
-            true,
-        )?;
-
-        let mut frame_bgr = Mat::default();
-        let mut frame_rgba = Mat::default();
-        loop {
-            if exit_receiver.try_recv().is_ok() {
-                break;
-            } else {
-                camera.read(&mut frame_bgr)?;
-
-                cvt_color(&frame_bgr, &mut frame_rgba, COLOR_BGR2RGBA, 0)?;
-
-                frame_sender.send(frame_rgba.data_bytes()?.to_vec())?;
-
-                if frame_bgr.size()?.width > 0 {
-                    out.write(&frame_bgr)?;
-                }
-
-                std::thread::sleep(Duration::from_millis(10)); // Add delay to control capture rate
+// Opens the camera for a profile and starts its capture thread, returns the thread and the
+// camera's frame rate
+fn start_capture(
+    args: &Args,
+    profile: &CameraProfile,
+    overrides: &CameraSettings,
+    record_dir: Option<PathBuf>,
+    frames: FrameFeed,
+    events: EventBus,
+) -> Result<(CaptureHandle, f64)> {
+    // Initialize the frame source: a camera, a folder of stills or a video
+    let source_spec = args.source_spec(profile, overrides);
+    if args.trigger_mode == TriggerMode::Hardware && !source_spec.supports_hardware_trigger() {
+        return Err(CameraError::HardwareTriggerUnsupported.into());
+    }
+    let source = source_spec.open()?;
+
+    // Anything tuned on the command line becomes this camera's new defaults
+    if let SourceSpec::Camera { index, settings, .. } = &source_spec {
+        if !overrides.is_empty() {
+            let key = camera_settings::device_key(*index);
+            match camera_settings::save(&key, settings) {
+                Ok(()) => log::info!("Saved camera settings for {}", key),
+                Err(e) => log::error!("Error saving camera settings: {:?}", e),
             }
         }
-        Ok(())
-    }))
-}
-
-fn start_chessboard_calibration(
-    grid_rows: i32,
-    grid_cols: i32,
-    frame_receiver: &Arc<Mutex<Receiver<Vec<u8>>>>,
-    frame_width: i32,
-    frame_height: i32,
-    window: slint::Weak<MainWindow>,
-) -> Result<()> {
-    let board_size = Size::new(grid_cols, grid_rows);
-
-    let object_point_set: Vector<Point3f> = (0..grid_rows)
-        .flat_map(|row| (0..grid_cols).map(move |col| Point3f::new(row as f32, col as f32, 0.)))
-        .collect();
-
-    let mut captured_frames = 0;
-    const REQUIRED_FRAMES: usize = 10; // Number of frames to capture for calibration
-
-    let mut object_points: Vector<Vector<Point3f>> = Vector::new();
-    let mut image_points: Vector<Vector<Point2f>> = Vector::new();
-
-    // Capture frames and detect chessboard corners
-    while captured_frames < REQUIRED_FRAMES {
-        if let Ok(frame_data) = frame_receiver.lock().unwrap().try_recv() {
-            let frame_slice = Mat::from_slice(frame_data.as_slice())?;
-            let frame_mat = frame_slice.reshape(4, frame_height)?;
-
-            let mut gray = Mat::default();
-            cvt_color(&frame_mat, &mut gray, COLOR_BGR2GRAY, 0)?;
-
-            let mut corners = opencv::types::VectorOfPoint2f::new();
-            let found = find_chessboard_corners(
-                &gray,
-                board_size,
-                &mut corners,
-                CALIB_CB_ADAPTIVE_THRESH | CALIB_CB_NORMALIZE_IMAGE,
-            )?;
-
-            if found {
-                // Refine the corner positions
-                let term_criteria = TermCriteria::new(
-                    TermCriteria_Type::COUNT as i32 | TermCriteria_Type::EPS as i32,
-                    30,
-                    0.1,
-                )?;
-                corner_sub_pix(
-                    &gray,
-                    &mut corners,
-                    Size::new(11, 11),
-                    Size::new(-1, -1),
-                    term_criteria,
-                )?;
+    }
 
-                image_points.push(corners);
-                object_points.push(object_point_set.clone());
+    // Get camera parameters
+    let metadata = source.metadata()?;
+    let (sensor_size, fps) = (metadata.size, metadata.fps);
+    log::info!(
+        "Camera: width {}, height {}, FPS: {}",
+        sensor_size.width, sensor_size.height, fps
+    );
 
-                captured_frames += 1;
+    // A session's frames were logged cropped, scaled and oriented already
+    let replaying = matches!(source_spec, SourceSpec::Session { .. });
+    let capture_config = CaptureConfig {
+        source_spec,
+        sensor_size,
+        roi: if replaying { None } else { profile.roi() },
+        scale: if replaying { 1.0 } else { profile.scale.unwrap_or(1.0) },
+        orientation: if replaying { Default::default() } else { profile.orientation() },
+        fps,
+        trigger_mode: args.trigger_mode,
+        record_dir,
+    };
+    capture_config.geometry()?; // Reject an ROI outside the sensor before capture starts
 
-                // Update status on Slint UI using the generated setter
-                if let Some(win) = window.upgrade() {
-                    win.set_status(format!("Captured frames: {}", captured_frames).into());
-                }
-            }
+    let capture = capture::start_camera_task(capture_config, source, frames, events)?;
+    Ok((capture, fps))
+}
 
-            imshow("Chessboard Calibration", &gray)?;
-            if wait_key(1)? == 27 {
-                break; // Exit if 'Esc' is pressed
-            }
-        } else {
-            thread::sleep(Duration::from_millis(10));
+// Returns the profile of the camera now running
+fn switch_camera(
+    role: CameraRole,
+    args: &Args,
+    capture: &Mutex<Option<CaptureHandle>>,
+    active_role: &Mutex<CameraRole>,
+    record_dir: Option<PathBuf>,
+    frames: FrameFeed,
+    events: EventBus,
+) -> Result<CameraProfile> {
+    let mut capture = capture.locked();
+    if let Some(running) = capture.take() {
+        if let Err(e) = running.stop() {
+            log::error!("Camera task stopped with an error: {:?}", e);
         }
     }
+    *active_role.locked() = role;
+    log::info!("Switching to the {}", role.label());
 
-    // Camera calibration using the captured points
-    let mut camera_matrix = Mat::eye(3, 3, CV_32F)?.to_mat()?; // 3x3 camera matrix
-    let mut dist_coeffs = Mat::zeros(8, 1, CV_32F)?.to_mat()?; // Distortion coefficients
-    let mut rvecs = opencv::types::VectorOfMat::new();
-    let mut tvecs = opencv::types::VectorOfMat::new();
-
-    calibrate_camera(
-        &object_points,
-        &image_points,
-        Size::new(frame_width, frame_height),
-        &mut camera_matrix,
-        &mut dist_coeffs,
-        &mut rvecs,
-        &mut tvecs,
-        0, // Calibration flags (can be customized)
-        TermCriteria::new(
-            TermCriteria_Type::COUNT as i32 | TermCriteria_Type::EPS as i32,
-            30,
-            0.1,
-        )?,
-    )?;
-
-    println!("Camera matrix: {:?}", camera_matrix);
-    println!("Distortion coefficients: {:?}", dist_coeffs);
-
-    Ok(())
-}
+    // Command line settings were for the camera we started with, use the stored ones here
+    let camera_profiles = profiles::load()?;
+    let profile = camera_profiles.get(role);
+    let (running, _) = start_capture(args, profile, &CameraSettings::default(), record_dir, frames, events)?;
+    *capture = Some(running);
 
-fn start_circle_grid_calibration(grid_rows: i32, grid_cols: i32) -> Result<()> {
-    eprintln!(
-        "Starting Circle Grid calibration with rows: {}, cols: {}",
-        grid_rows, grid_cols
-    );
-    stderr().flush().unwrap();
-    Ok(())
+    profiles::update(|profiles| profiles.active = role)?;
+    Ok(profile.clone())
 }
 
-fn start_aruco_calibration(loc_x: SharedString, loc_y: SharedString) -> Result<()> {
-    eprintln!(
-        "Starting Aruco calibration with loc_x: {}, loc_y: {}",
-        loc_x, loc_y
-    );
-    stderr().flush().unwrap();
-    Ok(())
+// Winds everything down in order when a front end exits: the camera, a running calibration
+// and its detection are all cancelled at once, then the calibration is waited for before the
+// camera closes its recording. Every step runs even when an earlier one failed; the failures
+// are logged and returned.
+fn shut_down(calibration: Option<JoinHandle<()>>, capture: Option<CaptureHandle>) -> Result<()> {
+    let mut failures = Vec::new();
+    runtime::shutdown_token().cancel();
+    if let Some(task) = calibration {
+        if let Err(e) = runtime::join_within(task, CANCEL_TIMEOUT, "calibration") {
+            failures.push(e);
+        }
+    }
+    if let Some(capture) = capture {
+        match capture.stop() {
+            Ok(()) => log::info!("Camera stopped and resources released"),
+            Err(e) => failures.push(e),
+        }
+    }
+    for failure in &failures {
+        log::error!("Error shutting down: {:#}", failure);
+    }
+    match failures.len() {
+        0 => Ok(()),
+        count => anyhow::bail!("{} problem(s) while shutting down, see the log above", count),
+    }
 }
@@ -0,0 +1,102 @@
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Point, Point2d, Scalar},
+    imgproc::{circle, line, LINE_AA},
+    prelude::*,
+};
+use rustyrabbit_core::{
+    frame::{Frame, FrameBuffer},
+    geometry::FrameGeometry,
+    lock::Locked,
+    preview::Undistort,
+};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct MeasureState {
+    enabled: bool,
+    // Size and geometry of the last preview frame, to place clicks on it
+    frame: Option<(i32, i32, FrameGeometry)>,
+    // Clicked points in preview pixels, a third click starts over
+    points: Vec<Point2d>,
+}
+
+// Distance between two points clicked on the preview, for checking the calibration
+// against something of known size on the working plane
+#[derive(Clone, Default)]
+pub struct Measurement(Arc<Mutex<MeasureState>>);
+
+impl Measurement {
+    pub fn set_enabled(&self, enabled: bool) {
+        let mut state = self.0.locked();
+        state.enabled = enabled;
+        state.points.clear();
+    }
+
+    // Points are in preview pixels, which change meaning when undistortion is switched
+    pub fn clear(&self) {
+        self.0.locked().points.clear();
+    }
+
+    // `u` and `v` run from 0 to 1 across the frame. Returns the text for the preview.
+    pub fn click(&self, u: f32, v: f32, undistort: &Undistort, mm_per_px: Option<f64>) -> Result<String> {
+        let mut state = self.0.locked();
+        let Some((width, height, geometry)) = state.frame else {
+            return Ok(String::new());
+        };
+        if state.points.len() == 2 {
+            state.points.clear();
+        }
+        let point = Point2d::new(
+            (u as f64 * width as f64).clamp(0.0, (width - 1) as f64),
+            (v as f64 * height as f64).clamp(0.0, (height - 1) as f64),
+        );
+        state.points.push(point);
+        let [a, b] = state.points[..] else {
+            return Ok("Click the second point".to_string());
+        };
+
+        let (Some(a), Some(b)) = (undistort.ideal_point(a, &geometry)?, undistort.ideal_point(b, &geometry)?) else {
+            return Ok("Calibrate this camera first, distances are only meaningful without lens distortion".to_string());
+        };
+        let px = (b.x - a.x).hypot(b.y - a.y);
+        Ok(match mm_per_px {
+            Some(mm_per_px) => format!(
+                "{:.3} mm ({:+.3}, {:+.3} mm)  {:.1} sensor px",
+                px * mm_per_px,
+                (b.x - a.x) * mm_per_px,
+                (b.y - a.y) * mm_per_px,
+                px
+            ),
+            None => format!("{:.1} sensor px, set --mm-per-px for millimetres", px),
+        })
+    }
+
+    // Call with every preview frame after undistortion, so the markers line up with it
+    pub fn apply(&self, frame: Frame) -> Result<Frame> {
+        let mut state = self.0.locked();
+        if !state.enabled {
+            return Ok(frame);
+        }
+        state.frame = Some((frame.width, frame.height, frame.geometry));
+        if state.points.is_empty() {
+            return Ok(frame);
+        }
+        let points: Vec<Point> = state
+            .points
+            .iter()
+            .map(|p| Point::new(p.x.round() as i32, p.y.round() as i32))
+            .collect();
+        drop(state);
+
+        let mut rgba = Mat::from_slice(frame.data.as_slice())?.reshape(4, frame.height)?.try_clone()?;
+        let color = Scalar::new(0.0, 255.0, 255.0, 255.0);
+        for &p in &points {
+            circle(&mut rgba, p, 5, color, 1, LINE_AA, 0)?;
+        }
+        if let [a, b] = points[..] {
+            line(&mut rgba, a, b, color, 1, LINE_AA, 0)?;
+        }
+        Ok(frame.with_pixels(FrameBuffer::copy_of(rgba.data_bytes()?)))
+    }
+}
@@ -0,0 +1,26 @@
+use anyhow::{anyhow, Result};
+use rustyrabbit_core::metrics;
+use std::thread;
+use tiny_http::{Header, Method, Response, Server, StatusCode};
+
+// Serves /metrics on `address` from a thread of its own for as long as the app runs, for
+// dashboards to scrape whichever interface is up
+pub fn serve(address: &str) -> Result<()> {
+    let server = Server::http(address).map_err(|e| anyhow!("Unable to listen on {}: {}", address, e))?;
+    log::info!("Serving Prometheus metrics on http://{}/metrics", address);
+    let content_type = Header::from_bytes("Content-Type", "text/plain; version=0.0.4").expect("valid header");
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match (request.method(), request.url()) {
+                (Method::Get, "/metrics") => {
+                    Response::from_string(metrics::prometheus()).with_header(content_type.clone())
+                }
+                _ => Response::from_string("Not found").with_status_code(StatusCode(404)),
+            };
+            if let Err(e) = request.respond(response) {
+                log::warn!("Unable to answer a metrics request: {:?}", e);
+            }
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,133 @@
+use crate::{
+    cli::Args,
+    headless::{CalibrationRequest, HeadlessApp},
+};
+use anyhow::{bail, Result};
+use rustyrabbit_core::{
+    frame::{FrameFeed, FrameSubscriber},
+    profiles::CameraRole,
+    session_log::{self, Command, Entry, Session, Started},
+    target_print::TargetKind,
+    workflow::WorkflowState,
+};
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+// How often the replay checks how far the frames got
+const POLL: Duration = Duration::from_millis(10);
+// A replay that delivers nothing for this long has stopped, the session's own gaps
+// between frames are shorter
+const PLAYBACK_IDLE: Duration = Duration::from_secs(5);
+
+// Plays a logged session back through the pipeline: its frames at their pace, and the
+// calibrations started and buttons pressed after as many frames as they were then. Every
+// detection is compared with the logged one, the exit status says whether all matched.
+pub fn run(args: Arc<Args>, role: CameraRole, frames: FrameFeed) -> Result<()> {
+    let Some(dir) = args.replay.clone() else {
+        bail!("Nothing to replay");
+    };
+    let session = Session::load(&dir)?;
+    let commands = session.commands();
+    for command in &commands {
+        if let Entry::Start {
+            started: Started { plugin: Some(plugin), .. },
+            ..
+        } = command.entry
+        {
+            if args.target_plugin.as_deref() != Some(plugin.as_str()) {
+                bail!("The session calibrated with the {0} plugin, replay it with --target-plugin {0}", plugin);
+            }
+        }
+    }
+    let total = session.frame_count();
+    log::info!("Replaying {} frames from {}", total, dir.display());
+
+    session.start_comparing();
+    // Subscribed before capture starts, so the first frame is counted too
+    let delivered = frames.subscribe();
+    let app = HeadlessApp::start(args, role, frames)?;
+    let mut progress = Progress::new(delivered);
+    let result = replay(&app, &commands, total, &mut progress);
+    app.stop()?;
+    result?;
+
+    let (compared, differed) = session_log::comparison().unwrap_or_default();
+    log::info!("Replayed {} frames, compared {} detections, {} differed", progress.seen, compared, differed);
+    if differed > 0 {
+        bail!("{} of {} detections came out differently than in the session", differed, compared);
+    }
+    Ok(())
+}
+
+fn replay(app: &HeadlessApp, commands: &[Command<'_>], total: u64, progress: &mut Progress) -> Result<()> {
+    for command in commands {
+        progress.wait_for(command.after_frames, total)?;
+        match command.entry {
+            Entry::Start { started, .. } => {
+                let request = CalibrationRequest {
+                    kind: started.kind.clone().unwrap_or(TargetKind::ChessBoard),
+                    board: (started.cols, started.rows),
+                    square_mm: started.square_mm,
+                    frames: started.frames,
+                    manual: started.manual,
+                    countdown: started.countdown_secs,
+                    stable_frames: started.stable_frames,
+                };
+                app.start_calibration(&request)?;
+            }
+            Entry::Press { button, .. } => app.controls.press(*button),
+            Entry::Delete { index, .. } => app.controls.delete(*index),
+            Entry::Frame { .. } | Entry::Detection { .. } => {}
+        }
+    }
+    progress.wait_for(total, total)?;
+
+    // The last detections and a solve still running get to finish
+    let last_frame = Instant::now();
+    loop {
+        let state = app.report().state();
+        if !state.calibrating || (state.workflow != WorkflowState::Solving && last_frame.elapsed() >= PLAYBACK_IDLE) {
+            break;
+        }
+        drop(state);
+        thread::sleep(POLL);
+    }
+    Ok(())
+}
+
+// The frames the replay has delivered so far
+struct Progress {
+    delivered: FrameSubscriber,
+    seen: u64,
+    last_frame: Instant,
+}
+
+impl Progress {
+    fn new(delivered: FrameSubscriber) -> Self {
+        Progress {
+            delivered,
+            seen: 0,
+            last_frame: Instant::now(),
+        }
+    }
+
+    fn wait_for(&mut self, count: u64, total: u64) -> Result<()> {
+        while self.seen < count {
+            match self.delivered.try_recv() {
+                // Frames that queued up too long are dropped, the highest number counts
+                Some(frame) => {
+                    self.seen = self.seen.max(frame.sequence);
+                    self.last_frame = Instant::now();
+                }
+                None if self.last_frame.elapsed() >= PLAYBACK_IDLE => {
+                    bail!("The replay stopped after frame {} of {}", self.seen, total)
+                }
+                None => thread::sleep(POLL),
+            }
+        }
+        Ok(())
+    }
+}
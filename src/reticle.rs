@@ -0,0 +1,116 @@
+use anyhow::Result;
+use opencv::{
+    core::{Mat, Point, Point2d, Scalar},
+    imgproc::{circle, line, put_text, FONT_HERSHEY_SIMPLEX, LINE_AA},
+    prelude::*,
+};
+use rustyrabbit_core::{
+    frame::{Frame, FrameBuffer},
+    lock::Locked,
+    profiles::CameraProfile,
+};
+use std::sync::{Arc, Mutex};
+
+// Ring spacings to pick from, in mm
+const RING_STEPS_MM: [f64; 10] = [0.1, 0.2, 0.5, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0];
+// Closest the rings may get to each other on screen
+const MIN_RING_SPACING_PX: f64 = 40.0;
+const MAX_RINGS: i32 = 20;
+
+#[derive(Default)]
+struct ReticleState {
+    enabled: bool,
+    // Sensor pixels
+    datum: Option<Point2d>,
+    mm_per_px: Option<f64>,
+}
+
+// Crosshair over the preview for lining the camera up with the nozzle axis
+#[derive(Clone, Default)]
+pub struct Reticle(Arc<Mutex<ReticleState>>);
+
+impl Reticle {
+    pub fn set_enabled(&self, enabled: bool) {
+        self.0.locked().enabled = enabled;
+    }
+
+    pub fn set_profile(&self, profile: &CameraProfile) {
+        let mut state = self.0.locked();
+        state.datum = profile.datum.map(|[x, y]| Point2d::new(x, y));
+        state.mm_per_px = profile.mm_per_px;
+    }
+
+    // Image scale at the working plane, in mm per sensor pixel
+    pub fn mm_per_px(&self) -> Option<f64> {
+        self.0.locked().mm_per_px
+    }
+
+    // Distance of a sensor pixel from the machine datum along the image axes, once the
+    // datum and image scale are known
+    pub fn offset_mm(&self, sensor: Point2d) -> Option<(f64, f64)> {
+        let state = self.0.locked();
+        let datum = state.datum?;
+        let mm_per_px = state.mm_per_px?;
+        Some(((sensor.x - datum.x) * mm_per_px, (sensor.y - datum.y) * mm_per_px))
+    }
+
+    // Centred on the machine datum if one is set, else on the principal point (sensor
+    // pixels) if the camera is calibrated, else on the middle of the frame
+    pub fn apply(&self, frame: Frame, principal_point: Option<Point2d>) -> Result<Frame> {
+        let state = self.0.locked();
+        if !state.enabled {
+            return Ok(frame);
+        }
+        let center = match state.datum.or(principal_point) {
+            Some(sensor) => frame.geometry.to_frame_point(sensor),
+            None => Point2d::new((frame.width - 1) as f64 / 2.0, (frame.height - 1) as f64 / 2.0),
+        };
+        // Sensor pixels to delivered pixels
+        let px_per_mm = state
+            .mm_per_px
+            .map(|mm_per_px| (frame.geometry.scale_x + frame.geometry.scale_y) / 2.0 / mm_per_px);
+        drop(state);
+
+        let mut rgba = Mat::from_slice(frame.data.as_slice())?.reshape(4, frame.height)?.try_clone()?;
+        draw(&mut rgba, center, px_per_mm)?;
+        Ok(frame.with_pixels(FrameBuffer::copy_of(rgba.data_bytes()?)))
+    }
+}
+
+fn draw(rgba: &mut Mat, center: Point2d, px_per_mm: Option<f64>) -> Result<()> {
+    let color = Scalar::new(255.0, 0.0, 255.0, 255.0);
+    let (width, height) = (rgba.cols(), rgba.rows());
+    let c = Point::new(center.x.round() as i32, center.y.round() as i32);
+    line(rgba, Point::new(0, c.y), Point::new(width, c.y), color, 1, LINE_AA, 0)?;
+    line(rgba, Point::new(c.x, 0), Point::new(c.x, height), color, 1, LINE_AA, 0)?;
+    circle(rgba, c, 4, color, 1, LINE_AA, 0)?;
+
+    let Some(px_per_mm) = px_per_mm.filter(|scale| scale.is_finite() && *scale > 0.0) else {
+        return Ok(());
+    };
+    let Some(step) = RING_STEPS_MM.iter().copied().find(|step| step * px_per_mm >= MIN_RING_SPACING_PX) else {
+        return Ok(());
+    };
+    let reach = ((width.max(height) as f64) * 1.5) as i32;
+    for ring in 1..=MAX_RINGS {
+        let radius = (ring as f64 * step * px_per_mm).round() as i32;
+        if radius > reach {
+            break;
+        }
+        circle(rgba, c, radius, color, 1, LINE_AA, 0)?;
+        let distance = ring as f64 * step;
+        let label = if step < 1.0 { format!("{:.1} mm", distance) } else { format!("{:.0} mm", distance) };
+        put_text(
+            rgba,
+            &label,
+            Point::new(c.x + radius + 3, c.y - 4),
+            FONT_HERSHEY_SIMPLEX,
+            0.4,
+            color,
+            1,
+            LINE_AA,
+            false,
+        )?;
+    }
+    Ok(())
+}
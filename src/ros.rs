@@ -0,0 +1,179 @@
+use crate::{
+    cli::Args,
+    headless::{CalibrationRequest, HeadlessApp},
+};
+use anyhow::{bail, Result};
+use opencv::prelude::*;
+use rclrs::{Context, Node, Publisher, Service, QOS_PROFILE_SENSOR_DATA};
+use rustyrabbit_core::{
+    calibration::Button,
+    frame::{Frame, FrameFeed},
+    profiles::{Calibration, CameraRole},
+};
+use sensor_msgs::msg::{CameraInfo, Image};
+use std::{
+    iter,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+use std_srvs::srv::{Trigger, Trigger_Request, Trigger_Response};
+
+const NODE_NAME: &str = "rustyrabbit";
+// How often the camera is checked for a new frame
+const POLL: Duration = Duration::from_millis(10);
+
+// Runs as a ROS 2 node, a stand-in for a camera driver and camera_calibration together. It
+// publishes the camera's frames and their CameraInfo, and the calibration is started and
+// driven with std_srvs/Trigger services, rclrs has no actions yet. Topics and services
+// are relative, so --ros-args remappings and namespaces apply.
+pub fn run(args: Arc<Args>, role: CameraRole, frames: FrameFeed) -> Result<()> {
+    let context = Context::new(iter::once(NODE_NAME.to_string()).chain(args.ros_args.iter().cloned()))?;
+    let node = rclrs::create_node(&context, NODE_NAME)?;
+    let defaults = CalibrationRequest::from_args(&args);
+    let app = Arc::new(HeadlessApp::start(args, role, frames)?);
+
+    let image = node.create_publisher::<Image>("image_raw", QOS_PROFILE_SENSOR_DATA)?;
+    let camera_info = node.create_publisher::<CameraInfo>("camera_info", QOS_PROFILE_SENSOR_DATA)?;
+    let _services = [
+        trigger(&node, "start_calibration", &app, move |app| {
+            app.start_calibration(&defaults)?;
+            Ok("Calibration started".to_string())
+        })?,
+        trigger(&node, "capture", &app, |app| press(app, Button::Capture))?,
+        trigger(&node, "undo", &app, |app| press(app, Button::Undo))?,
+        trigger(&node, "finish_calibration", &app, |app| press(app, Button::Finish))?,
+        trigger(&node, "cancel_calibration", &app, |app| press(app, Button::Cancel))?,
+        // Succeeds once a calibration was solved and saved, the message is the status line
+        service(&node, "calibration_status", &app, |app| {
+            let state = app.report().state();
+            let (workflow, captured, required) = (state.workflow.label(), state.captured, state.required);
+            Trigger_Response {
+                success: !state.calibrating && state.calibration.is_some(),
+                message: format!("{} ({}/{}): {}", workflow, captured, required, state.status),
+            }
+        })?,
+    ];
+
+    let stopped = Arc::new(AtomicBool::new(false));
+    let publisher = {
+        let (app, stopped) = (Arc::clone(&app), Arc::clone(&stopped));
+        thread::spawn(move || {
+            while !stopped.load(Ordering::Relaxed) {
+                match app.next_raw_frame() {
+                    Some(frame) => {
+                        if let Err(e) = publish(&app, &frame, &image, &camera_info) {
+                            log::error!("Error publishing a frame: {:?}", e);
+                        }
+                    }
+                    None => thread::sleep(POLL),
+                }
+            }
+        })
+    };
+
+    log::info!("Running as the ROS 2 node {}", node.fully_qualified_name());
+    let spun = rclrs::spin(Arc::clone(&node));
+    stopped.store(true, Ordering::Relaxed);
+    let _ = publisher.join();
+    app.stop()?;
+    Ok(spun?)
+}
+
+fn press(app: &HeadlessApp, button: Button) -> Result<String> {
+    if !app.report().state().calibrating {
+        bail!("No calibration is running");
+    }
+    app.controls.press(button);
+    Ok(format!("Pressed {:?}", button))
+}
+
+// A Trigger service that fails with the error's message
+fn trigger(
+    node: &Node,
+    name: &str,
+    app: &Arc<HeadlessApp>,
+    action: impl Fn(&HeadlessApp) -> Result<String> + Send + 'static,
+) -> Result<Arc<Service<Trigger>>> {
+    service(node, name, app, move |app| match action(app) {
+        Ok(message) => Trigger_Response { success: true, message },
+        Err(e) => Trigger_Response {
+            success: false,
+            message: e.to_string(),
+        },
+    })
+}
+
+fn service(
+    node: &Node,
+    name: &str,
+    app: &Arc<HeadlessApp>,
+    answer: impl Fn(&HeadlessApp) -> Trigger_Response + Send + 'static,
+) -> Result<Arc<Service<Trigger>>> {
+    let app = Arc::clone(app);
+    Ok(node.create_service::<Trigger, _>(name, move |_, _: Trigger_Request| answer(&app))?)
+}
+
+fn publish(
+    app: &HeadlessApp,
+    frame: &Frame,
+    image: &Publisher<Image>,
+    camera_info: &Publisher<CameraInfo>,
+) -> Result<()> {
+    let mut message = Image {
+        height: frame.height as u32,
+        width: frame.width as u32,
+        encoding: "rgba8".to_string(),
+        is_bigendian: 0,
+        step: frame.width as u32 * 4,
+        data: frame.data.as_slice().to_vec(),
+        ..Default::default()
+    };
+    message.header.stamp.sec = frame.timestamp.unix_timestamp() as i32;
+    message.header.stamp.nanosec = frame.timestamp.nanosecond();
+    message.header.frame_id = app.role().label().replace(' ', "_");
+
+    let calibration = app.report().state().calibration.clone();
+    let mut info = camera_info_for(frame, calibration.as_ref())?;
+    info.header = message.header.clone();
+    image.publish(message)?;
+    camera_info.publish(info)?;
+    Ok(())
+}
+
+// The calibration in the pixels of this frame, with its ROI, scale and rotation. All zeros,
+// ROS's uncalibrated, without one or when it was taken at another sensor size.
+fn camera_info_for(frame: &Frame, calibration: Option<&Calibration>) -> Result<CameraInfo> {
+    let mut info = CameraInfo {
+        height: frame.height as u32,
+        width: frame.width as u32,
+        distortion_model: "plumb_bob".to_string(),
+        ..Default::default()
+    };
+    let geometry = frame.geometry;
+    let sensor = geometry.sensor_size;
+    let Some(calibration) = calibration
+        .filter(|calibration| calibration.sensor_width == sensor.width && calibration.sensor_height == sensor.height)
+    else {
+        return Ok(info);
+    };
+    let camera_matrix = geometry.to_frame_camera_matrix(&calibration.camera_matrix()?)?;
+    let dist_coeffs = geometry.to_frame_dist_coeffs(&calibration.dist_coeffs()?)?;
+    for r in 0..3 {
+        for c in 0..3 {
+            let value = *camera_matrix.at_2d::<f64>(r as i32, c as i32)?;
+            info.k[r * 3 + c] = value;
+            // The images aren't rectified, so the projection is the camera matrix itself
+            info.p[r * 4 + c] = value;
+        }
+        info.r[r * 4] = 1.0;
+    }
+    info.d = dist_coeffs.data_typed::<f64>()?.to_vec();
+    if info.d.len() > 5 {
+        info.distortion_model = "rational_polynomial".to_string();
+    }
+    Ok(info)
+}
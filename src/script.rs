@@ -0,0 +1,274 @@
+use crate::{cli::Args, headless::HeadlessApp};
+use anyhow::{bail, Result};
+use opencv::{
+    core::{Mat, Vector},
+    imgcodecs::imwrite,
+    imgproc::COLOR_RGBA2BGRA,
+    prelude::*,
+};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope};
+use rustyrabbit_core::{
+    acceleration,
+    frame::{Frame, FrameFeed},
+    lock::Locked,
+    machine::{Firmware, LinuxCnc, Machine, Moonraker, Position, SerialMachine},
+    profiles::{self, CameraRole},
+    target::{CalibrationTarget, ChessBoard},
+    vision,
+};
+use std::{
+    fs,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+// How long capture() waits for the camera
+const CAPTURE_TIMEOUT: Duration = Duration::from_secs(2);
+// Until the script sets another with feed()
+const DEFAULT_FEED_MM_MIN: f64 = 3000.0;
+
+type ScriptResult<T> = Result<T, Box<EvalAltResult>>;
+
+// What the script's functions share: the camera and, once the script connects, the machine
+struct Context {
+    app: Arc<HeadlessApp>,
+    machine: Mutex<Option<Box<dyn Machine>>>,
+    feed_mm_min: Mutex<f64>,
+}
+
+impl Context {
+    fn with_machine<T>(&self, act: impl FnOnce(&mut dyn Machine) -> Result<T>) -> ScriptResult<T> {
+        let mut machine = self.machine.locked();
+        match machine.as_mut() {
+            Some(machine) => act(machine.as_mut()).map_err(fail),
+            None => Err("No machine, connect_serial, connect_moonraker or connect_linuxcnc first".into()),
+        }
+    }
+
+    fn connect(&self, connected: Result<Box<dyn Machine>>) -> ScriptResult<()> {
+        *self.machine.locked() = Some(connected.map_err(fail)?);
+        Ok(())
+    }
+}
+
+fn fail(e: anyhow::Error) -> Box<EvalAltResult> {
+    format!("{:#}", e).into()
+}
+
+// Scripts write 10 as readily as 10.0
+fn number(value: &Dynamic) -> ScriptResult<f64> {
+    if let Ok(float) = value.as_float() {
+        return Ok(float);
+    }
+    match value.as_int() {
+        Ok(int) => Ok(int as f64),
+        Err(kind) => Err(format!("Expected a number, got {}", kind).into()),
+    }
+}
+
+fn role(name: &str) -> ScriptResult<CameraRole> {
+    match name {
+        "top" => Ok(CameraRole::Top),
+        "bottom" => Ok(CameraRole::Bottom),
+        _ => Err(format!("{:?} isn't a camera, use \"top\" or \"bottom\"", name).into()),
+    }
+}
+
+fn firmware(name: &str) -> ScriptResult<Firmware> {
+    match name {
+        "marlin" => Ok(Firmware::Marlin),
+        "grbl" => Ok(Firmware::Grbl),
+        "smoothie" => Ok(Firmware::Smoothie),
+        _ => Err(format!("{:?} isn't a firmware, use \"marlin\", \"grbl\" or \"smoothie\"", name).into()),
+    }
+}
+
+fn point_map(x: f64, y: f64) -> Map {
+    let mut map = Map::new();
+    map.insert("x".into(), x.into());
+    map.insert("y".into(), y.into());
+    map
+}
+
+fn position_map(position: Position) -> Dynamic {
+    let mut map = point_map(position.x, position.y);
+    if let Some(z) = position.z {
+        map.insert("z".into(), z.into());
+    }
+    map.into()
+}
+
+// Runs a Rhai script against the camera and, once it connects to one, the machine, the way
+// each machine's own calibration routine goes: move, capture, find, compute, repeat. The
+// API is in the README.
+pub fn run(args: Arc<Args>, role: CameraRole, frames: FrameFeed) -> Result<()> {
+    let Some(path) = args.script.clone() else {
+        bail!("No script to run");
+    };
+    let context = Arc::new(Context {
+        app: Arc::new(HeadlessApp::start(args, role, frames)?),
+        machine: Mutex::new(None),
+        feed_mm_min: Mutex::new(DEFAULT_FEED_MM_MIN),
+    });
+    let engine = engine(&context);
+    log::info!("Running {}", path.display());
+    let ran = engine.run_file_with_scope(&mut Scope::new(), path.clone());
+    context.app.stop()?;
+    match ran {
+        Ok(()) => Ok(()),
+        Err(e) => bail!("{} failed: {}", path.display(), e),
+    }
+}
+
+fn engine(context: &Arc<Context>) -> Engine {
+    let mut engine = Engine::new();
+    engine.on_print(|text| log::info!("{}", text));
+    engine.on_debug(|text, _, position| log::debug!("{} ({})", text, position));
+    engine
+        .register_type_with_name::<Frame>("Frame")
+        .register_get("width", |frame: &mut Frame| frame.width as i64)
+        .register_get("height", |frame: &mut Frame| frame.height as i64)
+        .register_get("sequence", |frame: &mut Frame| frame.sequence as i64);
+
+    engine.register_fn("sleep", |ms: i64| thread::sleep(Duration::from_millis(ms.max(0) as u64)));
+    engine.register_fn("save_json", |path: &str, value: Dynamic| -> ScriptResult<()> {
+        let text = serde_json::to_string_pretty(&value).map_err(|e| fail(e.into()))?;
+        fs::write(path, text).map_err(|e| fail(e.into()))
+    });
+    register_machine(&mut engine, context);
+    register_camera(&mut engine, context);
+    engine
+}
+
+fn register_machine(engine: &mut Engine, context: &Arc<Context>) {
+    let c = Arc::clone(context);
+    engine.register_fn("connect_serial", move |port: &str, baud: i64, name: &str| -> ScriptResult<()> {
+        let firmware = firmware(name)?;
+        c.connect(SerialMachine::open(port, baud as u32, firmware).map(|machine| Box::new(machine) as Box<dyn Machine>))
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("connect_moonraker", move |url: &str| -> ScriptResult<()> {
+        c.connect(Moonraker::connect(url).map(|machine| Box::new(machine) as Box<dyn Machine>))
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("connect_linuxcnc", move |host: &str| -> ScriptResult<()> {
+        c.connect(LinuxCnc::connect(host, "EMC").map(|machine| Box::new(machine) as Box<dyn Machine>))
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("connect_linuxcnc", move |host: &str, password: &str| -> ScriptResult<()> {
+        c.connect(LinuxCnc::connect(host, password).map(|machine| Box::new(machine) as Box<dyn Machine>))
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("home", move || c.with_machine(|machine| machine.home()));
+    let c = Arc::clone(context);
+    engine.register_fn("feed", move |mm_min: Dynamic| -> ScriptResult<()> {
+        let mm_min = number(&mm_min)?;
+        if mm_min <= 0.0 {
+            return Err("The feed rate has to be more than 0".into());
+        }
+        *c.feed_mm_min.locked() = mm_min;
+        Ok(())
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("move_to", move |x: Dynamic, y: Dynamic| -> ScriptResult<()> {
+        let position = Position {
+            x: number(&x)?,
+            y: number(&y)?,
+            z: None,
+        };
+        let feed = *c.feed_mm_min.locked();
+        c.with_machine(|machine| machine.move_to(position, feed))
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("move_to", move |x: Dynamic, y: Dynamic, z: Dynamic| -> ScriptResult<()> {
+        let position = Position {
+            x: number(&x)?,
+            y: number(&y)?,
+            z: Some(number(&z)?),
+        };
+        let feed = *c.feed_mm_min.locked();
+        c.with_machine(|machine| machine.move_to(position, feed))
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("position", move || -> ScriptResult<Dynamic> {
+        Ok(c.with_machine(|machine| machine.position())?.map_or(Dynamic::UNIT, position_map))
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("joints", move || -> ScriptResult<Dynamic> {
+        let joints = c.with_machine(|machine| machine.joints())?;
+        Ok(joints.map_or(Dynamic::UNIT, |joints| {
+            joints.into_iter().map(Dynamic::from).collect::<Array>().into()
+        }))
+    });
+}
+
+fn register_camera(engine: &mut Engine, context: &Arc<Context>) {
+    let c = Arc::clone(context);
+    engine.register_fn("use_camera", move |name: &str| -> ScriptResult<()> {
+        c.app.use_camera(role(name)?).map_err(fail)
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("capture", move || -> ScriptResult<Frame> {
+        c.app.fresh_frame(CAPTURE_TIMEOUT).map_err(fail)
+    });
+    engine.register_fn("save_image", |frame: Frame, path: &str| -> ScriptResult<()> {
+        save_image(&frame, path).map_err(fail)
+    });
+    engine.register_fn(
+        "find_board",
+        |frame: Frame, cols: i64, rows: i64, square_mm: Dynamic| -> ScriptResult<Dynamic> {
+            let board = ChessBoard::new(cols as i32, rows as i32, number(&square_mm)?);
+            let found = board.detect(&frame).map_err(fail)?;
+            Ok(found.map_or(Dynamic::UNIT, |view| {
+                let points: Array = view
+                    .image_points
+                    .iter()
+                    .map(|point| point_map(point.x as f64, point.y as f64).into())
+                    .collect();
+                points.into()
+            }))
+        },
+    );
+    let c = Arc::clone(context);
+    engine.register_fn("find_fiducial", move |frame: Frame, diameter_px: Dynamic| -> ScriptResult<Dynamic> {
+        let profile = profiles::load().map_err(fail)?.get(c.app.role()).clone();
+        let found = vision::locate_fiducial(&frame, profile.calibration.as_ref(), number(&diameter_px)?);
+        let Some(fiducial) = found.map_err(fail)? else {
+            return Ok(Dynamic::UNIT);
+        };
+        let mut map = point_map(fiducial.center.x, fiducial.center.y);
+        map.insert("frame_x".into(), fiducial.frame_center.x.into());
+        map.insert("frame_y".into(), fiducial.frame_center.y.into());
+        map.insert("diameter".into(), fiducial.diameter.into());
+        if let Some(offset) = vision::offset_mm(&profile, fiducial.center) {
+            map.insert("offset".into(), point_map(offset.x, offset.y).into());
+        }
+        Ok(map.into())
+    });
+    let c = Arc::clone(context);
+    engine.register_fn("find_part", move |frame: Frame| -> ScriptResult<Dynamic> {
+        let profile = profiles::load().map_err(fail)?.get(c.app.role()).clone();
+        let Some(part) = vision::align_part(&frame, profile.calibration.as_ref()).map_err(fail)? else {
+            return Ok(Dynamic::UNIT);
+        };
+        let mut map = point_map(part.center.x, part.center.y);
+        map.insert("angle".into(), part.angle.into());
+        map.insert("width".into(), part.width.into());
+        map.insert("height".into(), part.height.into());
+        if let Some(offset) = vision::offset_mm(&profile, part.center) {
+            map.insert("offset".into(), point_map(offset.x, offset.y).into());
+        }
+        Ok(map.into())
+    });
+}
+
+fn save_image(frame: &Frame, path: &str) -> Result<()> {
+    let rgba = Mat::from_slice(frame.data.as_slice())?;
+    let mut bgra = Mat::default();
+    acceleration::convert_color(&rgba.reshape(4, frame.height)?, &mut bgra, COLOR_RGBA2BGRA)?;
+    if !imwrite(path, &bgra, &Vector::new())? {
+        bail!("Unable to write {}", path);
+    }
+    Ok(())
+}
@@ -0,0 +1,124 @@
+use crate::MainWindow;
+use rustyrabbit_core::config;
+use serde::{Deserialize, Serialize};
+use slint::{ComponentHandle, LogicalSize};
+use std::path::PathBuf;
+
+const SETTINGS_FILE: &str = "settings.toml";
+
+// The Calibration tab's target and capture fields
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TargetSettings {
+    pub kind: i32,
+    pub rows: i32,
+    pub cols: i32,
+    pub square_size: String,
+    pub dictionary: String,
+    pub frames: i32,
+    pub manual_capture: bool,
+    pub countdown: i32,
+}
+
+// Which preview overlays were switched on
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Overlays {
+    pub undistort: bool,
+    pub histogram: bool,
+    pub reticle: bool,
+    pub markers: bool,
+}
+
+// How the window was left when the app was last closed. The camera and its role are kept
+// with the camera profiles instead.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UiSettings {
+    // Logical pixels
+    pub window_size: Option<[f32; 2]>,
+    pub record_dir: Option<PathBuf>,
+    pub target: Option<TargetSettings>,
+    pub overlays: Overlays,
+}
+
+pub fn load() -> UiSettings {
+    config::load(SETTINGS_FILE)
+        .unwrap_or_else(|e| {
+            log::warn!("Ignoring saved window settings: {:?}", e);
+            None
+        })
+        .unwrap_or_default()
+}
+
+pub fn save(settings: &UiSettings) {
+    if let Err(e) = config::save(SETTINGS_FILE, settings) {
+        log::error!("Error saving window settings: {:?}", e);
+    }
+}
+
+// Call once all callbacks are set, the overlays are switched on through them
+pub fn restore(window: &MainWindow, settings: &UiSettings) {
+    if let Some([width, height]) = settings.window_size.filter(|_| !window.get_kiosk()) {
+        window.window().set_size(LogicalSize::new(width, height));
+    }
+    if let Some(target) = &settings.target {
+        window.set_target_kind(target.kind);
+        window.set_target_rows(target.rows);
+        window.set_target_cols(target.cols);
+        window.set_target_square(target.square_size.as_str().into());
+        window.set_target_dictionary(target.dictionary.as_str().into());
+        window.set_target_frames(target.frames);
+        window.set_target_manual(target.manual_capture);
+        window.set_target_countdown(target.countdown);
+    }
+
+    let overlays = &settings.overlays;
+    // Undistortion needs this camera's calibration
+    if overlays.undistort && window.get_undistort_available() {
+        window.set_undistorting(true);
+        window.invoke_undistort_toggled(true);
+    }
+    if overlays.histogram {
+        window.set_histogram_shown(true);
+        window.invoke_histogram_toggled(true);
+    }
+    if overlays.reticle {
+        window.set_reticle_shown(true);
+        window.invoke_reticle_toggled(true);
+    }
+    if overlays.markers {
+        window.set_detecting_markers(true);
+        window.invoke_markers_toggled(true);
+    }
+}
+
+// Reads the settings back from the window before it closes. A fullscreen kiosk keeps the
+// size saved from the last windowed run.
+pub fn from_window(window: &MainWindow, previous: &UiSettings) -> UiSettings {
+    let window_size = if window.get_kiosk() {
+        previous.window_size
+    } else {
+        let size = window.window().size().to_logical(window.window().scale_factor());
+        Some([size.width, size.height])
+    };
+    UiSettings {
+        window_size,
+        record_dir: Some(PathBuf::from(window.get_record_dir().as_str())),
+        target: Some(TargetSettings {
+            kind: window.get_target_kind(),
+            rows: window.get_target_rows(),
+            cols: window.get_target_cols(),
+            square_size: window.get_target_square().to_string(),
+            dictionary: window.get_target_dictionary().to_string(),
+            frames: window.get_target_frames(),
+            manual_capture: window.get_target_manual(),
+            countdown: window.get_target_countdown(),
+        }),
+        overlays: Overlays {
+            undistort: window.get_undistorting(),
+            histogram: window.get_histogram_shown(),
+            reticle: window.get_reticle_shown(),
+            markers: window.get_detecting_markers(),
+        },
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::{bail, Result};
+use rustyrabbit_core::config;
+use serde::{Deserialize, Serialize};
+use slint::{platform::Key, SharedString};
+
+const SHORTCUTS_FILE: &str = "shortcuts.toml";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Capture,
+    Snapshot,
+    Undistort,
+    Cancel,
+    SwitchCamera,
+}
+
+// Keys for the main window, by name (Space, Escape, F1...) or as the character itself.
+// Letters match regardless of case.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Shortcuts {
+    pub capture: String,
+    pub snapshot: String,
+    pub undistort: String,
+    pub cancel: String,
+    pub switch_camera: String,
+}
+
+impl Default for Shortcuts {
+    fn default() -> Self {
+        Shortcuts {
+            capture: "Space".into(),
+            snapshot: "S".into(),
+            undistort: "U".into(),
+            cancel: "Escape".into(),
+            switch_camera: "C".into(),
+        }
+    }
+}
+
+// Key text from Slint's key events, resolved once up front
+pub struct Keymap(Vec<(String, Action)>);
+
+impl Keymap {
+    pub fn action(&self, text: &str) -> Option<Action> {
+        let text = text.to_lowercase();
+        self.0.iter().find(|(key, _)| *key == text).map(|(_, action)| *action)
+    }
+}
+
+impl Shortcuts {
+    // Bad names are reported and fall back to the default key rather than leaving the
+    // action without one
+    pub fn keymap(&self) -> Keymap {
+        let defaults = Shortcuts::default();
+        let bindings = [
+            (&self.capture, &defaults.capture, Action::Capture),
+            (&self.snapshot, &defaults.snapshot, Action::Snapshot),
+            (&self.undistort, &defaults.undistort, Action::Undistort),
+            (&self.cancel, &defaults.cancel, Action::Cancel),
+            (&self.switch_camera, &defaults.switch_camera, Action::SwitchCamera),
+        ];
+        let keys = bindings
+            .into_iter()
+            .map(|(name, default, action)| {
+                let text = key_text(name).unwrap_or_else(|e| {
+                    log::warn!("Shortcut for {:?}: {}, using {}", action, e, default);
+                    key_text(default).unwrap()
+                });
+                (text, action)
+            })
+            .collect();
+        Keymap(keys)
+    }
+}
+
+fn key_text(name: &str) -> Result<String> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(c.to_lowercase().collect());
+    }
+    let key = match name.to_lowercase().as_str() {
+        "space" => return Ok(" ".into()),
+        "escape" | "esc" => Key::Escape,
+        "tab" => Key::Tab,
+        "return" | "enter" => Key::Return,
+        "backspace" => Key::Backspace,
+        "delete" => Key::Delete,
+        "up" => Key::UpArrow,
+        "down" => Key::DownArrow,
+        "left" => Key::LeftArrow,
+        "right" => Key::RightArrow,
+        "f1" => Key::F1,
+        "f2" => Key::F2,
+        "f3" => Key::F3,
+        "f4" => Key::F4,
+        "f5" => Key::F5,
+        "f6" => Key::F6,
+        "f7" => Key::F7,
+        "f8" => Key::F8,
+        "f9" => Key::F9,
+        "f10" => Key::F10,
+        "f11" => Key::F11,
+        "f12" => Key::F12,
+        _ => bail!("unknown key {:?}", name),
+    };
+    Ok(SharedString::from(key).to_string())
+}
+
+// Writes the defaults on first start so there is a file to edit
+pub fn load() -> Shortcuts {
+    match config::load(SHORTCUTS_FILE) {
+        Ok(Some(shortcuts)) => shortcuts,
+        Ok(None) => {
+            let shortcuts = Shortcuts::default();
+            if let Err(e) = config::save(SHORTCUTS_FILE, &shortcuts) {
+                log::warn!("Error saving default shortcuts: {:?}", e);
+            }
+            shortcuts
+        }
+        Err(e) => {
+            log::warn!("Ignoring stored shortcuts: {:?}", e);
+            Shortcuts::default()
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{bail, Context, Result};
+use opencv::{
+    core::{Mat, Vector},
+    imgcodecs::imwrite,
+    imgproc::{cvt_color, COLOR_RGBA2BGR},
+    prelude::*,
+};
+use rustyrabbit_core::{
+    frame::{self, Frame, FrameBuffer},
+    lock::Locked,
+    preview::Undistort,
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+// Saves the next preview frame as PNG stills for documentation and offline analysis
+#[derive(Clone)]
+pub struct Snapshot {
+    requested: Arc<AtomicBool>,
+    dir: Arc<Mutex<PathBuf>>,
+}
+
+impl Snapshot {
+    pub fn new(dir: PathBuf) -> Self {
+        Snapshot {
+            requested: Arc::default(),
+            dir: Arc::new(Mutex::new(dir)),
+        }
+    }
+
+    // For the snapshots from now on
+    pub fn set_dir(&self, dir: PathBuf) {
+        *self.dir.locked() = dir;
+    }
+
+    pub fn request(&self) {
+        self.requested.store(true, Ordering::Relaxed);
+    }
+
+    // Call with every raw frame before it is undistorted for the preview. Writing happens
+    // off the UI thread.
+    pub fn take_if_requested(&self, frame: &Frame, undistort: &Undistort) {
+        if !self.requested.swap(false, Ordering::Relaxed) {
+            return;
+        }
+        let undistorted = undistort.correct(frame).unwrap_or_else(|e| {
+            log::warn!("Snapshot saved without an undistorted copy: {:?}", e);
+            None
+        });
+        let frame = frame.clone();
+        let dir = self.dir.locked().clone();
+        thread::spawn(move || match save(&dir, &frame, undistorted) {
+            Ok(path) => log::info!("Saved snapshot {}", path.display()),
+            Err(e) => log::error!("Error saving snapshot: {:?}", e),
+        });
+    }
+}
+
+// Returns the raw image's path, the undistorted one sits next to it
+fn save(dir: &Path, frame: &Frame, undistorted: Option<FrameBuffer>) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("Unable to create {}", dir.display()))?;
+    let stem = format!("snapshot-{}-{}", frame::file_timestamp(frame.timestamp), frame.sequence);
+    let raw = dir.join(format!("{}-raw.png", stem));
+    write_png(&raw, &frame.data, frame.height)?;
+    if let Some(data) = undistorted {
+        write_png(&dir.join(format!("{}-undistorted.png", stem)), &data, frame.height)?;
+    }
+    Ok(raw)
+}
+
+fn write_png(path: &Path, rgba: &[u8], height: i32) -> Result<()> {
+    let rgba = Mat::from_slice(rgba)?.reshape(4, height)?.try_clone()?;
+    let mut bgr = Mat::default();
+    cvt_color(&rgba, &mut bgr, COLOR_RGBA2BGR, 0)?;
+    if !imwrite(&path.to_string_lossy(), &bgr, &Vector::new())? {
+        bail!("Unable to write {}", path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+use anyhow::{Context, Result};
+use log::Level;
+use rustyrabbit_core::lock::Locked;
+use std::{
+    collections::VecDeque,
+    fmt::{self, Write as _},
+    io,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+use time::OffsetDateTime;
+use tracing::{
+    field::{Field, Visit},
+    Event, Subscriber,
+};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{fmt::format::FmtSpan, layer::Context as LayerContext, prelude::*, EnvFilter, Layer};
+
+// Messages kept for the UI before the oldest are dropped
+pub const MAX_ENTRIES: usize = 500;
+// Days of log files kept in --log-dir
+const LOG_FILES_KEPT: usize = 7;
+
+pub struct LogEntry {
+    pub level: Level,
+    pub text: String,
+}
+
+// Queued for the log panel, so messages can be read when the app was started without a terminal
+static PENDING: Mutex<VecDeque<LogEntry>> = Mutex::new(VecDeque::new());
+// The terminal interface owns the screen, stderr output would tear through it
+static STDERR_ENABLED: AtomicBool = AtomicBool::new(true);
+
+// Everything goes through tracing: the core's capture, detect, calibrate and record spans,
+// and the log crate's messages, bridged, from both crates. RUST_LOG sets the levels as
+// before, without it our own messages are shown from info up. With `log_dir`, the same
+// lines go to a file there that starts over every day, and the returned guard writes out
+// whatever is still buffered when it is dropped at exit.
+pub fn init(log_dir: Option<&Path>) -> Result<Option<WorkerGuard>> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error,rustyrabbit=info"));
+    let stderr = tracing_subscriber::fmt::layer().with_writer(stderr_writer);
+
+    let (file, guard) = match log_dir {
+        Some(dir) => {
+            let appender = RollingFileAppender::builder()
+                .rotation(Rotation::DAILY)
+                .filename_prefix("rustyrabbit")
+                .filename_suffix("log")
+                .max_log_files(LOG_FILES_KEPT)
+                .build(dir)
+                .with_context(|| format!("Unable to write log files to {}", dir.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            // Spans closing with their duration, for working out afterwards where time went
+            let layer = tracing_subscriber::fmt::layer()
+                .with_writer(writer)
+                .with_ansi(false)
+                .with_span_events(FmtSpan::CLOSE);
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
+
+    // Fails only when a subscriber is set already, which then keeps logging
+    let _ = tracing_subscriber::registry()
+        .with(filter)
+        .with(stderr)
+        .with(file)
+        .with(PanelLayer)
+        .try_init();
+    Ok(guard)
+}
+
+pub fn set_stderr_enabled(enabled: bool) {
+    STDERR_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+// Messages logged since the last call, oldest first
+pub fn drain() -> Vec<LogEntry> {
+    PENDING.locked().drain(..).collect()
+}
+
+fn stderr_writer() -> Box<dyn io::Write> {
+    if STDERR_ENABLED.load(Ordering::Relaxed) {
+        Box::new(io::stderr())
+    } else {
+        Box::new(io::sink())
+    }
+}
+
+// Feeds the log panel with the message and fields of every event that passes the filter
+struct PanelLayer;
+
+impl<S: Subscriber> Layer<S> for PanelLayer {
+    fn on_event(&self, event: &Event<'_>, _: LayerContext<'_, S>) {
+        let mut message = PanelMessage::default();
+        event.record(&mut message);
+        let level = match *event.metadata().level() {
+            tracing::Level::ERROR => Level::Error,
+            tracing::Level::WARN => Level::Warn,
+            tracing::Level::INFO => Level::Info,
+            tracing::Level::DEBUG => Level::Debug,
+            tracing::Level::TRACE => Level::Trace,
+        };
+
+        // UTC like the frame timestamps
+        let now = OffsetDateTime::now_utc();
+        let text = format!(
+            "{:02}:{:02}:{:02}Z {:<5} {}{}",
+            now.hour(),
+            now.minute(),
+            now.second(),
+            level,
+            message.text,
+            message.fields
+        );
+        let mut pending = PENDING.locked();
+        pending.push_back(LogEntry { level, text });
+        if pending.len() > MAX_ENTRIES {
+            pending.pop_front();
+        }
+    }
+}
+
+#[derive(Default)]
+struct PanelMessage {
+    text: String,
+    fields: String,
+}
+
+impl Visit for PanelMessage {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "message" => {
+                let _ = write!(self.text, "{:?}", value);
+            }
+            // Where a bridged log message came from, the panel doesn't show that
+            name if name.starts_with("log.") => {}
+            name => {
+                let _ = write!(self.fields, " {}={:?}", name, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{MainWindow, TargetPreset};
+use anyhow::{bail, Result};
+use rustyrabbit_core::config;
+use serde::{Deserialize, Serialize};
+use slint::{ModelRc, SharedString, VecModel};
+
+const TARGETS_FILE: &str = "targets.toml";
+
+// Calibration target dimensions, picked from a list instead of typed in every time
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Preset {
+    pub name: String,
+    // Index into the calibration type list: chess board, circle grid, ArUco
+    pub kind: i32,
+    // Inner corners (or circles) per column and row
+    pub rows: i32,
+    pub cols: i32,
+    // Square side, or circle spacing, in mm
+    pub square_mm: f64,
+    // ArUco dictionary for marker boards
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub dictionary: String,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CustomPresets {
+    #[serde(default)]
+    preset: Vec<Preset>,
+}
+
+fn built_in() -> Vec<Preset> {
+    let preset = |name: &str, kind, rows, cols, square_mm, dictionary: &str| Preset {
+        name: name.into(),
+        kind,
+        rows,
+        cols,
+        square_mm,
+        dictionary: dictionary.into(),
+    };
+    vec![
+        preset("Chess board 9x6, 25 mm", 0, 6, 9, 25.0, ""),
+        preset("Chess board 7x5, 30 mm (A4)", 0, 5, 7, 30.0, ""),
+        preset("Chess board 11x8, 15 mm (A4)", 0, 8, 11, 15.0, ""),
+        preset("Asymmetric circles 4x11, 20 mm (A4)", 1, 11, 4, 20.0, ""),
+        preset("ChArUco 5x7, 40 mm, DICT_6X6_250", 2, 6, 4, 40.0, "DICT_6X6_250"),
+    ]
+}
+
+// Built-in presets first, then the ones saved from the Calibration tab
+pub fn load() -> Vec<Preset> {
+    let custom = config::load::<CustomPresets>(TARGETS_FILE).unwrap_or_else(|e| {
+        log::warn!("Ignoring saved calibration targets: {:?}", e);
+        None
+    });
+    let mut presets = built_in();
+    presets.extend(custom.unwrap_or_default().preset);
+    presets
+}
+
+// Replaces a saved preset of the same name. Built-in names are kept for the built-ins.
+pub fn save_custom(preset: Preset) -> Result<()> {
+    if preset.name.trim().is_empty() {
+        bail!("Give the preset a name");
+    }
+    if built_in().iter().any(|built_in| built_in.name == preset.name) {
+        bail!("{} is a built-in preset, pick another name", preset.name);
+    }
+    let mut custom: CustomPresets = config::load(TARGETS_FILE)?.unwrap_or_default();
+    custom.preset.retain(|saved| saved.name != preset.name);
+    custom.preset.push(preset);
+    Ok(config::save(TARGETS_FILE, &custom)?)
+}
+
+pub fn publish(window: &MainWindow, presets: &[Preset]) {
+    let names: Vec<SharedString> = presets.iter().map(|preset| preset.name.as_str().into()).collect();
+    let rows: Vec<TargetPreset> = presets
+        .iter()
+        .map(|preset| TargetPreset {
+            kind: preset.kind,
+            rows: preset.rows,
+            cols: preset.cols,
+            square: format!("{}", preset.square_mm).into(),
+            dictionary: preset.dictionary.as_str().into(),
+        })
+        .collect();
+    window.set_target_preset_names(ModelRc::new(VecModel::from(names)));
+    window.set_target_presets(ModelRc::new(VecModel::from(rows)));
+}
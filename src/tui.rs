@@ -0,0 +1,200 @@
+use crate::{
+    cli::Args,
+    headless::{CalibrationRequest, HeadlessApp},
+    status_log,
+};
+use anyhow::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Color, Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, Gauge, List, Paragraph, Row, Table, Wrap},
+    DefaultTerminal,
+};
+use rustyrabbit_core::{calibration::Button, frame::FrameFeed, profiles::CameraRole};
+use std::{collections::VecDeque, sync::Arc, time::Duration};
+
+// Log lines kept for the bottom pane
+const LOG_LINES: usize = 200;
+// Redraw at least this often while waiting for keys
+const TICK: Duration = Duration::from_millis(100);
+
+struct Tui {
+    app: HeadlessApp,
+    request: CalibrationRequest,
+}
+
+// Camera selection, capture progress, per-view errors and the result over SSH, for machine
+// controllers without a display server
+pub fn run(
+    args: Arc<Args>,
+    role: CameraRole,
+    frames: FrameFeed,
+) -> Result<()> {
+    let request = CalibrationRequest::from_args(&args);
+    let tui = Tui {
+        app: HeadlessApp::start(args, role, frames)?,
+        request,
+    };
+
+    status_log::set_stderr_enabled(false);
+    let result = ratatui::try_init()
+        .map_err(anyhow::Error::from)
+        .and_then(|mut terminal| {
+            let result = tui.event_loop(&mut terminal);
+            ratatui::restore();
+            result
+        });
+    status_log::set_stderr_enabled(true);
+
+    tui.app.stop()?;
+    result
+}
+
+impl Tui {
+    fn event_loop(&self, terminal: &mut DefaultTerminal) -> Result<()> {
+        let mut log = VecDeque::new();
+        loop {
+            for entry in status_log::drain() {
+                log.push_back(entry.text);
+                if log.len() > LOG_LINES {
+                    log.pop_front();
+                }
+            }
+            terminal.draw(|frame| self.draw(frame, &log))?;
+
+            if !event::poll(TICK)? {
+                continue;
+            }
+            let Event::Key(key) = event::read()? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            let controls = &self.app.controls;
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Tab => self.app.switch_camera(),
+                KeyCode::Enter => {
+                    if let Err(e) = self.app.start_calibration(&self.request) {
+                        log::warn!("{}", e);
+                    }
+                }
+                KeyCode::Char(' ') => controls.press(Button::Capture),
+                KeyCode::Char('u') => controls.press(Button::Undo),
+                KeyCode::Char('f') => controls.press(Button::Finish),
+                KeyCode::Esc => controls.press(Button::Cancel),
+                _ => {}
+            }
+        }
+    }
+
+    fn draw(&self, frame: &mut ratatui::Frame, log: &VecDeque<String>) {
+        let role = self.app.role();
+        let state = self.app.report().state();
+        let request = &self.request;
+        let (cols, rows) = request.board;
+
+        let [header, progress, status, body, log_area] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Length(3),
+            Constraint::Min(8),
+            Constraint::Length(8),
+        ])
+        .areas(frame.area());
+
+        let capture_mode = if request.manual { "space to capture" } else { "automatic capture" };
+        let keys = if state.calibrating {
+            "Space capture  u undo  f finish  Esc cancel  q quit"
+        } else {
+            "Enter calibrate  Tab switch camera  q quit"
+        };
+        frame.render_widget(
+            Paragraph::new(vec![
+                Line::from(format!(
+                    "{}: {}x{} chess board, {} mm squares, {} views, {}",
+                    role.label(),
+                    cols,
+                    rows,
+                    request.square_mm,
+                    request.frames,
+                    capture_mode
+                ))
+                .bold(),
+                Line::from(keys),
+            ])
+            .block(Block::bordered().title(" Rusty Rabbit ")),
+            header,
+        );
+
+        let ratio = if state.required > 0 { state.captured as f64 / state.required as f64 } else { 0.0 };
+        frame.render_widget(
+            Gauge::default()
+                .block(Block::bordered().title(" Capture "))
+                .gauge_style(Style::default().fg(Color::Green))
+                .ratio(ratio.clamp(0.0, 1.0))
+                .label(format!("{} of {}", state.captured, state.required)),
+            progress,
+        );
+        frame.render_widget(
+            Paragraph::new(state.status.as_str())
+                .wrap(Wrap { trim: true })
+                .block(Block::bordered().title(format!(" Status: {} ", state.workflow.label()))),
+            status,
+        );
+
+        let [views_area, result_area] =
+            Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)]).areas(body);
+        let view_rows: Vec<Row> = state
+            .views
+            .iter()
+            .enumerate()
+            .map(|(index, (sequence, error))| {
+                let error = match error {
+                    Some(error) => format!("{:.3} px", error),
+                    None => "excluded".to_string(),
+                };
+                Row::new([format!("{}", index + 1), format!("{}", sequence), error])
+            })
+            .collect();
+        frame.render_widget(
+            Table::new(view_rows, [Constraint::Length(5), Constraint::Length(8), Constraint::Min(10)])
+                .header(Row::new(["View", "Frame", "Error"]).style(Style::new().add_modifier(Modifier::BOLD)))
+                .block(Block::bordered().title(" Views ")),
+            views_area,
+        );
+
+        let result: Vec<Line> = match &state.calibration {
+            Some(calibration) => {
+                let m = calibration.camera_matrix;
+                let (horizontal, vertical, diagonal) = calibration.field_of_view();
+                let distortion: Vec<String> = calibration.dist_coeffs.iter().map(|k| format!("{:.5}", k)).collect();
+                vec![
+                    Line::from(format!("Sensor         {}x{}", calibration.sensor_width, calibration.sensor_height)),
+                    Line::from(format!("fx, fy         {:.1}, {:.1} px", m[0][0], m[1][1])),
+                    Line::from(format!("cx, cy         {:.1}, {:.1} px", m[0][2], m[1][2])),
+                    Line::from(format!("Distortion     {}", distortion.join(", "))),
+                    Line::from(format!("RMS error      {:.3} px", calibration.rms)),
+                    Line::from(format!(
+                        "Field of view  {:.1} x {:.1} deg ({:.1} diagonal)",
+                        horizontal, vertical, diagonal
+                    )),
+                    Line::from(format!("Calibrated     {}", calibration.calibrated_at)),
+                ]
+            }
+            None => vec![Line::from(format!("The {} is not calibrated yet", role.label()))],
+        };
+        frame.render_widget(
+            Paragraph::new(result).wrap(Wrap { trim: false }).block(Block::bordered().title(" Result ")),
+            result_area,
+        );
+
+        // Newest at the bottom, as many as fit
+        let visible = log_area.height.saturating_sub(2) as usize;
+        let lines: Vec<&str> = log.iter().skip(log.len().saturating_sub(visible)).map(String::as_str).collect();
+        frame.render_widget(List::new(lines).block(Block::bordered().title(" Log ")), log_area);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::{CalibrationResult, CalibrationView, CapturedThumbnail, MainWindow, StageTiming};
+use anyhow::Result;
+use opencv::{core::Mat, prelude::*};
+use rustyrabbit_core::{
+    calibration::{CalibrationViews, CapturedView, SharedViews, MIN_CALIBRATION_FRAMES},
+    distortion_view,
+    events::{Event, EventSubscriber},
+    frame::Frame,
+    lock::Locked,
+    metrics,
+    pose_view,
+    profiles::{Calibration, CameraRole},
+};
+use slint::{Image, ModelRc, Rgba8Pixel, SharedPixelBuffer, VecModel};
+use std::thread;
+
+// Shows camera and calibration progress in the main window. Turning views into images takes
+// a moment, so this runs on a thread of its own rather than the UI thread.
+pub fn follow(window: slint::Weak<MainWindow>, mut events: EventSubscriber) {
+    thread::spawn(move || {
+        // The capture gallery, shown again after every change
+        let mut gallery: Vec<CapturedView> = Vec::new();
+        while let Some(event) = events.blocking_recv() {
+            show(&window, event, &mut gallery);
+        }
+    });
+}
+
+fn show(window: &slint::Weak<MainWindow>, event: Event, gallery: &mut Vec<CapturedView>) {
+    match event {
+        Event::CameraStateChanged { connected, status } => {
+            let _ = window.upgrade_in_event_loop(move |win| {
+                win.set_camera_connected(connected);
+                win.set_camera_status(status.into());
+            });
+        }
+        Event::RecordingChanged(active) => {
+            let _ = window.upgrade_in_event_loop(move |win| win.set_recording(active));
+        }
+        Event::CalibratingChanged(calibrating) => {
+            let _ = window.upgrade_in_event_loop(move |win| win.set_calibrating(calibrating));
+        }
+        Event::WorkflowChanged(state) => {
+            let index = state.index() as i32;
+            let _ = window.upgrade_in_event_loop(move |win| win.set_workflow_state(index));
+        }
+        Event::CalibrationProgress {
+            captured,
+            required,
+            status,
+        } => {
+            let progress = captured as f32 / required as f32;
+            let can_finish = captured >= MIN_CALIBRATION_FRAMES && captured < required;
+            let can_undo = captured > 0 && captured < required;
+            let _ = window.upgrade_in_event_loop(move |win| {
+                win.set_calibration_progress(progress);
+                win.set_can_finish_calibration(can_finish);
+                win.set_can_undo_capture(can_undo);
+                win.set_status(status.into());
+            });
+        }
+        Event::Status(status) | Event::Error(status) => {
+            let _ = window.upgrade_in_event_loop(move |win| win.set_status(status.into()));
+        }
+        Event::ViewsCleared => {
+            gallery.clear();
+            publish_gallery(window, gallery);
+        }
+        Event::ViewAccepted(view) => {
+            gallery.push(*view);
+            publish_gallery(window, gallery);
+        }
+        Event::ViewRemoved(index) => {
+            if index < gallery.len() {
+                gallery.remove(index);
+            }
+            publish_gallery(window, gallery);
+        }
+        Event::Solved { views, calibration } => {
+            publish_result(window, views.role(), Some(&calibration));
+            publish_views(&views, window);
+        }
+    }
+}
+
+// The strip of views taken so far while capturing
+fn publish_gallery(window: &slint::Weak<MainWindow>, views: &[CapturedView]) {
+    let thumbnails: Result<Vec<_>> = views
+        .iter()
+        .enumerate()
+        .map(|(index, view)| {
+            let label = format!("View {} (frame {})", index + 1, view.sequence());
+            Ok((to_buffer(view.thumbnail())?, to_buffer(view.enlarged())?, label))
+        })
+        .collect();
+    let thumbnails = match thumbnails {
+        Ok(thumbnails) => thumbnails,
+        Err(e) => {
+            log::error!("Error showing the captured views: {:?}", e);
+            return;
+        }
+    };
+    let _ = window.upgrade_in_event_loop(move |win| {
+        let thumbnails: Vec<CapturedThumbnail> = thumbnails
+            .into_iter()
+            .map(|(thumbnail, enlarged, label)| CapturedThumbnail {
+                thumbnail: Image::from_rgba8(thumbnail),
+                enlarged: Image::from_rgba8(enlarged),
+                label: label.into(),
+            })
+            .collect();
+        win.set_captured_thumbnails(ModelRc::new(VecModel::from(thumbnails)));
+    });
+}
+
+// The pipeline timings so far, for the Diagnostics tab
+pub fn stage_timings() -> ModelRc<StageTiming> {
+    let ms = |took: std::time::Duration| format!("{:.1} ms", took.as_secs_f64() * 1000.0);
+    let rows: Vec<StageTiming> = metrics::timings()
+        .into_iter()
+        .map(|timing| StageTiming {
+            stage: timing.stage.index() as i32,
+            mean: ms(timing.mean).into(),
+            p95: ms(timing.p95).into(),
+            max: ms(timing.max).into(),
+            count: timing.count.min(i32::MAX as u64) as i32,
+        })
+        .collect();
+    ModelRc::new(VecModel::from(rows))
+}
+
+pub fn to_image(frame: &Frame) -> Image {
+    Image::from_rgba8(SharedPixelBuffer::<Rgba8Pixel>::clone_from_slice(
+        frame.data.as_slice(),
+        frame.width as u32,
+        frame.height as u32,
+    ))
+}
+
+// Images can only be created on the UI thread, pixel buffers travel there instead
+fn to_buffer(rgba: &Mat) -> Result<SharedPixelBuffer<Rgba8Pixel>> {
+    Ok(SharedPixelBuffer::clone_from_slice(
+        rgba.data_bytes()?,
+        rgba.cols() as u32,
+        rgba.rows() as u32,
+    ))
+}
+
+// Fills the results panel, or clears it for a camera without a calibration
+pub fn publish_result(window: &slint::Weak<MainWindow>, role: CameraRole, calibration: Option<&Calibration>) {
+    let result = calibration.map(|calibration| {
+        let m = calibration.camera_matrix;
+        let (horizontal, vertical, diagonal) = calibration.field_of_view();
+        let distortion: Vec<String> = calibration.dist_coeffs.iter().map(|k| format!("{:.5}", k)).collect();
+        CalibrationResult {
+            camera: role.label().into(),
+            sensor: format!("{}x{}", calibration.sensor_width, calibration.sensor_height).into(),
+            fx: m[0][0] as f32,
+            fy: m[1][1] as f32,
+            cx: m[0][2] as f32,
+            cy: m[1][2] as f32,
+            distortion: distortion.join(", ").into(),
+            rms: calibration.rms as f32,
+            fov: format!("{:.1}° x {:.1}° ({:.1}° diagonal)", horizontal, vertical, diagonal).into(),
+            calibrated_at: calibration.calibrated_at.clone().into(),
+        }
+    });
+    let distortion = calibration.and_then(|calibration| {
+        distortion_view::render(calibration)
+            .and_then(|grid| to_buffer(&grid))
+            .map_err(|e| log::error!("Error drawing the distortion grid: {:?}", e))
+            .ok()
+    });
+    let _ = window.upgrade_in_event_loop(move |win| {
+        win.set_has_calibration_result(result.is_some());
+        win.set_calibration_result(result.unwrap_or_default());
+        win.set_distortion_image(distortion.map(Image::from_rgba8).unwrap_or_default());
+    });
+}
+
+// Fills the per-view table on the Calibration tab
+fn publish_views(set: &CalibrationViews, window: &slint::Weak<MainWindow>) {
+    let rows: Result<Vec<_>> = set
+        .views()
+        .iter()
+        .enumerate()
+        .map(|(index, view)| {
+            let label = match view.error() {
+                Some(error) => format!("View {} (frame {}): {:.3} px", index + 1, view.sequence(), error),
+                None => format!("View {} (frame {}): excluded", index + 1, view.sequence()),
+            };
+            Ok((to_buffer(view.thumbnail())?, label, view.error().unwrap_or(0.0) as f32, view.included()))
+        })
+        .collect();
+    let rows = rows.unwrap_or_else(|e| {
+        log::error!("Error showing the calibration views: {:?}", e);
+        Vec::new()
+    });
+    let poses = render_poses(set, pose_view::DEFAULT_AZIMUTH).unwrap_or_else(|e| {
+        log::error!("Error drawing the board poses: {:?}", e);
+        None
+    });
+    let _ = window.upgrade_in_event_loop(move |win| {
+        if let Some(poses) = poses {
+            win.set_pose_image(Image::from_rgba8(poses));
+        }
+        let views: Vec<CalibrationView> = rows
+            .into_iter()
+            .map(|(thumbnail, label, error, included)| CalibrationView {
+                thumbnail: Image::from_rgba8(thumbnail),
+                label: label.into(),
+                error,
+                included,
+            })
+            .collect();
+        win.set_calibration_views(ModelRc::new(VecModel::from(views)));
+        win.set_undistort_available(true);
+    });
+}
+
+// Board poses of the last calibration seen from another angle, for the orbit slider
+pub fn rotate_poses(views: &SharedViews, azimuth: f64) -> Option<Image> {
+    let views = views.locked();
+    match render_poses(views.as_ref()?, azimuth) {
+        Ok(poses) => poses.map(Image::from_rgba8),
+        Err(e) => {
+            log::error!("Error drawing the board poses: {:?}", e);
+            None
+        }
+    }
+}
+
+fn render_poses(set: &CalibrationViews, azimuth: f64) -> Result<Option<SharedPixelBuffer<Rgba8Pixel>>> {
+    set.render_poses(azimuth)?.map(|poses| to_buffer(&poses)).transpose()
+}
@@ -0,0 +1,437 @@
+use crate::{
+    api,
+    cli::Args,
+    headless::{CalibrationRequest, HeadlessApp},
+    status_log,
+};
+use anyhow::{anyhow, bail, Result};
+use opencv::{
+    core::{Mat, Vector},
+    imgcodecs::{imencode, IMWRITE_JPEG_QUALITY, IMWRITE_WEBP_QUALITY},
+    imgproc::{cvt_color, COLOR_RGBA2BGR},
+    prelude::*,
+};
+use rustyrabbit_core::{
+    calibration::Button,
+    frame::{Frame, FrameFeed},
+    lock::Locked,
+    metrics::{self, Stage},
+    profiles::CameraRole,
+};
+use serde_json::json;
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read},
+    sync::{Arc, Condvar, Mutex, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tungstenite::{handshake::derive_accept_key, protocol::Role, Message, WebSocket};
+
+const PAGE: &str = include_str!("../web/index.html");
+const BOUNDARY: &str = "rustyrabbitframe";
+const JPEG_QUALITY: i32 = 80;
+// Smaller than the JPEG at the same look, for viewers on a slow link
+const WEBP_QUALITY: i32 = 75;
+// How often the camera is checked for a new frame
+const POLL: Duration = Duration::from_millis(30);
+// A stream ends after this long without a new frame, the camera stopped or went away
+const STALLED: Duration = Duration::from_secs(5);
+// Log lines sent with the status
+const LOG_LINES: usize = 100;
+
+// How a viewer gets the preview: the image format and whether a running calibration's
+// annotations are drawn over it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct View {
+    encoding: Encoding,
+    overlay: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Encoding {
+    Jpeg,
+    WebP,
+}
+
+impl View {
+    // From a stream's query, e.g. ?format=webp&overlay=0. Overlays are drawn unless turned off.
+    fn from_query(query: &str) -> Result<Self> {
+        let mut view = View {
+            encoding: Encoding::Jpeg,
+            overlay: true,
+        };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            match (key, value) {
+                ("format", "jpeg" | "jpg") => view.encoding = Encoding::Jpeg,
+                ("format", "webp") => view.encoding = Encoding::WebP,
+                ("format", _) => bail!("Unknown preview format {:?}, use jpeg or webp", value),
+                ("overlay", _) => view.overlay = !matches!(value, "0" | "false" | "off"),
+                _ => {}
+            }
+        }
+        Ok(view)
+    }
+
+    fn encode(self, frame: &Frame) -> Result<Vec<u8>> {
+        let rendering = Instant::now();
+        let pixels = Mat::from_slice(frame.data.as_slice())?;
+        let mut bgr = Mat::default();
+        cvt_color(&*pixels.reshape(4, frame.height)?, &mut bgr, COLOR_RGBA2BGR, 0)?;
+        let (extension, parameters) = match self.encoding {
+            Encoding::Jpeg => (".jpg", [IMWRITE_JPEG_QUALITY, JPEG_QUALITY]),
+            Encoding::WebP => (".webp", [IMWRITE_WEBP_QUALITY, WEBP_QUALITY]),
+        };
+        let mut encoded: Vector<u8> = Vector::new();
+        imencode(extension, &bgr, &mut encoded, &Vector::from_slice(&parameters))?;
+        metrics::record_since(Stage::Render, rendering);
+        Ok(encoded.to_vec())
+    }
+}
+
+// The newest preview frame, encoded once for each view the streams ask for. The counter
+// lets every stream send each frame once.
+#[derive(Default)]
+struct Preview {
+    sequence: u64,
+    // As the camera delivered it, and with the calibration's annotations while it runs
+    frame: Option<Frame>,
+    annotated: Option<Frame>,
+    encoded: HashMap<View, Arc<Vec<u8>>>,
+    // Set when the server shuts down, no frame comes after it
+    stopped: bool,
+}
+
+#[derive(Default)]
+struct PreviewFeed {
+    latest: Mutex<Preview>,
+    updated: Condvar,
+}
+
+impl PreviewFeed {
+    fn publish(&self, frame: Frame, annotated: Option<Frame>) {
+        let mut latest = self.latest.locked();
+        latest.sequence += 1;
+        latest.frame = Some(frame);
+        latest.annotated = annotated;
+        latest.encoded.clear();
+        self.updated.notify_all();
+    }
+
+    fn stop(&self) {
+        self.latest.locked().stopped = true;
+        self.updated.notify_all();
+    }
+
+    // Waits for a frame after `sent`, and returns its sequence and image, or None once the
+    // feed stopped or no frame came for STALLED. The first stream to ask for a view encodes
+    // it, outside the lock.
+    fn next(&self, sent: u64, view: View) -> Result<Option<(u64, Arc<Vec<u8>>)>> {
+        let deadline = Instant::now() + STALLED;
+        let mut latest = self.latest.locked();
+        while latest.sequence == sent || latest.frame.is_none() {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if latest.stopped || left.is_zero() {
+                return Ok(None);
+            }
+            latest = self.updated.wait_timeout(latest, left).unwrap_or_else(PoisonError::into_inner).0;
+        }
+        // Without annotations both views are the same image
+        let view = View {
+            overlay: view.overlay && latest.annotated.is_some(),
+            ..view
+        };
+        let sequence = latest.sequence;
+        if let Some(image) = latest.encoded.get(&view) {
+            return Ok(Some((sequence, Arc::clone(image))));
+        }
+        let frame = if view.overlay { latest.annotated.clone() } else { latest.frame.clone() };
+        drop(latest);
+
+        let image = Arc::new(view.encode(&frame.expect("checked above"))?);
+        let mut latest = self.latest.locked();
+        if latest.sequence == sequence {
+            latest.encoded.insert(view, Arc::clone(&image));
+        }
+        Ok(Some((sequence, image)))
+    }
+}
+
+// Everything the request threads share
+struct WebApp {
+    app: HeadlessApp,
+    defaults: CalibrationRequest,
+    // Asked of every request to /api/ when set
+    api_token: Option<String>,
+    feed: PreviewFeed,
+    log: Mutex<VecDeque<String>>,
+}
+
+// Serves the preview as an MJPEG stream and the calibration controls to a browser, for
+// running on the machine's SBC and operating it from another computer
+pub fn run(
+    args: Arc<Args>,
+    role: CameraRole,
+    frames: FrameFeed,
+) -> Result<()> {
+    let Some(address) = args.web.clone() else {
+        bail!("No address to serve on");
+    };
+    let server = Server::http(&address).map_err(|e| anyhow!("Unable to listen on {}: {}", address, e))?;
+    let web = Arc::new(WebApp {
+        defaults: CalibrationRequest::from_args(&args),
+        api_token: args.api_token.clone(),
+        app: HeadlessApp::start(args, role, frames)?,
+        feed: PreviewFeed::default(),
+        log: Mutex::new(VecDeque::new()),
+    });
+    log::info!("Serving the browser interface on http://{}/", address);
+
+    let web_for_frames = Arc::clone(&web);
+    thread::spawn(move || loop {
+        web_for_frames.update();
+        thread::sleep(POLL);
+    });
+
+    // The preview stream holds its request open, so every request gets its own thread
+    for request in server.incoming_requests() {
+        let web = Arc::clone(&web);
+        thread::spawn(move || {
+            if let Err(e) = web.handle(request) {
+                log::warn!("Error answering a browser request: {:?}", e);
+            }
+        });
+    }
+    web.feed.stop();
+    web.app.stop()
+}
+
+impl WebApp {
+    // Takes the newest frame and the log messages since the last call
+    fn update(&self) {
+        let entries = status_log::drain();
+        if !entries.is_empty() {
+            let mut log = self.log.locked();
+            log.extend(entries.into_iter().map(|entry| entry.text));
+            while log.len() > LOG_LINES {
+                log.pop_front();
+            }
+        }
+
+        if let Some(frame) = self.app.next_raw_frame() {
+            let annotated = self.app.annotate(&frame);
+            self.feed.publish(frame, annotated);
+        }
+    }
+
+    fn handle(self: &Arc<Self>, request: Request) -> Result<()> {
+        let url = request.url().to_string();
+        let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+        if path.starts_with("/api/") {
+            return api::handle(&self.app, &self.defaults, self.api_token.as_deref(), request);
+        }
+        let button = match path {
+            "/capture" => Some(Button::Capture),
+            "/undo" => Some(Button::Undo),
+            "/finish" => Some(Button::Finish),
+            "/cancel" => Some(Button::Cancel),
+            _ => None,
+        };
+        let response = match (request.method(), path, button) {
+            (Method::Get, "/", _) => {
+                Response::from_string(PAGE).with_header(header("Content-Type", "text/html; charset=utf-8"))
+            }
+            (Method::Get, "/stream", _) => {
+                let view = match View::from_query(query) {
+                    Ok(view) => view,
+                    Err(e) => return bad_request(request, e),
+                };
+                let stream = MjpegStream {
+                    web: Arc::clone(self),
+                    view: View {
+                        encoding: Encoding::Jpeg,
+                        ..view
+                    },
+                    sent: 0,
+                    pending: Vec::new(),
+                    offset: 0,
+                };
+                let content_type = format!("multipart/x-mixed-replace; boundary={}", BOUNDARY);
+                return Ok(request.respond(Response::new(
+                    StatusCode(200),
+                    vec![header("Content-Type", &content_type), header("Cache-Control", "no-store")],
+                    stream,
+                    None,
+                    None,
+                ))?);
+            }
+            (Method::Get, "/ws", _) => return self.stream_websocket(request, query),
+            (Method::Get, "/status", _) => Response::from_string(self.status().to_string())
+                .with_header(header("Content-Type", "application/json")),
+            // Time spent in each stage of the pipeline, for Prometheus or a look with curl
+            (Method::Get, "/metrics", _) => Response::from_string(metrics::prometheus())
+                .with_header(header("Content-Type", "text/plain; version=0.0.4")),
+            (Method::Post, "/calibrate", _) => match calibration_request(query, &self.defaults) {
+                Ok(calibration) => match self.app.start_calibration(&calibration) {
+                    Ok(()) => Response::from_string(""),
+                    Err(e) => Response::from_string(e.to_string()).with_status_code(StatusCode(409)),
+                },
+                Err(e) => Response::from_string(e.to_string()).with_status_code(StatusCode(400)),
+            },
+            (Method::Post, _, Some(button)) => {
+                self.app.controls.press(button);
+                Response::from_string("")
+            }
+            (Method::Post, "/switch-camera", _) => {
+                self.app.switch_camera();
+                Response::from_string("")
+            }
+            _ => Response::from_string("Not found").with_status_code(StatusCode(404)),
+        };
+        request.respond(response)?;
+        Ok(())
+    }
+
+    // Sends the preview as one binary message per frame until the viewer goes away, for
+    // browsers and HMIs that would rather not hold a multipart response open. A ping follows
+    // every frame and the next frame waits for its pong, which viewers send on their own: a
+    // slow link gets fewer frames rather than a growing backlog, and a viewer closing is seen.
+    fn stream_websocket(&self, request: Request, query: &str) -> Result<()> {
+        let view = match View::from_query(query) {
+            Ok(view) => view,
+            Err(e) => return bad_request(request, e),
+        };
+        let key = request
+            .headers()
+            .iter()
+            .find(|header| header.field.equiv("Sec-WebSocket-Key"))
+            .map(|header| header.value.to_string());
+        let Some(key) = key else {
+            return bad_request(request, "Expected a WebSocket upgrade");
+        };
+        let accept = header("Sec-WebSocket-Accept", &derive_accept_key(key.as_bytes()));
+        let stream = request.upgrade("websocket", Response::empty(StatusCode(101)).with_header(accept));
+        let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+        let mut sent = 0;
+        while let Some((sequence, image)) = self.feed.next(sent, view)? {
+            sent = sequence;
+            let sending = socket.send(Message::Binary(image.to_vec()));
+            if sending.and_then(|()| socket.send(Message::Ping(Vec::new()))).is_err() {
+                return Ok(());
+            }
+            loop {
+                match socket.read() {
+                    Ok(Message::Pong(_)) => break,
+                    // tungstenite queues the answer to a close, flushing sends it
+                    Ok(Message::Close(_)) => {
+                        let _ = socket.flush();
+                        return Ok(());
+                    }
+                    // Pings are answered the same way, anything else the viewer sends is ignored
+                    Ok(_) => {}
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+        // The camera stopped, close the connection the way the protocol asks
+        let _ = socket.close(None);
+        while socket.read().is_ok() {}
+        Ok(())
+    }
+
+    fn status(&self) -> serde_json::Value {
+        let role = self.app.role();
+        let state = self.app.report().state();
+        let views: Vec<_> = state
+            .views
+            .iter()
+            .map(|(frame, error)| json!({ "frame": frame, "error": error }))
+            .collect();
+        let (cols, rows) = self.defaults.board;
+        json!({
+            "role": role.label(),
+            "calibrating": state.calibrating,
+            "workflow": state.workflow.label(),
+            "captured": state.captured,
+            "required": state.required,
+            "status": state.status,
+            "views": views,
+            "result": state.calibration,
+            "request": {
+                "cols": cols,
+                "rows": rows,
+                "square_mm": self.defaults.square_mm,
+                "frames": self.defaults.frames,
+                "manual": self.defaults.manual,
+                "countdown": self.defaults.countdown,
+            },
+            "log": &*self.log.locked(),
+        })
+    }
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name.as_bytes(), value.as_bytes()).expect("valid header")
+}
+
+fn bad_request(request: Request, message: impl ToString) -> Result<()> {
+    Ok(request.respond(Response::from_string(message.to_string()).with_status_code(StatusCode(400)))?)
+}
+
+// Board and capture settings from the page's form, anything left out keeps the command
+// line value
+fn calibration_request(query: &str, defaults: &CalibrationRequest) -> Result<CalibrationRequest> {
+    let mut request = defaults.clone();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let number = |what: &str| value.parse::<f64>().map_err(|_| anyhow!("{} {:?} is not a number", what, value));
+        match key {
+            "cols" => request.board.0 = number("Columns")? as i32,
+            "rows" => request.board.1 = number("Rows")? as i32,
+            "square" => request.square_mm = number("Square size")?,
+            "frames" => request.frames = number("Frames")? as usize,
+            "manual" => request.manual = value == "1" || value == "true" || value == "on",
+            "countdown" => request.countdown = number("Countdown")? as u64,
+            _ => {}
+        }
+    }
+    request.check()?;
+    Ok(request)
+}
+
+// Waits for the next preview frame, then hands it out as one part of the multipart
+// response. Ends when the browser goes away and the write fails, or the camera stops.
+struct MjpegStream {
+    web: Arc<WebApp>,
+    view: View,
+    // Sequence of the last frame sent
+    sent: u64,
+    pending: Vec<u8>,
+    offset: usize,
+}
+
+impl Read for MjpegStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.pending.len() {
+            let Some((sequence, jpeg)) = self.web.feed.next(self.sent, self.view).map_err(io::Error::other)? else {
+                return Ok(0);
+            };
+            self.sent = sequence;
+            self.pending = format!(
+                "--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n",
+                BOUNDARY,
+                jpeg.len()
+            )
+            .into_bytes();
+            self.pending.extend_from_slice(&jpeg);
+            self.pending.extend_from_slice(b"\r\n");
+            self.offset = 0;
+        }
+        let count = (self.pending.len() - self.offset).min(buf.len());
+        buf[..count].copy_from_slice(&self.pending[self.offset..self.offset + count]);
+        self.offset += count;
+        Ok(count)
+    }
+}